@@ -0,0 +1,70 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "src/agent_stats.h"
+
+#include <sstream>
+
+#include "third_party/javaprofiler/heap_sampler.h"
+#include "third_party/javaprofiler/memory_budget.h"
+#include "third_party/javaprofiler/sample_budget.h"
+
+namespace cloud {
+namespace profiler {
+
+namespace {
+
+void AddCounter(std::ostringstream *out, const char *name, const char *help,
+                int64_t value) {
+  *out << "# HELP " << name << " " << help << "\n"
+       << "# TYPE " << name << " counter\n"
+       << name << " " << value << "\n";
+}
+
+}  // namespace
+
+AgentStats *AgentStats::Get() {
+  static AgentStats stats;
+  return &stats;
+}
+
+std::string AgentStats::PrometheusText() const {
+  std::ostringstream out;
+  AddCounter(&out, "cprof_profiles_collected_total",
+             "Number of profiles collected.", ProfilesCollected());
+  AddCounter(&out, "cprof_collection_failures_total",
+             "Number of failed profile collections.", CollectionFailures());
+  AddCounter(&out, "cprof_profiles_uploaded_total",
+             "Number of profiles uploaded.", ProfilesUploaded());
+  AddCounter(&out, "cprof_upload_failures_total",
+             "Number of failed profile uploads.", UploadFailures());
+  AddCounter(&out, "cprof_uploaded_bytes_total",
+             "Number of profile bytes uploaded.", UploadedBytes());
+  AddCounter(&out, "cprof_budget_dropped_samples_total",
+             "Number of samples dropped by the global sample budget.",
+             google::javaprofiler::SampleBudget::DroppedCount());
+  AddCounter(&out, "cprof_heap_null_object_samples_total",
+             "Number of heap samples dropped for a null object.",
+             google::javaprofiler::HeapMonitor::DroppedNullObjectCount());
+  AddCounter(&out, "cprof_heap_zero_size_samples_total",
+             "Number of heap samples dropped for a zero size.",
+             google::javaprofiler::HeapMonitor::DroppedZeroSizeCount());
+  AddCounter(&out, "cprof_memory_budget_rejected_total",
+             "Number of samples dropped by the agent memory budget.",
+             google::javaprofiler::MemoryBudget::RejectedCount());
+  return out.str();
+}
+
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,94 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_AGENT_STATS_H_
+#define CLOUD_PROFILER_AGENT_JAVA_AGENT_STATS_H_
+
+#include <atomic>
+#include <string>
+
+#include "src/globals.h"
+
+namespace cloud {
+namespace profiler {
+
+// AgentStats holds counters describing the health of the agent. The counters
+// are updated atomically and can be read from any thread.
+class AgentStats {
+ public:
+  AgentStats()
+      : profiles_collected_(),
+        collection_failures_(),
+        profiles_uploaded_(),
+        upload_failures_(),
+        uploaded_bytes_(),
+        samples_collected_(),
+        phase_("starting"),
+        next_upload_ns_() {}
+
+  // Returns the process-wide instance.
+  static AgentStats *Get();
+
+  void RecordCollection(bool ok) {
+    (ok ? profiles_collected_ : collection_failures_)++;
+  }
+  void RecordUpload(bool ok, int64_t bytes) {
+    if (ok) {
+      profiles_uploaded_++;
+      uploaded_bytes_ += bytes;
+    } else {
+      upload_failures_++;
+    }
+  }
+
+  void RecordSamples(int64_t count) { samples_collected_ += count; }
+
+  // Sets the phase of the profiling loop, a string literal, with the
+  // monotonic time of the next profile upload when known, 0 otherwise.
+  void SetPhase(const char *phase, int64_t next_upload_ns = 0) {
+    next_upload_ns_ = next_upload_ns;
+    phase_ = phase;
+  }
+
+  int64_t ProfilesCollected() const { return profiles_collected_; }
+  int64_t CollectionFailures() const { return collection_failures_; }
+  int64_t ProfilesUploaded() const { return profiles_uploaded_; }
+  int64_t UploadFailures() const { return upload_failures_; }
+  int64_t UploadedBytes() const { return uploaded_bytes_; }
+  int64_t SamplesCollected() const { return samples_collected_; }
+  const char *Phase() const { return phase_; }
+  int64_t NextUploadNanos() const { return next_upload_ns_; }
+
+  // Returns the counters in the Prometheus text exposition format.
+  std::string PrometheusText() const;
+
+ private:
+  std::atomic<int64_t> profiles_collected_;
+  std::atomic<int64_t> collection_failures_;
+  std::atomic<int64_t> profiles_uploaded_;
+  std::atomic<int64_t> upload_failures_;
+  std::atomic<int64_t> uploaded_bytes_;
+  std::atomic<int64_t> samples_collected_;
+  std::atomic<const char *> phase_;
+  std::atomic<int64_t> next_upload_ns_;
+
+  DISALLOW_COPY_AND_ASSIGN(AgentStats);
+};
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_AGENT_STATS_H_
@@ -0,0 +1,80 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/agent_stats.h"
+
+#include <string>
+
+#include "gtest/gtest.h"
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+bool Contains(const std::string &text, const std::string &part) {
+  return text.find(part) != std::string::npos;
+}
+
+TEST(AgentStatsTest, PrometheusTextStartsAtZero) {
+  AgentStats stats;
+  std::string text = stats.PrometheusText();
+  EXPECT_TRUE(Contains(text, "\ncprof_profiles_collected_total 0\n")) << text;
+  EXPECT_TRUE(Contains(text, "\ncprof_collection_failures_total 0\n"));
+  EXPECT_TRUE(Contains(text, "\ncprof_profiles_uploaded_total 0\n"));
+  EXPECT_TRUE(Contains(text, "\ncprof_upload_failures_total 0\n"));
+  EXPECT_TRUE(Contains(text, "\ncprof_uploaded_bytes_total 0\n"));
+}
+
+TEST(AgentStatsTest, PrometheusTextHasTheCounters) {
+  AgentStats stats;
+  stats.RecordCollection(true);
+  stats.RecordCollection(true);
+  stats.RecordCollection(false);
+  stats.RecordUpload(true, 100);
+  stats.RecordUpload(true, 23);
+  stats.RecordUpload(false, 1000);
+
+  std::string text = stats.PrometheusText();
+  EXPECT_TRUE(Contains(text, "\ncprof_profiles_collected_total 2\n")) << text;
+  EXPECT_TRUE(Contains(text, "\ncprof_collection_failures_total 1\n"));
+  EXPECT_TRUE(Contains(text, "\ncprof_profiles_uploaded_total 2\n"));
+  EXPECT_TRUE(Contains(text, "\ncprof_upload_failures_total 1\n"));
+  // The bytes of the failed uploads are not counted.
+  EXPECT_TRUE(Contains(text, "\ncprof_uploaded_bytes_total 123\n"));
+}
+
+TEST(AgentStatsTest, PrometheusTextDescribesEveryCounter) {
+  AgentStats stats;
+  std::string text = stats.PrometheusText();
+  for (const char *name :
+       {"cprof_profiles_collected_total", "cprof_collection_failures_total",
+        "cprof_profiles_uploaded_total", "cprof_upload_failures_total",
+        "cprof_uploaded_bytes_total", "cprof_budget_dropped_samples_total",
+        "cprof_heap_null_object_samples_total",
+        "cprof_heap_zero_size_samples_total",
+        "cprof_memory_budget_rejected_total"}) {
+    std::string counter(name);
+    EXPECT_TRUE(Contains(text, "# HELP " + counter + " ")) << counter;
+    EXPECT_TRUE(Contains(text, "# TYPE " + counter + " counter\n")) << counter;
+  }
+  // Every line is a comment or a sample, and the text ends with a newline.
+  ASSERT_FALSE(text.empty());
+  EXPECT_EQ('\n', text.back());
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
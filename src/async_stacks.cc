@@ -0,0 +1,226 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "src/async_stacks.h"
+
+#include <memory>
+#include <mutex>  // NOLINT(build/c++11)
+#include <unordered_map>
+
+#include "src/jni_env.h"
+
+namespace cloud {
+namespace profiler {
+
+namespace {
+
+// Maximum number of frames of a recorded submitter stack.
+const int kMaxAsyncFrames = 64;
+
+// Maximum number of submitted tasks waiting to run with a recorded stack.
+// The tasks submitted beyond it are not stitched.
+const size_t kMaxPendingTasks = 16 * 1024;
+
+struct ParentStack {
+  int num_frames;
+  JVMPI_CallFrame frames[kMaxAsyncFrames];
+};
+
+// The parent stack of the task the current thread runs. Only the thread
+// itself updates it, and the signal handler on that thread reads it.
+#if defined(JAVAPROFILER_GLOBAL_DYNAMIC_TLS) || defined(ALPINE)
+__thread ParentStack *parent_stack __attribute__((tls_model("global-dynamic")));
+#else
+__thread ParentStack *parent_stack __attribute__((tls_model("initial-exec")));
+#endif
+
+// The recorded stacks of the submitted tasks, by the JVMTI tag set on the
+// task object. Leaked, to be usable until the very end of the process.
+std::mutex pending_mutex;
+std::unordered_map<jlong, std::unique_ptr<ParentStack>> *pending =
+    new std::unordered_map<jlong, std::unique_ptr<ParentStack>>();
+jlong next_tag = 1;
+bool pending_full_logged = false;
+
+// Replaces the parent stack of the current thread, taking ownership of the
+// new one, which may be null.
+void SetParentStack(ParentStack *stack) {
+  ParentStack *old = parent_stack;
+  parent_stack = nullptr;
+  // Make sure a signal handler on this thread sees either stack complete.
+  std::atomic_signal_fence(std::memory_order_seq_cst);
+  delete old;
+  parent_stack = stack;
+}
+
+// Records the stack of the thread submitting the task at slot of the top
+// frame.
+void RecordSubmitter(jvmtiEnv *jvmti, JNIEnv *jni, jthread thread, jint slot) {
+  jobject task;
+  if (jvmti->GetLocalObject(thread, 0, slot, &task) != JVMTI_ERROR_NONE ||
+      task == nullptr) {
+    return;
+  }
+  jvmtiFrameInfo frames[kMaxAsyncFrames];
+  jint count = 0;
+  if (jvmti->GetStackTrace(thread, 0, kMaxAsyncFrames, frames, &count) ==
+      JVMTI_ERROR_NONE) {
+    std::unique_ptr<ParentStack> stack(new ParentStack);
+    stack->num_frames = count;
+    for (int i = 0; i < count; i++) {
+      // Like for AsyncGetCallTrace, the line number is the bytecode index.
+      stack->frames[i] = JVMPI_CallFrame{static_cast<jint>(frames[i].location),
+                                         frames[i].method};
+    }
+    jlong old_tag = 0;
+    jvmti->GetTag(task, &old_tag);
+    std::lock_guard<std::mutex> lock(pending_mutex);
+    if (old_tag != 0) {
+      // Submitted again before it ran.
+      pending->erase(old_tag);
+    }
+    if (pending->size() < kMaxPendingTasks) {
+      jlong tag = next_tag++;
+      if (jvmti->SetTag(task, tag) == JVMTI_ERROR_NONE) {
+        pending->emplace(tag, std::move(stack));
+      }
+    } else if (!pending_full_logged) {
+      pending_full_logged = true;
+      LOG(WARNING) << "Over " << kMaxPendingTasks << " submitted tasks are "
+                   << "waiting to run, not stitching the async stacks of the "
+                   << "newly submitted ones";
+    }
+  }
+  jni->DeleteLocalRef(task);
+}
+
+// Makes the recorded stack of the task at slot of the top frame, if any, the
+// parent stack of the current thread.
+void StartTask(jvmtiEnv *jvmti, JNIEnv *jni, jthread thread, jint slot) {
+  std::unique_ptr<ParentStack> stack;
+  jobject task;
+  if (jvmti->GetLocalObject(thread, 0, slot, &task) == JVMTI_ERROR_NONE &&
+      task != nullptr) {
+    jlong tag = 0;
+    if (jvmti->GetTag(task, &tag) == JVMTI_ERROR_NONE && tag != 0) {
+      jvmti->SetTag(task, 0);
+      std::lock_guard<std::mutex> lock(pending_mutex);
+      auto it = pending->find(tag);
+      if (it != pending->end()) {
+        stack = std::move(it->second);
+        pending->erase(it);
+      }
+    }
+    jni->DeleteLocalRef(task);
+  }
+  SetParentStack(stack.release());
+}
+
+}  // namespace
+
+std::atomic<jvmtiEnv *> AsyncStacks::jvmti_;
+jmethodID AsyncStacks::execute_;
+jmethodID AsyncStacks::before_execute_;
+jmethodID AsyncStacks::after_execute_;
+
+void AsyncStacks::AddCapabilities(jvmtiCapabilities *caps) {
+  caps->can_generate_breakpoint_events = 1;
+  caps->can_access_local_variables = 1;
+  caps->can_tag_objects = 1;
+}
+
+bool AsyncStacks::Supported(const jvmtiCapabilities &potential) {
+  return potential.can_generate_breakpoint_events &&
+         potential.can_access_local_variables && potential.can_tag_objects;
+}
+
+bool AsyncStacks::Enable(jvmtiEnv *jvmti, JNIEnv *jni) {
+  jclass executor = jni->FindClass("java/util/concurrent/ThreadPoolExecutor");
+  if (executor == nullptr) {
+    ClearJNIException(jni, "find ThreadPoolExecutor");
+    LOG(ERROR) << "Failed to find ThreadPoolExecutor, not stitching the "
+               << "async stacks";
+    return false;
+  }
+  execute_ = jni->GetMethodID(executor, "execute", "(Ljava/lang/Runnable;)V");
+  before_execute_ = jni->GetMethodID(
+      executor, "beforeExecute", "(Ljava/lang/Thread;Ljava/lang/Runnable;)V");
+  after_execute_ = jni->GetMethodID(
+      executor, "afterExecute", "(Ljava/lang/Runnable;Ljava/lang/Throwable;)V");
+  jni->DeleteLocalRef(executor);
+  if (execute_ == nullptr || before_execute_ == nullptr ||
+      after_execute_ == nullptr) {
+    ClearJNIException(jni, "find the ThreadPoolExecutor methods");
+    LOG(ERROR) << "Failed to find the ThreadPoolExecutor methods, not "
+               << "stitching the async stacks";
+    return false;
+  }
+
+  jvmti_ = jvmti;
+  for (jmethodID method : {execute_, before_execute_, after_execute_}) {
+    jvmtiError err = jvmti->SetBreakpoint(method, 0);
+    if (err != JVMTI_ERROR_NONE) {
+      LOG(ERROR) << "Failed to set the ThreadPoolExecutor breakpoints with "
+                 << "error " << err << ", not stitching the async stacks";
+      for (jmethodID m : {execute_, before_execute_, after_execute_}) {
+        jvmti->ClearBreakpoint(m, 0);
+      }
+      jvmti_ = nullptr;
+      return false;
+    }
+  }
+  LOG(INFO) << "Stitching the async stacks of the ThreadPoolExecutor tasks";
+  return true;
+}
+
+void JNICALL AsyncStacks::OnBreakpoint(jvmtiEnv *jvmti, JNIEnv *jni,
+                                       jthread thread, jmethodID method,
+                                       jlocation location) {
+  IMPLICITLY_USE(location);
+  if (jvmti_ == nullptr) {
+    return;
+  }
+  // The slots of the Runnable parameters, after the receiver.
+  if (method == execute_) {
+    RecordSubmitter(jvmti, jni, thread, 1);
+  } else if (method == before_execute_) {
+    StartTask(jvmti, jni, thread, 2);
+  } else if (method == after_execute_) {
+    SetParentStack(nullptr);
+  }
+}
+
+void AsyncStacks::OnThreadEnd() {
+  if (parent_stack != nullptr) {
+    SetParentStack(nullptr);
+  }
+}
+
+void AsyncStacks::AppendParentFrames(
+    google::javaprofiler::JVMPI_CallTrace *trace, int max_frames) {
+  const ParentStack *stack = parent_stack;
+  if (stack == nullptr || trace->num_frames <= 0 ||
+      trace->num_frames >= max_frames) {
+    return;
+  }
+  trace->frames[trace->num_frames++] = JVMPI_CallFrame{
+      kCallTraceErrorLineNum, reinterpret_cast<jmethodID>(kAsyncBoundary)};
+  for (int i = 0; i < stack->num_frames && trace->num_frames < max_frames;
+       i++) {
+    trace->frames[trace->num_frames++] = stack->frames[i];
+  }
+}
+
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,88 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_ASYNC_STACKS_H_
+#define CLOUD_PROFILER_AGENT_JAVA_ASYNC_STACKS_H_
+
+#include <jni.h>
+#include <jvmti.h>
+
+#include <atomic>
+
+#include "src/globals.h"
+#include "third_party/javaprofiler/stacktrace_decls.h"
+
+namespace cloud {
+namespace profiler {
+
+// Experimental stitching of the stacks of the tasks run by a
+// java.util.concurrent.ThreadPoolExecutor to the stack of the thread that
+// submitted them, so that the samples of a task show the logical call chain
+// across the thread handoff.
+//
+// The handoff is observed through JVMTI breakpoints, limited to:
+// - ThreadPoolExecutor.execute(Runnable), also reached through submit(),
+//   where the submitter stack is recorded and the task tagged with it;
+// - ThreadPoolExecutor.beforeExecute(Thread, Runnable), called by the worker
+//   thread before it runs each task, where the recorded stack becomes the
+//   parent stack of the worker thread;
+// - ThreadPoolExecutor.afterExecute(Runnable, Throwable), where it is cleared.
+// The executor subclasses overriding execute() or beforeExecute() without
+// calling the base implementation, e.g. ScheduledThreadPoolExecutor, and the
+// other executors, e.g. ForkJoinPool, are not stitched. The breakpoints keep
+// these methods interpreted, which slows down the task submission.
+//
+// The parent stack is appended to the samples after an "[Async boundary]"
+// frame, and is itself a recorded stack, so nested handoffs are not followed.
+class AsyncStacks {
+ public:
+  // Adds the JVMTI capabilities needed to the given ones.
+  static void AddCapabilities(jvmtiCapabilities *caps);
+
+  // Returns true if all the needed capabilities are potential ones.
+  static bool Supported(const jvmtiCapabilities &potential);
+
+  // Sets the breakpoints on the ThreadPoolExecutor methods. Must be called
+  // from the live phase. Returns false on error.
+  static bool Enable(jvmtiEnv *jvmti, JNIEnv *jni);
+
+  // The JVMTI breakpoint callback.
+  static void JNICALL OnBreakpoint(jvmtiEnv *jvmti, JNIEnv *jni,
+                                   jthread thread, jmethodID method,
+                                   jlocation location);
+
+  // Releases the parent stack of the current thread, called when it ends.
+  static void OnThreadEnd();
+
+  // Appends the parent stack of the current thread, if any, to the trace
+  // after an async boundary frame, up to max_frames in total.
+  // Async-signal-safe.
+  static void AppendParentFrames(google::javaprofiler::JVMPI_CallTrace *trace,
+                                 int max_frames);
+
+ private:
+  static std::atomic<jvmtiEnv *> jvmti_;
+  static jmethodID execute_;
+  static jmethodID before_execute_;
+  static jmethodID after_execute_;
+
+  DISALLOW_IMPLICIT_CONSTRUCTORS(AsyncStacks);
+};
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_ASYNC_STACKS_H_
@@ -46,6 +46,14 @@ inline int64_t TimeSpecToNanos(const struct timespec &ts) {
 
 inline Clock *DefaultClock() { return google::javaprofiler::DefaultClock(); }
 
+// Returns the current wall clock time, in nanoseconds since the epoch, for the
+// time stamps. The Clock time is of the monotonic clock, unrelated to it.
+inline int64_t WallClockNanos() {
+  struct timespec now;
+  clock_gettime(CLOCK_REALTIME, &now);
+  return TimeSpecToNanos(now);
+}
+
 }  // namespace profiler
 }  // namespace cloud
 
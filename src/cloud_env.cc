@@ -14,6 +14,10 @@
 
 #include "src/cloud_env.h"
 
+#include <limits.h>
+#include <string.h>
+#include <unistd.h>
+
 #include <cstdlib>
 #include <sstream>
 #include <string>
@@ -33,10 +37,23 @@ DEFINE_string(cprof_access_token_test_only, "",
               "override OAuth2 access token for testing");
 DEFINE_string(cprof_project_id, "", "cloud project ID");
 DEFINE_string(cprof_zone_name, "", "zone name");
+DEFINE_string(cprof_default_zone, "unknown",
+              "zone name used when it is neither specified nor available "
+              "from the metadata server, e.g. when running outside of GCP; "
+              "when empty, the profiles are not labeled with a zone");
+DEFINE_int32(cprof_metadata_retry_interval_sec, 300,
+             "when the project ID or the zone name is not available from the "
+             "metadata server, interval in seconds between the later attempts "
+             "to read it, the profiling waiting for the project ID and using "
+             "-cprof_default_zone meanwhile; when 0, the profiling stops "
+             "without a project ID and the default zone is kept");
 DEFINE_string(cprof_service, "", "deployment service name");
 DEFINE_string(cprof_service_version, "", "deployment service version");
 
 DEFINE_string(cprof_target, "", "deprecated, use -cprof_service instead");
+DEFINE_string(cprof_instance_name, "",
+              "instance name used to label the profiles; defaults to the "
+              "HOSTNAME environment variable or the host name");
 
 namespace cloud {
 namespace profiler {
@@ -87,6 +104,35 @@ std::string GceMetadataRequest(HTTPRequest* req, const std::string& path) {
   return kNoData;
 }
 
+// Returns true if all characters of s are lowercase letters, digits or in
+// extra, and s has between 1 and max_length characters.
+bool HasOnlyChars(const std::string& s, const char* extra, size_t max_length) {
+  if (s.empty() || s.length() > max_length) {
+    return false;
+  }
+  for (char c : s) {
+    if ((c < 'a' || c > 'z') && (c < '0' || c > '9') &&
+        (c == '\0' || strchr(extra, c) == nullptr)) {
+      return false;
+    }
+  }
+  return true;
+}
+
+// Returns true if the metadata server response looks like a project ID. The
+// metadata host name may be intercepted by a proxy returning an unrelated
+// body, e.g. an HTML page, which must not be taken for a project ID. Legacy
+// project IDs can be prefixed with a domain, as in "example.com:project".
+bool IsPlausibleProjectID(const std::string& s) {
+  return HasOnlyChars(s, "-.:", 100);
+}
+
+// Returns true if the last element of the metadata server zone response
+// looks like a zone name, e.g. "us-central1-a".
+bool IsPlausibleZoneName(const std::string& s) {
+  return HasOnlyChars(s, "-", 63);
+}
+
 const char* Getenv(const std::string& var) {
 #if __GLIBC__ > 2 || (__GLIBC__ == 2 && __GLIBC_MINOR__ >= 17) || ALPINE == 1
   return secure_getenv(var.c_str());
@@ -97,7 +143,7 @@ const char* Getenv(const std::string& var) {
 
 }  // namespace
 
-CloudEnv::CloudEnv() {
+CloudEnv::CloudEnv() : default_zone_(false), default_zone_ns_(0) {
   if (!FLAGS_cprof_service.empty()) {
     service_ = FLAGS_cprof_service;
   } else if (!FLAGS_cprof_target.empty()) {
@@ -142,6 +188,21 @@ CloudEnv::CloudEnv() {
     zone_name_ = FLAGS_cprof_zone_name;
     LOG(INFO) << "Using zone name '" << zone_name_ << "' from flags";
   }
+
+  if (!FLAGS_cprof_instance_name.empty()) {
+    instance_name_ = FLAGS_cprof_instance_name;
+  } else {
+    const char* val = Getenv("HOSTNAME");
+    if (val != nullptr && val[0] != '\0') {
+      instance_name_ = val;
+    } else {
+      char hostname[HOST_NAME_MAX + 1];
+      if (gethostname(hostname, sizeof(hostname)) == 0) {
+        hostname[HOST_NAME_MAX] = '\0';
+        instance_name_ = hostname;
+      }
+    }
+  }
 }
 
 std::string CloudEnv::ProjectID() {
@@ -159,8 +220,15 @@ std::string CloudEnv::ProjectID(HTTPRequest* req) {
     LOG(ERROR) << "Failed to read the project ID from the VM metadata";
     return resp;
   }
+  if (!IsPlausibleProjectID(resp)) {
+    LOG(ERROR) << "Malformed project ID in the VM metadata response, "
+               << "ignoring it: '" << resp.substr(0, 100) << "'";
+    return kNoData;
+  }
 
   project_id_ = resp;
+  LOG(INFO) << "Using project ID '" << project_id_ << "' from the metadata "
+            << "server";
   return project_id_;
 }
 
@@ -170,26 +238,61 @@ std::string CloudEnv::ZoneName() {
 }
 
 std::string CloudEnv::ZoneName(HTTPRequest* req) {
-  if (!zone_name_.empty()) {
+  if (!zone_name_.empty() && !RetryZoneName()) {
     return zone_name_;
   }
 
   std::string resp = GceMetadataRequest(req, kZoneNamePath);
   if (resp == kNoData) {
     LOG(ERROR) << "Failed to read the zone name";
-    return kNoData;
+    return DefaultZoneName();
   }
 
   std::vector<std::string> elems = Split(resp, '/');
-  if (elems.empty() || elems.back().empty()) {
-    LOG(ERROR) << "Failed to parse the zone name";
-    return kNoData;
+  if (elems.empty() || !IsPlausibleZoneName(elems.back())) {
+    LOG(ERROR) << "Failed to parse the zone name from '"
+               << resp.substr(0, 100) << "'";
+    return DefaultZoneName();
   }
 
+  if (default_zone_) {
+    LOG(INFO) << "Using zone name '" << elems.back() << "' from the metadata "
+              << "server for the next profiles, instead of '" << zone_name_
+              << "' from -cprof_default_zone";
+    default_zone_ = false;
+  }
   zone_name_ = elems.back();
   return zone_name_;
 }
 
+bool CloudEnv::RetryZoneName() {
+  if (!default_zone_ || FLAGS_cprof_metadata_retry_interval_sec <= 0) {
+    return false;
+  }
+  int64_t now_ns = TimeSpecToNanos(DefaultClock()->Now());
+  return now_ns - default_zone_ns_ >=
+         FLAGS_cprof_metadata_retry_interval_sec * kNanosPerSecond;
+}
+
+std::string CloudEnv::DefaultZoneName() {
+  if (FLAGS_cprof_default_zone.empty()) {
+    return kNoData;
+  }
+  // Retried after -cprof_metadata_retry_interval_sec from now.
+  default_zone_ns_ = TimeSpecToNanos(DefaultClock()->Now());
+  if (default_zone_) {
+    return zone_name_;
+  }
+  default_zone_ = true;
+  LOG(WARNING) << "The zone name is not set via flag and is not available "
+               << "from the metadata server, using '"
+               << FLAGS_cprof_default_zone << "' from -cprof_default_zone";
+  // Keep it so that the warning is logged once and the metadata server is not
+  // asked again before the retry interval.
+  zone_name_ = FLAGS_cprof_default_zone;
+  return zone_name_;
+}
+
 std::string CloudEnv::Oauth2AccessToken() {
   HTTPRequest req;
   return Oauth2AccessToken(&req);
@@ -228,6 +331,20 @@ std::string CloudEnv::Service() { return service_; }
 
 std::string CloudEnv::ServiceVersion() { return service_version_; }
 
+std::string CloudEnv::InstanceName() { return instance_name_; }
+
+void CloudEnv::SetDefaultServiceVersion(const std::string& service_version) {
+  if (service_version_.empty()) {
+    service_version_ = service_version;
+  }
+}
+
+void CloudEnv::SetDefaultService(const std::string& service) {
+  if (service_.empty()) {
+    service_ = service;
+  }
+}
+
 CloudEnv* DefaultCloudEnv() {
   // Deferred initialization to make sure the flags are parsed.
   static CloudEnv cloud_env;
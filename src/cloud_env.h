@@ -51,6 +51,17 @@ class CloudEnv {
   // Returns the profiled service version for the current environment.
   virtual std::string ServiceVersion();
 
+  // Returns the name of the instance the agent runs on.
+  virtual std::string InstanceName();
+
+  // Sets the service version to use when it is not specified via flags or
+  // the environment.
+  void SetDefaultServiceVersion(const std::string& service_version);
+
+  // Sets the service name to use when it is not specified via flags or the
+  // environment.
+  void SetDefaultService(const std::string& service);
+
   // Implements the method using the given HTTP request for communication.
   // Visible for testing.
   std::string ProjectID(HTTPRequest* req);
@@ -64,10 +75,22 @@ class CloudEnv {
   std::string Oauth2AccessToken(HTTPRequest* req);
 
  private:
+  // Returns the -cprof_default_zone fallback zone name, warning about it.
+  std::string DefaultZoneName();
+  // Returns true if the zone name is the -cprof_default_zone fallback and
+  // -cprof_metadata_retry_interval_sec elapsed since the metadata server last
+  // failed to provide it.
+  bool RetryZoneName();
+
   std::string project_id_;
   std::string zone_name_;
+  // Whether zone_name_ is the -cprof_default_zone fallback, and when the
+  // metadata server last failed to provide it.
+  bool default_zone_;
+  int64_t default_zone_ns_;
   std::string service_;
   std::string service_version_;
+  std::string instance_name_;
   DISALLOW_COPY_AND_ASSIGN(CloudEnv);
 };
 
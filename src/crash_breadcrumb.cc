@@ -0,0 +1,73 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "src/crash_breadcrumb.h"
+
+#include <inttypes.h>
+#include <stdio.h>
+#include <unistd.h>
+
+namespace cloud {
+namespace profiler {
+
+bool CrashBreadcrumb::Check(int64_t now_sec, int max_crashes,
+                            int64_t window_sec) {
+  crashes_ = 0;
+  window_start_sec_ = now_sec;
+
+  FILE *f = fopen(path_.c_str(), "r");
+  if (f == nullptr) {
+    // No breadcrumb, the previous run shut down cleanly or never started.
+    return false;
+  }
+  int crashes = 0;
+  int64_t window_start_sec = 0;
+  if (fscanf(f, "%d %" SCNd64, &crashes, &window_start_sec) != 2) {
+    LOG(WARNING) << "Ignoring malformed crash breadcrumb file " << path_;
+    crashes = 0;
+    window_start_sec = now_sec;
+  }
+  fclose(f);
+
+  if (now_sec - window_start_sec <= window_sec) {
+    crashes_ = crashes;
+    window_start_sec_ = window_start_sec;
+  }
+  crashes_++;
+
+  LOG(WARNING) << "Previous run did not shut down cleanly, " << crashes_
+               << " suspected crash(es) within " << now_sec - window_start_sec_
+               << "s";
+  return crashes_ >= max_crashes;
+}
+
+bool CrashBreadcrumb::Write() {
+  FILE *f = fopen(path_.c_str(), "w");
+  if (f == nullptr) {
+    LOG(WARNING) << "Failed to create crash breadcrumb file " << path_;
+    return false;
+  }
+  fprintf(f, "%d %" PRId64 "\n", crashes_, window_start_sec_);
+  fclose(f);
+  return true;
+}
+
+void CrashBreadcrumb::Clear() {
+  if (unlink(path_.c_str()) != 0) {
+    LOG(WARNING) << "Failed to remove crash breadcrumb file " << path_;
+  }
+}
+
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,58 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_CRASH_BREADCRUMB_H_
+#define CLOUD_PROFILER_AGENT_JAVA_CRASH_BREADCRUMB_H_
+
+#include <string>
+
+#include "src/globals.h"
+
+namespace cloud {
+namespace profiler {
+
+// CrashBreadcrumb is a safety net against crash loops caused by the agent.
+// A breadcrumb file is written once the agent is initialized and removed on
+// a clean VM shutdown. A breadcrumb found at startup means that the previous
+// run did not shut down cleanly, which is counted as a suspected crash.
+class CrashBreadcrumb {
+ public:
+  explicit CrashBreadcrumb(const std::string &path)
+      : path_(path), crashes_(0), window_start_sec_(0) {}
+
+  // Reads the breadcrumb left by the previous run, if any. Returns true if
+  // max_crashes suspected crashes happened within window_sec seconds, in
+  // which case the agent should start disabled.
+  bool Check(int64_t now_sec, int max_crashes, int64_t window_sec);
+  // Records that the agent has been initialized.
+  bool Write();
+  // Records a clean shutdown.
+  void Clear();
+
+ private:
+  std::string path_;
+  // Number of suspected crashes within the current window.
+  int crashes_;
+  // Start of the window of suspected crashes, in seconds since the epoch.
+  int64_t window_start_sec_;
+
+  DISALLOW_COPY_AND_ASSIGN(CrashBreadcrumb);
+};
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_CRASH_BREADCRUMB_H_
@@ -0,0 +1,113 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/crash_breadcrumb.h"
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#include <string>
+
+#include "gtest/gtest.h"
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+const int kMaxCrashes = 3;
+const int64_t kWindowSec = 60;
+
+class CrashBreadcrumbTest : public ::testing::Test {
+ protected:
+  void SetUp() override {
+    char dir[] = "/tmp/crash_breadcrumb_testXXXXXX";
+    ASSERT_NE(nullptr, mkdtemp(dir));
+    dir_ = dir;
+    path_ = dir_ + "/breadcrumb";
+  }
+
+  void TearDown() override {
+    unlink(path_.c_str());
+    rmdir(dir_.c_str());
+  }
+
+  // Simulates the startup of an agent at now_sec, returns whether it starts
+  // disabled. The breadcrumb is left behind as after a crash.
+  bool StartAndCrash(int64_t now_sec) {
+    CrashBreadcrumb breadcrumb(path_);
+    bool disabled = breadcrumb.Check(now_sec, kMaxCrashes, kWindowSec);
+    EXPECT_TRUE(breadcrumb.Write());
+    return disabled;
+  }
+
+  void WriteFile(const std::string &content) {
+    FILE *f = fopen(path_.c_str(), "w");
+    ASSERT_NE(nullptr, f);
+    fputs(content.c_str(), f);
+    fclose(f);
+  }
+
+  std::string dir_;
+  std::string path_;
+};
+
+TEST_F(CrashBreadcrumbTest, NoBreadcrumbIsNotACrash) {
+  CrashBreadcrumb breadcrumb(path_);
+  EXPECT_FALSE(breadcrumb.Check(100, 1, kWindowSec));
+}
+
+TEST_F(CrashBreadcrumbTest, DisablesAfterRepeatedCrashesWithinTheWindow) {
+  EXPECT_FALSE(StartAndCrash(100));
+  EXPECT_FALSE(StartAndCrash(110));
+  EXPECT_FALSE(StartAndCrash(120));
+  EXPECT_TRUE(StartAndCrash(130));
+}
+
+TEST_F(CrashBreadcrumbTest, ForgetsTheCrashesOutsideTheWindow) {
+  WriteFile("2 100\n");
+  CrashBreadcrumb breadcrumb(path_);
+  EXPECT_FALSE(breadcrumb.Check(100 + kWindowSec + 1, kMaxCrashes, kWindowSec));
+  ASSERT_TRUE(breadcrumb.Write());
+
+  // The window restarted with a single crash.
+  EXPECT_FALSE(StartAndCrash(100 + kWindowSec + 2));
+  EXPECT_TRUE(StartAndCrash(100 + kWindowSec + 3));
+}
+
+TEST_F(CrashBreadcrumbTest, CleanShutdownClearsTheCrashes) {
+  EXPECT_FALSE(StartAndCrash(100));
+  EXPECT_FALSE(StartAndCrash(110));
+  {
+    CrashBreadcrumb breadcrumb(path_);
+    EXPECT_FALSE(breadcrumb.Check(120, kMaxCrashes, kWindowSec));
+    ASSERT_TRUE(breadcrumb.Write());
+    breadcrumb.Clear();
+  }
+  EXPECT_NE(0, access(path_.c_str(), F_OK));
+  EXPECT_FALSE(StartAndCrash(130));
+}
+
+TEST_F(CrashBreadcrumbTest, MalformedBreadcrumbCountsAsOneCrash) {
+  WriteFile("garbage");
+  CrashBreadcrumb breadcrumb(path_);
+  EXPECT_TRUE(breadcrumb.Check(100, 1, kWindowSec));
+  EXPECT_FALSE(breadcrumb.Check(100, 2, kWindowSec));
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,149 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "src/crash_handler.h"
+
+#include <signal.h>
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+#include "src/profiler.h"
+
+namespace cloud {
+namespace profiler {
+
+namespace {
+
+// Context message formatted at installation time, since the signal handler
+// can only use async-signal-safe functions.
+char crash_context[512];
+
+struct sigaction old_segv_action;
+struct sigaction old_bus_action;
+struct sigaction old_abrt_action;
+
+// Fault received on the thread while recording a sample, reported if the
+// JVM does not recover from it and aborts. Same TLS model as the accessors,
+// see accessors.h.
+#if defined(JAVAPROFILER_GLOBAL_DYNAMIC_TLS) || defined(ALPINE)
+__thread int pending_fault __attribute__((tls_model("global-dynamic")));
+#else
+__thread int pending_fault __attribute__((tls_model("initial-exec")));
+#endif
+
+void WriteStderr(const char *s) {
+  ssize_t unused = write(STDERR_FILENO, s, strlen(s));
+  IMPLICITLY_USE(unused);
+}
+
+bool HasHandler(const struct sigaction &action) {
+  if (action.sa_flags & SA_SIGINFO) {
+    return action.sa_sigaction != nullptr;
+  }
+  return action.sa_handler != SIG_DFL && action.sa_handler != SIG_IGN;
+}
+
+void ReportFault(int signum, bool in_flight) {
+  WriteStderr("cprof: received ");
+  WriteStderr(signum == SIGSEGV ? "SIGSEGV" : "SIGBUS");
+  WriteStderr(", ");
+  WriteStderr(crash_context);
+  WriteStderr(in_flight ? ", sample in flight: yes\n"
+                        : ", sample in flight: no\n");
+}
+
+void HandleCrash(int signum, siginfo_t *info, void *context) {
+  struct sigaction *old_action;
+  switch (signum) {
+    case SIGSEGV:
+      old_action = &old_segv_action;
+      break;
+    case SIGBUS:
+      old_action = &old_bus_action;
+      break;
+    default:
+      old_action = &old_abrt_action;
+  }
+  bool chained = HasHandler(*old_action);
+
+  if (signum == SIGABRT) {
+    // The JVM aborts after failing to handle a fault.
+    if (pending_fault != 0) {
+      ReportFault(pending_fault, true);
+      pending_fault = 0;
+    }
+  } else if (!chained) {
+    ReportFault(signum, Profiler::SampleInFlight());
+  }
+
+  if (chained) {
+    // The JVM recovers from some faults, e.g. the implicit null checks or
+    // the ones of AsyncGetCallTrace, by returning from its handler. A fault
+    // during a sample is only reported if it aborts instead.
+    int saved_pending_fault = pending_fault;
+    if (signum != SIGABRT && Profiler::SampleInFlight()) {
+      pending_fault = signum;
+    }
+    if (old_action->sa_flags & SA_SIGINFO) {
+      old_action->sa_sigaction(signum, info, context);
+    } else {
+      old_action->sa_handler(signum);
+    }
+    pending_fault = saved_pending_fault;
+    return;
+  }
+
+  // Restore the default action and re-raise. The signal is blocked while in
+  // the handler, so it is delivered with the default action on return.
+  struct sigaction action;
+  memset(&action, 0, sizeof(action));
+  action.sa_handler = SIG_DFL;
+  sigemptyset(&action.sa_mask);
+  sigaction(signum, &action, nullptr);
+  raise(signum);
+}
+
+}  // namespace
+
+bool InstallCrashHandler(const std::string &jvm_version) {
+  snprintf(crash_context, sizeof(crash_context),
+           "agent version: %s, JVM version: %s", CLOUD_PROFILER_AGENT_VERSION,
+           jvm_version.c_str());
+
+  struct sigaction action;
+  memset(&action, 0, sizeof(action));
+  action.sa_sigaction = &HandleCrash;
+  action.sa_flags = SA_SIGINFO | SA_RESTART | SA_ONSTACK;
+  sigemptyset(&action.sa_mask);
+  if (sigaction(SIGSEGV, &action, &old_segv_action) != 0) {
+    LOG(ERROR) << "Failed to install the SIGSEGV crash handler";
+    return false;
+  }
+  if (sigaction(SIGBUS, &action, &old_bus_action) != 0) {
+    LOG(ERROR) << "Failed to install the SIGBUS crash handler";
+    sigaction(SIGSEGV, &old_segv_action, nullptr);
+    return false;
+  }
+  if (sigaction(SIGABRT, &action, &old_abrt_action) != 0) {
+    LOG(ERROR) << "Failed to install the SIGABRT crash handler";
+    sigaction(SIGSEGV, &old_segv_action, nullptr);
+    sigaction(SIGBUS, &old_bus_action, nullptr);
+    return false;
+  }
+  return true;
+}
+
+}  // namespace profiler
+}  // namespace cloud
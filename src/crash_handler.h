@@ -0,0 +1,41 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_CRASH_HANDLER_H_
+#define CLOUD_PROFILER_AGENT_JAVA_CRASH_HANDLER_H_
+
+#include <string>
+
+#include "src/globals.h"
+
+namespace cloud {
+namespace profiler {
+
+// Installs SIGSEGV and SIGBUS handlers that log the agent version, the JVM
+// version and whether the faulting thread was recording a sample. The
+// previously installed handlers, normally the JVM's own, are invoked
+// afterwards; without one the signal is re-raised with the default action
+// so that the core dump is preserved. As the JVM relies on SIGSEGV for its
+// own purposes and recovers from most, the context is only logged when no
+// handler was installed before, or when the fault happened on a thread
+// recording a sample and the JVM aborts on it, which a SIGABRT handler
+// catches. Returns false on failure.
+bool InstallCrashHandler(const std::string &jvm_version);
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_CRASH_HANDLER_H_
@@ -0,0 +1,85 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/crash_handler.h"
+
+#include <signal.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/mman.h>
+#include <unistd.h>
+
+#include "gtest/gtest.h"
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+const int kChainedExitCode = 42;
+
+// Writes to an inaccessible page, the kernel sends SIGSEGV.
+void Fault() {
+  void *page = mmap(nullptr, getpagesize(), PROT_NONE,
+                    MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+  if (page == MAP_FAILED) {
+    _exit(1);
+  }
+  *static_cast<volatile int *>(page) = 1;
+}
+
+void ChainedHandler(int signum) {
+  const char msg[] = "chained handler\n";
+  ssize_t unused = write(STDERR_FILENO, msg, sizeof(msg) - 1);
+  IMPLICITLY_USE(unused);
+  _exit(kChainedExitCode);
+}
+
+// Installs the crash handler as the JVM would find it, with the default
+// action for SIGSEGV, and faults.
+void InstallAndFault() {
+  signal(SIGSEGV, SIG_DFL);
+  if (!InstallCrashHandler("test-jvm")) {
+    _exit(1);
+  }
+  Fault();
+}
+
+// Installs the crash handler over a previous SIGSEGV handler, as the one of
+// the JVM, and faults.
+void InstallOverHandlerAndFault() {
+  signal(SIGSEGV, &ChainedHandler);
+  if (!InstallCrashHandler("test-jvm")) {
+    _exit(1);
+  }
+  Fault();
+}
+
+TEST(CrashHandlerDeathTest, LogsTheContextAndReraises) {
+  EXPECT_EXIT(InstallAndFault(), ::testing::KilledBySignal(SIGSEGV),
+              "cprof: received SIGSEGV, agent version: .*, JVM version: "
+              "test-jvm, sample in flight: no");
+}
+
+TEST(CrashHandlerDeathTest, ChainsToThePreviousHandler) {
+  // The previous handler may recover, the context is not logged.
+  EXPECT_EXIT(InstallOverHandlerAndFault(),
+              ::testing::ExitedWithCode(kChainedExitCode),
+              "chained handler");
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
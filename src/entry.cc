@@ -13,55 +13,176 @@
 // limitations under the License.
 
 #include <limits.h>
+#include <time.h>
 
+#include <mutex>  // NOLINT(build/c++11)
 #include <string>
 
+#include "src/async_stacks.h"
+#include "src/crash_breadcrumb.h"
+#include "src/crash_handler.h"
+#include "src/entry.h"
 #include "src/globals.h"
+#include "src/inlined_frames.h"
+#include "src/profiler.h"
 #include "src/string.h"
 #include "src/worker.h"
 #include "third_party/javaprofiler/accessors.h"
+#include "third_party/javaprofiler/display.h"
 #include "third_party/javaprofiler/globals.h"
 #include "third_party/javaprofiler/heap_sampler.h"
+#include "third_party/javaprofiler/memory_budget.h"
+#include "third_party/javaprofiler/sample_budget.h"
+#include "third_party/javaprofiler/stacktrace_fixer.h"
 #include "third_party/javaprofiler/stacktraces.h"
 
 DEFINE_bool(cprof_cpu_use_per_thread_timers, false,
             "when true, use per-thread CLOCK_THREAD_CPUTIME_ID timers; "
             "only profiles Java threads, non-Java threads will be missed. "
             "This flag is ignored on Alpine.");
+DEFINE_string(cprof_cpu_profiling_mode, "itimer",
+              "how the CPU profiling is driven: 'itimer' uses the process "
+              "ITIMER_PROF timer, or the per-thread timers with "
+              "-cprof_cpu_use_per_thread_timers; 'perf_event' uses per-thread "
+              "task clock perf events, only profiling Java threads, and falls "
+              "back to 'itimer' when perf events are not available");
+DEFINE_string(cprof_redact_packages, "",
+              "comma-separated list of Java packages whose frames are "
+              "replaced by a '<redacted>' frame in all profiles, along with "
+              "their line numbers and arguments");
+DEFINE_bool(cprof_anonymize_symbols, false,
+            "when true, replace the Java class, method, file and thread names "
+            "with stable salted hashes in all profiles, keeping the shape of "
+            "the profiles, e.g. to share them without exposing the code");
+DEFINE_string(cprof_anonymize_salt, "",
+              "salt of the -cprof_anonymize_symbols hashes; keep it to map "
+              "the known names to their hashes locally");
+DEFINE_bool(cprof_resolve_line_numbers, true,
+            "when false, the profile frames have no line numbers, which "
+            "saves looking up the line number tables of the methods during "
+            "the symbolization on CPU-constrained hosts");
 DEFINE_bool(cprof_force_debug_non_safepoints, true,
             "when true, force DebugNonSafepoints flag by subscribing to the"
             "code generation events. This improves the accuracy of profiles,"
             "but may incur a bit of overhead.");
+DEFINE_bool(cprof_show_inlined_frames, true,
+            "when true, the CPU and wall profiles show the frames of the "
+            "methods inlined by the JIT compilers as separate frames; when "
+            "false, they are collapsed into the frame of the compiled method "
+            "they were inlined into, from the code generation events");
 DEFINE_bool(cprof_enable_heap_sampling, false,
             "when unset, heap allocation sampling is disabled");
 DEFINE_int32(cprof_heap_sampling_interval, 512 * 1024,
              "sampling interval for heap allocation sampling, 512k by default");
+DEFINE_bool(cprof_allow_sample_every_allocation, false,
+            "when true, accept a zero -cprof_heap_sampling_interval, which "
+            "samples every allocation at an extreme overhead");
+DEFINE_string(cprof_crash_breadcrumb_file, "",
+              "when set, path of the file used to detect the agent crashing "
+              "the JVM repeatedly; the agent starts disabled after "
+              "-cprof_crash_loop_max_restarts suspected crashes");
+DEFINE_int32(cprof_crash_loop_max_restarts, 3,
+             "number of unclean restarts within -cprof_crash_loop_window_sec "
+             "after which the agent starts disabled");
+DEFINE_int32(cprof_crash_loop_window_sec, 600,
+             "time window in seconds for counting unclean restarts");
+DEFINE_bool(cprof_install_crash_handler, false,
+            "when true, log the agent context on SIGSEGV and SIGBUS before "
+            "handing the signal over to the JVM or re-raising it. With "
+            "libjsig preloaded, the JVM handlers stay installed and only "
+            "chain to the agent one for the signals they do not handle "
+            "themselves, so most faults are not logged. With -Xcheck:jni, "
+            "the JVM warns that its SIGSEGV, SIGBUS and SIGABRT handlers "
+            "were modified and stops checking them");
+DEFINE_bool(cprof_capture_top_frame_args, false,
+            "experimental: when true, label the CPU and wall samples with the "
+            "names and types of the parameters of the top frame method. "
+            "Requires the access local variables capability, which may limit "
+            "the JIT compiler optimizations. Argument values are not "
+            "captured.");
+DEFINE_int32(cprof_total_sample_budget_per_sec, 0,
+             "when set, maximum number of samples recorded per second across "
+             "all the profile types, including the heap allocation samples; "
+             "the samples over budget are dropped");
+DEFINE_int32(cprof_init_retries, 2,
+             "number of times to retry the JVMTI initialization steps which "
+             "fail with a transient error");
+DEFINE_int32(cprof_init_retry_delay_ms, 100,
+             "delay between the JVMTI initialization retries, in "
+             "milliseconds");
+DEFINE_bool(cprof_label_gc_samples, false,
+            "when true, label the CPU and wall samples taken while a garbage "
+            "collection is in progress with gc=true, from the JVMTI garbage "
+            "collection events");
+DEFINE_bool(cprof_experimental_async_stacks, false,
+            "experimental; when true, stitch the stacks of the "
+            "ThreadPoolExecutor tasks to the stack of the thread that "
+            "submitted them, at the cost of a slower task submission");
+DEFINE_string(cprof_jvmti_version, "",
+              "for compatibility testing, when set to 1.0, 1.1 or 1.2, "
+              "request this JVMTI version instead of the latest one the "
+              "agent was built with");
+DEFINE_int64(cprof_max_agent_heap_bytes, 0,
+             "when set, bound the memory held by the agent's heap samples to "
+             "this many bytes, coarsening the heap sampling and resampling "
+             "the samples held as it is approached, and dropping the samples "
+             "beyond it");
+DEFINE_bool(cprof_fail_repeated_load, false,
+            "when true, fail the repeated loads of the agent, e.g. with both "
+            "-agentpath and a launcher calling Agent_OnLoad_with_len, rather "
+            "than ignoring them; the options of the first load apply");
+DEFINE_string(cprof_options_property, "cprof.options",
+              "JVM system property from which to read additional agent "
+              "options, e.g. set with -Dcprof.options=-cprof_service=foo, for "
+              "the environments that can set the JVM flags but not the agent "
+              "options; the agent options take precedence. Unlike the other "
+              "properties, it is read when the agent is loaded rather than at "
+              "VMInit, for the options configuring the sampling and the JVMTI "
+              "capabilities to take effect, so it must be set on the command "
+              "line. Empty to not read any");
+
+DECLARE_int32(cprof_cpu_sampling_period_msec);
+DECLARE_bool(cprof_wall_skip_gc_rounds);
 
 namespace cloud {
 namespace profiler {
 
 static Worker *worker;
 
+// Serializes the agent initialization, and records the JVM it was done for.
+// The JVM may load the agent more than once, only the first load starts the
+// profiler. There is a single JVM per process.
+static std::mutex init_mutex;
+static JavaVM *initialized_vm;
+
 // ThreadStart / ThreadEnd events may arrive after VMDeath event which destroys
 // the worker, so managing the lifetime of the thread table is a bit tricky.
 // Just make it a global singleton cleared up when the process exit.
 static ThreadTable *threads;
 
+// Set when crash loop detection is enabled.
+static CrashBreadcrumb *breadcrumb;
+
+// Set by PrepareJvmti when a flag needs the garbage collection events and the
+// JVM supports them.
+static bool gc_events;
+
 static void JNICALL OnThreadStart(jvmtiEnv *jvmti_env, JNIEnv *jni_env,
                                   jthread thread) {
   IMPLICITLY_USE(jvmti_env);
-  IMPLICITLY_USE(thread);
   google::javaprofiler::Accessors::SetCurrentJniEnv(jni_env);
-  threads->RegisterCurrent();
+  threads->RegisterCurrent(jni_env, thread);
 }
 
 static void JNICALL OnThreadEnd(jvmtiEnv *jvmti_env, JNIEnv *jni_env,
                                 jthread thread) {
   IMPLICITLY_USE(jvmti_env);
-  IMPLICITLY_USE(jni_env);
   IMPLICITLY_USE(thread);
   google::javaprofiler::Accessors::SetCurrentJniEnv(nullptr);
-  threads->UnregisterCurrent();
+  threads->UnregisterCurrent(jni_env);
+  if (FLAGS_cprof_experimental_async_stacks) {
+    AsyncStacks::OnThreadEnd();
+  }
 }
 
 // This has to be here, or the VM turns off class loading events.
@@ -79,7 +200,7 @@ static void JNICALL OnCompiledMethodLoad(jvmtiEnv *jvmti_env, jmethodID method,
                                          jint map_length,
                                          const jvmtiAddrLocationMap *map,
                                          const void *compile_info) {
-  // The callback is here to enable DebugNonSafepoints by default. See
+  // The callback is also here to enable DebugNonSafepoints by default. See
   // https://stackoverflow.com/questions/37298962/how-can-jvmti-agent-set-a-jvm-flag-on-startup.
   IMPLICITLY_USE(jvmti_env);
   IMPLICITLY_USE(method);
@@ -87,7 +208,9 @@ static void JNICALL OnCompiledMethodLoad(jvmtiEnv *jvmti_env, jmethodID method,
   IMPLICITLY_USE(code_addr);
   IMPLICITLY_USE(map_length);
   IMPLICITLY_USE(map);
-  IMPLICITLY_USE(compile_info);
+  if (!FLAGS_cprof_show_inlined_frames) {
+    InlinedFrames::Record(compile_info);
+  }
 }
 
 // Calls GetClassMethods on a given class to force the creation of
@@ -102,8 +225,38 @@ void CreateJMethodIDsForClass(jvmtiEnv *jvmti, jclass klass) {
     google::javaprofiler::JvmtiScopedPtr<char> ksig(jvmti);
     JVMTI_ERROR((jvmti->GetClassSignature(klass, ksig.GetRef(), NULL)));
     LOG(ERROR) << "Failed to create method IDs for methods in class "
-               << ksig.Get() << " with error " << e;
+               << (ksig.Get() == nullptr ? "<unknown>" : ksig.Get())
+               << " with error " << e;
+  }
+}
+
+const int kMinRecommendedHeapSamplingInterval =
+    google::javaprofiler::HeapMonitor::kMinRecommendedSamplingInterval;
+
+// Checks the heap sampling interval, with JVMTI semantics where zero means
+// sampling every allocation. Returns false if it must not be used.
+static bool ValidateHeapSamplingInterval(int interval) {
+  if (interval < 0) {
+    LOG(ERROR) << "Invalid negative heap sampling interval " << interval
+               << ", heap sampling is disabled";
+    return false;
+  }
+  if (interval == 0) {
+    if (!FLAGS_cprof_allow_sample_every_allocation) {
+      LOG(ERROR) << "A zero heap sampling interval samples every allocation, "
+                 << "set '-cprof_allow_sample_every_allocation' to allow it. "
+                 << "Heap sampling is disabled";
+      return false;
+    }
+    LOG(WARNING) << "Sampling every allocation, expect an extreme overhead";
+    return true;
   }
+  if (interval < kMinRecommendedHeapSamplingInterval) {
+    LOG(WARNING) << "Heap sampling interval of " << interval << " bytes is "
+                 << "below " << kMinRecommendedHeapSamplingInterval
+                 << " bytes and likely to incur a high overhead";
+  }
+  return true;
 }
 
 void JNICALL OnVMInit(jvmtiEnv *jvmti, JNIEnv *jni_env, jthread thread) {
@@ -121,11 +274,23 @@ void JNICALL OnVMInit(jvmtiEnv *jvmti, JNIEnv *jni_env, jthread thread) {
     CreateJMethodIDsForClass(jvmti, klass);
   }
 
-  if (FLAGS_cprof_enable_heap_sampling) {
+  if (FLAGS_cprof_enable_heap_sampling &&
+      ValidateHeapSamplingInterval(FLAGS_cprof_heap_sampling_interval)) {
     google::javaprofiler::HeapMonitor::Enable(
         jvmti, jni_env, FLAGS_cprof_heap_sampling_interval);
   }
 
+  if (FLAGS_cprof_experimental_async_stacks) {
+    AsyncStacks::Enable(jvmti, jni_env);
+  }
+
+  // The thread CPU timer info is only available from the live phase.
+  if (!threads->CalibrateTimers(
+          jvmti, FLAGS_cprof_cpu_sampling_period_msec * 1000LL)) {
+    // The process interval timer may not deliver the configured signal.
+    ConfigureSamplingSignal(true);
+  }
+
   worker->Start(jni_env);
 }
 
@@ -140,6 +305,22 @@ void JNICALL OnClassPrepare(jvmtiEnv *jvmti_env, JNIEnv *jni_env,
   CreateJMethodIDsForClass(jvmti_env, klass);
 }
 
+// The garbage collection callbacks run while the GC is in progress, and may
+// only do async-signal-safe work.
+void JNICALL OnGarbageCollectionStart(jvmtiEnv *jvmti_env) {
+  IMPLICITLY_USE(jvmti_env);
+  Profiler::SetInGc(true);
+}
+
+void JNICALL OnGarbageCollectionFinish(jvmtiEnv *jvmti_env) {
+  IMPLICITLY_USE(jvmti_env);
+  Profiler::SetInGc(false);
+  // Chain to the heap sampler, until it is disabled.
+  if (google::javaprofiler::HeapMonitor::Enabled()) {
+    google::javaprofiler::HeapMonitor::NotifyGCWaitingThread();
+  }
+}
+
 void JNICALL OnVMDeath(jvmtiEnv *jvmti_env, JNIEnv *jni_env) {
   IMPLICITLY_USE(jvmti_env);
   IMPLICITLY_USE(jni_env);
@@ -151,6 +332,105 @@ void JNICALL OnVMDeath(jvmtiEnv *jvmti_env, JNIEnv *jni_env) {
   if (google::javaprofiler::HeapMonitor::Enabled()) {
     google::javaprofiler::HeapMonitor::Disable();
   }
+
+  if (breadcrumb != nullptr) {
+    breadcrumb->Clear();
+  }
+}
+
+// Returns the JVM name and version, for the crash handler context.
+static std::string JvmVersion(jvmtiEnv *jvmti) {
+  std::string version;
+  for (const char *property : {"java.vm.name", "java.vm.version"}) {
+    google::javaprofiler::JvmtiScopedPtr<char> value(jvmti);
+    if (jvmti->GetSystemProperty(property, value.GetRef()) ==
+            JVMTI_ERROR_NONE &&
+        value.Get() != nullptr) {
+      if (!version.empty()) {
+        version += " ";
+      }
+      version += value.Get();
+    }
+  }
+  return version.empty() ? "unknown" : version;
+}
+
+// Whether a JVMTI error during the initialization may go away on retry, e.g.
+// when memory is short during a crowded startup.
+static bool IsRetryableJvmtiError(jvmtiError error) {
+  return error == JVMTI_ERROR_OUT_OF_MEMORY || error == JVMTI_ERROR_INTERNAL;
+}
+
+static void SleepBeforeInitRetry(const char *step, int attempt) {
+  LOG(WARNING) << step << " failed with a transient error, retrying ("
+               << attempt << "/" << FLAGS_cprof_init_retries << ") in "
+               << FLAGS_cprof_init_retry_delay_ms << "ms";
+  struct timespec delay = {FLAGS_cprof_init_retry_delay_ms / 1000,
+                           (FLAGS_cprof_init_retry_delay_ms % 1000) * 1000000L};
+  nanosleep(&delay, nullptr);
+}
+
+// Returns the JVMTI version forced with -cprof_jvmti_version, or 0 when the
+// latest one is to be requested.
+static jint ForcedJvmtiVersion() {
+  const std::string &version = FLAGS_cprof_jvmti_version;
+  if (version.empty()) {
+    return 0;
+  }
+  if (version == "1.0") {
+    return JVMTI_VERSION_1_0;
+  }
+  if (version == "1.1") {
+    return JVMTI_VERSION_1_1;
+  }
+  if (version == "1.2") {
+    return JVMTI_VERSION_1_2;
+  }
+  LOG(WARNING) << "Unknown JVMTI version '" << version
+               << "', requesting the latest one";
+  return 0;
+}
+
+// Acquires the JVMTI environment, retrying on transient failures.
+static jint GetJvmtiEnv(JavaVM *vm, jvmtiEnv **jvmti) {
+  jint forced_version = ForcedJvmtiVersion();
+  if (forced_version != 0) {
+    LOG(INFO) << "Requesting JVMTI version " << FLAGS_cprof_jvmti_version;
+  }
+  for (int attempt = 1;; attempt++) {
+    // Try to get the latest JVMTI_VERSION the agent was built with, unless
+    // another version is forced.
+    jint err = vm->GetEnv(reinterpret_cast<void **>(jvmti),
+                          forced_version != 0 ? forced_version : JVMTI_VERSION);
+    if (err == JNI_EVERSION && forced_version == 0) {
+      // The above call can fail if the VM is actually from an older VM,
+      // therefore try to get an older JVMTI (compatible with JDK8).
+      err = vm->GetEnv(reinterpret_cast<void **>(jvmti), JVMTI_VERSION_1_2);
+    }
+    if ((err != JNI_ERR && err != JNI_ENOMEM) ||
+        attempt > FLAGS_cprof_init_retries) {
+      return err;
+    }
+    SleepBeforeInitRetry("GetEnv", attempt);
+  }
+}
+
+// Returns whether the CPU profiling is to be driven by perf events.
+static bool UsePerfEvents() {
+  if (FLAGS_cprof_cpu_profiling_mode == "itimer") {
+    return false;
+  }
+  if (FLAGS_cprof_cpu_profiling_mode != "perf_event") {
+    LOG(WARNING) << "Unknown CPU profiling mode '"
+                 << FLAGS_cprof_cpu_profiling_mode << "', using 'itimer'";
+    return false;
+  }
+  if (!PerfEventsAvailable()) {
+    LOG(WARNING) << "Falling back to the 'itimer' CPU profiling mode";
+    return false;
+  }
+  LOG(INFO) << "Using perf events for CPU profiling";
+  return true;
 }
 
 static bool PrepareJvmti(JavaVM *vm, jvmtiEnv *jvmti) {
@@ -167,15 +447,86 @@ static bool PrepareJvmti(JavaVM *vm, jvmtiEnv *jvmti) {
   caps.can_get_line_numbers = 1;
   caps.can_get_bytecodes = 1;
   caps.can_get_constant_pool = 1;
-  if (FLAGS_cprof_force_debug_non_safepoints) {
+  if (FLAGS_cprof_force_debug_non_safepoints ||
+      !FLAGS_cprof_show_inlined_frames) {
     caps.can_generate_compiled_method_load_events = 1;
   }
+  if (FLAGS_cprof_capture_top_frame_args) {
+    caps.can_access_local_variables = 1;
+  }
+  if (FLAGS_cprof_label_gc_samples || FLAGS_cprof_wall_skip_gc_rounds) {
+    caps.can_generate_garbage_collection_events = 1;
+  }
+  if (FLAGS_cprof_experimental_async_stacks) {
+    AsyncStacks::AddCapabilities(&caps);
+  }
 
   jvmtiCapabilities all_caps;
   int error;
 
   if (JVMTI_ERROR_NONE ==
       (error = jvmti->GetPotentialCapabilities(&all_caps))) {
+    // Some JVMs cannot generate the code generation events that force
+    // DebugNonSafepoints. Carry on without them rather than failing, since
+    // they only improve the accuracy of the profiles.
+    if (FLAGS_cprof_force_debug_non_safepoints &&
+        !all_caps.can_generate_compiled_method_load_events) {
+      LOG(WARNING) << "JVM does not support compiled method load events, "
+                   << "disabling '-cprof_force_debug_non_safepoints'";
+      FLAGS_cprof_force_debug_non_safepoints = false;
+      caps.can_generate_compiled_method_load_events = 0;
+    }
+    if (!FLAGS_cprof_show_inlined_frames &&
+        !all_caps.can_generate_compiled_method_load_events) {
+      LOG(WARNING) << "JVM does not support compiled method load events, "
+                   << "showing the inlined frames";
+      FLAGS_cprof_show_inlined_frames = true;
+      caps.can_generate_compiled_method_load_events = 0;
+    }
+    // The heap sampling adds its capabilities when enabled at VMInit, but
+    // the JVMs before Java 11 lack the sampled allocation events altogether,
+    // so tell it apart from the other failures up front.
+    if (FLAGS_cprof_enable_heap_sampling &&
+        !all_caps.can_generate_sampled_object_alloc_events) {
+      LOG(WARNING) << "JVM does not support sampled object allocation "
+                   << "events, the heap sampling requires Java 11 or later, "
+                   << "disabling '-cprof_enable_heap_sampling' and profiling "
+                   << "the other types";
+      FLAGS_cprof_enable_heap_sampling = false;
+    }
+    if (FLAGS_cprof_capture_top_frame_args &&
+        !all_caps.can_access_local_variables) {
+      LOG(WARNING) << "JVM does not support accessing local variables, "
+                   << "disabling '-cprof_capture_top_frame_args'";
+      FLAGS_cprof_capture_top_frame_args = false;
+      caps.can_access_local_variables = 0;
+    }
+    if ((FLAGS_cprof_label_gc_samples || FLAGS_cprof_wall_skip_gc_rounds) &&
+        !all_caps.can_generate_garbage_collection_events) {
+      // -cprof_wall_skip_gc_rounds still skips the late rounds.
+      LOG(WARNING) << "JVM does not support garbage collection events, "
+                   << "disabling '-cprof_label_gc_samples' and the garbage "
+                   << "collection rounds of '-cprof_wall_skip_gc_rounds'";
+      FLAGS_cprof_label_gc_samples = false;
+      caps.can_generate_garbage_collection_events = 0;
+    }
+    if (FLAGS_cprof_experimental_async_stacks &&
+        !AsyncStacks::Supported(all_caps)) {
+      LOG(WARNING) << "JVM does not support breakpoints, local variable "
+                   << "access or object tagging, disabling "
+                   << "'-cprof_experimental_async_stacks'";
+      FLAGS_cprof_experimental_async_stacks = false;
+      caps.can_generate_breakpoint_events = 0;
+      caps.can_tag_objects = 0;
+      caps.can_access_local_variables = FLAGS_cprof_capture_top_frame_args;
+    }
+    // Needed to check the per-thread timers, which are used without it
+    // otherwise.
+    if (FLAGS_cprof_cpu_use_per_thread_timers &&
+        all_caps.can_get_current_thread_cpu_time) {
+      caps.can_get_current_thread_cpu_time = 1;
+    }
+
     // This makes sure that if we need a capability, it is one of the
     // potential capabilities.  The technique isn't wonderful, but it
     // is compact and as likely to be compatible between versions as
@@ -187,20 +538,41 @@ static bool PrepareJvmti(JavaVM *vm, jvmtiEnv *jvmti) {
         return false;
       }
     }
+  } else {
+    // Without the potential capabilities the desired ones cannot be checked
+    // up front. Request them anyway and let AddCapabilities report the actual
+    // problem, if any.
+    LOG(WARNING) << "Failed to get potential capabilities with error " << error
+                 << ", adding the capabilities unchecked";
+  }
 
-    // This adds the capabilities.
-    if ((error = jvmti->AddCapabilities(&caps)) != JVMTI_ERROR_NONE) {
+  // This adds the capabilities.
+  for (int attempt = 1;
+       (error = jvmti->AddCapabilities(&caps)) != JVMTI_ERROR_NONE;
+       attempt++) {
+    if (!IsRetryableJvmtiError(static_cast<jvmtiError>(error)) ||
+        attempt > FLAGS_cprof_init_retries) {
       LOG(ERROR) << "Failed to add capabilities with error " << error;
       return false;
     }
+    SleepBeforeInitRetry("AddCapabilities", attempt);
   }
+  gc_events = caps.can_generate_garbage_collection_events;
 
   return true;
 }
 
+// The callbacks set by RegisterJvmti, kept for the features disabled at
+// runtime to remove theirs from.
+static jvmtiEventCallbacks consolidated_callbacks;
+
 static bool RegisterJvmti(jvmtiEnv *jvmti) {
-  // Create the list of callbacks to be called on given events.
-  jvmtiEventCallbacks callbacks;
+  // Create the list of callbacks to be called on given events. This is the
+  // only place adding the callbacks: SetEventCallbacks replaces all of the
+  // previously set ones, so every feature has to add its callbacks to this
+  // consolidated list instead of setting them itself. A feature disabled at
+  // runtime removes its own from the list, and sets it again.
+  jvmtiEventCallbacks &callbacks = consolidated_callbacks;
   memset(&callbacks, 0, sizeof(jvmtiEventCallbacks));
 
   callbacks.ThreadStart = &OnThreadStart;
@@ -218,11 +590,24 @@ static bool RegisterJvmti(jvmtiEnv *jvmti) {
       JVMTI_EVENT_VM_DEATH,   JVMTI_EVENT_VM_INIT,
   };
 
-  if (FLAGS_cprof_force_debug_non_safepoints) {
+  if (FLAGS_cprof_force_debug_non_safepoints ||
+      !FLAGS_cprof_show_inlined_frames) {
     callbacks.CompiledMethodLoad = &OnCompiledMethodLoad;
     events.push_back(JVMTI_EVENT_COMPILED_METHOD_LOAD);
   }
 
+  if (FLAGS_cprof_experimental_async_stacks) {
+    callbacks.Breakpoint = &AsyncStacks::OnBreakpoint;
+    events.push_back(JVMTI_EVENT_BREAKPOINT);
+  }
+
+  if (gc_events) {
+    callbacks.GarbageCollectionStart = &OnGarbageCollectionStart;
+    callbacks.GarbageCollectionFinish = &OnGarbageCollectionFinish;
+    events.push_back(JVMTI_EVENT_GARBAGE_COLLECTION_START);
+    events.push_back(JVMTI_EVENT_GARBAGE_COLLECTION_FINISH);
+  }
+
   JVMTI_ERROR_1(
       (jvmti->SetEventCallbacks(&callbacks, sizeof(jvmtiEventCallbacks))),
       false);
@@ -238,6 +623,24 @@ static bool RegisterJvmti(jvmtiEnv *jvmti) {
   return true;
 }
 
+// Returns true if the agent option is a known flag, e.g. -cprof_service=foo,
+// --cprof_enabled or -nocprof_enabled.
+static bool IsKnownFlagOption(const std::string &option) {
+  size_t start = option.find_first_not_of('-');
+  if (start == 0 || start == std::string::npos) {
+    return false;
+  }
+  std::string name = option.substr(start, option.find('=') - start);
+  gflags::CommandLineFlagInfo info;
+  if (gflags::GetCommandLineFlagInfo(name.c_str(), &info)) {
+    return true;
+  }
+  // The negated form of a boolean flag.
+  return name.compare(0, 2, "no") == 0 &&
+         gflags::GetCommandLineFlagInfo(name.c_str() + 2, &info) &&
+         info.type == "bool" && option.find('=') == std::string::npos;
+}
+
 static void ParseArguments(const char *options) {
   // Split agent options to command line argument style data structure.
   if (options == nullptr) {
@@ -245,9 +648,21 @@ static void ParseArguments(const char *options) {
   }
   std::vector<std::string> split_options = Split(options, ',');
 
+  // The flags parser treats the bare tokens as positional arguments, which
+  // the agent has none of, and exits the process on an unknown flag. Both
+  // are ignored instead, as a typo in the agent options must not take the
+  // application down.
+  std::vector<std::string> ignored_options;
   std::vector<char *> argv_vector;
   argv_vector.push_back(const_cast<char *>("cprof_java_agent"));
   for (const std::string &split_option : split_options) {
+    if (split_option.empty()) {
+      continue;
+    }
+    if (!IsKnownFlagOption(split_option)) {
+      ignored_options.push_back(split_option);
+      continue;
+    }
     argv_vector.push_back(const_cast<char *>(split_option.c_str()));
   }
 
@@ -260,6 +675,96 @@ static void ParseArguments(const char *options) {
 #else
   InitGoogle(argv[0], &argc, &argv, true);
 #endif
+
+  // Logged once the logging is initialized.
+  for (const std::string &option : ignored_options) {
+    if (option[0] == '-') {
+      LOG(WARNING) << "Ignoring unknown agent option '" << option << "'";
+    } else {
+      LOG(WARNING) << "Ignoring agent argument '" << option
+                   << "', the options are of the form -cprof_name=value";
+    }
+  }
+}
+
+void ApplyPropertyOptions(const std::string &options) {
+  const std::string &property = FLAGS_cprof_options_property;
+  LOG(INFO) << "Applying the options of the " << property
+            << " system property: " << options;
+  for (const std::string &option : Split(options, ',')) {
+    if (option.empty()) {
+      continue;
+    }
+    if (!IsKnownFlagOption(option)) {
+      LOG(WARNING) << "Ignoring unknown option '" << option
+                   << "' of the " << property << " system property";
+      continue;
+    }
+    size_t start = option.find_first_not_of('-');
+    size_t equals = option.find('=');
+    std::string name = option.substr(start, equals - start);
+    std::string flag_value =
+        equals == std::string::npos ? "" : option.substr(equals + 1);
+    gflags::CommandLineFlagInfo info;
+    if (!gflags::GetCommandLineFlagInfo(name.c_str(), &info)) {
+      // The negated form of a boolean flag.
+      name = name.substr(2);
+      flag_value = "false";
+    } else if (equals == std::string::npos) {
+      if (info.type != "bool") {
+        LOG(WARNING) << "Ignoring option '" << option
+                     << "' of the " << property << " system property, it "
+                     << "has no value";
+        continue;
+      }
+      flag_value = "true";
+    }
+    if (!gflags::GetCommandLineFlagInfo(name.c_str(), &info) ||
+        !info.is_default) {
+      LOG(INFO) << "Option '" << option << "' of the " << property
+                << " system property is overridden by the agent options";
+      continue;
+    }
+    if (gflags::SetCommandLineOption(name.c_str(), flag_value.c_str())
+            .empty()) {
+      LOG(WARNING) << "Ignoring invalid option '" << option
+                   << "' of the " << property << " system property";
+    }
+  }
+}
+
+// Applies the options of the -cprof_options_property system property, if set.
+// The options used to acquire the JVMTI environment, e.g.
+// -cprof_jvmti_version, are only read from the agent options.
+static void ApplyOptionsProperty(jvmtiEnv *jvmti) {
+  if (FLAGS_cprof_options_property.empty()) {
+    return;
+  }
+  google::javaprofiler::JvmtiScopedPtr<char> value(jvmti);
+  if (jvmti->GetSystemProperty(FLAGS_cprof_options_property.c_str(),
+                               value.GetRef()) != JVMTI_ERROR_NONE ||
+      value.Get() == nullptr) {
+    return;
+  }
+  ApplyPropertyOptions(value.Get());
+}
+
+// Handles a load of the agent after the first one, either ignored or failed
+// by -cprof_fail_repeated_load. The flags were parsed by the first load.
+static jint RepeatedLoad(JavaVM *vm, const char *entry_point) {
+  if (vm != initialized_vm) {
+    LOG(ERROR) << entry_point << " called for another JVM, the profiler agent "
+               << "supports a single JVM per process";
+    return JNI_ERR;
+  }
+  if (FLAGS_cprof_fail_repeated_load) {
+    LOG(ERROR) << "The profiler agent is already loaded, failing "
+               << entry_point;
+    return JNI_ERR;
+  }
+  LOG(WARNING) << "The profiler agent is already loaded, ignoring "
+               << entry_point << " and its options";
+  return JNI_OK;
 }
 
 jint JNICALL Agent_OnLoad(JavaVM *vm, char *options, void *reserved) {
@@ -267,24 +772,57 @@ jint JNICALL Agent_OnLoad(JavaVM *vm, char *options, void *reserved) {
   int err;
   jvmtiEnv *jvmti;
 
+  std::lock_guard<std::mutex> lock(init_mutex);
+  if (initialized_vm != nullptr) {
+    return RepeatedLoad(vm, "Agent_OnLoad");
+  }
+  initialized_vm = vm;
+
   ParseArguments(options);  // Initializes logger -- do not log before this call
 
   LOG(INFO) << "Google Cloud Profiler Java agent version: "
             << CLOUD_PROFILER_AGENT_VERSION;
   LOG(INFO) << "Profiler agent loaded";
-  google::javaprofiler::AttributeTable::Init();
 
-  // Try to get the latest JVMTI_VERSION the agent was built with.
-  err = vm->GetEnv(reinterpret_cast<void **>(&jvmti), JVMTI_VERSION);
-  if (err == JNI_EVERSION) {
-    // The above call can fail if the VM is actually from an older VM, therefore
-    // try to get an older JVMTI (compatible with JDK8).
-    err = (vm->GetEnv(reinterpret_cast<void **>(&jvmti), JVMTI_VERSION_1_2));
-  }
+  err = GetJvmtiEnv(vm, &jvmti);
   if (err != JNI_OK) {
     LOG(ERROR) << "JNI Error " << err;
     return 1;
   }
+  ApplyOptionsProperty(jvmti);
+
+  google::javaprofiler::AttributeTable::Init();
+  google::javaprofiler::SampleBudget::SetRate(
+      FLAGS_cprof_total_sample_budget_per_sec);
+  google::javaprofiler::MemoryBudget::SetLimit(
+      FLAGS_cprof_max_agent_heap_bytes);
+  google::javaprofiler::SetResolveLineNumbers(FLAGS_cprof_resolve_line_numbers);
+  if (FLAGS_cprof_anonymize_symbols) {
+    google::javaprofiler::SetAnonymizedSymbols(FLAGS_cprof_anonymize_salt);
+  }
+  if (!FLAGS_cprof_redact_packages.empty()) {
+    google::javaprofiler::SetRedactedPackages(
+        Split(FLAGS_cprof_redact_packages, ','));
+  }
+
+  if (!FLAGS_cprof_crash_breadcrumb_file.empty()) {
+    breadcrumb = new CrashBreadcrumb(FLAGS_cprof_crash_breadcrumb_file);
+    if (breadcrumb->Check(time(nullptr), FLAGS_cprof_crash_loop_max_restarts,
+                          FLAGS_cprof_crash_loop_window_sec)) {
+      LOG(ERROR) << "The JVM was restarted without a clean shutdown "
+                 << FLAGS_cprof_crash_loop_max_restarts << " times within "
+                 << FLAGS_cprof_crash_loop_window_sec << "s, the profiler "
+                 << "agent may be crashing it. PROFILING IS DISABLED. Remove "
+                 << FLAGS_cprof_crash_breadcrumb_file << " to re-enable it.";
+      return 0;
+    }
+  }
+
+  if (FLAGS_cprof_install_crash_handler) {
+    // The JVM has installed its own signal handlers at this point, the crash
+    // handler chains to them.
+    InstallCrashHandler(JvmVersion(jvmti));
+  }
 
   if (!PrepareJvmti(vm, jvmti)) {
     LOG(ERROR) << "Failed to initialize JVMTI.  Continuing...";
@@ -300,10 +838,17 @@ jint JNICALL Agent_OnLoad(JavaVM *vm, char *options, void *reserved) {
     LOG(WARNING) << "Per thread timers not available in Alpine. "
                  << "Ignoring '-cprof_cpu_use_per_thread_timers' flag.";
   }
-  threads = new ThreadTable(false);
+  threads = new ThreadTable(false, UsePerfEvents());
 #else
-  threads = new ThreadTable(FLAGS_cprof_cpu_use_per_thread_timers);
+  bool use_timers = FLAGS_cprof_cpu_use_per_thread_timers;
+  if (use_timers && !ThreadTimersAvailable()) {
+    LOG(WARNING) << "Per thread timers are not available, falling back to "
+                 << "the process interval timer for CPU profiling";
+    use_timers = false;
+  }
+  threads = new ThreadTable(use_timers, UsePerfEvents());
 #endif
+  ConfigureSamplingSignal(!threads->UseTimers());
 
   if (!RegisterJvmti(jvmti)) {
     LOG(ERROR) << "Failed to enable JVMTI events.  Continuing...";
@@ -316,8 +861,18 @@ jint JNICALL Agent_OnLoad(JavaVM *vm, char *options, void *reserved) {
   google::javaprofiler::Asgct::SetAsgct(
       google::javaprofiler::Accessors::GetJvmFunction<
           google::javaprofiler::ASGCTType>("AsyncGetCallTrace"));
+  if (google::javaprofiler::Asgct::GetAsgct() == nullptr) {
+    // Calling it from the signal handler would crash the JVM.
+    LOG(ERROR) << "AsyncGetCallTrace is not available in this JVM, which is "
+               << "likely not HotSpot-based. CPU and wall profiling are "
+               << "disabled.";
+  }
 
   worker = new Worker(jvmti, threads);
+
+  if (breadcrumb != nullptr) {
+    breadcrumb->Write();
+  }
   return 0;
 }
 
@@ -326,11 +881,30 @@ void JNICALL Agent_OnUnload(JavaVM *vm) { IMPLICITLY_USE(vm); }
 }  // namespace profiler
 }  // namespace cloud
 
+// The options are a null-terminated string, or null when no options are given.
+// They are only read during the call and not retained, so the caller keeps
+// the ownership and may release them once the call returns.
 AGENTEXPORT jint JNICALL Agent_OnLoad(JavaVM *vm, char *options,
                                       void *reserved) {
   return cloud::profiler::Agent_OnLoad(vm, options, reserved);
 }
 
+// Variant of Agent_OnLoad for the launchers which link the agent in and call
+// it directly, with the options given as a buffer of options_len bytes which
+// does not need to be null-terminated. A null options pointer means no
+// options.
+extern "C" AGENTEXPORT jint JNICALL Agent_OnLoad_with_len(JavaVM *vm,
+                                                          const char *options,
+                                                          size_t options_len,
+                                                          void *reserved) {
+  if (options == nullptr) {
+    return cloud::profiler::Agent_OnLoad(vm, nullptr, reserved);
+  }
+  std::string terminated_options(options, strnlen(options, options_len));
+  return cloud::profiler::Agent_OnLoad(
+      vm, const_cast<char *>(terminated_options.c_str()), reserved);
+}
+
 AGENTEXPORT void JNICALL Agent_OnUnload(JavaVM *vm) {
   return cloud::profiler::Agent_OnUnload(vm);
 }
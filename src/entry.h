@@ -0,0 +1,34 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_ENTRY_H_
+#define CLOUD_PROFILER_AGENT_JAVA_ENTRY_H_
+
+#include <string>
+
+namespace cloud {
+namespace profiler {
+
+// Applies the options read from the -cprof_options_property system property,
+// of the same form as the agent options. The flags the agent options set
+// keep their value, and the unknown or invalid options are ignored with a
+// warning. Public for testing.
+void ApplyPropertyOptions(const std::string &options);
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_ENTRY_H_
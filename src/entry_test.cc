@@ -0,0 +1,66 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/entry.h"
+
+#include <string>
+
+#include "gtest/gtest.h"
+#include "src/globals.h"
+
+DECLARE_bool(cprof_fail_repeated_load);
+DECLARE_int32(cprof_init_retries);
+DECLARE_string(cprof_gcs_prefix);
+DECLARE_string(cprof_service);
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+TEST(ApplyPropertyOptionsTest, AppliesTheOptions) {
+  gflags::FlagSaver saver;
+  ApplyPropertyOptions(
+      "-cprof_gcs_prefix=property/,,--cprof_fail_repeated_load");
+  EXPECT_EQ("property/", FLAGS_cprof_gcs_prefix);
+  EXPECT_TRUE(FLAGS_cprof_fail_repeated_load);
+}
+
+TEST(ApplyPropertyOptionsTest, AgentOptionsTakePrecedence) {
+  gflags::FlagSaver saver;
+  // As set by the agent options, parsed before the property is read.
+  gflags::SetCommandLineOption("cprof_service", "agent");
+  gflags::SetCommandLineOption("cprof_fail_repeated_load", "true");
+  ApplyPropertyOptions(
+      "-cprof_service=property,-nocprof_fail_repeated_load,"
+      "-cprof_gcs_prefix=property/");
+  EXPECT_EQ("agent", FLAGS_cprof_service);
+  EXPECT_TRUE(FLAGS_cprof_fail_repeated_load);
+  EXPECT_EQ("property/", FLAGS_cprof_gcs_prefix);
+}
+
+TEST(ApplyPropertyOptionsTest, IgnoresTheInvalidOptions) {
+  gflags::FlagSaver saver;
+  ApplyPropertyOptions(
+      "-cprof_unknown=1,cprof_service=bare,-cprof_gcs_prefix,"
+      "-cprof_init_retries=many");
+  EXPECT_EQ("", FLAGS_cprof_service);
+  EXPECT_EQ("", FLAGS_cprof_gcs_prefix);
+  EXPECT_EQ(2, FLAGS_cprof_init_retries);
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
@@ -55,6 +55,7 @@ namespace profiler {
 // easier to just re-use the constants this way to keep the code easy to read.
 //
 // Remove this when porting is done.
+using google::javaprofiler::kAsyncBoundary;
 using google::javaprofiler::kDeopt;
 using google::javaprofiler::kGcActive;
 using google::javaprofiler::kNativeStackTrace;
@@ -84,8 +85,9 @@ using google::javaprofiler::JVMPI_CallTrace;
 // value and require cleanup.
 #define JVMTI_ERROR_CLEANUP_1(error, retval, cleanup) \
   {                                                   \
-    int err;                                          \
-    if ((err = (error)) != JVMTI_ERROR_NONE) {        \
+    int err = (error);                                \
+    JVMTI_TRACE(error, err);                          \
+    if (err != JVMTI_ERROR_NONE) {                    \
       LOG(ERROR) << "JVMTI error " << err;            \
       cleanup;                                        \
       return (retval);                                \
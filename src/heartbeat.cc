@@ -0,0 +1,69 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "src/heartbeat.h"
+
+#include <pthread.h>
+
+#include <algorithm>
+
+namespace cloud {
+namespace profiler {
+
+void Heartbeat::Start(int interval_sec) {
+  thread_ = std::thread(&Heartbeat::Run, this, interval_sec * kNanosPerSecond);
+}
+
+void Heartbeat::Stop() {
+  if (!thread_.joinable()) {
+    return;
+  }
+  stopping_ = true;
+  thread_.join();
+}
+
+void Heartbeat::Run(int64_t interval_ns) {
+  pthread_setname_np(pthread_self(), "cprof-heartbeat");
+  int64_t last_samples = stats_->SamplesCollected();
+  while (!stopping_) {
+    // Sleep in short steps to not hold up the shutdown.
+    const int64_t kStepNanos = kNanosPerSecond;
+    for (int64_t left_ns = interval_ns; left_ns > 0 && !stopping_;
+         left_ns -= kStepNanos) {
+      clock_->SleepFor(NanosToTimeSpec(std::min(left_ns, kStepNanos)));
+    }
+    if (!stopping_) {
+      Log(&last_samples);
+    }
+  }
+}
+
+void Heartbeat::Log(int64_t *last_samples) {
+  int64_t samples = stats_->SamplesCollected();
+  int64_t next_upload_ns = stats_->NextUploadNanos();
+  std::string next_upload = "not scheduled yet";
+  if (next_upload_ns > 0) {
+    int64_t in_sec = std::max<int64_t>(
+        0, (next_upload_ns - TimeSpecToNanos(clock_->Now())) / kNanosPerSecond);
+    next_upload = "in " + std::to_string(in_sec) + "s";
+  }
+  LOG(INFO) << "Profiler agent heartbeat: phase " << stats_->Phase() << ", "
+            << samples - *last_samples << " samples collected since the last "
+            << "heartbeat, " << stats_->ProfilesUploaded()
+            << " profiles uploaded, next upload " << next_upload;
+  *last_samples = samples;
+}
+
+}  // namespace profiler
+}  // namespace cloud
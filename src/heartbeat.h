@@ -0,0 +1,60 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_HEARTBEAT_H_
+#define CLOUD_PROFILER_AGENT_JAVA_HEARTBEAT_H_
+
+#include <atomic>
+#include <thread>  // NOLINT(build/c++11)
+
+#include "src/agent_stats.h"
+#include "src/clock.h"
+#include "src/globals.h"
+
+namespace cloud {
+namespace profiler {
+
+// Heartbeat periodically logs a summary of the agent state: the phase of the
+// profiling loop, the samples collected since the last heartbeat and the
+// next upload, so that the agent is seen alive through the long quiet
+// periods, e.g. the warmup before the first profile.
+class Heartbeat {
+ public:
+  Heartbeat(AgentStats *stats, Clock *clock)
+      : stats_(stats), clock_(clock), stopping_() {}
+  ~Heartbeat() { Stop(); }
+
+  // Starts logging every interval_sec seconds.
+  void Start(int interval_sec);
+  // Stops logging and waits for the logging thread to exit.
+  void Stop();
+
+ private:
+  void Run(int64_t interval_ns);
+  void Log(int64_t *last_samples);
+
+  AgentStats *stats_;
+  Clock *clock_;
+  std::atomic<bool> stopping_;
+  std::thread thread_;
+
+  DISALLOW_COPY_AND_ASSIGN(Heartbeat);
+};
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_HEARTBEAT_H_
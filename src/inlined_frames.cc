@@ -0,0 +1,80 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "src/inlined_frames.h"
+
+#include <jvmticmlr.h>
+
+#include <mutex>  // NOLINT(build/c++11)
+#include <tuple>
+#include <unordered_set>
+
+namespace cloud {
+namespace profiler {
+
+namespace {
+
+// A call site: the inlined method, the caller method and the bci of the call
+// in the caller.
+typedef std::tuple<jmethodID, jmethodID, jint> CallSite;
+
+class CallSiteHasher {
+ public:
+  size_t operator()(const CallSite &s) const {
+    size_t hash = reinterpret_cast<size_t>(std::get<0>(s));
+    hash = hash + ((hash << 8) ^ reinterpret_cast<size_t>(std::get<1>(s)));
+    hash = hash + ((hash << 8) ^ std::get<2>(s));
+    return hash;
+  }
+};
+
+std::mutex call_sites_mutex;
+// Never destroyed, the compiled method load events may come up to the
+// process exit.
+std::unordered_set<CallSite, CallSiteHasher> *call_sites =
+    new std::unordered_set<CallSite, CallSiteHasher>();
+
+}  // namespace
+
+void InlinedFrames::Record(const void *compile_info) {
+  std::lock_guard<std::mutex> lock(call_sites_mutex);
+  for (const jvmtiCompiledMethodLoadRecordHeader *header =
+           static_cast<const jvmtiCompiledMethodLoadRecordHeader *>(
+               compile_info);
+       header != nullptr; header = header->next) {
+    if (header->kind != JVMTI_CMLR_INLINE_INFO) {
+      continue;
+    }
+    const jvmtiCompiledMethodLoadInlineRecord *record =
+        reinterpret_cast<const jvmtiCompiledMethodLoadInlineRecord *>(header);
+    for (jint i = 0; i < record->numpcs; i++) {
+      const PCStackInfo &info = record->pcinfo[i];
+      // The innermost method first, each one inlined into the next one, up
+      // to the compiled method.
+      for (jint j = 0; j + 1 < info.numstackframes; j++) {
+        call_sites->insert(
+            CallSite(info.methods[j], info.methods[j + 1], info.bcis[j + 1]));
+      }
+    }
+  }
+}
+
+bool InlinedFrames::IsInlined(jmethodID method, jmethodID caller,
+                              jint caller_bci) {
+  std::lock_guard<std::mutex> lock(call_sites_mutex);
+  return call_sites->count(CallSite(method, caller, caller_bci)) != 0;
+}
+
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,55 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_INLINED_FRAMES_H_
+#define CLOUD_PROFILER_AGENT_JAVA_INLINED_FRAMES_H_
+
+#include <jni.h>
+
+#include "src/globals.h"
+
+namespace cloud {
+namespace profiler {
+
+// The call sites inlined by the JIT compilers, recorded from the inline
+// information of the JVMTI compiled method load events, used to collapse the
+// inlined frames into the physical frame of the compiled method when
+// -cprof_show_inlined_frames is unset.
+//
+// The stack traces of AsyncGetCallTrace carry the logical frames, including
+// the inlined ones, but not the compiled code they run, so a frame is
+// considered inlined when its method was inlined by any compilation at the
+// call site of the caller frame. This is exact unless the caller also runs
+// interpreted or from an older compilation which did not inline the call.
+// The call sites are kept when their compiled code is unloaded.
+class InlinedFrames {
+ public:
+  // Records the call sites of the inline records in the compile_info of a
+  // compiled method load event.
+  static void Record(const void *compile_info);
+
+  // Returns true if method was inlined into the caller method at the
+  // caller_bci call site.
+  static bool IsInlined(jmethodID method, jmethodID caller, jint caller_bci);
+
+ private:
+  DISALLOW_IMPLICIT_CONSTRUCTORS(InlinedFrames);
+};
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_INLINED_FRAMES_H_
@@ -0,0 +1,100 @@
+#!/bin/bash
+#
+# Copyright 2018 Google LLC
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+# The script runs a Java workload with the agent, a CPU and a wall profile
+# being collected on a timer and saved to local files instead of uploaded to
+# the Cloud Profiler API, and checks the agent behavior.
+#
+# Usage: src/integration_test.sh [agent .so path]
+# JAVA_HOME selects the JDK, the java and javac in the PATH are used
+# otherwise. PROTOC selects the protoc used to decode the profiles.
+
+set -o errexit
+set -o nounset
+set -o pipefail
+
+AGENT=$(realpath "${1:-.out/profiler_java_agent.so}")
+JAVA_BIN="${JAVA_HOME:+${JAVA_HOME}/bin/}"
+PROTOC="${PROTOC:-protoc}"
+PROFILE_PROTO_PATH=third_party/perftools/profiles/proto
+# A single round of the two 5s profiles, well within the workload duration.
+AGENT_OPTIONS="-cprof_duration_sec=5,-cprof_interval_sec=10,-cprof_max_count=1"
+WORKLOAD_SEC=15
+
+TEST_DIR=$(mktemp -d)
+trap 'rm -rf "${TEST_DIR}"' EXIT
+
+"${JAVA_BIN}javac" -d "${TEST_DIR}/classes" \
+    src/testdata/com/example/Testo.java
+
+FAILURES=0
+
+fail() {
+  echo "FAIL: $*"
+  FAILURES=$((FAILURES + 1))
+}
+
+# Runs the workload with the agent options given as the first argument, the
+# profiles being saved under the directory given as the second argument and
+# the stderr output to its stderr file.
+run_workload() {
+  local options=$1
+  local out_dir=$2
+  mkdir -p "${out_dir}"
+  "${JAVA_BIN}java" \
+      "-agentpath:${AGENT}=${AGENT_OPTIONS},-cprof_profile_filename=${out_dir}/,-log_dir=${out_dir}${options}" \
+      -cp "${TEST_DIR}/classes" com.example.Testo "${WORKLOAD_SEC}" \
+      2> "${out_dir}/stderr"
+}
+
+# The agent is quiet by default: the profiling logs go to the log files.
+run_workload "" "${TEST_DIR}/quiet"
+if [[ -s "${TEST_DIR}/quiet/stderr" ]]; then
+  fail "the agent wrote to stderr while profiling:"
+  cat "${TEST_DIR}/quiet/stderr"
+fi
+
+# The saved profiles are symbolized: the frames of the workload method,
+# only encoded for the stacks with a nonzero count, show up by name.
+for type in cpu wall; do
+  profile=$(ls "${TEST_DIR}/quiet/${type}_"*.pb.gz 2> /dev/null | head -n 1)
+  if [[ -z "${profile}" ]]; then
+    fail "no ${type} profile was saved"
+    continue
+  fi
+  gunzip -c "${profile}" | \
+      "${PROTOC}" -I "${PROFILE_PROTO_PATH}" \
+          --decode=perftools.profiles.Profile profile.proto \
+      > "${TEST_DIR}/quiet/${type}.txt"
+  if ! grep -q '"com.example.Testo.hotLoop' "${TEST_DIR}/quiet/${type}.txt"; then
+    fail "com.example.Testo.hotLoop is missing from the ${type} profile"
+  fi
+  if ! grep -Eq '^  value: [1-9]' "${TEST_DIR}/quiet/${type}.txt"; then
+    fail "the ${type} profile has no sample with a nonzero value"
+  fi
+done
+
+# With -logtostderr, the logs go to stderr instead.
+run_workload ",-logtostderr" "${TEST_DIR}/verbose"
+if ! grep -q "Profiler agent loaded" "${TEST_DIR}/verbose/stderr"; then
+  fail "the agent did not log to stderr with -logtostderr"
+fi
+
+if [[ "${FAILURES}" -ne 0 ]]; then
+  echo "${FAILURES} check(s) failed"
+  exit 1
+fi
+echo "PASS"
@@ -0,0 +1,189 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "src/jni_env.h"
+
+#include <stdarg.h>
+
+DEFINE_bool(cprof_describe_jni_exceptions, false,
+            "when true, print the stack traces of the JNI exceptions cleared "
+            "by the agent to stderr");
+
+namespace cloud {
+namespace profiler {
+
+namespace {
+
+// Returns the class name of the exception, e.g.
+// "java.security.AccessControlException". Called with no exception pending.
+std::string ExceptionClassName(JNIEnv *jni, jthrowable exception) {
+  std::string name = "unknown exception";
+  jclass exception_class = jni->GetObjectClass(exception);
+  jclass class_class = jni->FindClass("java/lang/Class");
+  jmethodID get_name =
+      class_class == nullptr
+          ? nullptr
+          : jni->GetMethodID(class_class, "getName", "()Ljava/lang/String;");
+  if (exception_class != nullptr && get_name != nullptr) {
+    jstring jstr = reinterpret_cast<jstring>(
+        jni->CallObjectMethod(exception_class, get_name));
+    if (jstr != nullptr && !jni->ExceptionCheck()) {
+      const char *s = jni->GetStringUTFChars(jstr, nullptr);
+      if (s != nullptr) {
+        name = s;
+        jni->ReleaseStringUTFChars(jstr, s);
+      }
+      jni->DeleteLocalRef(jstr);
+    }
+  }
+  // Not logged, a failure to name the exception is of no interest.
+  jni->ExceptionClear();
+  jni->DeleteLocalRef(class_class);
+  jni->DeleteLocalRef(exception_class);
+  return name;
+}
+
+}  // namespace
+
+bool ClearJNIException(JNIEnv *jni, const std::string &what) {
+  jthrowable exception = jni->ExceptionOccurred();
+  if (exception == nullptr) {
+    return false;
+  }
+  if (FLAGS_cprof_describe_jni_exceptions) {
+    // Prints the stack trace and clears the exception.
+    jni->ExceptionDescribe();
+  }
+  jni->ExceptionClear();
+  LOG(WARNING) << "Failed to " << what << ": "
+               << ExceptionClassName(jni, exception);
+  jni->DeleteLocalRef(exception);
+  return true;
+}
+
+jclass JNIEnvWrapper::FindClass(const char *name) {
+  jclass cls = jni_->FindClass(name);
+  if (ExceptionClear(std::string("find class ") + name)) {
+    return nullptr;
+  }
+  return cls;
+}
+
+jmethodID JNIEnvWrapper::GetMethodID(jclass cls, const char *name,
+                                     const char *signature) {
+  jmethodID method = jni_->GetMethodID(cls, name, signature);
+  if (ExceptionClear(std::string("find method ") + name)) {
+    return nullptr;
+  }
+  return method;
+}
+
+jmethodID JNIEnvWrapper::GetStaticMethodID(jclass cls, const char *name,
+                                           const char *signature) {
+  jmethodID method = jni_->GetStaticMethodID(cls, name, signature);
+  if (ExceptionClear(std::string("find static method ") + name)) {
+    return nullptr;
+  }
+  return method;
+}
+
+jstring JNIEnvWrapper::NewStringUTF(const char *s) {
+  jstring str = jni_->NewStringUTF(s);
+  if (ExceptionClear("create a Java string")) {
+    return nullptr;
+  }
+  return str;
+}
+
+std::string JNIEnvWrapper::GetStringUTF(jstring s) {
+  if (s == nullptr) {
+    return "";
+  }
+  const char *chars = jni_->GetStringUTFChars(s, nullptr);
+  if (chars == nullptr) {
+    // Fails with a pending OutOfMemoryError.
+    ExceptionClear("read a Java string");
+    return "";
+  }
+  std::string ret(chars);
+  jni_->ReleaseStringUTFChars(s, chars);
+  return ret;
+}
+
+jobject JNIEnvWrapper::NewObject(jclass cls, jmethodID constructor, ...) {
+  va_list args;
+  va_start(args, constructor);
+  jobject obj = jni_->NewObjectV(cls, constructor, args);
+  va_end(args);
+  if (ExceptionClear("construct a Java object")) {
+    return nullptr;
+  }
+  return obj;
+}
+
+jobject JNIEnvWrapper::CallObjectMethod(jobject obj, jmethodID method, ...) {
+  va_list args;
+  va_start(args, method);
+  jobject ret = jni_->CallObjectMethodV(obj, method, args);
+  va_end(args);
+  if (ExceptionClear("call a Java method")) {
+    return nullptr;
+  }
+  return ret;
+}
+
+jobject JNIEnvWrapper::CallStaticObjectMethod(jclass cls, jmethodID method,
+                                              ...) {
+  va_list args;
+  va_start(args, method);
+  jobject ret = jni_->CallStaticObjectMethodV(cls, method, args);
+  va_end(args);
+  if (ExceptionClear("call a static Java method")) {
+    return nullptr;
+  }
+  return ret;
+}
+
+bool JNIEnvWrapper::CallVoidMethod(jobject obj, jmethodID method, ...) {
+  va_list args;
+  va_start(args, method);
+  jni_->CallVoidMethodV(obj, method, args);
+  va_end(args);
+  return !ExceptionClear("call a Java method");
+}
+
+std::string JNIEnvWrapper::SystemProperty(const char *name) {
+  jclass system_class = FindClass("java/lang/System");
+  if (system_class == nullptr) {
+    return "";
+  }
+  jmethodID get_property = GetStaticMethodID(
+      system_class, "getProperty", "(Ljava/lang/String;)Ljava/lang/String;");
+  jstring jname = NewStringUTF(name);
+  std::string value;
+  if (get_property != nullptr && jname != nullptr) {
+    // Throws a SecurityException when the security manager denies the
+    // access.
+    jstring jvalue = reinterpret_cast<jstring>(
+        CallStaticObjectMethod(system_class, get_property, jname));
+    value = GetStringUTF(jvalue);
+    DeleteLocalRef(jvalue);
+  }
+  DeleteLocalRef(jname);
+  DeleteLocalRef(system_class);
+  return value;
+}
+
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,89 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_JNI_ENV_H_
+#define CLOUD_PROFILER_AGENT_JAVA_JNI_ENV_H_
+
+#include <string>
+
+#include "src/globals.h"
+
+namespace cloud {
+namespace profiler {
+
+// Clears the exception pending in jni, if any, and logs its class along with
+// what failed. Returns true if an exception was pending.
+//
+// Calling most JNI functions with an exception pending is undefined, so every
+// JNI call which can throw must be followed by this before the next one. Under
+// a security manager or the module encapsulation, e.g. reading a system
+// property or opening a file may throw a SecurityException, the agent then
+// goes on without the value.
+bool ClearJNIException(JNIEnv *jni, const std::string &what);
+
+// JNIEnvWrapper wraps the JNIEnv of the current thread with the JNI calls the
+// agent makes to run Java code. Every call which can throw is followed by
+// ClearJNIException, and returns nullptr, or an empty string, when it threw.
+// The local references returned are owned by the caller.
+class JNIEnvWrapper {
+ public:
+  explicit JNIEnvWrapper(JNIEnv *jni) : jni_(jni) {}
+
+  JNIEnv *Get() const { return jni_; }
+
+  // Returns true if an exception is pending.
+  bool ExceptionCheck() const { return jni_->ExceptionCheck(); }
+  // Prints the pending exception and its stack trace to stderr, and clears
+  // it.
+  void ExceptionDescribe() { jni_->ExceptionDescribe(); }
+  // Clears the pending exception, see ClearJNIException.
+  bool ExceptionClear(const std::string &what) {
+    return ClearJNIException(jni_, what);
+  }
+
+  // The name is in the internal form, e.g. "java/lang/System".
+  jclass FindClass(const char *name);
+  jmethodID GetMethodID(jclass cls, const char *name, const char *signature);
+  jmethodID GetStaticMethodID(jclass cls, const char *name,
+                              const char *signature);
+
+  jstring NewStringUTF(const char *s);
+  // Returns the UTF-8 content of the string, or an empty string if it is
+  // null.
+  std::string GetStringUTF(jstring s);
+
+  jobject NewObject(jclass cls, jmethodID constructor, ...);
+  jobject CallObjectMethod(jobject obj, jmethodID method, ...);
+  jobject CallStaticObjectMethod(jclass cls, jmethodID method, ...);
+  // Returns false if the method threw.
+  bool CallVoidMethod(jobject obj, jmethodID method, ...);
+
+  // Returns the value of a Java system property, or an empty string if it is
+  // not set or its access is denied.
+  std::string SystemProperty(const char *name);
+
+  void DeleteLocalRef(jobject ref) { jni_->DeleteLocalRef(ref); }
+
+ private:
+  JNIEnv *jni_;
+
+  DISALLOW_COPY_AND_ASSIGN(JNIEnvWrapper);
+};
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_JNI_ENV_H_
@@ -0,0 +1,123 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "src/metrics_server.h"
+
+#include <arpa/inet.h>
+#include <netinet/in.h>
+#include <poll.h>
+#include <pthread.h>
+#include <sys/socket.h>
+#include <unistd.h>
+
+#include <string>
+
+namespace cloud {
+namespace profiler {
+
+namespace {
+
+// How often the serving thread checks whether the server is stopping.
+const int kPollTimeoutMillis = 500;
+
+}  // namespace
+
+bool MetricsServer::Start(const std::string &address, int port) {
+  struct sockaddr_in addr = {};
+  addr.sin_family = AF_INET;
+  addr.sin_port = htons(port);
+  if (inet_pton(AF_INET, address.c_str(), &addr.sin_addr) != 1) {
+    LOG(ERROR) << "Invalid metrics server address '" << address
+               << "', expected an IPv4 address";
+    return false;
+  }
+
+  fd_ = socket(AF_INET, SOCK_STREAM | SOCK_CLOEXEC, 0);
+  if (fd_ < 0) {
+    LOG(ERROR) << "Failed to create the metrics server socket, errno " << errno;
+    return false;
+  }
+  int reuse = 1;
+  setsockopt(fd_, SOL_SOCKET, SO_REUSEADDR, &reuse, sizeof(reuse));
+
+  if (bind(fd_, reinterpret_cast<struct sockaddr *>(&addr), sizeof(addr)) !=
+          0 ||
+      listen(fd_, 4) != 0) {
+    LOG(ERROR) << "Failed to listen on metrics address " << address << ":"
+               << port << ", errno " << errno;
+    close(fd_);
+    fd_ = -1;
+    return false;
+  }
+
+  LOG(INFO) << "Serving agent metrics on " << address << ":" << port;
+  thread_ = std::thread(&MetricsServer::Serve, this);
+  return true;
+}
+
+void MetricsServer::Stop() {
+  if (!thread_.joinable()) {
+    return;
+  }
+  stopping_ = true;
+  thread_.join();
+  close(fd_);
+  fd_ = -1;
+}
+
+void MetricsServer::Serve() {
+  pthread_setname_np(pthread_self(), "cprof-metrics");
+  while (!stopping_) {
+    struct pollfd pfd = {fd_, POLLIN, 0};
+    if (poll(&pfd, 1, kPollTimeoutMillis) <= 0) {
+      continue;
+    }
+    int conn = accept4(fd_, nullptr, nullptr, SOCK_CLOEXEC);
+    if (conn < 0) {
+      continue;
+    }
+    HandleConnection(conn);
+    close(conn);
+  }
+}
+
+void MetricsServer::HandleConnection(int conn) {
+  // Read and discard the request, the response does not depend on it.
+  struct pollfd pfd = {conn, POLLIN, 0};
+  if (poll(&pfd, 1, kPollTimeoutMillis) > 0) {
+    char buf[1024];
+    if (read(conn, buf, sizeof(buf)) < 0) {
+      return;
+    }
+  }
+
+  std::string body = stats_->PrometheusText();
+  std::string resp =
+      "HTTP/1.0 200 OK\r\n"
+      "Content-Type: text/plain; version=0.0.4\r\n"
+      "Content-Length: " +
+      std::to_string(body.size()) + "\r\n\r\n" + body;
+  size_t sent = 0;
+  while (sent < resp.size()) {
+    ssize_t n = send(conn, resp.data() + sent, resp.size() - sent,
+                     MSG_NOSIGNAL);
+    if (n <= 0) {
+      return;
+    }
+    sent += n;
+  }
+}
+
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,60 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_METRICS_SERVER_H_
+#define CLOUD_PROFILER_AGENT_JAVA_METRICS_SERVER_H_
+
+#include <atomic>
+#include <string>
+#include <thread>  // NOLINT(build/c++11)
+
+#include "src/agent_stats.h"
+#include "src/globals.h"
+
+namespace cloud {
+namespace profiler {
+
+// MetricsServer is a minimal HTTP server exposing the agent stats in the
+// Prometheus text format. Every request gets the stats as the response,
+// regardless of its method and path.
+class MetricsServer {
+ public:
+  explicit MetricsServer(AgentStats *stats)
+      : stats_(stats), fd_(-1), stopping_() {}
+  ~MetricsServer() { Stop(); }
+
+  // Starts serving on the given IPv4 address and port. Returns false on
+  // failure, including when the address is invalid.
+  bool Start(const std::string &address, int port);
+  // Stops the server and waits for the serving thread to exit.
+  void Stop();
+
+ private:
+  void Serve();
+  void HandleConnection(int conn);
+
+  AgentStats *stats_;
+  int fd_;
+  std::atomic<bool> stopping_;
+  std::thread thread_;
+
+  DISALLOW_COPY_AND_ASSIGN(MetricsServer);
+};
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_METRICS_SERVER_H_
@@ -0,0 +1,98 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/metrics_server.h"
+
+#include <arpa/inet.h>
+#include <netinet/in.h>
+#include <sys/socket.h>
+#include <unistd.h>
+
+#include <string>
+
+#include "gtest/gtest.h"
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+// Returns a loopback port which was free when checked.
+int FreePort() {
+  int fd = socket(AF_INET, SOCK_STREAM, 0);
+  struct sockaddr_in addr = {};
+  addr.sin_family = AF_INET;
+  addr.sin_addr.s_addr = htonl(INADDR_LOOPBACK);
+  socklen_t len = sizeof(addr);
+  int port = 0;
+  if (bind(fd, reinterpret_cast<struct sockaddr *>(&addr), sizeof(addr)) ==
+          0 &&
+      getsockname(fd, reinterpret_cast<struct sockaddr *>(&addr), &len) ==
+          0) {
+    port = ntohs(addr.sin_port);
+  }
+  close(fd);
+  return port;
+}
+
+// Sends a request to the server on the loopback port, returns the response.
+std::string Get(int port) {
+  int fd = socket(AF_INET, SOCK_STREAM, 0);
+  struct sockaddr_in addr = {};
+  addr.sin_family = AF_INET;
+  addr.sin_addr.s_addr = htonl(INADDR_LOOPBACK);
+  addr.sin_port = htons(port);
+  std::string resp;
+  if (connect(fd, reinterpret_cast<struct sockaddr *>(&addr), sizeof(addr)) ==
+      0) {
+    const char req[] = "GET /metrics HTTP/1.0\r\n\r\n";
+    if (write(fd, req, sizeof(req) - 1) == sizeof(req) - 1) {
+      char buf[4096];
+      ssize_t n;
+      while ((n = read(fd, buf, sizeof(buf))) > 0) {
+        resp.append(buf, n);
+      }
+    }
+  }
+  close(fd);
+  return resp;
+}
+
+TEST(MetricsServerTest, RejectsInvalidAddresses) {
+  AgentStats stats;
+  MetricsServer server(&stats);
+  EXPECT_FALSE(server.Start("localhost", 9090));
+  EXPECT_FALSE(server.Start("", 9090));
+  EXPECT_FALSE(server.Start("::1", 9090));
+}
+
+TEST(MetricsServerTest, ServesTheStatsOnLoopback) {
+  int port = FreePort();
+  ASSERT_NE(0, port);
+  AgentStats stats;
+  stats.RecordCollection(true);
+  MetricsServer server(&stats);
+  ASSERT_TRUE(server.Start("127.0.0.1", port));
+
+  std::string resp = Get(port);
+  EXPECT_EQ(0u, resp.find("HTTP/1.0 200 OK\r\n")) << resp;
+  EXPECT_NE(std::string::npos,
+            resp.find("\ncprof_profiles_collected_total 1\n"));
+  server.Stop();
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
@@ -19,21 +19,60 @@
 #include <sys/time.h>
 #include <sys/ucontext.h>
 
+#include <algorithm>
 #include <cstdlib>
 #include <cstring>
 
+#include "src/async_stacks.h"
 #include "src/clock.h"
 #include "src/globals.h"
 #include "src/proto.h"
+#include "src/string.h"
 #include "third_party/javaprofiler/accessors.h"
+#include "third_party/javaprofiler/sample_budget.h"
 
 DEFINE_int32(cprof_wall_num_threads_cutoff, 4096,
              "Do not take wall profiles if more than this # of threads exist.");
 DEFINE_int32(cprof_wall_max_threads_per_sec, 160,
              "Max total # of threads to wake up per second in wall profiling.");
+DEFINE_string(cprof_wall_thread_filter, "",
+              "comma-separated list of thread name prefixes; when set, the "
+              "wall profiles only sample the Java threads alive when the "
+              "collection starts whose name starts with one of them, walking "
+              "their stacks with GetThreadListStackTraces rather than "
+              "signaling the threads. Their samples have no labels");
+DEFINE_bool(cprof_wall_skip_gc_rounds, false,
+            "when true, skip the wall sampling rounds the sampler was "
+            "late for, e.g. during a pause, and the ones while a garbage "
+            "collection is in progress, per the JVMTI garbage collection "
+            "events, rather than sampling the threads stopped by them");
 // Off by default since it may cause rare crashes, b/27615794.
 DEFINE_bool(cprof_record_native_stack, false,
             "Whether to unwind native stack and put atop of the Java one.");
+DEFINE_bool(cprof_cpu_sample_non_java_threads, true,
+            "when false, ignore the CPU profiling signals delivered to the "
+            "threads not started as Java threads, such as the GC and JIT "
+            "compiler threads, so that only the application threads are "
+            "profiled");
+DEFINE_string(cprof_aggregation_key, "stack_labels",
+              "how the CPU and wall samples are aggregated: 'stack' merges "
+              "the samples with the same stack, producing the smallest "
+              "profiles; 'stack_labels' also separates them by the sample "
+              "labels, including the thread name when labeled");
+DEFINE_bool(cprof_timestamped_samples, false,
+            "when true, label the CPU and wall samples with the offset of "
+            "their time bucket within the collection window, giving a rough "
+            "time series at the cost of larger profiles");
+DEFINE_int32(cprof_timestamp_bucket_msec, 1000,
+             "width of the time buckets of -cprof_timestamped_samples, in "
+             "milliseconds");
+DEFINE_string(cprof_cpu_signal, "SIGPROF",
+              "signal used to sample the threads: SIGPROF, SIGVTALRM or "
+              "SIGRTMIN+n, for when the application or another agent uses "
+              "SIGPROF; with the process interval timer, SIGVTALRM only "
+              "counts the user CPU time and SIGRTMIN+n is not supported");
+
+DECLARE_bool(cprof_label_gc_samples);
 
 namespace cloud {
 namespace profiler {
@@ -42,6 +81,12 @@ using google::javaprofiler::AlmostThere;
 
 google::javaprofiler::AsyncSafeTraceMultiset *Profiler::fixed_traces_ = nullptr;
 std::atomic<int> Profiler::unknown_stack_count_;
+std::atomic<bool> Profiler::record_labels_;
+std::atomic<bool> Profiler::record_thread_ids_;
+__thread int Profiler::sample_in_flight_;
+std::atomic<bool> Profiler::in_gc_;
+std::atomic<int64_t> Profiler::time_bucket_ns_;
+std::atomic<int64_t> Profiler::collection_start_ns_;
 
 namespace {
 
@@ -57,21 +102,120 @@ class ErrnoRaii {
   DISALLOW_COPY_AND_ASSIGN(ErrnoRaii);
 };
 
+// Helper class to count the signal handler invocations in progress.
+class InFlightRaii {
+ public:
+  explicit InFlightRaii(int *count) : count_(count) { (*count_)++; }
+  ~InFlightRaii() { (*count_)--; }
+
+ private:
+  int *count_;
+
+  DISALLOW_COPY_AND_ASSIGN(InFlightRaii);
+};
+
 }  // namespace
 
+int ParseSamplingSignal(const std::string &name) {
+  if (name == "SIGPROF") {
+    return SIGPROF;
+  }
+  if (name == "SIGVTALRM") {
+    return SIGVTALRM;
+  }
+  const std::string rt_prefix = "SIGRTMIN+";
+  if (name.compare(0, rt_prefix.size(), rt_prefix) != 0 ||
+      name.size() == rt_prefix.size() || name.size() > rt_prefix.size() + 2) {
+    return 0;
+  }
+  int n = 0;
+  for (size_t i = rt_prefix.size(); i < name.size(); i++) {
+    if (name[i] < '0' || name[i] > '9') {
+      return 0;
+    }
+    n = n * 10 + (name[i] - '0');
+  }
+  // SIGRTMIN already excludes the real-time signals reserved by glibc.
+  if (SIGRTMIN + n > SIGRTMAX) {
+    return 0;
+  }
+  return SIGRTMIN + n;
+}
+
+bool ConfigureSamplingSignal(bool process_timer) {
+  const std::string &name = FLAGS_cprof_cpu_signal;
+  int signum = ParseSamplingSignal(name);
+  if (signum == 0) {
+    LOG(ERROR) << "Rejecting -cprof_cpu_signal '" << name << "', expected "
+               << "SIGPROF, SIGVTALRM or SIGRTMIN+n; using SIGPROF";
+    SetSamplingSignal(SIGPROF);
+    return false;
+  }
+  if (process_timer && signum != SIGPROF && signum != SIGVTALRM) {
+    LOG(ERROR) << "The process interval timer cannot deliver " << name
+               << ", use per-thread timers or perf events for it; using "
+               << "SIGPROF";
+    SetSamplingSignal(SIGPROF);
+    return false;
+  }
+  struct sigaction action;
+  if (sigaction(signum, nullptr, &action) == 0 &&
+      ((action.sa_flags & SA_SIGINFO) ? action.sa_sigaction != nullptr
+                                      : (action.sa_handler != SIG_DFL &&
+                                         action.sa_handler != SIG_IGN))) {
+    if (signum != SIGPROF) {
+      LOG(ERROR) << "Rejecting -cprof_cpu_signal '" << name << "', the "
+                 << "signal is already handled; using SIGPROF";
+      SetSamplingSignal(SIGPROF);
+      return false;
+    }
+    LOG(WARNING) << "SIGPROF is already handled, the profiler replaces the "
+                 << "handler while profiling; use -cprof_cpu_signal to pick "
+                 << "another signal";
+  }
+  if (signum != SIGPROF) {
+    LOG(INFO) << "Sampling the threads with " << name;
+  }
+  SetSamplingSignal(signum);
+  return true;
+}
+
 void Profiler::Handle(int signum, siginfo_t *info, void *context) {
   IMPLICITLY_USE(signum);
   IMPLICITLY_USE(info);
   ErrnoRaii err_storage;  // stores and resets errno
+  InFlightRaii in_flight(&sample_in_flight_);
+  // Only the Java threads have a JNI environment, set when they start.
+  JNIEnv *env = google::javaprofiler::Accessors::CurrentJniEnv();
+  if (env == nullptr && !FLAGS_cprof_cpu_sample_non_java_threads) {
+    return;
+  }
+  if (!google::javaprofiler::SampleBudget::TryAcquire()) {
+    return;
+  }
 
   JVMPI_CallTrace trace;
   JVMPI_CallFrame frames[kMaxFramesToCapture];
 
-  JNIEnv *env = google::javaprofiler::Accessors::CurrentJniEnv();
   trace.frames = frames;
   trace.env_id = env;
   trace.num_frames = 0;
-  int attr = google::javaprofiler::Accessors::GetAttribute();
+  int attr =
+      record_labels_ ? google::javaprofiler::Accessors::GetAttribute() : 0;
+  google::javaprofiler::SampleContext sample_context = {};
+  if (record_thread_ids_) {
+    sample_context.thread_id =
+        google::javaprofiler::Accessors::GetThreadId();
+  }
+  if (time_bucket_ns_ > 0) {
+    // clock_gettime is async-signal-safe.
+    struct timespec now;
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    sample_context.time_bucket =
+        (TimeSpecToNanos(now) - collection_start_ns_) / time_bucket_ns_ + 1;
+  }
+  // The GC events are also tracked for -cprof_wall_skip_gc_rounds.
+  sample_context.in_gc = FLAGS_cprof_label_gc_samples && in_gc_;
 
   if (env != nullptr) {
     // This is a java thread.
@@ -85,15 +229,20 @@ void Profiler::Handle(int signum, siginfo_t *info, void *context) {
           JVMPI_CallFrame{kCallTraceErrorLineNum,
                           reinterpret_cast<jmethodID>(trace.num_frames)};
       trace.num_frames = 1;
-      if (!fixed_traces_->Add(attr, &trace)) {
+      if (!fixed_traces_->Add(attr, sample_context, &trace)) {
         unknown_stack_count_++;
       }
       return;
     }
+    // Checked before the async parent and the native frames are added, which
+    // may fill the buffer with shallow stacks.
+    sample_context.truncated = trace.num_frames >= kMaxFramesToCapture;
+
+    AsyncStacks::AppendParentFrames(&trace, kMaxFramesToCapture);
 
     if (frames[0].lineno >= 0) {
       // Leaf is a java frame, return java trace.
-      if (!fixed_traces_->Add(attr, &trace)) {
+      if (!fixed_traces_->Add(attr, sample_context, &trace)) {
         unknown_stack_count_++;
       }
       return;
@@ -146,19 +295,20 @@ void Profiler::Handle(int signum, siginfo_t *info, void *context) {
     ++trace.num_frames;
   }
 
-  if (!fixed_traces_->Add(attr, &trace)) {
+  if (!fixed_traces_->Add(attr, sample_context, &trace)) {
     unknown_stack_count_++;
   }
 }
 
-// This method schedules the SIGPROF timer to go off every specified interval.
-// seconds, usec microseconds.
+// This method schedules the SIGPROF, or SIGVTALRM, timer to go off every
+// specified interval. seconds, usec microseconds.
 bool SignalHandler::SetSigprofInterval(int64_t period_usec) {
   static struct itimerval timer;
   timer.it_interval.tv_sec = 0;
   timer.it_interval.tv_usec = period_usec;
   timer.it_value = timer.it_interval;
-  if (setitimer(ITIMER_PROF, &timer, 0) == -1) {
+  int which = SamplingSignal() == SIGVTALRM ? ITIMER_VIRTUAL : ITIMER_PROF;
+  if (setitimer(which, &timer, 0) == -1) {
     LOG(ERROR) << "Scheduling profiler interval failed with error " << errno;
     return false;
   }
@@ -175,7 +325,7 @@ struct sigaction SignalHandler::SetAction(void (*action)(int, siginfo_t *,
   sigemptyset(&sa.sa_mask);
 
   struct sigaction old_handler;
-  if (sigaction(SIGPROF, &sa, &old_handler) != 0) {
+  if (sigaction(SamplingSignal(), &sa, &old_handler) != 0) {
     LOG(ERROR) << "Scheduling profiler action failed with error " << errno;
     return old_handler;
   }
@@ -190,6 +340,18 @@ void Profiler::Reset() {
     fixed_traces_->Reset();
   }
   unknown_stack_count_ = 0;
+  const std::string &key = FLAGS_cprof_aggregation_key;
+  if (key != "stack" && key != "stack_labels") {
+    LOG(WARNING) << "Unknown aggregation key '" << key
+                 << "', aggregating by 'stack_labels'";
+  }
+  record_labels_ = key != "stack";
+  record_thread_ids_ = record_labels_ && threads_->LabelsThreads();
+  time_bucket_ns_ =
+      FLAGS_cprof_timestamped_samples && FLAGS_cprof_timestamp_bucket_msec > 0
+          ? FLAGS_cprof_timestamp_bucket_msec * kNanosPerMilli
+          : 0;
+  collection_start_ns_ = TimeSpecToNanos(DefaultClock()->Now());
 
   if (FLAGS_cprof_record_native_stack) {
     // When native stack collection requested, gather a single backtrace before
@@ -206,9 +368,27 @@ void Profiler::Reset() {
 
 std::string Profiler::SerializeProfile(
     JNIEnv *jni, const google::javaprofiler::NativeProcessInfo &native_info) {
-  return SerializeAndClearJavaCpuTraces(
-      jni, jvmti_, native_info, ProfileType(), duration_nanos_, period_nanos_,
-      &aggregated_traces_, unknown_stack_count_);
+  google::javaprofiler::TraceMultiset snapshot;
+  int64_t unknown_count = TakeSnapshot(&snapshot);
+  sample_count_ = unknown_count;
+  stack_count_ = unknown_count > 0 ? 1 : 0;
+  for (const auto &trace : snapshot) {
+    if (trace.second != 0) {
+      sample_count_ += trace.second;
+      stack_count_++;
+    }
+  }
+  std::string profile = SerializeAndClearJavaCpuTraces(
+      jni, jvmti_, native_info, threads_, ProfileType(), duration_nanos_,
+      period_nanos_, &snapshot, unknown_count);
+  threads_->PruneThreadNames();
+  return profile;
+}
+
+int64_t Profiler::TakeSnapshot(google::javaprofiler::TraceMultiset *snapshot) {
+  Flush();
+  snapshot->Swap(&aggregated_traces_);
+  return unknown_stack_count_.exchange(0);
 }
 
 bool CPUProfiler::Collect() {
@@ -238,6 +418,28 @@ bool CPUProfiler::Collect() {
   return true;
 }
 
+bool CPUProfiler::CollectUntil(const std::atomic<bool> &stop) {
+  Reset();
+
+  if (!Start()) {
+    return false;
+  }
+
+  Clock *clock = DefaultClock();
+  struct timespec flush_interval = {0, 100 * 1000 * 1000};  // 100 millisec
+  struct timespec start = clock->Now();
+  while (!stop) {
+    clock->SleepFor(flush_interval);
+    Flush();
+  }
+  Stop();
+  duration_nanos_ = TimeSpecToNanos(clock->Now()) - TimeSpecToNanos(start);
+  // Delay to allow last signals to be processed.
+  clock->SleepFor(flush_interval);
+  Flush();
+  return true;
+}
+
 bool CPUProfiler::Start() {
   int period_usec = period_nanos_ / 1000;
   if (threads_->UseTimers()) {
@@ -255,15 +457,16 @@ void CPUProfiler::Stop() {
     handler_.SetSigprofInterval(0);
   }
   // Breaks encapsulation, but whatever.
-  signal(SIGPROF, SIG_IGN);
+  signal(SamplingSignal(), SIG_IGN);
 }
 
-WallProfiler::WallProfiler(jvmtiEnv *jvmti, ThreadTable *threads,
+WallProfiler::WallProfiler(jvmtiEnv *jvmti, JNIEnv *jni, ThreadTable *threads,
                            int64_t duration_nanos, int64_t period_nanos)
     : Profiler(jvmti, threads, duration_nanos,
                EffectivePeriodNanos(period_nanos, threads->Size(),
                                     FLAGS_cprof_wall_max_threads_per_sec,
-                                    duration_nanos)) {}
+                                    duration_nanos)),
+      jni_(jni) {}
 
 int64_t WallProfiler::EffectivePeriodNanos(int64_t period_nanos,
                                            int64_t num_threads,
@@ -287,10 +490,72 @@ int64_t WallProfiler::EffectivePeriodNanos(int64_t period_nanos,
   return period_nanos;
 }
 
+std::vector<jthread> WallProfiler::FilteredThreads() {
+  std::vector<std::string> prefixes =
+      Split(FLAGS_cprof_wall_thread_filter, ',');
+  std::vector<jthread> filtered;
+  jint count = 0;
+  google::javaprofiler::JvmtiScopedPtr<jthread> all(jvmti_);
+  if (jvmti_->GetAllThreads(&count, all.GetRef()) != JVMTI_ERROR_NONE) {
+    LOG(ERROR) << "Failed to get the threads to filter";
+    return filtered;
+  }
+  for (jint i = 0; i < count; i++) {
+    jthread thread = all.Get()[i];
+    jvmtiThreadInfo info;
+    if (jvmti_->GetThreadInfo(thread, &info) == JVMTI_ERROR_NONE) {
+      google::javaprofiler::JvmtiScopedPtr<char> name(jvmti_, info.name);
+      jni_->DeleteLocalRef(info.thread_group);
+      jni_->DeleteLocalRef(info.context_class_loader);
+      std::string thread_name = name.Get() == nullptr ? "" : name.Get();
+      for (const std::string &prefix : prefixes) {
+        if (!prefix.empty() &&
+            thread_name.compare(0, prefix.size(), prefix) == 0) {
+          filtered.push_back(
+              static_cast<jthread>(jni_->NewGlobalRef(thread)));
+          break;
+        }
+      }
+    }
+    jni_->DeleteLocalRef(thread);
+  }
+  return filtered;
+}
+
+void WallProfiler::SampleThreads(const std::vector<jthread> &threads) {
+  std::vector<std::vector<jvmtiFrameInfo>> traces;
+  if (!google::javaprofiler::GetThreadListStackTraces(
+          jvmti_, threads, kMaxFramesToCapture, &traces)) {
+    return;
+  }
+  JVMPI_CallFrame frames[kMaxFramesToCapture];
+  for (const auto &trace : traces) {
+    // Empty for the threads which terminated or are not running Java code.
+    if (trace.empty()) {
+      continue;
+    }
+    google::javaprofiler::SampleContext context = {};
+    context.truncated = trace.size() >= kMaxFramesToCapture;
+    int num_frames = google::javaprofiler::ToCallFrames(
+        trace, kMaxFramesToCapture, frames);
+    AddTrace(context, num_frames, frames);
+  }
+}
+
 bool WallProfiler::Collect() {
   Reset();
   pid_t my_tid = GetTid();
 
+  bool filtered = !FLAGS_cprof_wall_thread_filter.empty() && jni_ != nullptr;
+  std::vector<jthread> filtered_threads;
+  if (filtered) {
+    filtered_threads = FilteredThreads();
+    if (filtered_threads.empty()) {
+      LOG(WARNING) << "No thread matches -cprof_wall_thread_filter '"
+                   << FLAGS_cprof_wall_thread_filter << "'";
+    }
+  }
+
   Clock *clock = DefaultClock();
   struct timespec profile_period = {0, period_nanos_};
   struct timespec finish_line =
@@ -302,6 +567,10 @@ bool WallProfiler::Collect() {
 
   int64_t count = 0;
   const int kFlushPeriod = 128;  // Flush table every 128 samples
+  // The rounds the sampler was late for, e.g. not scheduled for a while, and
+  // the ones skipped during a garbage collection. The late rounds are only
+  // skipped with -cprof_wall_skip_gc_rounds.
+  int64_t late_rounds = 0, max_delay_ns = 0, gc_rounds = 0;
   while (TimeLessThan(next, finish_line)) {
     if (count > kFlushPeriod) {
       count = 0;
@@ -309,6 +578,30 @@ bool WallProfiler::Collect() {
       Flush();
     }
     clock->SleepUntil(next);
+    int64_t delay_ns = TimeSpecToNanos(clock->Now()) - TimeSpecToNanos(next);
+    if (delay_ns >= period_nanos_) {
+      int64_t missed = delay_ns / period_nanos_;
+      late_rounds += missed;
+      max_delay_ns = std::max(max_delay_ns, delay_ns);
+      if (FLAGS_cprof_wall_skip_gc_rounds) {
+        // Skip the missed rounds rather than catching up with a burst of
+        // signals, which would oversample the time after the delay.
+        next = TimeAdd(next, NanosToTimeSpec(missed * period_nanos_));
+        if (!TimeLessThan(next, finish_line)) {
+          break;
+        }
+      }
+    }
+    if (FLAGS_cprof_wall_skip_gc_rounds && InGc()) {
+      gc_rounds++;
+      next = TimeAdd(next, profile_period);
+      continue;
+    }
+    if (filtered) {
+      SampleThreads(filtered_threads);
+      next = TimeAdd(next, profile_period);
+      continue;
+    }
     std::vector<pid_t> threads = threads_->Threads();
     if (threads.size() > FLAGS_cprof_wall_num_threads_cutoff) {
       LOG(WARNING) << "Aborting wall profiling due to too many threads. "
@@ -320,15 +613,27 @@ bool WallProfiler::Collect() {
     for (pid_t tid : threads) {
       if (tid != my_tid) {
         // Skip profiler worker thread.
-        TgKill(tid, SIGPROF);
+        TgKill(tid, SamplingSignal());
       }
     }
     next = TimeAdd(next, profile_period);
   }
+  for (jthread thread : filtered_threads) {
+    jni_->DeleteGlobalRef(thread);
+  }
   // Delay to allow last signals to be processed.
   clock->SleepUntil(TimeAdd(next, profile_period));
-  signal(SIGPROF, SIG_IGN);
+  signal(SamplingSignal(), SIG_IGN);
   Flush();
+  if (late_rounds > 0) {
+    LOG(INFO) << "Wall profiling was late for " << late_rounds << " sampling "
+              << "round(s), delayed by up to " << max_delay_ns / kNanosPerMilli
+              << "ms" << (FLAGS_cprof_wall_skip_gc_rounds ? ", skipped" : "");
+  }
+  if (gc_rounds > 0) {
+    LOG(INFO) << "Wall profiling skipped " << gc_rounds << " sampling "
+              << "round(s) during garbage collections";
+  }
   return true;
 }
 
@@ -20,6 +20,8 @@
 #include <signal.h>
 
 #include <atomic>
+#include <string>
+#include <vector>
 
 #include "src/threads.h"
 #include "third_party/javaprofiler/stacktraces.h"
@@ -27,6 +29,18 @@
 namespace cloud {
 namespace profiler {
 
+// Returns the number of the signal named by -cprof_cpu_signal: SIGPROF,
+// SIGVTALRM or SIGRTMIN+n. Returns 0 for any other name, notably for the
+// signals the JVM relies on. Public for testing.
+int ParseSamplingSignal(const std::string &name);
+
+// Validates -cprof_cpu_signal and makes it the sampling signal. The process
+// interval timer can only deliver SIGPROF or SIGVTALRM, process_timer tells
+// whether it drives the CPU profiling. Falls back to SIGPROF and returns false
+// when the signal is not usable or is already handled by someone else. May be
+// called again when the CPU profiling falls back to the process timer.
+bool ConfigureSamplingSignal(bool process_timer);
+
 class SignalHandler {
  public:
   SignalHandler() {}
@@ -46,7 +60,9 @@ class Profiler {
       : threads_(threads),
         duration_nanos_(duration_nanos),
         period_nanos_(period_nanos),
-        jvmti_(jvmti) {
+        jvmti_(jvmti),
+        sample_count_(0),
+        stack_count_(0) {
     Reset();
   }
   virtual ~Profiler() {}
@@ -59,9 +75,27 @@ class Profiler {
   std::string SerializeProfile(
       JNIEnv *jni, const google::javaprofiler::NativeProcessInfo &native_info);
 
+  // Duration of the profile, in nanoseconds.
+  int64_t DurationNanos() const { return duration_nanos_; }
+
+  // Number of samples in the last serialized profile.
+  int64_t SampleCount() const { return sample_count_; }
+
+  // Number of unique stacks in the last serialized profile.
+  int64_t StackCount() const { return stack_count_; }
+
   // Signal handler, which records the current stack trace into the profile.
   static void Handle(int signum, siginfo_t *info, void *context);
 
+  // Returns whether the calling thread is in the signal handler recording a
+  // sample. Async-signal-safe.
+  static bool SampleInFlight() { return sample_in_flight_ > 0; }
+
+  // Records whether a garbage collection is in progress, for the samples to
+  // be labeled with it. Called from the GC start and finish events.
+  static void SetInGc(bool in_gc) { in_gc_ = in_gc; }
+  static bool InGc() { return in_gc_; }
+
   // Reset internal state to support data collection.
   void Reset();
 
@@ -69,14 +103,28 @@ class Profiler {
   // Returns number of entries extracted.
   int Flush() { return HarvestSamples(fixed_traces_, &aggregated_traces_); }
 
+  // Moves the traces accumulated so far into snapshot, which must be empty,
+  // and returns the number of samples with an unknown stack. The profiler
+  // starts accumulating afresh, also when the sampling is still running, so
+  // the samples of a window never leak into the next one.
+  int64_t TakeSnapshot(google::javaprofiler::TraceMultiset *snapshot);
+
   // String description of the profile type
   virtual const char *ProfileType() = 0;
 
  protected:
+  // Records a trace walked outside of the signal handler straight into the
+  // aggregated traces, without labels.
+  void AddTrace(const google::javaprofiler::SampleContext &context,
+                int num_frames, JVMPI_CallFrame *frames) {
+    aggregated_traces_.Add(0, context, num_frames, frames, 1);
+  }
+
   ThreadTable *threads_;
   SignalHandler handler_;
   int64_t duration_nanos_;
   int64_t period_nanos_;
+  jvmtiEnv *jvmti_;
 
  private:
   // Points to a fixed multiset of traces used during collection. This
@@ -88,18 +136,44 @@ class Profiler {
   // Aggregated profile data, populated using data extracted from
   // fixed_traces.
   google::javaprofiler::TraceMultiset aggregated_traces_;
-  jvmtiEnv *jvmti_;
+  int64_t sample_count_;
+  int64_t stack_count_;
 
   struct sigaction old_action_;
 
   // Number of samples where the stack aggregation failed.
   static std::atomic<int> unknown_stack_count_;
 
+  // Whether the signal handler records the sample labels, otherwise the
+  // samples are aggregated by stack only.
+  static std::atomic<bool> record_labels_;
+
+  // Whether the signal handler records the ID of the sampled thread.
+  static std::atomic<bool> record_thread_ids_;
+
+  // Number of signal handler invocations recording a sample on the thread.
+  // The TLS model follows the accessors, see accessors.h.
+#if defined(JAVAPROFILER_GLOBAL_DYNAMIC_TLS) || defined(ALPINE)
+  static __thread int sample_in_flight_
+      __attribute__((tls_model("global-dynamic")));
+#else
+  static __thread int sample_in_flight_
+      __attribute__((tls_model("initial-exec")));
+#endif
+
+  // Whether a garbage collection is in progress, see SetInGc().
+  static std::atomic<bool> in_gc_;
+
+  // Non-zero when the samples are bucketed by time, width of the buckets.
+  static std::atomic<int64_t> time_bucket_ns_;
+  // Monotonic time the collection started at, start of the first bucket.
+  static std::atomic<int64_t> collection_start_ns_;
+
   DISALLOW_COPY_AND_ASSIGN(Profiler);
 };
 
 // CPUProfiler collects cpu profiles by setting up a CPU timer and
-// collecting a sample each time it is triggered (via SIGPROF).
+// collecting a sample each time it is triggered (via the sampling signal).
 class CPUProfiler : public Profiler {
  public:
   using Profiler::Profiler;
@@ -107,6 +181,10 @@ class CPUProfiler : public Profiler {
   // Collect profiling data.
   bool Collect() override;
 
+  // Collects profiling data until stop is set, rather than for the configured
+  // duration which is then updated to the actual collection time.
+  bool CollectUntil(const std::atomic<bool> &stop);
+
   const char *ProfileType() override { return "cpu"; }
 
  private:
@@ -120,11 +198,15 @@ class CPUProfiler : public Profiler {
 };
 
 // WallProfiler collects wallclock profiles by explicitly sending
-// SIGPROF to each thread in the thread table.
+// the sampling signal to each thread in the thread table. With
+// -cprof_wall_thread_filter, it samples the matching Java threads only,
+// walking their stacks with a single GetThreadListStackTraces call per round.
 class WallProfiler : public Profiler {
  public:
-  WallProfiler(jvmtiEnv *jvmti, ThreadTable *threads, int64_t duration_nanos,
-               int64_t period_nanos);
+  // The JNI environment of the calling thread is needed for the thread
+  // filter, which is ignored when it is null.
+  WallProfiler(jvmtiEnv *jvmti, JNIEnv *jni, ThreadTable *threads,
+               int64_t duration_nanos, int64_t period_nanos);
 
   // Collect profiling data.
   bool Collect() override;
@@ -138,6 +220,15 @@ class WallProfiler : public Profiler {
   const char *ProfileType() override { return "wall"; }
 
  private:
+  // Returns global references to the live Java threads whose name starts
+  // with one of the -cprof_wall_thread_filter prefixes.
+  std::vector<jthread> FilteredThreads();
+
+  // Records the stacks of the threads, walked with GetThreadListStackTraces.
+  void SampleThreads(const std::vector<jthread> &threads);
+
+  JNIEnv *jni_;
+
   DISALLOW_COPY_AND_ASSIGN(WallProfiler);
 };
 
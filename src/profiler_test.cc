@@ -0,0 +1,71 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/profiler.h"
+
+#include <signal.h>
+
+#include <string>
+
+#include "gtest/gtest.h"
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+TEST(ParseSamplingSignalTest, AcceptsTheProfilingSignals) {
+  EXPECT_EQ(SIGPROF, ParseSamplingSignal("SIGPROF"));
+  EXPECT_EQ(SIGVTALRM, ParseSamplingSignal("SIGVTALRM"));
+}
+
+TEST(ParseSamplingSignalTest, AcceptsTheRealTimeSignals) {
+  EXPECT_EQ(SIGRTMIN, ParseSamplingSignal("SIGRTMIN+0"));
+  EXPECT_EQ(SIGRTMIN + 3, ParseSamplingSignal("SIGRTMIN+3"));
+  EXPECT_EQ(SIGRTMIN + 3, ParseSamplingSignal("SIGRTMIN+03"));
+  EXPECT_EQ(SIGRTMAX,
+            ParseSamplingSignal("SIGRTMIN+" +
+                                std::to_string(SIGRTMAX - SIGRTMIN)));
+}
+
+TEST(ParseSamplingSignalTest, RejectsTheRealTimeSignalsOutOfRange) {
+  EXPECT_EQ(0, ParseSamplingSignal("SIGRTMIN+" +
+                                   std::to_string(SIGRTMAX - SIGRTMIN + 1)));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGRTMIN+99"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGRTMIN+100"));
+}
+
+TEST(ParseSamplingSignalTest, RejectsTheMalformedNames) {
+  EXPECT_EQ(0, ParseSamplingSignal(""));
+  EXPECT_EQ(0, ParseSamplingSignal("sigprof"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGPROF "));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGRTMIN"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGRTMIN+"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGRTMIN-1"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGRTMIN+-1"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGRTMIN+1a"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGRTMAX"));
+}
+
+TEST(ParseSamplingSignalTest, RejectsTheSignalsOfTheJvm) {
+  EXPECT_EQ(0, ParseSamplingSignal("SIGSEGV"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGUSR2"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGQUIT"));
+  EXPECT_EQ(0, ParseSamplingSignal("SIGALRM"));
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
@@ -15,16 +15,52 @@
 #include "src/proto.h"
 
 #include <errno.h>
+#include <regex.h>
 #include <stdlib.h>
+#include <string.h>
 #include <sys/time.h>
 
+#include <algorithm>
 #include <map>
+#include <random>
 #include <string>
+#include <unordered_set>
 
 #include "perftools/profiles/proto/builder.h"
+#include "src/clock.h"
+#include "src/inlined_frames.h"
+#include "src/string.h"
 #include "third_party/javaprofiler/display.h"
 #include "third_party/javaprofiler/stacktrace_fixer.h"
 
+DEFINE_bool(cprof_normalize_lambdas, false,
+            "when true, collapse the lambda methods of an enclosing method "
+            "(e.g. lambda$main$0, lambda$main$1) into a single function name");
+DEFINE_string(cprof_native_lib_filter, "",
+              "extended regular expression; when set, only the native "
+              "libraries with a matching path are included in the profile "
+              "mappings. Prefix with '!' to exclude the matching libraries "
+              "instead.");
+DEFINE_string(cprof_include_packages, "",
+              "comma-separated list of Java packages; when set, the Java "
+              "frames of the classes outside these packages are folded into "
+              "a single '[External code]' frame per run of such frames");
+DEFINE_int64(cprof_max_samples_per_profile, 0,
+             "when set, the profiles with more samples are uniformly "
+             "downsampled to about this number of samples, with the sampling "
+             "period scaled up accordingly");
+DEFINE_int32(cprof_max_locations, 0,
+             "when set, the profiles with more locations keep the most "
+             "sampled ones and fold the others into a '[Folded frames]' "
+             "frame per run of such frames, to stay within the backend "
+             "limits");
+DECLARE_bool(cprof_capture_top_frame_args);
+DECLARE_bool(cprof_label_daemon_threads);
+DECLARE_bool(cprof_label_thread_ids);
+DECLARE_bool(cprof_label_thread_names);
+DECLARE_bool(cprof_show_inlined_frames);
+DECLARE_int32(cprof_timestamp_bucket_msec);
+
 namespace cloud {
 namespace profiler {
 
@@ -34,11 +70,20 @@ class ProfileProtoBuilder {
  public:
   ProfileProtoBuilder(
       jvmtiEnv *jvmti,
-      const google::javaprofiler::NativeProcessInfo &native_info)
-      : jvmti_(jvmti), native_info_(native_info) {
+      const google::javaprofiler::NativeProcessInfo &native_info,
+      ThreadTable *threads)
+      : jvmti_(jvmti), native_info_(native_info), threads_(threads) {
     for (const auto &it : google::javaprofiler::AttributeTable::GetStrings()) {
       builder_.StringId(it.c_str());
     }
+    if (!FLAGS_cprof_include_packages.empty()) {
+      for (const std::string &package :
+           Split(FLAGS_cprof_include_packages, ',')) {
+        if (!package.empty()) {
+          included_packages_.push_back(package + ".");
+        }
+      }
+    }
   }
 
   // Populate the profile with a set of traces
@@ -49,10 +94,18 @@ class ProfileProtoBuilder {
                            int64_t weight);
   int64_t TotalCount() const;
   int64_t TotalWeight() const;
+  // Bounds the number of locations to max_locations, keeping the ones with
+  // the highest sample counts and folding the others. The functions only
+  // referenced by the folded locations are dropped.
+  void FoldLocations(int max_locations);
 
   std::string Emit() {
     std::string out;
-    builder_.Emit(&out);
+    if (!builder_.Finalize() || !ValidateProfile(*builder_.mutable_profile()) ||
+        !perftools::profiles::Builder::Marshal(*builder_.mutable_profile(),
+                                               &out)) {
+      out.clear();
+    }
     return out;
   }
   void Encode(perftools::profiles::Profile *p) {
@@ -60,16 +113,31 @@ class ProfileProtoBuilder {
   }
 
  private:
+  typedef std::vector<std::pair<std::string, std::string>> Labels;
+
+  // Adds a sample with the given locations, labeled with the attribute and
+  // the additional string labels.
   void AddSample(const std::vector<uint64_t> &locations, int64_t count,
-                 int64_t weight, int64_t attr);
+                 int64_t weight, int64_t attr, const Labels &labels = Labels());
   uint64_t LocationID(JNIEnv *jni,
                       const google::javaprofiler::JVMPI_CallFrame &frame);
+  // Returns the formal parameters of the method, cached per method. Empty
+  // for the methods of the redacted classes.
+  const std::string &TopFrameArgs(JNIEnv *jni, jmethodID method);
+  // Returns whether the frame is to be kept as is, rather than folded as
+  // external code: the non-Java frames and, when -cprof_include_packages is
+  // set, the frames of the classes in the included packages.
+  bool IsIncludedFrame(JNIEnv *jni,
+                       const google::javaprofiler::JVMPI_CallFrame &frame);
   uint64_t LocationID(uint64_t address);
   uint64_t LocationID(const std::string &name);
   uint64_t LocationID(const std::string &class_name,
                       const std::string &method_name,
                       const std::string &signature,
-                      const std::string &file_name, int line_number);
+                      const std::string &file_name, int line_number,
+                      int start_line = 0);
+  // Returns the first line of the method, cached per method.
+  int StartLine(jmethodID method);
 
   jvmtiEnv *jvmti_;
   int64_t total_count_ = 0;
@@ -88,13 +156,75 @@ class ProfileProtoBuilder {
 
   std::unordered_map<Line, uint64_t, LineHasher> line_map_;
   std::unordered_map<uint64_t, uint64_t> address_location_;
+  std::unordered_map<jmethodID, std::string> top_frame_args_;
+  std::unordered_map<jmethodID, int> start_lines_;
+  // Package name prefixes, including the trailing '.'.
+  std::vector<std::string> included_packages_;
+  std::unordered_map<jmethodID, bool> included_methods_;
 
   const google::javaprofiler::NativeProcessInfo &native_info_;
+  ThreadTable *threads_;
   DISALLOW_COPY_AND_ASSIGN(ProfileProtoBuilder);
 };
 
 namespace {
 
+// Maximum length of the top frame arguments label value.
+const size_t kMaxTopFrameArgsLength = 256;
+
+// Returns the formal parameters of a method as comma-separated "type name"
+// pairs, read from its local variable table. Returns an empty string when
+// the method has no parameters or the table is not available.
+std::string MethodParameters(jvmtiEnv *jvmti, jmethodID method) {
+  jint args_size, modifiers;
+  if (jvmti->GetArgumentsSize(method, &args_size) != JVMTI_ERROR_NONE ||
+      jvmti->GetMethodModifiers(method, &modifiers) != JVMTI_ERROR_NONE) {
+    return "";
+  }
+  // Slot 0 holds 'this' for the instance methods.
+  const jint kAccStatic = 0x0008;
+  jint first_slot = (modifiers & kAccStatic) ? 0 : 1;
+
+  jint entry_count;
+  jvmtiLocalVariableEntry *table = nullptr;
+  // Fails with JVMTI_ERROR_ABSENT_INFORMATION for the classes compiled
+  // without debug information, the parameters are then unknown.
+  if (jvmti->GetLocalVariableTable(method, &entry_count, &table) !=
+      JVMTI_ERROR_NONE) {
+    return "";
+  }
+  // The parameters are the variables live at the method entry, ordered by
+  // slot.
+  std::map<jint, std::string> params;
+  for (int i = 0; i < entry_count; i++) {
+    const jvmtiLocalVariableEntry &entry = table[i];
+    if (entry.start_location == 0 && entry.slot >= first_slot &&
+        entry.slot < args_size) {
+      std::string type = entry.signature;
+      google::javaprofiler::PrettyPrintSignature(&type);
+      params[entry.slot] = type + " " + entry.name;
+    }
+    jvmti->Deallocate(reinterpret_cast<unsigned char *>(entry.name));
+    jvmti->Deallocate(reinterpret_cast<unsigned char *>(entry.signature));
+    jvmti->Deallocate(
+        reinterpret_cast<unsigned char *>(entry.generic_signature));
+  }
+  jvmti->Deallocate(reinterpret_cast<unsigned char *>(table));
+
+  std::string result;
+  for (const auto &param : params) {
+    if (!result.empty()) {
+      result += ", ";
+    }
+    result += param.second;
+  }
+  if (result.length() > kMaxTopFrameArgsLength) {
+    result.resize(kMaxTopFrameArgsLength - 3);
+    result += "...";
+  }
+  return result;
+}
+
 std::string CallTraceErrorToName(int64_t err) {
   switch (err) {
     case kNativeStackTrace:
@@ -117,25 +247,121 @@ std::string CallTraceErrorToName(int64_t err) {
       return "[Deopt]";
     case kSafepoint:
       return "[Safepoint]";
+    case kAsyncBoundary:
+      return "[Async boundary]";
     default:
       return "[Unknown]";
   }
 }
 
+// Returns count divided by factor, rounded up or down at random in proportion
+// to the remainder so that the proportions of the counts are preserved.
+int64_t DecimateCount(int64_t count, int64_t factor,
+                      std::default_random_engine *gen) {
+  int64_t decimated = count / factor;
+  int64_t remainder = count % factor;
+  if (remainder > 0 &&
+      std::uniform_int_distribution<int64_t>(0, factor - 1)(*gen) <
+          remainder) {
+    decimated++;
+  }
+  return decimated;
+}
+
 }  // namespace
 
+int64_t DecimateTraces(google::javaprofiler::TraceMultiset *traces,
+                       int64_t *unknown_count, int64_t max_samples) {
+  if (max_samples <= 0) {
+    return 1;
+  }
+  int64_t total = *unknown_count;
+  for (const auto &trace : *traces) {
+    total += trace.second;
+  }
+  if (total <= max_samples) {
+    return 1;
+  }
+  int64_t factor = (total + max_samples - 1) / max_samples;
+  // A fixed seed keeps the decimation of a given profile reproducible.
+  std::default_random_engine gen(factor);
+  for (auto &trace : *traces) {
+    trace.second = DecimateCount(trace.second, factor, &gen);
+  }
+  *unknown_count = DecimateCount(*unknown_count, factor, &gen);
+  LOG(INFO) << "Decimated a profile of " << total << " samples by a factor of "
+            << factor;
+  return factor;
+}
+
 void ProfileProtoBuilder::AddArtificialSample(const std::string &name,
                                               int64_t count, int64_t weight) {
   AddSample({LocationID(name)}, count, weight, 0);
 }
 
+const std::string &ProfileProtoBuilder::TopFrameArgs(JNIEnv *jni,
+                                                     jmethodID method) {
+  auto it = top_frame_args_.find(method);
+  if (it == top_frame_args_.end()) {
+    std::string method_name, class_name, file_name, signature;
+    google::javaprofiler::JVMPI_CallFrame frame = {0, method};
+    google::javaprofiler::GetStackFrameElements(jni, jvmti_, frame, &file_name,
+                                                &class_name, &method_name,
+                                                &signature, nullptr);
+    it = top_frame_args_
+             .emplace(method, google::javaprofiler::IsRedactedClass(class_name)
+                                  ? ""
+                                  : MethodParameters(jvmti_, method))
+             .first;
+  }
+  return it->second;
+}
+
+bool ProfileProtoBuilder::IsIncludedFrame(
+    JNIEnv *jni, const google::javaprofiler::JVMPI_CallFrame &frame) {
+  if (included_packages_.empty() || frame.lineno < 0) {
+    return true;
+  }
+  auto it = included_methods_.find(frame.method_id);
+  if (it != included_methods_.end()) {
+    return it->second;
+  }
+
+  std::string method_name, class_name, file_name, signature;
+  google::javaprofiler::GetStackFrameElements(jni, jvmti_, frame, &file_name,
+                                              &class_name, &method_name,
+                                              &signature, nullptr);
+  bool included = false;
+  for (const std::string &package : included_packages_) {
+    if (class_name.compare(0, package.length(), package) == 0) {
+      included = true;
+      break;
+    }
+  }
+  included_methods_[frame.method_id] = included;
+  return included;
+}
+
 int64_t ProfileProtoBuilder::TotalCount() const { return total_count_; }
 
+void ProfileProtoBuilder::FoldLocations(int max_locations) {
+  perftools::profiles::Profile *profile = builder_.mutable_profile();
+  if (max_locations <= 1 || profile->location_size() <= max_locations) {
+    return;
+  }
+  cloud::profiler::FoldLocations(max_locations, LocationID("[Folded frames]"),
+                                 profile);
+}
+
 int64_t ProfileProtoBuilder::TotalWeight() const { return total_weight_; }
 
 uint64_t ProfileProtoBuilder::LocationID(
     JNIEnv *jni, const google::javaprofiler::JVMPI_CallFrame &frame) {
   if (frame.lineno == google::javaprofiler::kNativeFrameLineNum) {
+    if (!native_info_.Available()) {
+      // Without the mappings the address cannot be symbolized.
+      return LocationID(CallTraceErrorToName(kNativeStackTrace));
+    }
     return LocationID(reinterpret_cast<uint64_t>(frame.method_id));
   }
 
@@ -149,9 +375,31 @@ uint64_t ProfileProtoBuilder::LocationID(
   google::javaprofiler::GetStackFrameElements(jni, jvmti_, frame, &file_name,
                                               &class_name, &method_name,
                                               &signature, &line_number);
+  if (google::javaprofiler::IsRedactedClass(class_name)) {
+    // One location per redacted frame keeps the shape of the call graph.
+    return LocationID(google::javaprofiler::kRedactedFrameName);
+  }
   google::javaprofiler::FixMethodParameters(&signature);
+  if (google::javaprofiler::AnonymizedSymbols()) {
+    // The whole class and method names are hashed, the line numbers are
+    // kept so that distinct lines stay distinct locations.
+    google::javaprofiler::AnonymizeFrame(&class_name, &method_name, &signature,
+                                         &file_name);
+  }
 
-  return LocationID(class_name, method_name, signature, file_name, line_number);
+  return LocationID(class_name, method_name, signature, file_name, line_number,
+                    StartLine(frame.method_id));
+}
+
+int ProfileProtoBuilder::StartLine(jmethodID method) {
+  auto it = start_lines_.find(method);
+  if (it == start_lines_.end()) {
+    it = start_lines_
+             .emplace(method,
+                      google::javaprofiler::GetMethodStartLine(jvmti_, method))
+             .first;
+  }
+  return it->second;
 }
 
 uint64_t ProfileProtoBuilder::LocationID(uint64_t address) {
@@ -179,7 +427,7 @@ uint64_t ProfileProtoBuilder::LocationID(const std::string &class_name,
                                          const std::string &method_name,
                                          const std::string &signature,
                                          const std::string &file_name,
-                                         int line_number) {
+                                         int line_number, int start_line) {
   perftools::profiles::Profile *profile = builder_.mutable_profile();
 
   std::string frame_name;
@@ -194,9 +442,13 @@ uint64_t ProfileProtoBuilder::LocationID(const std::string &class_name,
 
   std::string simplified_name = frame_name;
   ::google::javaprofiler::SimplifyFunctionName(&simplified_name);
+  if (FLAGS_cprof_normalize_lambdas) {
+    ::google::javaprofiler::CollapseLambdaMethodName(&simplified_name);
+  }
 
   uint64_t function_id = builder_.FunctionId(
-      simplified_name.c_str(), frame_name.c_str(), file_name.c_str(), 0);
+      simplified_name.c_str(), frame_name.c_str(), file_name.c_str(),
+      start_line);
 
   uint64_t location_id = profile->location_size() + 1;
   Line function_line(function_id, line_number);
@@ -230,32 +482,127 @@ void ProfileProtoBuilder::Populate(
   sample_type = profile->add_sample_type();
   sample_type->set_type(builder_.StringId(profile_type));
   sample_type->set_unit(builder_.StringId("nanoseconds"));
+  profile->set_default_sample_type(builder_.StringId(profile_type));
 
+  // The collection started duration_ns ago.
+  profile->set_time_nanos(WallClockNanos() - duration_ns);
   profile->set_duration_nanos(duration_ns);
 
   for (const auto &trace : traces) {
     int64_t count = trace.second;
     if (count != 0) {
       std::vector<uint64_t> locations;
-      for (const auto &frame : trace.first.frames) {
-        locations.push_back(LocationID(jni, frame));
+      bool folding = false;
+      const auto &frames = trace.first.frames;
+      for (size_t i = 0; i < frames.size(); i++) {
+        const auto &frame = frames[i];
+        // The caller frame stands for the frames inlined into it. Only Java
+        // frames have a non-negative line number, actually the bci.
+        if (!FLAGS_cprof_show_inlined_frames && i + 1 < frames.size() &&
+            frame.lineno >= 0 && frames[i + 1].lineno >= 0 &&
+            InlinedFrames::IsInlined(frame.method_id, frames[i + 1].method_id,
+                                     frames[i + 1].lineno)) {
+          continue;
+        }
+        if (IsIncludedFrame(jni, frame)) {
+          locations.push_back(LocationID(jni, frame));
+          folding = false;
+        } else if (!folding) {
+          // A run of consecutive external frames becomes one frame, so the
+          // call path through the included frames stays intact.
+          locations.push_back(LocationID("[External code]"));
+          folding = true;
+        }
+      }
+      Labels labels;
+      if (trace.first.context.truncated) {
+        labels.emplace_back("truncated", "true");
+      }
+      // Only Java frames have a non-negative line number (actually the bci).
+      // The argument names and types are not anonymized, so are left out.
+      if (FLAGS_cprof_capture_top_frame_args &&
+          !google::javaprofiler::AnonymizedSymbols() &&
+          !trace.first.frames.empty() &&
+          trace.first.frames[0].lineno >= 0) {
+        const std::string &args =
+            TopFrameArgs(jni, trace.first.frames[0].method_id);
+        if (!args.empty()) {
+          labels.emplace_back("top_frame_args", args);
+        }
+      }
+      if (trace.first.context.in_gc) {
+        labels.emplace_back("gc", "true");
       }
-      AddSample(locations, count, count * period_ns, trace.first.attr);
+      int64_t time_bucket = trace.first.context.time_bucket;
+      if (time_bucket != 0) {
+        labels.emplace_back(
+            "time_offset_ms",
+            std::to_string((time_bucket - 1) *
+                           FLAGS_cprof_timestamp_bucket_msec));
+      }
+      int64_t thread_id = trace.first.context.thread_id;
+      if (thread_id != 0 && FLAGS_cprof_label_thread_ids) {
+        labels.emplace_back("thread_id", std::to_string(thread_id));
+      }
+      if (thread_id != 0 && threads_->TracksNames()) {
+        bool is_daemon;
+        std::string name =
+            threads_->ThreadName(jvmti_, jni, thread_id, &is_daemon);
+        if (FLAGS_cprof_label_thread_names && !name.empty()) {
+          // The thread names tell about the code as much as the symbols.
+          if (google::javaprofiler::AnonymizedSymbols()) {
+            name = google::javaprofiler::AnonymizedName("T", name);
+          }
+          labels.emplace_back("thread_name", name);
+        }
+        if (FLAGS_cprof_label_daemon_threads &&
+            strcmp(profile_type, "wall") == 0) {
+          labels.emplace_back("daemon", is_daemon ? "true" : "false");
+        }
+      }
+      AddSample(locations, count, count * period_ns, trace.first.attr, labels);
     }
   }
 
+  // Filter the native libraries. This could be done with RE2, but including
+  // it increases the size of the agent.
+  std::string filter = FLAGS_cprof_native_lib_filter;
+  bool exclude = !filter.empty() && filter[0] == '!';
+  if (exclude) {
+    filter.erase(0, 1);
+  }
+  regex_t filter_re;
+  bool use_filter = !filter.empty();
+  if (use_filter && regcomp(&filter_re, filter.c_str(),
+                            REG_EXTENDED | REG_NOSUB) != 0) {
+    LOG(ERROR) << "Invalid native library filter '"
+               << FLAGS_cprof_native_lib_filter
+               << "', including all native libraries";
+    use_filter = false;
+  }
+
   for (const auto &mapping : native_info_.Mappings()) {
+    if (use_filter && (regexec(&filter_re, mapping.name.c_str(), 0, nullptr,
+                               0) == 0) == exclude) {
+      continue;
+    }
     perftools::profiles::Mapping *m = profile->add_mapping();
     m->set_id(profile->mapping_size());
     m->set_memory_start(mapping.start);
     m->set_memory_limit(mapping.limit);
     m->set_filename(builder_.StringId(mapping.name.c_str()));
+    if (!mapping.build_id.empty()) {
+      m->set_build_id(builder_.StringId(mapping.build_id.c_str()));
+    }
+  }
+  if (use_filter) {
+    regfree(&filter_re);
   }
 }
 
 void ProfileProtoBuilder::AddSample(const std::vector<uint64_t> &locations,
                                     int64_t count, int64_t weight,
-                                    int64_t attr) {
+                                    int64_t attr, const Labels &labels) {
   perftools::profiles::Profile *profile = builder_.mutable_profile();
 
   perftools::profiles::Sample *sample = profile->add_sample();
@@ -273,16 +620,31 @@ void ProfileProtoBuilder::AddSample(const std::vector<uint64_t> &locations,
     label->set_key(builder_.StringId("attr"));
     label->set_str(attr);
   }
+
+  for (const auto &kv : labels) {
+    perftools::profiles::Label *label = sample->add_label();
+    label->set_key(builder_.StringId(kv.first.c_str()));
+    label->set_str(builder_.StringId(kv.second.c_str()));
+  }
 }
 
 std::string SerializeAndClearJavaCpuTraces(
     JNIEnv *env, jvmtiEnv *jvmti,
     const google::javaprofiler::NativeProcessInfo &native_info,
-    const char *profile_type, int64_t duration_ns, int64_t period_ns,
-    google::javaprofiler::TraceMultiset *traces, int64_t unknown_count) {
-  ProfileProtoBuilder b(jvmti, native_info);
+    ThreadTable *threads, const char *profile_type, int64_t duration_ns,
+    int64_t period_ns, google::javaprofiler::TraceMultiset *traces,
+    int64_t unknown_count) {
+  // Each decimated sample stands for factor samples, as if sampled with a
+  // period that many times longer.
+  period_ns *= DecimateTraces(traces, &unknown_count,
+                              FLAGS_cprof_max_samples_per_profile);
+  ProfileProtoBuilder b(jvmti, native_info, threads);
   b.Populate(env, profile_type, *traces, duration_ns, period_ns);
-  b.AddArtificialSample("[Unknown]", unknown_count, unknown_count * period_ns);
+  if (unknown_count > 0) {
+    b.AddArtificialSample("[Unknown]", unknown_count,
+                          unknown_count * period_ns);
+  }
+  b.FoldLocations(FLAGS_cprof_max_locations);
   LOG(INFO) << "Collected a profile: total count=" << b.TotalCount()
             << ", weight=" << b.TotalWeight();
 
@@ -290,5 +652,105 @@ std::string SerializeAndClearJavaCpuTraces(
   return b.Emit();
 }
 
+void FoldLocations(int max_locations, uint64_t folded_id,
+                   perftools::profiles::Profile *profile) {
+  std::unordered_map<uint64_t, int64_t> counts;
+  for (const auto &sample : profile->sample()) {
+    for (uint64_t id : sample.location_id()) {
+      counts[id] += sample.value(0);
+    }
+  }
+  std::vector<std::pair<int64_t, uint64_t>> ranked;
+  for (const auto &location : profile->location()) {
+    if (location.id() != folded_id) {
+      ranked.emplace_back(counts[location.id()], location.id());
+    }
+  }
+  int location_count = ranked.size();
+  if (max_locations <= 1 || location_count <= max_locations) {
+    return;
+  }
+  // Highest counts first, ties broken by ID to be deterministic.
+  std::sort(ranked.begin(), ranked.end(),
+            [](const std::pair<int64_t, uint64_t> &a,
+               const std::pair<int64_t, uint64_t> &b) {
+              return a.first != b.first ? a.first > b.first
+                                        : a.second < b.second;
+            });
+  // One location is left for the folded frames.
+  std::unordered_set<uint64_t> kept;
+  for (int i = 0; i < max_locations - 1; i++) {
+    kept.insert(ranked[i].second);
+  }
+  kept.insert(folded_id);
+
+  for (auto &sample : *profile->mutable_sample()) {
+    const google::protobuf::RepeatedField<uint64_t> ids = sample.location_id();
+    sample.clear_location_id();
+    bool folding = false;
+    for (uint64_t id : ids) {
+      if (kept.count(id) != 0) {
+        sample.add_location_id(id);
+        folding = false;
+      } else if (!folding) {
+        sample.add_location_id(folded_id);
+        folding = true;
+      }
+    }
+  }
+
+  google::protobuf::RepeatedPtrField<perftools::profiles::Location> locations;
+  locations.Swap(profile->mutable_location());
+  std::unordered_set<uint64_t> functions;
+  for (auto &location : locations) {
+    if (kept.count(location.id()) != 0) {
+      for (const auto &line : location.line()) {
+        functions.insert(line.function_id());
+      }
+      profile->add_location()->Swap(&location);
+    }
+  }
+  google::protobuf::RepeatedPtrField<perftools::profiles::Function> all;
+  all.Swap(profile->mutable_function());
+  for (auto &function : all) {
+    if (functions.count(function.id()) != 0) {
+      profile->add_function()->Swap(&function);
+    }
+  }
+  LOG(INFO) << "Folded " << location_count - (max_locations - 1) << " of the "
+            << location_count << " profile locations";
+}
+
+bool ValidateProfile(const perftools::profiles::Profile &profile) {
+  // The references and the IDs are checked by Builder::CheckValid.
+  std::vector<std::string> violations;
+  if (profile.sample_type_size() == 0) {
+    violations.push_back("no sample type");
+  }
+  if (profile.time_nanos() <= 0) {
+    violations.push_back("time_nanos " + std::to_string(profile.time_nanos()) +
+                         " is not set");
+  }
+  if (profile.duration_nanos() <= 0) {
+    violations.push_back("duration_nanos " +
+                         std::to_string(profile.duration_nanos()) +
+                         " is not set");
+  }
+  if (!profile.has_period_type() || profile.period() <= 0) {
+    violations.push_back("period " + std::to_string(profile.period()) +
+                         " or its type is not set");
+  }
+
+  if (!violations.empty()) {
+    for (const std::string &violation : violations) {
+      LOG(ERROR) << "Invalid profile: " << violation;
+    }
+    LOG(ERROR) << "Refusing to upload the profile, it violates the "
+               << "constraints of the Cloud Profiler backend";
+    return false;
+  }
+  return true;
+}
+
 }  // namespace profiler
 }  // namespace cloud
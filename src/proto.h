@@ -25,12 +25,34 @@ namespace profiler {
 
 // Generates a CPU profile in a compressed serialized profile.proto
 // from a collection of java stack traces, symbolized using the jvmti.
-// Data in traces will be cleared.
+// Data in traces will be cleared. The thread table is used to look up the
+// names of the sampled threads.
 std::string SerializeAndClearJavaCpuTraces(
     JNIEnv *jni, jvmtiEnv *jvmti,
     const google::javaprofiler::NativeProcessInfo &native_info,
-    const char *profile_type, int64_t duration_nanos, int64_t period_nanos,
-    google::javaprofiler::TraceMultiset *traces, int64_t unknown_count);
+    ThreadTable *threads, const char *profile_type, int64_t duration_nanos,
+    int64_t period_nanos, google::javaprofiler::TraceMultiset *traces,
+    int64_t unknown_count);
+
+// Downsamples the traces and the unknown count when they add up to more than
+// max_samples samples, preserving their proportions. Returns the decimation
+// factor, 1 when not decimated or when max_samples is not positive.
+int64_t DecimateTraces(google::javaprofiler::TraceMultiset *traces,
+                       int64_t *unknown_count, int64_t max_samples);
+
+// Bounds the number of locations of the profile to max_locations when it has
+// more besides the folded_id one, which must be in the profile. The
+// max_locations - 1 locations with the highest sample counts are kept, and
+// each run of the others in a sample is replaced by folded_id. The functions
+// only referenced by the dropped locations are dropped too.
+void FoldLocations(int max_locations, uint64_t folded_id,
+                   perftools::profiles::Profile *profile);
+
+// Checks that the profile satisfies the constraints the Cloud Profiler
+// backend adds to the ones of Builder::CheckValid: a sample type, and the
+// time, duration, period and period type set. Logs each violation and returns
+// false if there is any, the profile must then not be uploaded.
+bool ValidateProfile(const perftools::profiles::Profile &profile);
 
 }  // namespace profiler
 }  // namespace cloud
@@ -0,0 +1,289 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/proto.h"
+
+#include <stdint.h>
+#include <time.h>
+
+#include <map>
+#include <string>
+#include <vector>
+
+#include "google/protobuf/io/gzip_stream.h"
+#include "google/protobuf/io/zero_copy_stream_impl_lite.h"
+#include "gtest/gtest.h"
+#include "src/clock.h"
+#include "src/threads.h"
+#include "third_party/javaprofiler/native.h"
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+using google::javaprofiler::JVMPI_CallFrame;
+using google::javaprofiler::SampleContext;
+using google::javaprofiler::TraceMultiset;
+
+// The folded frames location of the FoldLocations tests.
+const uint64_t kFoldedId = 100;
+
+// Adds a single frame trace, told apart from the others by its ID.
+void AddTrace(TraceMultiset *traces, intptr_t id, int64_t count) {
+  JVMPI_CallFrame frame = {0, reinterpret_cast<jmethodID>(id)};
+  SampleContext context = {};
+  traces->Add(0, context, 1, &frame, count);
+}
+
+// Returns the counts of the traces by ID.
+std::map<intptr_t, int64_t> Counts(const TraceMultiset &traces) {
+  std::map<intptr_t, int64_t> counts;
+  for (const auto &trace : traces) {
+    counts[reinterpret_cast<intptr_t>(trace.first.frames[0].method_id)] =
+        trace.second;
+  }
+  return counts;
+}
+
+TEST(DecimateTracesTest, KeepsTheProfilesUnderTheLimit) {
+  TraceMultiset traces;
+  AddTrace(&traces, 1, 60);
+  AddTrace(&traces, 2, 30);
+  int64_t unknown_count = 10;
+  EXPECT_EQ(1, DecimateTraces(&traces, &unknown_count, 100));
+  EXPECT_EQ(10, unknown_count);
+  std::map<intptr_t, int64_t> expected = {{1, 60}, {2, 30}};
+  EXPECT_EQ(expected, Counts(traces));
+}
+
+TEST(DecimateTracesTest, KeepsTheProfilesWithoutLimit) {
+  TraceMultiset traces;
+  AddTrace(&traces, 1, 1000);
+  int64_t unknown_count = 1000;
+  EXPECT_EQ(1, DecimateTraces(&traces, &unknown_count, 0));
+  EXPECT_EQ(1, DecimateTraces(&traces, &unknown_count, -1));
+  EXPECT_EQ(1000, unknown_count);
+  std::map<intptr_t, int64_t> expected = {{1, 1000}};
+  EXPECT_EQ(expected, Counts(traces));
+}
+
+TEST(DecimateTracesTest, DividesTheCountsByTheRoundedUpFactor) {
+  TraceMultiset traces;
+  AddTrace(&traces, 1, 150);
+  AddTrace(&traces, 2, 90);
+  int64_t unknown_count = 9;
+  // 249 samples for at most 100, each decimated sample stands for 3.
+  EXPECT_EQ(3, DecimateTraces(&traces, &unknown_count, 100));
+  EXPECT_EQ(3, unknown_count);
+  std::map<intptr_t, int64_t> expected = {{1, 50}, {2, 30}};
+  EXPECT_EQ(expected, Counts(traces));
+}
+
+TEST(DecimateTracesTest, RoundsTheRemaindersInProportion) {
+  // A thousand traces sampled once, for at most a hundred samples.
+  TraceMultiset traces;
+  for (int i = 1; i <= 1000; i++) {
+    AddTrace(&traces, i, 1);
+  }
+  int64_t unknown_count = 0;
+  EXPECT_EQ(10, DecimateTraces(&traces, &unknown_count, 100));
+
+  int64_t total = unknown_count;
+  for (const auto &trace : traces) {
+    EXPECT_LE(trace.second, 1u);
+    total += trace.second;
+  }
+  // About one trace in ten is kept.
+  EXPECT_GT(total, 60);
+  EXPECT_LT(total, 140);
+}
+
+TEST(DecimateTracesTest, IsReproducible) {
+  TraceMultiset traces1, traces2;
+  for (int i = 1; i <= 100; i++) {
+    AddTrace(&traces1, i, i);
+    AddTrace(&traces2, i, i);
+  }
+  int64_t unknown_count1 = 77, unknown_count2 = 77;
+  EXPECT_EQ(DecimateTraces(&traces1, &unknown_count1, 500),
+            DecimateTraces(&traces2, &unknown_count2, 500));
+  EXPECT_EQ(unknown_count1, unknown_count2);
+  EXPECT_EQ(Counts(traces1), Counts(traces2));
+}
+
+// Returns a profile with the folded frames location and the given number of
+// other locations, of IDs 1 to locations. Each location has a function of
+// the same ID.
+perftools::profiles::Profile FoldingProfile(int locations) {
+  perftools::profiles::Profile profile;
+  std::vector<uint64_t> ids;
+  for (int i = 1; i <= locations; i++) {
+    ids.push_back(i);
+  }
+  ids.push_back(kFoldedId);
+  for (uint64_t id : ids) {
+    profile.add_function()->set_id(id);
+    perftools::profiles::Location *location = profile.add_location();
+    location->set_id(id);
+    location->add_line()->set_function_id(id);
+  }
+  return profile;
+}
+
+void AddSample(perftools::profiles::Profile *profile,
+               const std::vector<uint64_t> &location_ids, int64_t count) {
+  perftools::profiles::Sample *sample = profile->add_sample();
+  for (uint64_t id : location_ids) {
+    sample->add_location_id(id);
+  }
+  sample->add_value(count);
+}
+
+std::vector<uint64_t> LocationIds(const perftools::profiles::Sample &sample) {
+  return std::vector<uint64_t>(sample.location_id().begin(),
+                               sample.location_id().end());
+}
+
+TEST(FoldLocationsTest, KeepsTheMostSampledLocations) {
+  perftools::profiles::Profile profile = FoldingProfile(4);
+  AddSample(&profile, {1, 2, 3}, 10);
+  AddSample(&profile, {1, 4}, 5);
+  AddSample(&profile, {4, 3, 2}, 1);
+  // Location 1 is in 15 samples, 2 and 3 in 11, 4 in 6. The tie is broken by
+  // the ID.
+  FoldLocations(3, kFoldedId, &profile);
+
+  ASSERT_EQ(3, profile.location_size());
+  EXPECT_EQ(1u, profile.location(0).id());
+  EXPECT_EQ(2u, profile.location(1).id());
+  EXPECT_EQ(kFoldedId, profile.location(2).id());
+  ASSERT_EQ(3, profile.function_size());
+  EXPECT_EQ(1u, profile.function(0).id());
+  EXPECT_EQ(2u, profile.function(1).id());
+  EXPECT_EQ(kFoldedId, profile.function(2).id());
+
+  ASSERT_EQ(3, profile.sample_size());
+  EXPECT_EQ(std::vector<uint64_t>({1, 2, kFoldedId}),
+            LocationIds(profile.sample(0)));
+  EXPECT_EQ(std::vector<uint64_t>({1, kFoldedId}),
+            LocationIds(profile.sample(1)));
+  // The run of folded locations becomes a single one.
+  EXPECT_EQ(std::vector<uint64_t>({kFoldedId, 2}),
+            LocationIds(profile.sample(2)));
+  EXPECT_EQ(1, profile.sample(2).value(0));
+}
+
+TEST(FoldLocationsTest, KeepsTheProfilesUnderTheLimit) {
+  perftools::profiles::Profile profile = FoldingProfile(4);
+  AddSample(&profile, {1, 2, 3, 4}, 1);
+  // The folded frames location is not counted.
+  FoldLocations(4, kFoldedId, &profile);
+  EXPECT_EQ(5, profile.location_size());
+  EXPECT_EQ(5, profile.function_size());
+  EXPECT_EQ(std::vector<uint64_t>({1, 2, 3, 4}),
+            LocationIds(profile.sample(0)));
+}
+
+TEST(FoldLocationsTest, KeepsTheProfilesWithoutLimit) {
+  perftools::profiles::Profile profile = FoldingProfile(4);
+  AddSample(&profile, {1, 2, 3, 4}, 1);
+  FoldLocations(0, kFoldedId, &profile);
+  FoldLocations(1, kFoldedId, &profile);
+  EXPECT_EQ(5, profile.location_size());
+  EXPECT_EQ(std::vector<uint64_t>({1, 2, 3, 4}),
+            LocationIds(profile.sample(0)));
+}
+
+// Returns the profile of the compressed serialized bytes.
+perftools::profiles::Profile Unmarshal(const std::string &bytes) {
+  google::protobuf::io::ArrayInputStream stream(bytes.data(), bytes.size());
+  google::protobuf::io::GzipInputStream gzip_stream(&stream);
+  perftools::profiles::Profile profile;
+  EXPECT_TRUE(profile.ParseFromZeroCopyStream(&gzip_stream));
+  return profile;
+}
+
+TEST(SerializeAndClearJavaCpuTracesTest, StampsTheWallClockTime) {
+  google::javaprofiler::NativeProcessInfo native_info("/proc/self/maps");
+  ThreadTable threads(false);
+  TraceMultiset traces;
+  const int64_t kDuration = 10 * kNanosPerSecond;
+  // Only the unknown samples, which need no JVM to be symbolized.
+  std::string bytes = SerializeAndClearJavaCpuTraces(
+      nullptr, nullptr, native_info, &threads, "cpu", kDuration,
+      10 * kNanosPerMilli, &traces, 5);
+  ASSERT_FALSE(bytes.empty());
+
+  perftools::profiles::Profile profile = Unmarshal(bytes);
+  EXPECT_EQ(kDuration, profile.duration_nanos());
+  // The collection started the duration ago.
+  int64_t end_sec = (profile.time_nanos() + kDuration) / kNanosPerSecond;
+  EXPECT_NEAR(time(nullptr), end_sec, 5);
+}
+
+// Returns a profile the backend accepts.
+perftools::profiles::Profile ValidProfile() {
+  perftools::profiles::Profile profile;
+  profile.add_string_table("");
+  profile.add_string_table("cpu");
+  profile.add_string_table("nanoseconds");
+  perftools::profiles::ValueType *sample_type = profile.add_sample_type();
+  sample_type->set_type(1);
+  sample_type->set_unit(2);
+  profile.mutable_period_type()->set_type(1);
+  profile.mutable_period_type()->set_unit(2);
+  profile.set_period(10 * kNanosPerMilli);
+  profile.set_time_nanos(WallClockNanos());
+  profile.set_duration_nanos(10 * kNanosPerSecond);
+  return profile;
+}
+
+TEST(ValidateProfileTest, AcceptsTheCompleteProfiles) {
+  EXPECT_TRUE(ValidateProfile(ValidProfile()));
+}
+
+TEST(ValidateProfileTest, RejectsTheProfilesWithoutSampleType) {
+  perftools::profiles::Profile profile = ValidProfile();
+  profile.clear_sample_type();
+  EXPECT_FALSE(ValidateProfile(profile));
+}
+
+TEST(ValidateProfileTest, RejectsTheProfilesWithoutTime) {
+  perftools::profiles::Profile profile = ValidProfile();
+  profile.clear_time_nanos();
+  EXPECT_FALSE(ValidateProfile(profile));
+  profile.set_time_nanos(-kNanosPerSecond);
+  EXPECT_FALSE(ValidateProfile(profile));
+}
+
+TEST(ValidateProfileTest, RejectsTheProfilesWithoutDuration) {
+  perftools::profiles::Profile profile = ValidProfile();
+  profile.clear_duration_nanos();
+  EXPECT_FALSE(ValidateProfile(profile));
+}
+
+TEST(ValidateProfileTest, RejectsTheProfilesWithoutPeriod) {
+  perftools::profiles::Profile profile = ValidProfile();
+  profile.clear_period();
+  EXPECT_FALSE(ValidateProfile(profile));
+  profile = ValidProfile();
+  profile.clear_period_type();
+  EXPECT_FALSE(ValidateProfile(profile));
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,63 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "src/status_file.h"
+
+#include <inttypes.h>
+#include <stdio.h>
+#include <time.h>
+#include <unistd.h>
+
+namespace cloud {
+namespace profiler {
+
+void StatusFile::SetState(const char *state) {
+  std::lock_guard<std::mutex> lock(mutex_);
+  state_ = state;
+  Write();
+}
+
+void StatusFile::RecordUpload() {
+  std::lock_guard<std::mutex> lock(mutex_);
+  last_upload_sec_ = time(nullptr);
+  Write();
+}
+
+void StatusFile::Write() {
+  // Written next to the status file, so that the rename stays within the
+  // file system and replaces it atomically.
+  std::string tmp_path = path_ + ".tmp";
+  FILE *f = fopen(tmp_path.c_str(), "w");
+  if (f == nullptr) {
+    LOG(WARNING) << "Failed to create status file " << tmp_path;
+    return;
+  }
+  fprintf(f,
+          "state=%s\npid=%d\nupdated_sec=%" PRId64 "\nlast_upload_sec=%" PRId64
+          "\n",
+          state_, static_cast<int>(getpid()),
+          static_cast<int64_t>(time(nullptr)), last_upload_sec_);
+  if (fclose(f) != 0) {
+    LOG(WARNING) << "Failed to write status file " << tmp_path;
+    unlink(tmp_path.c_str());
+    return;
+  }
+  if (rename(tmp_path.c_str(), path_.c_str()) != 0) {
+    LOG(WARNING) << "Failed to replace status file " << path_;
+    unlink(tmp_path.c_str());
+  }
+}
+
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,64 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_STATUS_FILE_H_
+#define CLOUD_PROFILER_AGENT_JAVA_STATUS_FILE_H_
+
+#include <mutex>  // NOLINT(build/c++11)
+#include <string>
+
+#include "src/globals.h"
+
+namespace cloud {
+namespace profiler {
+
+// StatusFile reports the state of the agent in a file, for the process
+// supervisors to check the agent health without parsing its logs. The file
+// holds name=value lines:
+//   state=profiling
+//   pid=1234
+//   updated_sec=1760000000
+//   last_upload_sec=1759999990
+// The state is one of initializing, profiling, error or stopped, the times
+// are in seconds since the epoch, and last_upload_sec is 0 until a profile
+// is uploaded. The file is replaced atomically on every update, through a
+// temporary file renamed over it. The methods are thread-safe.
+class StatusFile {
+ public:
+  explicit StatusFile(const std::string &path)
+      : path_(path), state_("initializing"), last_upload_sec_(0) {}
+
+  // Sets the state, a string literal, and writes the file.
+  void SetState(const char *state);
+  // Records a successful upload now and writes the file.
+  void RecordUpload();
+
+ private:
+  // Writes the file, with mutex_ held.
+  void Write();
+
+  std::mutex mutex_;
+  std::string path_;
+  const char *state_;
+  int64_t last_upload_sec_;
+
+  DISALLOW_COPY_AND_ASSIGN(StatusFile);
+};
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_STATUS_FILE_H_
@@ -0,0 +1,36 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+package com.example;
+
+/** Workload of the integration test, burning CPU in a known method. */
+public class Testo {
+  private static volatile long sink;
+
+  static long hotLoop(long deadlineNanos) {
+    long acc = 0;
+    while (System.nanoTime() < deadlineNanos) {
+      for (int i = 0; i < 100000; i++) {
+        acc += i * 31L ^ acc;
+      }
+    }
+    return acc;
+  }
+
+  /** Runs hotLoop for the number of seconds given as the argument. */
+  public static void main(String[] args) {
+    long seconds = Long.parseLong(args[0]);
+    sink = hotLoop(System.nanoTime() + seconds * 1000000000L);
+  }
+}
@@ -14,11 +14,36 @@
 
 #include "src/threads.h"
 
+#include <errno.h>
+#include <fcntl.h>
+#include <linux/perf_event.h>
 #include <signal.h>
+#include <sys/ioctl.h>
 #include <sys/syscall.h>
 #include <time.h>
 #include <unistd.h>
 
+#include "src/clock.h"
+#include "src/jni_env.h"
+#include "third_party/javaprofiler/accessors.h"
+
+DEFINE_bool(cprof_label_thread_names, false,
+            "when true, label the CPU and wall profile samples with the name "
+            "of the sampled thread");
+DEFINE_bool(cprof_label_thread_ids, false,
+            "when true, label the CPU and wall profile samples with a "
+            "synthetic ID of the sampled thread, telling apart the threads "
+            "sharing a name");
+DEFINE_bool(cprof_label_daemon_threads, false,
+            "when true, label the wall profile samples with whether the "
+            "sampled thread is a daemon thread");
+DEFINE_string(cprof_thread_name_cache, "ttl",
+              "thread name caching policy: 'ttl' caches the names for "
+              "-cprof_thread_name_cache_ttl_sec, 'none' looks them up for "
+              "every profile");
+DEFINE_int32(cprof_thread_name_cache_ttl_sec, 10,
+             "time to live of the cached thread names, in seconds");
+
 namespace cloud {
 namespace profiler {
 
@@ -26,6 +51,8 @@ namespace {
 
 const timer_t kInvalidTimer = reinterpret_cast<timer_t>(-1LL);
 
+std::atomic<int> sampling_signal(SIGPROF);
+
 timer_t CreateTimer(pid_t tid) {
 #ifdef ALPINE
   // Per thread timers are not available on Alpine.
@@ -34,7 +61,7 @@ timer_t CreateTimer(pid_t tid) {
   struct sigevent sevp = {};
   sevp.sigev_notify = SIGEV_THREAD_ID;
   sevp._sigev_un._tid = tid;
-  sevp.sigev_signo = SIGPROF;
+  sevp.sigev_signo = SamplingSignal();
   timer_t timer = kInvalidTimer;
   int err = timer_create(CLOCK_THREAD_CPUTIME_ID, &sevp, &timer);
   if (err) {
@@ -65,33 +92,148 @@ void DeleteTimer(timer_t timer) {
   }
 }
 
+// Opens a disabled task clock perf event for the thread, delivering the
+// sampling signal to the thread on each period overflow. Returns -1 and sets
+// errno on error.
+int OpenPerfEvent(pid_t tid) {
+  struct perf_event_attr attr = {};
+  attr.size = sizeof(attr);
+  attr.type = PERF_TYPE_SOFTWARE;
+  attr.config = PERF_COUNT_SW_TASK_CLOCK;
+  // Replaced when the event is started.
+  attr.sample_period = kNanosPerSecond;
+  attr.wakeup_events = 1;
+  attr.disabled = 1;
+  int fd = syscall(__NR_perf_event_open, &attr, tid, -1, -1, 0);
+  if (fd == -1) {
+    return -1;
+  }
+  struct f_owner_ex owner = {F_OWNER_TID, tid};
+  if (fcntl(fd, F_SETFD, FD_CLOEXEC) == -1 ||
+      fcntl(fd, F_SETOWN_EX, &owner) == -1 ||
+      fcntl(fd, F_SETSIG, SamplingSignal()) == -1 ||
+      fcntl(fd, F_SETFL, fcntl(fd, F_GETFL) | O_ASYNC) == -1) {
+    int err = errno;
+    close(fd);
+    errno = err;
+    return -1;
+  }
+  return fd;
+}
+
+int CreatePerfEvent(pid_t tid) {
+  int fd = OpenPerfEvent(tid);
+  if (fd == -1) {
+    LOG(ERROR) << "Failed to open perf event: " << errno;
+  }
+  return fd;
+}
+
+bool SetPerfEvent(int fd, int64_t period_usec) {
+  if (period_usec == 0) {
+    if (ioctl(fd, PERF_EVENT_IOC_DISABLE, 0) == -1) {
+      LOG(ERROR) << "Failed to disable perf event: " << errno;
+      return false;
+    }
+    return true;
+  }
+  uint64_t period_ns = period_usec * 1000;
+  if (ioctl(fd, PERF_EVENT_IOC_PERIOD, &period_ns) == -1 ||
+      ioctl(fd, PERF_EVENT_IOC_RESET, 0) == -1 ||
+      ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) == -1) {
+    LOG(ERROR) << "Failed to start perf event: " << errno;
+    return false;
+  }
+  return true;
+}
+
+// Starts, or stops with a zero period, the timer or the perf event of the
+// thread, if any.
+void SetThreadSampler(const ThreadTable::ThreadEntry &entry,
+                      int64_t period_usec) {
+  if (entry.timer != kInvalidTimer) {
+    SetTimer(entry.timer, period_usec);
+  } else if (entry.perf_fd != -1) {
+    SetPerfEvent(entry.perf_fd, period_usec);
+  }
+}
+
+// Releases the timer or the perf event of the thread, if any.
+void DeleteThreadSampler(const ThreadTable::ThreadEntry &entry) {
+  if (entry.timer != kInvalidTimer) {
+    DeleteTimer(entry.timer);
+  }
+  if (entry.perf_fd != -1) {
+    close(entry.perf_fd);
+  }
+}
+
 }  // namespace
 
-void ThreadTable::RegisterCurrent() {
+void ThreadTable::CreateSampler(ThreadEntry *entry) {
+  if (entry->sampler_created) {
+    return;
+  }
+  entry->sampler_created = true;
+  if (use_perf_events_) {
+    entry->perf_fd = CreatePerfEvent(entry->tid);
+  } else if (use_timers_) {
+    entry->timer = CreateTimer(entry->tid);
+  }
+}
+
+void ThreadTable::RegisterCurrent(JNIEnv *jni, jthread thread) {
   pid_t tid = GetTid();
-  timer_t timer = kInvalidTimer;
-  if (use_timers_) {
-    timer = CreateTimer(tid);
+  ThreadEntry entry = {tid, kInvalidTimer, -1, false};
+  // A new ID on every registration, so a reused OS thread ID never maps to
+  // the samples or the name of the thread which previously had it.
+  int64_t thread_id = next_thread_id_++;
+  google::javaprofiler::Accessors::SetThreadId(thread_id);
+  if (TracksNames() && thread != nullptr) {
+    jobject ref = jni->NewGlobalRef(thread);
+    if (ClearJNIException(jni, "reference the new thread")) {
+      ref = nullptr;
+    }
+    std::lock_guard<std::mutex> lock(names_mutex_);
+    names_[thread_id] = NameEntry{ref, "", false, 0};
   }
   std::lock_guard<std::mutex> lock(thread_mutex_);
-  threads_.push_back({tid, timer});
-  if (timer != kInvalidTimer && period_usec_ > 0) {
-    SetTimer(timer, period_usec_);
+  auto i = threads_.find(tid);
+  if (i != threads_.end()) {
+    // The thread exited without a ThreadEnd event and its ID was reused,
+    // release the timer left behind.
+    DeleteThreadSampler(i->second);
+    threads_.erase(i);
   }
+  if (period_usec_ > 0 && UseTimers()) {
+    // Profiling is in progress, start sampling the new thread right away.
+    // Otherwise the sampler is created on the first profiling, so the short
+    // lived threads of a churning pool never pay for it.
+    CreateSampler(&entry);
+    SetThreadSampler(entry, period_usec_);
+  }
+  threads_[tid] = entry;
 }
 
-void ThreadTable::UnregisterCurrent() {
+void ThreadTable::UnregisterCurrent(JNIEnv *jni) {
   pid_t tid = GetTid();
-  std::lock_guard<std::mutex> lock(thread_mutex_);
-  for (auto i = threads_.begin(); i != threads_.end(); ++i) {
-    if (i->first == tid) {
-      if (i->second != kInvalidTimer) {
-        DeleteTimer(i->second);
-      }
-      threads_.erase(i);
-      return;
+  int64_t thread_id = google::javaprofiler::Accessors::GetThreadId();
+  google::javaprofiler::Accessors::SetThreadId(0);
+  if (TracksNames()) {
+    std::lock_guard<std::mutex> lock(names_mutex_);
+    auto it = names_.find(thread_id);
+    if (it != names_.end() && it->second.thread != nullptr) {
+      // Keep the last known name for the samples already collected.
+      jni->DeleteGlobalRef(it->second.thread);
+      it->second.thread = nullptr;
     }
   }
+  std::lock_guard<std::mutex> lock(thread_mutex_);
+  auto i = threads_.find(tid);
+  if (i != threads_.end()) {
+    DeleteThreadSampler(i->second);
+    threads_.erase(i);
+  }
 }
 
 int64_t ThreadTable::Size() const {
@@ -111,18 +253,163 @@ std::vector<pid_t> ThreadTable::Threads() const {
 void ThreadTable::StartTimers(int64_t period_usec) {
   std::lock_guard<std::mutex> lock(thread_mutex_);
   period_usec_ = period_usec;
-  for (const auto& t : threads_) {
-    SetTimer(t.second, period_usec);
+  for (auto& t : threads_) {
+    if (period_usec > 0 && UseTimers()) {
+      // Kept for the next profilings once created.
+      CreateSampler(&t.second);
+    }
+    SetThreadSampler(t.second, period_usec);
   }
 }
 
 void ThreadTable::StopTimers() { StartTimers(0); }
 
+bool ThreadTable::TracksNames() const {
+  return FLAGS_cprof_label_thread_names || FLAGS_cprof_label_daemon_threads;
+}
+
+bool ThreadTable::LabelsThreads() const {
+  return TracksNames() || FLAGS_cprof_label_thread_ids;
+}
+
+std::string ThreadTable::ThreadName(jvmtiEnv *jvmti, JNIEnv *jni,
+                                    int64_t thread_id, bool *is_daemon) {
+  std::lock_guard<std::mutex> lock(names_mutex_);
+  auto it = names_.find(thread_id);
+  if (it == names_.end()) {
+    if (is_daemon != nullptr) {
+      *is_daemon = false;
+    }
+    return "";
+  }
+  NameEntry &entry = it->second;
+  if (entry.thread != nullptr) {
+    RefreshName(jvmti, jni, &entry);
+  }
+  if (is_daemon != nullptr) {
+    *is_daemon = entry.is_daemon;
+  }
+  return entry.name;
+}
+
+void ThreadTable::RefreshName(jvmtiEnv *jvmti, JNIEnv *jni, NameEntry *entry) {
+  int64_t now_ns = TimeSpecToNanos(DefaultClock()->Now());
+  int64_t ttl_ns =
+      FLAGS_cprof_thread_name_cache == "none"
+          ? 0
+          : FLAGS_cprof_thread_name_cache_ttl_sec * kNanosPerSecond;
+  if (entry->refreshed_ns != 0 && now_ns - entry->refreshed_ns < ttl_ns) {
+    return;
+  }
+
+  jvmtiThreadInfo info;
+  if (jvmti->GetThreadInfo(entry->thread, &info) != JVMTI_ERROR_NONE) {
+    return;
+  }
+  google::javaprofiler::JvmtiScopedPtr<char> name(jvmti, info.name);
+  jni->DeleteLocalRef(info.thread_group);
+  jni->DeleteLocalRef(info.context_class_loader);
+  entry->name = name.Get() == nullptr ? "" : name.Get();
+  entry->is_daemon = info.is_daemon;
+  entry->refreshed_ns = now_ns;
+
+  // The thread may be terminating with its ThreadEnd event still to come. Its
+  // name is final, don't look it up again.
+  jint state;
+  if (jvmti->GetThreadState(entry->thread, &state) != JVMTI_ERROR_NONE ||
+      (state & JVMTI_THREAD_STATE_ALIVE) == 0) {
+    jni->DeleteGlobalRef(entry->thread);
+    entry->thread = nullptr;
+  }
+}
+
+void ThreadTable::PruneThreadNames() {
+  std::lock_guard<std::mutex> lock(names_mutex_);
+  for (auto it = names_.begin(); it != names_.end();) {
+    if (it->second.thread == nullptr) {
+      it = names_.erase(it);
+    } else {
+      ++it;
+    }
+  }
+}
+
+bool ThreadTable::CalibrateTimers(jvmtiEnv *jvmti, int64_t period_usec) {
+  if (!use_timers_ || use_perf_events_) {
+    return true;
+  }
+  jvmtiTimerInfo info;
+  jvmtiError err = jvmti->GetCurrentThreadCpuTimerInfo(&info);
+  if (err != JVMTI_ERROR_NONE) {
+    LOG(INFO) << "Thread CPU timer info not available, error " << err
+              << ", keeping the per-thread timers unchecked";
+    return true;
+  }
+  // The sampling period should span several ticks of the clock.
+  const int64_t kMinTicksPerPeriod = 10;
+  struct timespec res;
+  int64_t resolution_ns = clock_getres(CLOCK_THREAD_CPUTIME_ID, &res) == 0
+                              ? TimeSpecToNanos(res)
+                              : 0;
+  if (info.kind != JVMTI_TIMER_ELAPSED &&
+      resolution_ns * kMinTicksPerPeriod <= period_usec * 1000) {
+    LOG(INFO) << "Using the per-thread timers, thread CPU clock resolution "
+              << resolution_ns << "ns";
+    return true;
+  }
+  LOG(WARNING) << "Disabling the per-thread timers, the thread CPU clock "
+               << (info.kind == JVMTI_TIMER_ELAPSED
+                       ? "measures the elapsed time"
+                       : "is too coarse for the sampling period")
+               << " (resolution " << resolution_ns << "ns), using the "
+               << "process interval timer";
+  std::lock_guard<std::mutex> lock(thread_mutex_);
+  use_timers_ = false;
+  for (auto &t : threads_) {
+    if (t.second.timer != kInvalidTimer) {
+      DeleteTimer(t.second.timer);
+      t.second.timer = kInvalidTimer;
+    }
+  }
+  return false;
+}
+
+bool ThreadTimersAvailable() {
+#ifdef ALPINE
+  return false;
+#else
+  timer_t timer = CreateTimer(GetTid());
+  if (timer == kInvalidTimer) {
+    return false;
+  }
+  DeleteTimer(timer);
+  return true;
+#endif
+}
+
+bool PerfEventsAvailable() {
+  int fd = OpenPerfEvent(GetTid());
+  if (fd == -1) {
+    LOG(WARNING) << "perf events are not available, error " << errno
+                 << (errno == EACCES || errno == EPERM
+                         ? ": check kernel.perf_event_paranoid and the "
+                           "container security profile"
+                         : "");
+    return false;
+  }
+  close(fd);
+  return true;
+}
+
 pid_t GetTid() { return syscall(__NR_gettid); }
 
 bool TgKill(pid_t tid, int signum) {
   return syscall(__NR_tgkill, getpid(), tid, signum) == 0;
 }
 
+int SamplingSignal() { return sampling_signal; }
+
+void SetSamplingSignal(int signum) { sampling_signal = signum; }
+
 }  // namespace profiler
 }  // namespace cloud
@@ -19,7 +19,10 @@
 
 #include <time.h>
 
+#include <atomic>
 #include <mutex>  // NOLINT(build/c++11)
+#include <string>
+#include <unordered_map>
 #include <utility>
 
 #include "src/globals.h"
@@ -29,18 +32,40 @@ namespace profiler {
 
 // ThreadTable keeps track of the thread IDs of the known active threads.
 // It is meant to be updated from the OnThreadStart and OnThreadEnd callbacks.
-// When configured to do so, it manages per thread CPU time timers and allows
-// starting and stopping them to generate SIGPROF signal when certain amount of
-// the CPU time expires.
+// When configured to do so, it manages per thread CPU time timers, or per
+// thread task clock perf events, and allows starting and stopping them to
+// generate the sampling signal when certain amount of the CPU time expires.
+// When thread names are tracked, it also keeps a reference to the Java thread
+// objects to look up their names.
 class ThreadTable {
  public:
-  explicit ThreadTable(bool use_timers)
-      : use_timers_(use_timers), period_usec_() {}
+  // The perf events take precedence over the timers when both are requested.
+  explicit ThreadTable(bool use_timers, bool use_perf_events = false)
+      : use_timers_(use_timers),
+        use_perf_events_(use_perf_events),
+        period_usec_(),
+        next_thread_id_(1) {}
 
-  // Registers the current thread.
-  void RegisterCurrent();
+  // A registered thread and its CPU time sampler.
+  struct ThreadEntry {
+    pid_t tid;
+    // kInvalidTimer when the timer usage is off or the timer creation failed
+    // for the thread.
+    timer_t timer;
+    // -1 when the perf event usage is off or the perf event creation failed
+    // for the thread.
+    int perf_fd;
+    // Whether the timer or the perf event creation was attempted. They are
+    // created when the thread is first profiled.
+    bool sampler_created;
+  };
+
+  // Registers the current thread, given its Java thread object. Assigns the
+  // thread a synthetic ID, unique for the lifetime of the process, available
+  // from Accessors::GetThreadId() on the thread.
+  void RegisterCurrent(JNIEnv *jni, jthread thread);
   // Unregisters the current thread.
-  void UnregisterCurrent();
+  void UnregisterCurrent(JNIEnv *jni);
   // Returns the number of registered threads.
   int64_t Size() const;
   // Returns the IDs of all registered threads.
@@ -49,28 +74,89 @@ class ThreadTable {
   void StartTimers(int64_t period_usec);
   // Stops per-thread timers.
   void StopTimers();
-  // Whether CPU time sampling is configured to use per-thread timers.
-  bool UseTimers() const { return use_timers_; }
+  // Checks the thread CPU timer the JVM reports, and turns the per-thread
+  // timers off when it does not measure the CPU time or is too coarse to
+  // sample every period_usec. Must be called from the live phase. Returns
+  // false when it turned the per-thread timers off.
+  bool CalibrateTimers(jvmtiEnv *jvmti, int64_t period_usec);
+  // Whether CPU time sampling is configured to use per-thread timers or perf
+  // events.
+  bool UseTimers() const { return use_timers_ || use_perf_events_; }
+  // Whether the samples are labeled with thread names or daemon status.
+  bool TracksNames() const;
+  // Whether the samples are labeled with anything about their thread, so
+  // they need to record its synthetic ID.
+  bool LabelsThreads() const;
+  // Returns the name of the thread with the given synthetic ID, or an empty
+  // string if unknown. Names of live threads are cached according to the
+  // -cprof_thread_name_cache flag, the last known name is returned for
+  // threads which have exited. When not null, is_daemon is set to whether
+  // the thread is a daemon thread, cached along with the name.
+  std::string ThreadName(jvmtiEnv *jvmti, JNIEnv *jni, int64_t thread_id,
+                         bool *is_daemon = nullptr);
+  // Forgets the names of the threads which have exited.
+  void PruneThreadNames();
 
  private:
+  struct NameEntry {
+    // Global reference to the thread object, null once the thread exited.
+    jobject thread;
+    std::string name;
+    bool is_daemon;
+    // Monotonic time of the last name lookup.
+    int64_t refreshed_ns;
+  };
+
+  // Creates the timer or the perf event of the thread, unless already
+  // attempted. Must be called with thread_mutex_ held.
+  void CreateSampler(ThreadEntry *entry);
+
+  // Looks up the name of a live thread again once its cached name expired.
+  void RefreshName(jvmtiEnv *jvmti, JNIEnv *jni, NameEntry *entry);
+
   mutable std::mutex thread_mutex_;
-  // List of threads and associated timers. The timer ID is kInvalidTimer when
-  // the timer usage is off or the timer creation failed for the thread.
-  std::vector<std::pair<pid_t, timer_t>> threads_;
-  // True when the timer usage is requested.
-  bool use_timers_;
+  // Threads and associated timers or perf events, keyed by thread ID.
+  std::unordered_map<pid_t, ThreadEntry> threads_;
+  // True when the timer usage is requested, until turned off by
+  // CalibrateTimers().
+  std::atomic<bool> use_timers_;
+  // True when the perf event usage is requested.
+  bool use_perf_events_;
   // Non-zero when the thread timers have been started.
   int64_t period_usec_;
 
+  // Next synthetic thread ID to assign.
+  std::atomic<int64_t> next_thread_id_;
+
+  // Guards the thread names, held while looking the names up.
+  std::mutex names_mutex_;
+  // Thread names, keyed by synthetic thread ID.
+  std::unordered_map<int64_t, NameEntry> names_;
+
   DISALLOW_COPY_AND_ASSIGN(ThreadTable);
 };
 
+// Returns whether the per thread CPU time timers can be created, which some
+// container runtimes restrict.
+bool ThreadTimersAvailable();
+
+// Returns whether the task clock perf events can be opened, logging the reason
+// when not, e.g. when restricted by kernel.perf_event_paranoid.
+bool PerfEventsAvailable();
+
 // Returns the thread ID of the current thread.
 pid_t GetTid();
 
 // Sends a signal to the specified thread.
 bool TgKill(pid_t tid, int signum);
 
+// Returns the signal delivered to the threads to sample them, SIGPROF unless
+// changed with SetSamplingSignal().
+int SamplingSignal();
+
+// Sets the sampling signal. Must be called before the sampling starts.
+void SetSamplingSignal(int signum);
+
 }  // namespace profiler
 }  // namespace cloud
 
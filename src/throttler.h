@@ -18,6 +18,7 @@
 #define CLOUD_PROFILER_AGENT_JAVA_THROTTLER_H_
 
 #include <memory>
+#include <string>
 
 #include "src/globals.h"
 
@@ -29,6 +30,18 @@ constexpr char kTypeCPU[] = "cpu";
 constexpr char kTypeWall[] = "wall";
 constexpr char kTypeHeap[] = "heap";
 
+// A collected profile, encoded once and handed as is to whichever throttler
+// stores or uploads it, so all paths see the same bytes and statistics.
+struct EncodedProfile {
+  // Compressed serialized profile.proto bytes.
+  std::string bytes;
+  // One of the kType* constants.
+  std::string profile_type;
+  int64_t sample_count = 0;
+  int64_t stack_count = 0;
+  int64_t duration_nanos = 0;
+};
+
 // Iterator-like abstraction used to guide a profiling loop comprising of
 // waiting for when the next profile may be collected and saving its data once
 // gathered the data. The client pseudocode for using the interface is:
@@ -59,8 +72,18 @@ class Throttler {
   // by a successful call to WaitNext().
   virtual int64_t DurationNanos() = 0;
 
-  // Upload the compressed profile proto bytes. Returns false on error.
-  virtual bool Upload(std::string profile) = 0;
+  // Upload the profile collected at this iteration. Returns false on error.
+  virtual bool Upload(EncodedProfile profile) = 0;
+
+  // Uploads a profile collected outside of the WaitNext() schedule, e.g. at
+  // the process exit, of the type and duration given by the profile. Returns
+  // false on error.
+  virtual bool UploadOffline(EncodedProfile profile) = 0;
+
+  // Returns true if the last Upload() failed with an error that retrying
+  // will not fix, e.g. missing permissions. The client may then stop
+  // profiling altogether.
+  virtual bool PermanentUploadFailure() { return false; }
 
   // Closes the throttler by trying to cancel WaitNext() / Upload() in flight.
   // Those calls may return cancellation error. This method is thread-safe.
@@ -14,8 +14,13 @@
 
 #include "src/throttler_api.h"
 
+#include <errno.h>
+#include <stdlib.h>
+
 #include <algorithm>
 #include <chrono>  // NOLINT
+#include <mutex>  // NOLINT
+#include <set>
 #include <sstream>
 
 #include "src/clock.h"
@@ -23,6 +28,7 @@
 #include "src/globals.h"
 #include "src/pem_roots.h"
 #include "src/string.h"
+#include "src/uploader_gcs.h"
 #include "google/devtools/cloudprofiler/v2/profiler.grpc.pb.h"
 #include "google/protobuf/duration.pb.h"  // NOLINT
 #include "google/rpc/error_details.pb.h"  // NOLINT
@@ -36,14 +42,46 @@
 // API curated profiling configuration.
 DEFINE_string(cprof_api_address, "cloudprofiler.googleapis.com",
               "API server address");
+DEFINE_string(cprof_api_endpoints, "",
+              "comma-separated list of API server addresses; when set, "
+              "overrides -cprof_api_address and the profile creation fails "
+              "over to the next address when the current one is persistently "
+              "unavailable");
 DEFINE_string(cprof_deployment_labels, "",
               "comma-separated list of name=value deployment labels; "
               "names must be in dns-label-like-format");
 DEFINE_string(cprof_profile_labels, "",
               "comma-separated list of name=value profile labels; "
-              "names must be in dns-label-like-format");
+              "names must be in dns-label-like-format and can be scoped "
+              "to a profile type as type:name=value, e.g. cpu:name=value");
+DEFINE_bool(cprof_collapse_patch_versions, false,
+            "when true, keep only the leading "
+            "-cprof_service_version_components components of a dotted "
+            "service version, e.g. 1.2.3 becomes 1.2, so that the profiles "
+            "of frequent releases aggregate together");
+DEFINE_int32(cprof_service_version_components, 2,
+             "number of leading service version components kept with "
+             "-cprof_collapse_patch_versions");
+DEFINE_int32(cprof_max_upload_message_bytes, 4 * 1024 * 1024,
+             "maximum size of the profile upload messages, in bytes; larger "
+             "profiles are not uploaded, lower -cprof_max_samples_per_profile "
+             "to keep them within the limit");
+DEFINE_string(cprof_gcs_bucket, "",
+              "when set, Google Cloud Storage bucket to which a copy of each "
+              "uploaded profile is written, as a gzipped pprof named "
+              "<prefix><instance>/<type>_<timestamp>.pb.gz, using the same "
+              "credentials; to write to GCS only, without the Profiler API, "
+              "use -cprof_profile_filename=gs://<bucket>/<prefix> instead");
+DEFINE_string(cprof_gcs_prefix, "",
+              "object name prefix of the -cprof_gcs_bucket profile copies");
 DEFINE_bool(cprof_use_insecure_creds_for_testing, false,
             "use insecure channel creds, for testing only");
+DEFINE_int32(cprof_connect_timeout_ms, 0,
+             "when set, time to wait for the initial connection to the API "
+             "server, in milliseconds; a failure to connect within this time "
+             "is logged as a DNS resolution or connectivity problem");
+
+DECLARE_int32(cprof_metadata_retry_interval_sec);
 
 namespace cloud {
 namespace profiler {
@@ -59,14 +97,30 @@ const float kBackoffFactor = 1.3;
 const int64_t kMaxBackoffNanos = 60 * 60 * kNanosPerSecond;
 // Name of the optional trailing metadata with the server-guided backoff.
 const char kRetryInfoMetadata[] = "google.rpc.retryinfo-bin";
+// Name of the optional trailing metadata with a retry-after style delay, in
+// seconds, sent by some proxies when rate-limiting.
+const char kRetryAfterMetadata[] = "retry-after";
 // Standard zone name label key.
 const char kZoneNameLabel[] = "zone";
 // Standard language label key.
 const char kLanguageLabel[] = "language";
 // Standard service version label key.
 const char kServiceVersionLabel[] = "version";
+// Standard instance name label key.
+const char kInstanceLabel[] = "instance";
+// Agent identity label key, telling the profiles of this agent apart from the
+// ones of the other Java agents.
+const char kAgentLabel[] = "agent";
+// Agent identity label value prefix, followed by the agent version. The '/'
+// of a product token is not allowed in a label value.
+const char kAgentLabelPrefix[] = "cloud-profiler-java_";
+// Number of consecutive UNAVAILABLE profile creation errors after which the
+// next API endpoint is used.
+const int kUnavailableErrorsBeforeFailover = 3;
 // Range of random number
 const int64_t kRandomRange = 65536;
+// Maximum length of a label value accepted by the API.
+const size_t kMaxLabelValueLength = 63;
 
 // Routes GRPC logging through cloud profiler logger.
 // Otherwise GRPC would log to stderr.
@@ -114,6 +168,7 @@ NewProfilerServiceStub(const std::string& addr, const std::string& language) {
   channel_arguments.SetUserAgentPrefix(
       "gcloud-" + language + "-profiler/" +
       std::string(CLOUD_PROFILER_AGENT_VERSION));
+  channel_arguments.SetMaxSendMessageSize(FLAGS_cprof_max_upload_message_bytes);
 
   std::shared_ptr<grpc::ChannelInterface> ch =
       grpc::CreateCustomChannel(addr, creds, channel_arguments);
@@ -122,6 +177,18 @@ NewProfilerServiceStub(const std::string& addr, const std::string& language) {
     return nullptr;
   }
 
+  if (FLAGS_cprof_connect_timeout_ms > 0) {
+    // Keep going on failure, the channel keeps retrying the connection in the
+    // background and the profile creation backs off as usual.
+    if (!ch->WaitForConnected(
+            std::chrono::system_clock::now() +
+            std::chrono::milliseconds(FLAGS_cprof_connect_timeout_ms))) {
+      LOG(ERROR) << "Failed to connect to " << addr << " within "
+                 << FLAGS_cprof_connect_timeout_ms
+                 << "ms, check the DNS resolution and network connectivity";
+    }
+  }
+
   std::unique_ptr<api::grpc::ProfilerService::StubInterface> stub =
       api::grpc::ProfilerService::NewStub(ch);
   if (stub == nullptr) {
@@ -137,12 +204,63 @@ std::string DebugString(const grpc::Status& st) {
   return os.str();
 }
 
+// Returns a hint about the likely cause of an RPC failure, to tell apart the
+// connectivity problems from the authentication and permission ones.
+std::string FailureHint(const grpc::Status& st) {
+  switch (st.error_code()) {
+    case grpc::StatusCode::UNAVAILABLE:
+    case grpc::StatusCode::DEADLINE_EXCEEDED:
+      return "connection failure, check the DNS resolution and network "
+             "connectivity";
+    case grpc::StatusCode::UNAUTHENTICATED:
+      return "authentication failure, check the credentials";
+    case grpc::StatusCode::PERMISSION_DENIED:
+      return "permission failure, check that the credentials are allowed to "
+             "use the Cloud Profiler API";
+    default:
+      return "";
+  }
+}
+
+// Returns true if the upload error is not expected to go away on retry, as
+// the request itself or the credentials are rejected.
+bool IsPermanentUploadError(const grpc::Status& st) {
+  switch (st.error_code()) {
+    case grpc::StatusCode::UNAUTHENTICATED:
+    case grpc::StatusCode::PERMISSION_DENIED:
+    case grpc::StatusCode::NOT_FOUND:
+    case grpc::StatusCode::UNIMPLEMENTED:
+      return true;
+    default:
+      return false;
+  }
+}
+
+// Returns true if the profile fits in an upload message, logging a warning
+// otherwise. The profile bytes are most of the message, the rest is a small
+// allowance for the profile metadata.
+bool FitsUploadMessage(const EncodedProfile& profile) {
+  const int64_t kMetadataAllowanceBytes = 64 * 1024;
+  int64_t limit = FLAGS_cprof_max_upload_message_bytes;
+  if (static_cast<int64_t>(profile.bytes.size()) + kMetadataAllowanceBytes <=
+      limit) {
+    return true;
+  }
+  LOG(WARNING) << "The " << profile.bytes.size() << " bytes '"
+               << profile.profile_type << "' profile with "
+               << profile.sample_count << " samples exceeds the " << limit
+               << " bytes upload limit, raise -cprof_max_upload_message_bytes "
+               << "or lower -cprof_max_samples_per_profile";
+  return false;
+}
+
 // Attempts to read the backoff delay information from the server trailing
-// metadata. Should only be used when a call failed with ABORTED error as only
-// then the backoff info may be returned. Returns false if there was any
-// unexpected error parsing the returned retry information proto.
-bool AbortedBackoffDuration(const grpc::ClientContext& ctx,
-                            int64_t* backoff_ns) {
+// metadata. Should only be used when a call failed with ABORTED or
+// RESOURCE_EXHAUSTED error as only then the backoff info may be returned.
+// The RetryInfo proto is preferred over a retry-after delay in seconds.
+// Returns false if there is no backoff info or it could not be parsed.
+bool ServerBackoffDuration(const grpc::ClientContext& ctx,
+                           int64_t* backoff_ns) {
   *backoff_ns = 0;
   auto md = ctx.GetServerTrailingMetadata();
   auto it = md.find(kRetryInfoMetadata);
@@ -155,66 +273,103 @@ bool AbortedBackoffDuration(const grpc::ClientContext& ctx,
     *backoff_ns = kNanosPerSecond * delay.seconds() + delay.nanos();
     return true;
   }
+  it = md.find(kRetryAfterMetadata);
+  if (it != md.end() && !it->second.empty()) {
+    std::string value(it->second.data(), it->second.size());
+    char* end;
+    errno = 0;
+    long long seconds = strtoll(value.c_str(), &end, 10);  // NOLINT
+    if (errno != 0 || *end != '\0' || seconds < 0 ||
+        seconds > kMaxBackoffNanos / kNanosPerSecond) {
+      return false;
+    }
+    *backoff_ns = seconds * kNanosPerSecond;
+    return true;
+  }
   return false;
 }
 
-// Initializes deployment information from environment properties and label
-// string in "name1=val1,name2=val2,..." format. Returns false on error.
-bool InitializeDeployment(CloudEnv* env, const std::string& labels,
-                          const std::string& language, api::Deployment* d) {
-  std::string project_id = env->ProjectID();
-  if (project_id.empty()) {
-    LOG(ERROR) << "Project ID is unknown";
-    return false;
+// Returns the label value sanitized with the given function, logging a warning
+// the first time a given value is modified. A value left empty by the
+// sanitization, e.g. made of invalid characters only, is returned empty and
+// the label must then be dropped.
+std::string SanitizeLabelValueOnce(
+    const std::string& value, std::string (*sanitize)(const std::string&)) {
+  std::string s = sanitize(value);
+  if (s != value) {
+    static std::mutex* mu = new std::mutex();
+    static std::set<std::string>* warned = new std::set<std::string>();
+    std::lock_guard<std::mutex> lock(*mu);
+    if (warned->insert(value).second) {
+      if (s.empty()) {
+        LOG(WARNING) << "Label value '" << value << "' has no valid "
+                     << "character, dropping the label";
+      } else {
+        LOG(WARNING) << "Label value '" << value << "' sanitized to '" << s
+                     << "'";
+      }
+    }
   }
-  d->set_project_id(project_id);
+  return s;
+}
 
-  std::string service = env->Service();
-  if (service.empty()) {
-    LOG(ERROR) << "Deployment service name is not configured";
-    return false;
-  }
-  if (!IsValidServiceName(service)) {
-    LOG(ERROR)
-        << "Deployment service name '" << service
-        << "' does not match pattern '^[a-z]([-a-z0-9_.]{0,253}[a-z0-9])?$'";
-    return false;
-  }
-  d->set_target(service);
+std::string SanitizeLabelValueOnce(const std::string& value) {
+  return SanitizeLabelValueOnce(value, SanitizeLabelValue);
+}
 
-  std::map<std::string, std::string> label_kvs;
-  if (!ParseKeyValueList(labels, &label_kvs)) {
-    LOG(ERROR) << "Failed to parse deployment labels '" << labels << "'";
-    return false;
+// Sets the label of the name to the sanitized value, or drops the label when
+// the sanitization leaves the value empty.
+void SetSanitizedLabel(const std::string& name, const std::string& value,
+                       std::map<std::string, std::string>* kvs) {
+  std::string s = SanitizeLabelValueOnce(value);
+  if (s.empty() && !value.empty()) {
+    kvs->erase(name);
+    return;
   }
+  (*kvs)[name] = s;
+}
 
-  std::string service_version = env->ServiceVersion();
-  if (!service_version.empty()) {
-    label_kvs[kServiceVersionLabel] = service_version;
+// Adds the profile labels applying to the given profile type: the unscoped
+// labels, and the labels scoped to the type as type:name=value, which take
+// precedence over unscoped labels of the same name.
+bool AddProfileLabels(CloudEnv* env, api::Profile* p,
+                      const std::string& profile_type,
+                      const std::string& labels) {
+  std::map<std::string, std::string> parsed_kvs;
+  if (!ParseKeyValueList(labels, &parsed_kvs)) {
+    LOG(ERROR) << "Failed to parse profile labels '" << labels << "'";
+    return false;
   }
 
-  std::string zone_name = env->ZoneName();
-  if (!zone_name.empty()) {
-    label_kvs[kZoneNameLabel] = zone_name;
+  std::map<std::string, std::string> label_kvs;
+  std::string instance_name = env->InstanceName();
+  if (!instance_name.empty()) {
+    SetSanitizedLabel(kInstanceLabel, instance_name, &label_kvs);
   }
-
-  label_kvs[kLanguageLabel] = language;
-  for (const auto& kv : label_kvs) {
-    (*d->mutable_labels())[kv.first] = kv.second;
+  std::map<std::string, std::string> scoped_kvs;
+  for (const auto& kv : parsed_kvs) {
+    size_t pos = kv.first.find(':');
+    if (pos == std::string::npos) {
+      SetSanitizedLabel(kv.first, kv.second, &label_kvs);
+      continue;
+    }
+    std::string scope = kv.first.substr(0, pos);
+    std::string name = kv.first.substr(pos + 1);
+    if (scope != kTypeCPU && scope != kTypeWall && scope != kTypeHeap) {
+      LOG(ERROR) << "Unknown profile type '" << scope << "' in profile label '"
+                 << kv.first << "'";
+      return false;
+    }
+    if (name.empty()) {
+      LOG(ERROR) << "Empty name in profile label '" << kv.first << "'";
+      return false;
+    }
+    if (scope == profile_type) {
+      SetSanitizedLabel(name, kv.second, &scoped_kvs);
+    }
   }
-
-  LOG(INFO) << "Initialized deployment: project_id=" << project_id
-            << ", service=" << service
-            << ", service_version=" << service_version
-            << ", zone_name=" << zone_name;
-  return true;
-}
-
-bool AddProfileLabels(api::Profile* p, const std::string& labels) {
-  std::map<std::string, std::string> label_kvs;
-  if (!ParseKeyValueList(labels, &label_kvs)) {
-    LOG(ERROR) << "Failed to parse profile labels '" << labels << "'";
-    return false;
+  for (const auto& kv : scoped_kvs) {
+    label_kvs[kv.first] = kv.second;
   }
 
   for (const auto& kv : label_kvs) {
@@ -248,6 +403,148 @@ bool IsValidServiceName(std::string s) {
   return true;
 }
 
+namespace {
+
+// Sanitizes a label value, see SanitizeLabelValue. With keep_case, the upper
+// case letters are kept rather than lowered.
+std::string SanitizeValue(const std::string& value, bool keep_case) {
+  std::string s = value;
+  auto is_alnum = [keep_case](char c) {
+    return (c >= 'a' && c <= 'z') || (c >= '0' && c <= '9') ||
+           (keep_case && c >= 'A' && c <= 'Z');
+  };
+  for (size_t i = 0; i < s.length(); ++i) {
+    char c = s[i];
+    if (!keep_case && c >= 'A' && c <= 'Z') {
+      s[i] = c - 'A' + 'a';
+    } else if (!is_alnum(c) && c != '.' && c != '-' && c != '_') {
+      s[i] = '_';
+    }
+  }
+  size_t start = 0;
+  while (start < s.length() && !is_alnum(s[start])) {
+    start++;
+  }
+  s = s.substr(start, kMaxLabelValueLength);
+  while (!s.empty() && !is_alnum(s.back())) {
+    s.pop_back();
+  }
+  return s;
+}
+
+}  // namespace
+
+std::string SanitizeLabelValue(const std::string& value) {
+  return SanitizeValue(value, false);
+}
+
+std::string SanitizeServiceVersion(const std::string& version) {
+  return SanitizeValue(version, true);
+}
+
+std::string CollapseServiceVersion(const std::string& version,
+                                   int components) {
+  if (components <= 0) {
+    return version;
+  }
+  size_t pos = 0;
+  for (int i = 0; i < components; i++) {
+    pos = version.find('.', i == 0 ? 0 : pos + 1);
+    if (pos == std::string::npos) {
+      return version;
+    }
+  }
+  return version.substr(0, pos);
+}
+
+bool Deployment::Validate() const {
+  if (project_id.empty()) {
+    LOG(ERROR) << "Project ID is unknown";
+    return false;
+  }
+  if (service.empty()) {
+    LOG(ERROR) << "Deployment service name is not configured, set it with "
+               << "the '-cprof_service' agent option";
+    return false;
+  }
+  if (!IsValidServiceName(service)) {
+    LOG(ERROR)
+        << "Deployment service name '" << service
+        << "' does not match pattern '^[a-z]([-a-z0-9_.]{0,253}[a-z0-9])?$'";
+    return false;
+  }
+  return true;
+}
+
+std::string Deployment::ResourceName() const {
+  return "projects/" + project_id;
+}
+
+void Deployment::ToProto(api::Deployment* d) const {
+  d->set_project_id(project_id);
+  d->set_target(service);
+  for (const auto& kv : labels) {
+    std::string value = SanitizeLabelValue(kv.second);
+    if (!value.empty()) {
+      (*d->mutable_labels())[kv.first] = value;
+    }
+  }
+  std::string version = SanitizeServiceVersion(service_version);
+  if (!version.empty()) {
+    (*d->mutable_labels())[kServiceVersionLabel] = version;
+  }
+}
+
+bool NewDeployment(CloudEnv* env, const std::string& labels,
+                   const std::string& language, Deployment* d) {
+  d->project_id = env->ProjectID();
+  d->service = env->Service();
+  if (!d->Validate()) {
+    return false;
+  }
+
+  d->labels.clear();
+  if (!ParseKeyValueList(labels, &d->labels)) {
+    LOG(ERROR) << "Failed to parse deployment labels '" << labels << "'";
+    return false;
+  }
+
+  d->service_version = env->ServiceVersion();
+  if (FLAGS_cprof_collapse_patch_versions) {
+    d->service_version = CollapseServiceVersion(
+        d->service_version, FLAGS_cprof_service_version_components);
+  }
+  if (d->service_version.empty()) {
+    // An explicit version label is then the service version.
+    auto it = d->labels.find(kServiceVersionLabel);
+    if (it != d->labels.end()) {
+      d->service_version = it->second;
+    }
+  }
+  d->labels.erase(kServiceVersionLabel);
+
+  std::string zone_name = env->ZoneName();
+  if (!zone_name.empty()) {
+    d->labels[kZoneNameLabel] = zone_name;
+  }
+  d->labels[kLanguageLabel] = language;
+  d->labels[kAgentLabel] =
+      kAgentLabelPrefix + std::string(CLOUD_PROFILER_AGENT_VERSION);
+  std::map<std::string, std::string> label_kvs;
+  label_kvs.swap(d->labels);
+  for (const auto& kv : label_kvs) {
+    SetSanitizedLabel(kv.first, kv.second, &d->labels);
+  }
+  d->service_version =
+      SanitizeLabelValueOnce(d->service_version, SanitizeServiceVersion);
+
+  LOG(INFO) << "Initialized deployment: project_id=" << d->project_id
+            << ", service=" << d->service
+            << ", service_version=" << d->service_version
+            << ", zone_name=" << zone_name;
+  return true;
+}
+
 APIThrottler::APIThrottler(
     const std::vector<google::devtools::cloudprofiler::v2::ProfileType>& types,
     const std::string& language, const std::string& language_version)
@@ -267,6 +564,10 @@ APIThrottler::APIThrottler(
       env_(env),
       clock_(clock),
       stub_(std::move(stub)),
+      endpoint_index_(0),
+      unavailable_errors_(0),
+      permanent_upload_failure_(false),
+      upload_backoff_ns_(0),
       creation_backoff_envelope_ns_(kBackoffNanos),
       closed_(false) {
   grpc_init();
@@ -277,10 +578,57 @@ APIThrottler::APIThrottler(
   dist_ = std::uniform_int_distribution<int64_t>(0, kRandomRange);
 
   if (!stub_) {  // Set in tests
-    LOG(INFO) << "Will use profiler service " << FLAGS_cprof_api_address
+    if (!FLAGS_cprof_api_endpoints.empty()) {
+      for (const std::string& endpoint :
+           Split(FLAGS_cprof_api_endpoints, ',')) {
+        if (!endpoint.empty()) {
+          endpoints_.push_back(endpoint);
+        }
+      }
+    }
+    if (endpoints_.empty()) {
+      endpoints_.push_back(FLAGS_cprof_api_address);
+    }
+    LOG(INFO) << "Will use profiler service " << endpoints_[0]
               << " to create and upload profiles";
-    stub_ = NewProfilerServiceStub(FLAGS_cprof_api_address, language_);
+    stub_ = NewProfilerServiceStub(endpoints_[0], language_);
+  }
+
+  if (!FLAGS_cprof_gcs_bucket.empty()) {
+    std::string prefix = FLAGS_cprof_gcs_bucket + "/" +
+                         FLAGS_cprof_gcs_prefix + env_->InstanceName() + "/";
+    LOG(INFO) << "Will also write the profiles to gs://" << prefix;
+    gcs_uploader_.reset(new GcsUploader(env_, prefix));
+  }
+}
+
+void APIThrottler::SetGcsUploader(std::unique_ptr<ProfileUploader> uploader) {
+  gcs_uploader_ = std::move(uploader);
+}
+
+void APIThrottler::CopyToGcs(const std::string& profile_type,
+                             const std::string& bytes) {
+  if (gcs_uploader_ == nullptr) {
+    return;
+  }
+  if (!gcs_uploader_->Upload(profile_type, bytes)) {
+    LOG(WARNING) << "Failed to write the '" << profile_type
+                 << "' profile copy to Google Cloud Storage";
+  }
+}
+
+void APIThrottler::FailOver() {
+  endpoint_index_ = (endpoint_index_ + 1) % endpoints_.size();
+  const std::string& endpoint = endpoints_[endpoint_index_];
+  LOG(WARNING) << "Profiler service persistently unavailable, failing over to "
+               << endpoint;
+  auto stub = NewProfilerServiceStub(endpoint, language_);
+  if (stub == nullptr) {
+    LOG(ERROR) << "Failed to fail over to " << endpoint
+               << ", keeping the current endpoint";
+    return;
   }
+  stub_ = std::move(stub);
 }
 
 bool APIThrottler::WaitNext() {
@@ -293,12 +641,32 @@ bool APIThrottler::WaitNext() {
   for (const auto& type : types_) {
     req.add_profile_type(type);
   }
-  if (!InitializeDeployment(env_, FLAGS_cprof_deployment_labels, language_,
-                            req.mutable_deployment())) {
-    LOG(ERROR) << "Failed to initialize deployment, stop profiling";
-    return false;
+  Deployment deployment;
+  while (!NewDeployment(env_, FLAGS_cprof_deployment_labels, language_,
+                        &deployment)) {
+    // The metadata server may become available later, e.g. when the
+    // network is not ready yet at startup.
+    if (!deployment.project_id.empty() ||
+        FLAGS_cprof_metadata_retry_interval_sec <= 0) {
+      LOG(ERROR) << "Failed to initialize deployment, stop profiling";
+      return false;
+    }
+    LOG(WARNING) << "Project ID is not available, retrying in "
+                 << FLAGS_cprof_metadata_retry_interval_sec << "s";
+    SleepUnlessClosed(FLAGS_cprof_metadata_retry_interval_sec *
+                      kNanosPerSecond);
+    if (closed_) {
+      return false;
+    }
+  }
+  deployment.ToProto(req.mutable_deployment());
+  req.set_parent(deployment.ResourceName());
+
+  if (upload_backoff_ns_ > 0) {
+    // The last upload was rate-limited with a server-specified backoff.
+    SleepUnlessClosed(upload_backoff_ns_);
+    upload_backoff_ns_ = 0;
   }
-  req.set_parent("projects/" + req.deployment().project_id());
 
   while (true) {
     LOG(INFO) << "Creating a new profile via profiler service";
@@ -319,11 +687,19 @@ bool APIThrottler::WaitNext() {
                 << profile_.name();
       // Reset the backoff envelope to the base on success.
       creation_backoff_envelope_ns_ = kBackoffNanos;
+      unavailable_errors_ = 0;
       break;
     }
     if (closed_) {
       return false;
     }
+    if (st.error_code() != grpc::StatusCode::UNAVAILABLE) {
+      unavailable_errors_ = 0;
+    } else if (++unavailable_errors_ >= kUnavailableErrorsBeforeFailover &&
+               endpoints_.size() > 1) {
+      unavailable_errors_ = 0;
+      FailOver();
+    }
     OnCreationError(st);
   }
 
@@ -351,19 +727,32 @@ int64_t APIThrottler::DurationNanos() {
   return d.seconds() * kNanosPerSecond + d.nanos();
 }
 
-bool APIThrottler::Upload(std::string profile) {
-  LOG(INFO) << "Uploading " << profile.size() << " bytes of '" << ProfileType()
-            << "' profile data";
+bool APIThrottler::Upload(EncodedProfile profile) {
+  LOG(INFO) << "Uploading " << profile.bytes.size() << " bytes of '"
+            << ProfileType() << "' profile data";
+  permanent_upload_failure_ = false;
+  if (!FitsUploadMessage(profile)) {
+    return false;
+  }
 
-  if (!AddProfileLabels(&profile_, FLAGS_cprof_profile_labels)) {
+  if (!AddProfileLabels(env_, &profile_, ProfileType(),
+                        FLAGS_cprof_profile_labels)) {
     LOG(ERROR) << "Failed to add profile labels, won't upload the profile";
     return false;
   }
 
   api::UpdateProfileRequest req;
   *req.mutable_profile() = profile_;
+  // The profile may have been collected for another duration than asked,
+  // see -cprof_cpu_duration_sec.
+  if (profile.duration_nanos > 0) {
+    req.mutable_profile()->mutable_duration()->set_seconds(
+        profile.duration_nanos / kNanosPerSecond);
+    req.mutable_profile()->mutable_duration()->set_nanos(
+        profile.duration_nanos % kNanosPerSecond);
+  }
 
-  req.mutable_profile()->set_profile_bytes(std::move(profile));
+  req.mutable_profile()->set_profile_bytes(std::move(profile.bytes));
   ResetClientContext();
 
   // The system clock is used here directly, because clock_->now() returns
@@ -373,39 +762,141 @@ bool APIThrottler::Upload(std::string profile) {
   ctx_->set_deadline(std::chrono::system_clock::now() +
                      std::chrono::seconds{20});
   grpc::Status st = stub_->UpdateProfile(ctx_.get(), req, &profile_);
+  // The copy is written after the API call, which has a deadline to meet.
+  CopyToGcs(profile.profile_type, req.profile().profile_bytes());
 
   if (!st.ok()) {
     // TODO: Recognize and retry transient errors.
-    LOG(ERROR) << "Profile bytes upload failed: " << DebugString(st);
+    std::string hint = FailureHint(st);
+    LOG(ERROR) << "Profile bytes upload failed: " << DebugString(st)
+               << (hint.empty() ? "" : ": ") << hint;
+    permanent_upload_failure_ = IsPermanentUploadError(st);
+    if (st.error_code() == grpc::StatusCode::RESOURCE_EXHAUSTED &&
+        ServerBackoffDuration(*ctx_, &upload_backoff_ns_) &&
+        upload_backoff_ns_ > 0) {
+      LOG(WARNING) << "The agent is rate-limited by the profiler service, "
+                   << "will back off for "
+                   << upload_backoff_ns_ / kNanosPerMilli
+                   << "ms before the next profile";
+    }
     return false;
   }
 
   return true;
 }
 
+bool APIThrottler::PermanentUploadFailure() {
+  return permanent_upload_failure_;
+}
+
+bool APIThrottler::UploadOffline(EncodedProfile profile) {
+  if (stub_ == nullptr) {
+    LOG(ERROR) << "Profiler API is not initialized, won't upload the profile";
+    return false;
+  }
+  const std::string& profile_type = profile.profile_type;
+  int64_t duration_ns = profile.duration_nanos;
+  LOG(INFO) << "Uploading " << profile.bytes.size() << " bytes of offline '"
+            << profile_type << "' profile data";
+  if (!FitsUploadMessage(profile)) {
+    return false;
+  }
+
+  api::CreateOfflineProfileRequest req;
+  api::Profile* p = req.mutable_profile();
+  Deployment deployment;
+  if (!NewDeployment(env_, FLAGS_cprof_deployment_labels, language_,
+                     &deployment)) {
+    LOG(ERROR) << "Failed to initialize deployment, won't upload the profile";
+    return false;
+  }
+  deployment.ToProto(p->mutable_deployment());
+  req.set_parent(deployment.ResourceName());
+  if (profile_type == kTypeCPU) {
+    p->set_profile_type(api::CPU);
+  } else if (profile_type == kTypeWall) {
+    p->set_profile_type(api::WALL);
+  } else if (profile_type == kTypeHeap) {
+    p->set_profile_type(api::HEAP);
+  } else {
+    LOG(ERROR) << "Unsupported offline profile type " << profile_type;
+    return false;
+  }
+  p->mutable_duration()->set_seconds(duration_ns / kNanosPerSecond);
+  p->mutable_duration()->set_nanos(duration_ns % kNanosPerSecond);
+  if (!AddProfileLabels(env_, p, profile_type, FLAGS_cprof_profile_labels)) {
+    LOG(ERROR) << "Failed to add profile labels, won't upload the profile";
+    return false;
+  }
+  p->set_profile_bytes(std::move(profile.bytes));
+  ResetClientContext();
+
+  // Same timeout as for UpdateProfile requests.
+  ctx_->set_deadline(std::chrono::system_clock::now() +
+                     std::chrono::seconds{20});
+  api::Profile created;
+  grpc::Status st = stub_->CreateOfflineProfile(ctx_.get(), req, &created);
+  CopyToGcs(profile_type, p->profile_bytes());
+  if (!st.ok()) {
+    std::string hint = FailureHint(st);
+    LOG(ERROR) << "Offline profile upload failed: " << DebugString(st)
+               << (hint.empty() ? "" : ": ") << hint;
+    return false;
+  }
+  LOG(INFO) << "Offline profile created: " << created.name();
+  return true;
+}
+
 void APIThrottler::OnCreationError(const grpc::Status& st) {
-  if (st.error_code() == grpc::StatusCode::ABORTED) {
+  if (st.error_code() == grpc::StatusCode::RESOURCE_EXHAUSTED) {
+    int64_t backoff_ns;
+    if (ServerBackoffDuration(*ctx_, &backoff_ns) && backoff_ns > 0) {
+      LOG(WARNING) << "The agent is rate-limited by the profiler service, "
+                   << "will retry after backing off for "
+                   << backoff_ns / kNanosPerMilli << "ms";
+      SleepUnlessClosed(backoff_ns);
+      return;
+    }
+    LOG(WARNING) << "The agent is rate-limited by the profiler service "
+                 << "without a server-specified backoff, will retry: "
+                 << DebugString(st);
+  } else if (st.error_code() == grpc::StatusCode::ABORTED) {
+    // The server paces the agents this way: no profile is wanted right now,
+    // which is not an error.
     int64_t backoff_ns;
-    if (AbortedBackoffDuration(*ctx_, &backoff_ns)) {
+    if (ServerBackoffDuration(*ctx_, &backoff_ns)) {
       if (backoff_ns > 0) {
         LOG(INFO) << "Got ABORTED, will retry after backing off for "
                   << backoff_ns / kNanosPerMilli << "ms";
-        clock_->SleepFor(NanosToTimeSpec(backoff_ns));
+        SleepUnlessClosed(backoff_ns);
         return;
       }
     }
+    LOG(INFO) << "Got ABORTED without a server-specified backoff, will retry";
+  } else {
+    std::string hint = FailureHint(st);
+    LOG(WARNING) << "Failed to create profile, will retry: " << DebugString(st)
+                 << (hint.empty() ? "" : ": ") << hint;
   }
 
-  LOG(WARNING) << "Failed to create profile, will retry: " << DebugString(st);
-
   double random_factor = static_cast<double>(dist_(gen_)) / kRandomRange;
-  clock_->SleepFor(
-      NanosToTimeSpec(creation_backoff_envelope_ns_ * random_factor));
+  SleepUnlessClosed(creation_backoff_envelope_ns_ * random_factor);
   creation_backoff_envelope_ns_ = std::min(
       static_cast<int64_t>(creation_backoff_envelope_ns_ * kBackoffFactor),
       kMaxBackoffNanos);
 }
 
+void APIThrottler::SleepUnlessClosed(int64_t duration_ns) {
+  // Sleep in short steps, a server-specified backoff can be long and must not
+  // hold up the shutdown.
+  const int64_t kStepNanos = kNanosPerSecond;
+  while (duration_ns > 0 && !closed_) {
+    int64_t step_ns = std::min(duration_ns, kStepNanos);
+    clock_->SleepFor(NanosToTimeSpec(step_ns));
+    duration_ns -= step_ns;
+  }
+}
+
 void APIThrottler::ResetClientContext() {
   std::lock_guard<std::mutex> lock(ctx_mutex_);
   ctx_.reset(new grpc::ClientContext());  // NOLINT
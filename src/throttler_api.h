@@ -20,6 +20,7 @@
 #include <jni.h>
 
 #include <atomic>
+#include <map>
 #include <memory>
 #include <mutex>  // NOLINT
 #include <random>
@@ -29,6 +30,7 @@
 #include "src/clock.h"
 #include "src/cloud_env.h"
 #include "src/throttler.h"
+#include "src/uploader.h"
 #include "google/devtools/cloudprofiler/v2/profiler.grpc.pb.h"
 #include "grpcpp/client_context.h"
 #include "grpcpp/support/status.h"
@@ -36,6 +38,35 @@
 namespace cloud {
 namespace profiler {
 
+// The deployment the profiles are attributed to.
+struct Deployment {
+  std::string project_id;
+  std::string service;
+  // Empty when the version is not known.
+  std::string service_version;
+  // Deployment labels, besides the service version.
+  std::map<std::string, std::string> labels;
+
+  // Returns true if the deployment can be used with the API, logging the
+  // reason otherwise: the project ID and the service are set, and the service
+  // name is valid.
+  bool Validate() const;
+
+  // Returns the parent resource name of the profiles, "projects/<id>".
+  std::string ResourceName() const;
+
+  // Fills the API form of the deployment, with the service version as the
+  // "version" label and the label values sanitized. The labels the
+  // sanitization leaves empty are dropped.
+  void ToProto(google::devtools::cloudprofiler::v2::Deployment* d) const;
+};
+
+// Returns the deployment from the environment properties, the label string in
+// "name1=val1,name2=val2,..." format and the language label. Returns false on
+// error or if the deployment is not valid.
+bool NewDeployment(CloudEnv* env, const std::string& labels,
+                   const std::string& language, Deployment* d);
+
 // Throttler implementation using the Cloud Profiler API.
 class APIThrottler : public Throttler {
  public:
@@ -57,9 +88,14 @@ class APIThrottler : public Throttler {
   bool WaitNext() override;
   std::string ProfileType() override;
   int64_t DurationNanos() override;
-  bool Upload(std::string profile) override;
+  bool Upload(EncodedProfile profile) override;
+  bool UploadOffline(EncodedProfile profile) override;
+  bool PermanentUploadFailure() override;
   void Close() override;
 
+  // Replaces the uploader of the profile copies. Testing-only.
+  void SetGcsUploader(std::unique_ptr<ProfileUploader> uploader);
+
  private:
   FRIEND_TEST(APIThrottlerTest, TestCreatesAndUploadsProfile);
   // Takes a backoff on profile creation error. The backoff duration
@@ -67,9 +103,21 @@ class APIThrottler : public Throttler {
   // exponentially increasing value, bounded by kMaxBackoffNanos.
   void OnCreationError(const grpc::Status& st);
 
+  // Sleeps for the given duration, returning early once the throttler is
+  // closed.
+  void SleepUnlessClosed(int64_t duration_ns);
+
   // Resets the client gRPC context for the next call.
   void ResetClientContext();
 
+  // Switches the stub to the next of the API endpoints, wrapping around.
+  void FailOver();
+
+  // Writes a copy of the profile to Google Cloud Storage when
+  // -cprof_gcs_bucket is set. Called once the API call is done, only for the
+  // profiles the API accepts the size of. Failures are logged only.
+  void CopyToGcs(const std::string& profile_type, const std::string& bytes);
+
  private:
   const std::vector<google::devtools::cloudprofiler::v2::ProfileType> types_;
   const std::string language_;
@@ -81,6 +129,22 @@ class APIThrottler : public Throttler {
       stub_;
   google::devtools::cloudprofiler::v2::Profile profile_;
 
+  // API server addresses, tried in order, and the one currently in use.
+  // Empty when the stub is set in tests.
+  std::vector<std::string> endpoints_;
+  size_t endpoint_index_;
+  // Consecutive UNAVAILABLE profile creation errors.
+  int unavailable_errors_;
+
+  // The last profile upload failed with a non-retryable error.
+  bool permanent_upload_failure_;
+  // Server-specified backoff of a rate-limited upload, honored before the
+  // next profile creation.
+  int64_t upload_backoff_ns_;
+
+  // Uploader of the profile copies, null unless -cprof_gcs_bucket is set.
+  std::unique_ptr<ProfileUploader> gcs_uploader_;
+
   // Profile creation error handling.
   int64_t creation_backoff_envelope_ns_;
   std::default_random_engine gen_;
@@ -97,6 +161,22 @@ class APIThrottler : public Throttler {
 // Public for testing.
 bool IsValidServiceName(std::string service);
 
+// Returns the label value converted to the form accepted by the API: at most
+// 63 characters, with upper case letters lowered, characters other than
+// [a-z0-9._-] replaced by '_', and starting and ending with [a-z0-9].
+// Public for testing.
+std::string SanitizeLabelValue(const std::string& value);
+
+// Same as SanitizeLabelValue for the service version label, keeping the upper
+// case letters of the version, e.g. "1.0-RC1". Public for testing.
+std::string SanitizeServiceVersion(const std::string& version);
+
+// Returns the service version truncated to its leading dot-separated
+// components, e.g. ("1.2.3", 2) -> "1.2". Versions with no more components
+// are returned unchanged, as are all versions when components is not
+// positive. Public for testing.
+std::string CollapseServiceVersion(const std::string& version, int components);
+
 }  // namespace profiler
 }  // namespace cloud
 
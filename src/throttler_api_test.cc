@@ -0,0 +1,266 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/throttler_api.h"
+
+#include <memory>
+#include <string>
+#include <vector>
+
+#include "gtest/gtest.h"
+
+DECLARE_int32(cprof_max_upload_message_bytes);
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+namespace api = google::devtools::cloudprofiler::v2;
+
+class FakeCloudEnv : public CloudEnv {
+ public:
+  std::string ProjectID() override { return "test-project"; }
+  std::string ZoneName() override { return "us-central1-a"; }
+  std::string Oauth2AccessToken() override { return ""; }
+  std::string Service() override { return "test-service"; }
+  std::string ServiceVersion() override { return "1.0"; }
+  std::string InstanceName() override { return "test-instance"; }
+};
+
+// Profiler service stub recording the uploads in the shared call log.
+class FakeProfilerStub : public api::grpc::ProfilerService::StubInterface {
+ public:
+  explicit FakeProfilerStub(std::vector<std::string>* calls) : calls_(calls) {}
+
+  grpc::Status CreateProfile(grpc::ClientContext* context,
+                             const api::CreateProfileRequest& request,
+                             api::Profile* response) override {
+    return grpc::Status(grpc::StatusCode::UNIMPLEMENTED, "");
+  }
+
+  grpc::Status CreateOfflineProfile(
+      grpc::ClientContext* context,
+      const api::CreateOfflineProfileRequest& request,
+      api::Profile* response) override {
+    calls_->push_back("api:" + request.profile().profile_bytes());
+    return grpc::Status::OK;
+  }
+
+  grpc::Status UpdateProfile(grpc::ClientContext* context,
+                             const api::UpdateProfileRequest& request,
+                             api::Profile* response) override {
+    calls_->push_back("api:" + request.profile().profile_bytes());
+    return grpc::Status::OK;
+  }
+
+ private:
+  grpc::ClientAsyncResponseReaderInterface<api::Profile>* AsyncCreateProfileRaw(
+      grpc::ClientContext* context, const api::CreateProfileRequest& request,
+      grpc::CompletionQueue* cq) override {
+    return nullptr;
+  }
+  grpc::ClientAsyncResponseReaderInterface<api::Profile>*
+  PrepareAsyncCreateProfileRaw(grpc::ClientContext* context,
+                               const api::CreateProfileRequest& request,
+                               grpc::CompletionQueue* cq) override {
+    return nullptr;
+  }
+  grpc::ClientAsyncResponseReaderInterface<api::Profile>*
+  AsyncCreateOfflineProfileRaw(grpc::ClientContext* context,
+                               const api::CreateOfflineProfileRequest& request,
+                               grpc::CompletionQueue* cq) override {
+    return nullptr;
+  }
+  grpc::ClientAsyncResponseReaderInterface<api::Profile>*
+  PrepareAsyncCreateOfflineProfileRaw(
+      grpc::ClientContext* context,
+      const api::CreateOfflineProfileRequest& request,
+      grpc::CompletionQueue* cq) override {
+    return nullptr;
+  }
+  grpc::ClientAsyncResponseReaderInterface<api::Profile>* AsyncUpdateProfileRaw(
+      grpc::ClientContext* context, const api::UpdateProfileRequest& request,
+      grpc::CompletionQueue* cq) override {
+    return nullptr;
+  }
+  grpc::ClientAsyncResponseReaderInterface<api::Profile>*
+  PrepareAsyncUpdateProfileRaw(grpc::ClientContext* context,
+                               const api::UpdateProfileRequest& request,
+                               grpc::CompletionQueue* cq) override {
+    return nullptr;
+  }
+
+  std::vector<std::string>* calls_;
+};
+
+// Object store recording the profile copies in the shared call log.
+class MockObjectStore : public ProfileUploader {
+ public:
+  explicit MockObjectStore(std::vector<std::string>* calls) : calls_(calls) {}
+
+  bool Upload(const std::string& profile_type,
+              const std::string& profile) override {
+    calls_->push_back("gcs:" + profile_type + ":" + profile);
+    return true;
+  }
+
+ private:
+  std::vector<std::string>* calls_;
+};
+
+TEST(ThrottlerApiTest, NewDeploymentLabelsTheAgent) {
+  FakeCloudEnv env;
+  Deployment deployment;
+  ASSERT_TRUE(NewDeployment(&env, "", "java", &deployment));
+  const std::string kWant = SanitizeLabelValue(
+      std::string("cloud-profiler-java_") + CLOUD_PROFILER_AGENT_VERSION);
+  EXPECT_EQ(kWant, deployment.labels.at("agent"));
+  EXPECT_EQ(0u, kWant.find("cloud-profiler-java_"));
+
+  api::Deployment d;
+  deployment.ToProto(&d);
+  EXPECT_EQ(kWant, d.labels().at("agent"));
+  EXPECT_EQ("java", d.labels().at("language"));
+}
+
+TEST(ThrottlerApiTest, NewDeploymentKeepsTheAgentLabel) {
+  FakeCloudEnv env;
+  Deployment deployment;
+  ASSERT_TRUE(NewDeployment(&env, "agent=other,team=core", "java",
+                            &deployment));
+  EXPECT_EQ(0u, deployment.labels.at("agent").find("cloud-profiler-java_"));
+  EXPECT_EQ("core", deployment.labels.at("team"));
+}
+
+class GcsCopyTest : public ::testing::Test {
+ protected:
+  void SetUp() override {
+    saved_max_upload_message_bytes_ = FLAGS_cprof_max_upload_message_bytes;
+    throttler_.reset(new APIThrottler(
+        {api::CPU}, "java", "", &env_, DefaultClock(),
+        std::unique_ptr<api::grpc::ProfilerService::StubInterface>(
+            new FakeProfilerStub(&calls_))));
+    throttler_->SetGcsUploader(
+        std::unique_ptr<ProfileUploader>(new MockObjectStore(&calls_)));
+  }
+
+  void TearDown() override {
+    FLAGS_cprof_max_upload_message_bytes = saved_max_upload_message_bytes_;
+  }
+
+  static EncodedProfile NewProfile(const std::string& bytes) {
+    EncodedProfile profile;
+    profile.bytes = bytes;
+    profile.profile_type = kTypeCPU;
+    profile.duration_nanos = kNanosPerSecond;
+    return profile;
+  }
+
+  int32_t saved_max_upload_message_bytes_;
+  FakeCloudEnv env_;
+  std::vector<std::string> calls_;
+  std::unique_ptr<APIThrottler> throttler_;
+};
+
+TEST_F(GcsCopyTest, CopiesAfterTheUpload) {
+  ASSERT_TRUE(throttler_->Upload(NewProfile("profile")));
+  std::vector<std::string> want = {"api:profile", "gcs:cpu:profile"};
+  EXPECT_EQ(want, calls_);
+}
+
+TEST_F(GcsCopyTest, CopiesAfterTheOfflineUpload) {
+  ASSERT_TRUE(throttler_->UploadOffline(NewProfile("profile")));
+  std::vector<std::string> want = {"api:profile", "gcs:cpu:profile"};
+  EXPECT_EQ(want, calls_);
+}
+
+TEST_F(GcsCopyTest, SkipsTheRejectedProfiles) {
+  FLAGS_cprof_max_upload_message_bytes = 1024;
+  EXPECT_FALSE(throttler_->Upload(NewProfile("profile")));
+  EXPECT_FALSE(throttler_->UploadOffline(NewProfile("profile")));
+  EXPECT_TRUE(calls_.empty());
+}
+
+TEST(ThrottlerApiTest, ValidServiceNames) {
+  EXPECT_TRUE(IsValidServiceName("a"));
+  EXPECT_TRUE(IsValidServiceName("my-service_1.2"));
+  EXPECT_FALSE(IsValidServiceName(""));
+  EXPECT_FALSE(IsValidServiceName("1service"));
+  EXPECT_FALSE(IsValidServiceName("service-"));
+  EXPECT_FALSE(IsValidServiceName("My-Service"));
+  EXPECT_FALSE(IsValidServiceName(std::string(256, 'a')));
+}
+
+TEST(ThrottlerApiTest, SanitizeLabelValueKeepsValidValues) {
+  EXPECT_EQ("", SanitizeLabelValue(""));
+  EXPECT_EQ("us-central1-a", SanitizeLabelValue("us-central1-a"));
+  EXPECT_EQ("1.2.3_rc", SanitizeLabelValue("1.2.3_rc"));
+}
+
+TEST(ThrottlerApiTest, SanitizeLabelValueReplacesInvalidCharacters) {
+  EXPECT_EQ("my_instance", SanitizeLabelValue("My/Instance"));
+  EXPECT_EQ("a_b_c", SanitizeLabelValue("a b:c"));
+}
+
+TEST(ThrottlerApiTest, SanitizeLabelValueTrimsTheEdges) {
+  EXPECT_EQ("foo", SanitizeLabelValue("_foo"));
+  EXPECT_EQ("foo", SanitizeLabelValue("foo-"));
+  EXPECT_EQ("foo", SanitizeLabelValue("/.foo./"));
+  EXPECT_EQ("", SanitizeLabelValue("-_."));
+}
+
+TEST(ThrottlerApiTest, SanitizeLabelValueTruncates) {
+  EXPECT_EQ(std::string(63, 'a'), SanitizeLabelValue(std::string(100, 'a')));
+  // The trailing characters left by the truncation are trimmed too.
+  EXPECT_EQ(std::string(62, 'a'),
+            SanitizeLabelValue(std::string(62, 'a') + "-bbb"));
+}
+
+TEST(ThrottlerApiTest, SanitizeServiceVersionKeepsTheCase) {
+  EXPECT_EQ("1.0-RC1", SanitizeServiceVersion("1.0-RC1"));
+  EXPECT_EQ("Build_42", SanitizeServiceVersion("/Build 42/"));
+  EXPECT_EQ("", SanitizeServiceVersion("-_."));
+}
+
+TEST(ThrottlerApiTest, ToProtoDropsTheLabelsLeftEmpty) {
+  Deployment deployment;
+  deployment.project_id = "test-project";
+  deployment.service = "test-service";
+  deployment.service_version = "1.0-RC1";
+  deployment.labels["zone"] = "us-central1-a";
+  deployment.labels["invalid"] = "-_.";
+
+  google::devtools::cloudprofiler::v2::Deployment d;
+  deployment.ToProto(&d);
+  EXPECT_EQ(2, d.labels_size());
+  EXPECT_EQ("us-central1-a", d.labels().at("zone"));
+  EXPECT_EQ("1.0-RC1", d.labels().at("version"));
+  EXPECT_EQ(0, d.labels().count("invalid"));
+}
+
+TEST(ThrottlerApiTest, CollapseServiceVersion) {
+  EXPECT_EQ("1.2", CollapseServiceVersion("1.2.3", 2));
+  EXPECT_EQ("1", CollapseServiceVersion("1.2.3", 1));
+  EXPECT_EQ("1.2.3", CollapseServiceVersion("1.2.3", 3));
+  EXPECT_EQ("1.2", CollapseServiceVersion("1.2", 3));
+  EXPECT_EQ("1.2.3", CollapseServiceVersion("1.2.3", 0));
+  EXPECT_EQ("1.2.3", CollapseServiceVersion("1.2.3", -1));
+  EXPECT_EQ("", CollapseServiceVersion("", 2));
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
@@ -0,0 +1,161 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/throttler_replay.h"
+
+#include <fstream>
+#include <sstream>
+
+#include "src/clock.h"
+#include "src/uploader_file.h"
+#include "google/protobuf/text_format.h"
+
+namespace cloud {
+namespace profiler {
+
+namespace api = google::devtools::cloudprofiler::v2;
+
+namespace {
+
+const char kRecordSeparator[] = "---";
+
+// Reads the file at path into content. Returns false on error.
+bool ReadFile(const std::string& path, std::string* content) {
+  std::ifstream in(path);
+  if (!in) {
+    return false;
+  }
+  std::stringstream ss;
+  ss << in.rdbuf();
+  *content = ss.str();
+  return !in.bad();
+}
+
+std::string ReadFixture(const std::string& fixture_path) {
+  std::string fixture;
+  if (!ReadFile(fixture_path, &fixture)) {
+    LOG(ERROR) << "Failed to read the replay fixture " << fixture_path;
+  }
+  return fixture;
+}
+
+}  // namespace
+
+ReplayThrottler::ReplayThrottler(const std::string& fixture_path,
+                                 const std::string& path)
+    : ReplayThrottler(
+          ReadFixture(fixture_path),
+          std::unique_ptr<ProfileUploader>(new FileUploader(path))) {}
+
+ReplayThrottler::ReplayThrottler(const std::string& fixture,
+                                 std::unique_ptr<ProfileUploader> uploader)
+    : next_(0), closed_(false), uploader_(std::move(uploader)) {
+  valid_ = ParseFixture(fixture);
+  if (valid_) {
+    LOG(INFO) << "Will replay " << profiles_.size()
+              << " recorded profile creations";
+  }
+}
+
+bool ReplayThrottler::ParseFixture(const std::string& fixture) {
+  std::istringstream in(fixture);
+  std::string line, record;
+  bool more = true;
+  while (more) {
+    more = static_cast<bool>(std::getline(in, line));
+    if (more && line != kRecordSeparator) {
+      record += line + "\n";
+      continue;
+    }
+    if (record.find_first_not_of(" \t\n") == std::string::npos) {
+      record.clear();
+      continue;
+    }
+    api::Profile profile;
+    if (!google::protobuf::TextFormat::ParseFromString(record, &profile)) {
+      LOG(ERROR) << "Failed to parse the replay fixture record "
+                 << profiles_.size() + 1;
+      return false;
+    }
+    profiles_.push_back(profile);
+    record.clear();
+  }
+  if (profiles_.empty()) {
+    LOG(ERROR) << "The replay fixture has no profile creations";
+    return false;
+  }
+  return true;
+}
+
+bool ReplayThrottler::WaitNext() {
+  if (!valid_ || closed_) {
+    return false;
+  }
+  if (next_ >= profiles_.size()) {
+    LOG(INFO) << "Replayed all of the recorded profile creations";
+    return false;
+  }
+  next_++;
+  LOG(INFO) << "Replaying profile creation " << next_ << " of "
+            << profiles_.size() << ": " << ProfileType();
+  return true;
+}
+
+std::string ReplayThrottler::ProfileType() {
+  if (next_ == 0) {
+    return "";
+  }
+  api::ProfileType pt = profiles_[next_ - 1].profile_type();
+  switch (pt) {
+    case api::CPU:
+      return kTypeCPU;
+    case api::WALL:
+      return kTypeWall;
+    case api::HEAP:
+      return kTypeHeap;
+    default:
+      const std::string& pt_name = api::ProfileType_Name(pt);
+      LOG(ERROR) << "Unsupported profile type " << pt_name;
+      return "unsupported-" + pt_name;
+  }
+}
+
+int64_t ReplayThrottler::DurationNanos() {
+  if (next_ == 0) {
+    return 0;
+  }
+  auto d = profiles_[next_ - 1].duration();
+  return d.seconds() * kNanosPerSecond + d.nanos();
+}
+
+bool ReplayThrottler::Upload(EncodedProfile profile) {
+  if (next_ == 0 || !uploader_) {
+    return false;
+  }
+  return uploader_->Upload(ProfileType(), profile.bytes);
+}
+
+bool ReplayThrottler::UploadOffline(EncodedProfile profile) {
+  if (!uploader_) {
+    return false;
+  }
+  return uploader_->Upload(profile.profile_type, profile.bytes);
+}
+
+void ReplayThrottler::Close() { closed_ = true; }
+
+}  // namespace profiler
+}  // namespace cloud
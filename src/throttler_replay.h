@@ -0,0 +1,81 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef CLOUD_PROFILER_AGENT_JAVA_THROTTLER_REPLAY_H_
+#define CLOUD_PROFILER_AGENT_JAVA_THROTTLER_REPLAY_H_
+
+#include <atomic>
+#include <memory>
+#include <string>
+#include <vector>
+
+#include "src/throttler.h"
+#include "src/uploader.h"
+#include "google/devtools/cloudprofiler/v2/profiler.pb.h"
+
+namespace cloud {
+namespace profiler {
+
+// Throttler implementation replaying the profile creations recorded in a
+// fixture file, to run the profiling loop hermetically, without the Cloud
+// Profiler API or a mock server. The fixture holds the CreateProfile
+// responses, as text format Profile messages separated by "---" lines, e.g.:
+//
+//   name: "projects/p/profiles/1"
+//   profile_type: CPU
+//   duration { seconds: 10 }
+//   ---
+//   name: "projects/p/profiles/2"
+//   profile_type: HEAP
+//
+// WaitNext returns each of them in turn without waiting, and false once they
+// are all replayed. The profiles sent with UpdateProfile are saved through
+// the uploader instead, to be compared with the expected ones.
+class ReplayThrottler : public Throttler {
+ public:
+  // Creates a replay throttler from the fixture at fixture_path, saving the
+  // uploaded profiles at the prefix path.
+  ReplayThrottler(const std::string& fixture_path, const std::string& path);
+
+  // Testing-only constructor.
+  ReplayThrottler(const std::string& fixture,
+                  std::unique_ptr<ProfileUploader> uploader);
+
+  bool WaitNext() override;
+  std::string ProfileType() override;
+  int64_t DurationNanos() override;
+  bool Upload(EncodedProfile profile) override;
+  bool UploadOffline(EncodedProfile profile) override;
+  void Close() override;
+
+ private:
+  // Parses the fixture content into profiles_. Returns false on error.
+  bool ParseFixture(const std::string& fixture);
+
+  std::vector<google::devtools::cloudprofiler::v2::Profile> profiles_;
+  // The index of the next profile to replay.
+  size_t next_;
+  // The fixture was read and parsed successfully.
+  bool valid_;
+  // The throttler is closing, cancel future requests.
+  std::atomic<bool> closed_;
+  std::unique_ptr<ProfileUploader> uploader_;
+};
+
+}  // namespace profiler
+}  // namespace cloud
+
+#endif  // CLOUD_PROFILER_AGENT_JAVA_THROTTLER_REPLAY_H_
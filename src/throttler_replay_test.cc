@@ -0,0 +1,138 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/throttler_replay.h"
+
+#include <fstream>
+#include <memory>
+#include <sstream>
+#include <string>
+#include <utility>
+#include <vector>
+
+#include "src/clock.h"
+#include "gtest/gtest.h"
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+const char kFixturePath[] = "src/testdata/replay_fixture.textproto";
+
+// Records the uploaded profiles.
+class RecordingUploader : public ProfileUploader {
+ public:
+  explicit RecordingUploader(
+      std::vector<std::pair<std::string, std::string>> *uploads)
+      : uploads_(uploads) {}
+
+  bool Upload(const std::string &profile_type,
+              const std::string &profile) override {
+    uploads_->emplace_back(profile_type, profile);
+    return true;
+  }
+
+ private:
+  std::vector<std::pair<std::string, std::string>> *uploads_;
+};
+
+std::string ReadFixture() {
+  std::ifstream in(kFixturePath);
+  std::stringstream ss;
+  ss << in.rdbuf();
+  return ss.str();
+}
+
+class ReplayThrottlerTest : public ::testing::Test {
+ protected:
+  std::unique_ptr<ReplayThrottler> NewThrottler(const std::string &fixture) {
+    return std::unique_ptr<ReplayThrottler>(new ReplayThrottler(
+        fixture,
+        std::unique_ptr<ProfileUploader>(new RecordingUploader(&uploads_))));
+  }
+
+  EncodedProfile Profile(const std::string &type, const std::string &bytes) {
+    EncodedProfile profile;
+    profile.profile_type = type;
+    profile.bytes = bytes;
+    return profile;
+  }
+
+  std::vector<std::pair<std::string, std::string>> uploads_;
+};
+
+TEST_F(ReplayThrottlerTest, ReplaysTheFixture) {
+  std::string fixture = ReadFixture();
+  ASSERT_FALSE(fixture.empty()) << "Failed to read " << kFixturePath;
+  std::unique_ptr<ReplayThrottler> t = NewThrottler(fixture);
+
+  ASSERT_TRUE(t->WaitNext());
+  EXPECT_EQ(kTypeCPU, t->ProfileType());
+  EXPECT_EQ(5 * kNanosPerSecond, t->DurationNanos());
+  EXPECT_TRUE(t->Upload(Profile(kTypeCPU, "cpu bytes")));
+
+  ASSERT_TRUE(t->WaitNext());
+  EXPECT_EQ(kTypeWall, t->ProfileType());
+  EXPECT_EQ(5 * kNanosPerSecond, t->DurationNanos());
+  EXPECT_TRUE(t->Upload(Profile(kTypeWall, "wall bytes")));
+
+  EXPECT_FALSE(t->WaitNext());
+
+  std::vector<std::pair<std::string, std::string>> want = {
+      {kTypeCPU, "cpu bytes"}, {kTypeWall, "wall bytes"}};
+  EXPECT_EQ(want, uploads_);
+}
+
+TEST_F(ReplayThrottlerTest, UploadsUnderTheReplayedType) {
+  std::unique_ptr<ReplayThrottler> t =
+      NewThrottler("profile_type: HEAP\n");
+  ASSERT_TRUE(t->WaitNext());
+  EXPECT_EQ(kTypeHeap, t->ProfileType());
+  EXPECT_EQ(0, t->DurationNanos());
+  EXPECT_TRUE(t->Upload(Profile(kTypeCPU, "heap bytes")));
+  ASSERT_EQ(1u, uploads_.size());
+  EXPECT_EQ(kTypeHeap, uploads_[0].first);
+}
+
+TEST_F(ReplayThrottlerTest, UploadBeforeWaitNextFails) {
+  std::unique_ptr<ReplayThrottler> t = NewThrottler(ReadFixture());
+  EXPECT_FALSE(t->Upload(Profile(kTypeCPU, "bytes")));
+  EXPECT_TRUE(uploads_.empty());
+}
+
+TEST_F(ReplayThrottlerTest, UploadOfflineKeepsTheProfileType) {
+  std::unique_ptr<ReplayThrottler> t = NewThrottler(ReadFixture());
+  EXPECT_TRUE(t->UploadOffline(Profile(kTypeWall, "bytes")));
+  ASSERT_EQ(1u, uploads_.size());
+  EXPECT_EQ(kTypeWall, uploads_[0].first);
+}
+
+TEST_F(ReplayThrottlerTest, InvalidFixtureReplaysNothing) {
+  EXPECT_FALSE(NewThrottler("")->WaitNext());
+  EXPECT_FALSE(NewThrottler("---\n---\n")->WaitNext());
+  EXPECT_FALSE(NewThrottler("profile_type: NOT_A_TYPE\n")->WaitNext());
+}
+
+TEST_F(ReplayThrottlerTest, CloseStopsTheReplay) {
+  std::unique_ptr<ReplayThrottler> t = NewThrottler(ReadFixture());
+  ASSERT_TRUE(t->WaitNext());
+  t->Close();
+  EXPECT_FALSE(t->WaitNext());
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
@@ -189,11 +189,18 @@ int64_t TimedThrottler::DurationNanos() {
   return cur_.empty() ? 0 : cur_.back().second;
 }
 
-bool TimedThrottler::Upload(std::string profile) {
+bool TimedThrottler::Upload(EncodedProfile profile) {
   if (cur_.empty() || !uploader_) {
     return false;
   }
-  return uploader_->Upload(cur_.back().first, profile);
+  return uploader_->Upload(cur_.back().first, profile.bytes);
+}
+
+bool TimedThrottler::UploadOffline(EncodedProfile profile) {
+  if (!uploader_) {
+    return false;
+  }
+  return uploader_->Upload(profile.profile_type, profile.bytes);
 }
 
 void TimedThrottler::Close() { closed_ = true; }
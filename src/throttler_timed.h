@@ -43,7 +43,8 @@ class TimedThrottler : public Throttler {
   bool WaitNext() override;
   std::string ProfileType() override;
   int64_t DurationNanos() override;
-  bool Upload(std::string profile) override;
+  bool Upload(EncodedProfile profile) override;
+  bool UploadOffline(EncodedProfile profile) override;
   void Close() override;
 
  private:
@@ -14,9 +14,24 @@
 
 #include "src/worker.h"
 
+#include <pthread.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <time.h>
+
+#include <algorithm>
+#include <string>
+#include <utility>
+#include <vector>
+
+#include "src/agent_stats.h"
 #include "src/clock.h"
+#include "src/cloud_env.h"
+#include "src/jni_env.h"
 #include "src/profiler.h"
+#include "src/proto.h"
 #include "src/throttler_api.h"
+#include "src/throttler_replay.h"
 #include "src/throttler_timed.h"
 #include "google/devtools/cloudprofiler/v2/profiler.grpc.pb.h"
 #include "third_party/javaprofiler/heap_sampler.h"
@@ -30,6 +45,78 @@ DEFINE_int32(cprof_cpu_sampling_period_msec, 10,
              "sampling period for CPU time profiling, in milliseconds");
 DEFINE_int32(cprof_wall_sampling_period_msec, 100,
              "sampling period for wall time profiling, in milliseconds");
+DEFINE_bool(cprof_autodetect_version, false,
+            "when true and the service version is not otherwise specified, "
+            "use the Implementation-Version of the main jar manifest");
+DEFINE_bool(cprof_infer_service, false,
+            "when true and the service name is not otherwise specified, "
+            "derive it from the main class or jar of the application");
+DEFINE_int32(cprof_metrics_port, 0,
+             "when set, serve the agent metrics in the Prometheus text format "
+             "on the specified port");
+DEFINE_string(cprof_metrics_bind_address, "127.0.0.1",
+              "IPv4 address the metrics server listens on, e.g. 0.0.0.0 to "
+              "serve the metrics to other hosts. The default only serves the "
+              "local host");
+DEFINE_int32(cprof_warmup_delay_sec, 0,
+             "time to wait after the VM initialization before requesting the "
+             "first profile, in seconds");
+DEFINE_bool(cprof_skip_first_profile, false,
+            "when true, discard the first collected profile instead of "
+            "uploading it, as it often captures the JVM warmup");
+DEFINE_bool(cprof_flush_on_exit, false,
+            "when true, for short-lived processes, collect a single CPU "
+            "profile over the whole process lifetime and upload it when the "
+            "VM exits, instead of the periodic profiles");
+DEFINE_int32(cprof_max_profile_cycles, 0,
+             "when set, stop profiling after this number of profile "
+             "collections, leaving the application running. The cycles "
+             "skipped while the profiling is disabled do not count");
+DEFINE_int32(cprof_local_ref_capacity, 100,
+             "number of JNI local references reserved for each profile "
+             "collection and encoding; raise it when the JVM warns about "
+             "the local reference capacity being exceeded");
+DEFINE_bool(cprof_stop_on_permanent_upload_failure, true,
+            "when true, stop profiling after an upload error that retrying "
+            "will not fix, e.g. missing permissions; when false, keep "
+            "collecting profiles");
+DEFINE_int32(cprof_heartbeat_sec, 0,
+             "when set, log a summary of the agent state at this interval, "
+             "in seconds, to show the agent is alive through the quiet "
+             "periods");
+DEFINE_int32(cprof_align_windows_sec, 0,
+             "when set, delay the start of each profile collection to the "
+             "next multiple of this many seconds of the wall clock, e.g. 60 "
+             "for on the minute, so that the profiles of a fleet cover "
+             "comparable time ranges; at most 60, for the uploads to meet "
+             "the backend deadline");
+DEFINE_string(cprof_replay_fixture, "",
+              "when set, replay the profile creations recorded in this "
+              "fixture file instead of using the Cloud Profiler API, saving "
+              "the profiles at the -cprof_profile_filename prefix, for "
+              "testing");
+DEFINE_bool(cprof_dump_config, false,
+            "when true, print the effective configuration to stderr as JSON "
+            "once the deployment is resolved, with the secrets redacted, e.g. "
+            "to include it in a bug report");
+DEFINE_int32(cprof_cpu_duration_sec, 0,
+             "when set, collect the CPU profiles for this many seconds "
+             "instead of the duration asked by the backend, within 1 to 60 "
+             "seconds");
+DEFINE_int32(cprof_wall_duration_sec, 0,
+             "when set, collect the wall profiles for this many seconds "
+             "instead of the duration asked by the backend, within 1 to 60 "
+             "seconds");
+DEFINE_int32(cprof_heap_duration_sec, 0,
+             "when set, wait this many seconds, within 1 to 60, before taking "
+             "the heap profiles, and report it as their duration, instead of "
+             "the duration asked by the backend");
+DEFINE_string(cprof_status_file, "",
+              "when set, path of a file the agent keeps updated with its "
+              "state, initializing, profiling, error or stopped, and the time "
+              "of the last successful upload, for the process supervisors");
+DEFINE_bool(cprof_upload_empty_profiles, true,
+            "when unset, skip the upload of the profiles without any samples");
 
 namespace cloud {
 namespace profiler {
@@ -37,100 +124,433 @@ namespace {
 
 namespace api = google::devtools::cloudprofiler::v2;
 
-std::string JavaVersion(JNIEnv *jni) {
-  const std::string kUnknownVersion = "unknown_version";
+// Name of the worker thread, both in Java and for the OS. The OS thread names
+// are limited to 15 characters.
+const char kWorkerThreadName[] = "cprof-worker";
+
+// Returns the string quoted as a JSON string.
+std::string JsonQuote(const std::string &s) {
+  std::string quoted = "\"";
+  for (unsigned char c : s) {
+    if (c == '"' || c == '\\') {
+      quoted += '\\';
+      quoted += c;
+    } else if (c < 0x20) {
+      char escaped[7];
+      snprintf(escaped, sizeof(escaped), "\\u%04x", c);
+      quoted += escaped;
+    } else {
+      quoted += c;
+    }
+  }
+  return quoted + "\"";
+}
 
-  jclass system_class = jni->FindClass("java/lang/System");
-  if (system_class == nullptr) {
-    return kUnknownVersion;
+// Returns true if the flag holds a secret, whose value is not to be shown.
+bool IsSecretFlag(const std::string &name) {
+  for (const char *word : {"token", "secret", "password", "credential"}) {
+    if (name.find(word) != std::string::npos) {
+      return true;
+    }
   }
-  jmethodID get_property_method = jni->GetStaticMethodID(
-      system_class, "getProperty", "(Ljava/lang/String;)Ljava/lang/String;");
-  if (get_property_method == nullptr) {
-    return kUnknownVersion;
+  return false;
+}
+
+// Returns the effective configuration as name and value pairs: the agent
+// flags, with their defaults, followed by the deployment as resolved from the
+// flags, the environment and the metadata server. The secrets are redacted,
+// and the credentials file is only reported as set or not.
+std::vector<std::pair<std::string, std::string>> EffectiveConfig(
+    CloudEnv *env) {
+  std::vector<std::pair<std::string, std::string>> config;
+  std::vector<gflags::CommandLineFlagInfo> flags;
+  gflags::GetAllFlags(&flags);
+  for (const auto &flag : flags) {
+    if (flag.name.compare(0, 6, "cprof_") != 0) {
+      continue;
+    }
+    bool redact = IsSecretFlag(flag.name) && !flag.current_value.empty();
+    config.emplace_back("flags." + flag.name,
+                        redact ? "<redacted>" : flag.current_value);
   }
-  jstring jstr = reinterpret_cast<jstring>(jni->CallStaticObjectMethod(
-      system_class, get_property_method, jni->NewStringUTF("java.version")));
-  if (jstr == nullptr) {
-    return kUnknownVersion;
+  config.emplace_back("deployment.project_id", env->ProjectID());
+  config.emplace_back("deployment.service", env->Service());
+  config.emplace_back("deployment.service_version", env->ServiceVersion());
+  config.emplace_back("deployment.zone", env->ZoneName());
+  config.emplace_back("deployment.instance", env->InstanceName());
+  const char *credentials = getenv("GOOGLE_APPLICATION_CREDENTIALS");
+  config.emplace_back("credentials_file", credentials != nullptr
+                                              ? "<redacted, set>"
+                                              : "<not set>");
+  return config;
+}
+
+// Returns the configuration as a JSON object, one field per line.
+std::string ConfigJson(
+    const std::vector<std::pair<std::string, std::string>> &config) {
+  std::string json = "{";
+  for (size_t i = 0; i < config.size(); i++) {
+    json += i == 0 ? "\n  " : ",\n  ";
+    json += JsonQuote(config[i].first) + ": " + JsonQuote(config[i].second);
   }
-  // Copy the returned value and release the memory allocated by JNI.
-  const char *s = jni->GetStringUTFChars(jstr, nullptr);
-  std::string ret = std::string(s);
-  jni->ReleaseStringUTFChars(jstr, s);
-  return ret;
+  return json + "\n}\n";
+}
+
+std::string JavaVersion(JNIEnvWrapper *jni) {
+  std::string version = jni->SystemProperty("java.version");
+  return version.empty() ? "unknown_version" : version;
+}
+
+// Returns the Implementation-Version attribute of the manifest of the main
+// jar when the application was started with 'java -jar', or an empty string.
+std::string ManifestVersion(JNIEnvWrapper *jni) {
+  std::string command = jni->SystemProperty("sun.java.command");
+  std::string jar = command.substr(0, command.find(' '));
+  const std::string kJarSuffix = ".jar";
+  if (jar.size() <= kJarSuffix.size() ||
+      jar.compare(jar.size() - kJarSuffix.size(), kJarSuffix.size(),
+                  kJarSuffix) != 0) {
+    return "";
+  }
+
+  std::string version;
+  jclass jar_file_class = jni->FindClass("java/util/jar/JarFile");
+  jclass manifest_class = jni->FindClass("java/util/jar/Manifest");
+  jclass attributes_class = jni->FindClass("java/util/jar/Attributes");
+  if (jar_file_class == nullptr || manifest_class == nullptr ||
+      attributes_class == nullptr) {
+    return "";
+  }
+  jmethodID constructor =
+      jni->GetMethodID(jar_file_class, "<init>", "(Ljava/lang/String;)V");
+  jmethodID get_manifest = jni->GetMethodID(jar_file_class, "getManifest",
+                                            "()Ljava/util/jar/Manifest;");
+  jmethodID close = jni->GetMethodID(jar_file_class, "close", "()V");
+  jmethodID get_main_attributes = jni->GetMethodID(
+      manifest_class, "getMainAttributes", "()Ljava/util/jar/Attributes;");
+  jmethodID get_value = jni->GetMethodID(
+      attributes_class, "getValue", "(Ljava/lang/String;)Ljava/lang/String;");
+  if (constructor == nullptr || get_manifest == nullptr || close == nullptr ||
+      get_main_attributes == nullptr || get_value == nullptr) {
+    return "";
+  }
+
+  jobject jar_file = jni->NewObject(jar_file_class, constructor,
+                                    jni->NewStringUTF(jar.c_str()));
+  if (jar_file == nullptr) {
+    LOG(WARNING) << "Failed to open " << jar << " to read its manifest";
+    return "";
+  }
+  jobject manifest = jni->CallObjectMethod(jar_file, get_manifest);
+  if (manifest != nullptr) {
+    jobject attributes = jni->CallObjectMethod(manifest, get_main_attributes);
+    if (attributes != nullptr) {
+      version = jni->GetStringUTF(
+          reinterpret_cast<jstring>(jni->CallObjectMethod(
+              attributes, get_value,
+              jni->NewStringUTF("Implementation-Version"))));
+    }
+  }
+  jni->CallVoidMethod(jar_file, close);
+  return version;
+}
+
+// Returns a service name derived from the command line of the JVM, empty if
+// no valid name results.
+std::string InferredService(JNIEnvWrapper *jni) {
+  return ServiceFromCommand(jni->SystemProperty("sun.java.command"));
 }
 
 }  // namespace
 
+std::string ServiceFromCommand(const std::string &command) {
+  std::string main = command.substr(0, command.find(' '));
+  const std::string kJarSuffix = ".jar";
+  if (main.size() > kJarSuffix.size() &&
+      main.compare(main.size() - kJarSuffix.size(), kJarSuffix.size(),
+                   kJarSuffix) == 0) {
+    main = main.substr(0, main.size() - kJarSuffix.size());
+    main = main.substr(main.find_last_of('/') + 1);
+  } else {
+    main = main.substr(main.find_last_of('.') + 1);
+  }
+
+  std::string service;
+  for (char c : main) {
+    if (c >= 'A' && c <= 'Z') {
+      service += c - 'A' + 'a';
+    } else if ((c >= 'a' && c <= 'z') || (c >= '0' && c <= '9') || c == '-' ||
+               c == '_' || c == '.') {
+      service += c;
+    } else {
+      service += '-';
+    }
+  }
+  return IsValidServiceName(service) ? service : "";
+}
+
+int64_t NanosToAlignedBoundary(int64_t now_ns, int64_t period_ns) {
+  // Close enough past a boundary is aligned, rather than waiting for the
+  // next one over a scheduling delay.
+  const int64_t kToleranceNanos = 100 * kNanosPerMilli;
+  int64_t past_ns = now_ns % period_ns;
+  if (past_ns < 0 || past_ns <= kToleranceNanos) {
+    return 0;
+  }
+  return period_ns - past_ns;
+}
+
 std::atomic<bool> Worker::enabled_;
 
-void Worker::Start(JNIEnv *jni) {
-  jclass cls = jni->FindClass("java/lang/Thread");
-  jmethodID constructor = jni->GetMethodID(cls, "<init>", "()V");
-  jobject thread = jni->NewGlobalRef(jni->NewObject(cls, constructor));
+void Worker::Start(JNIEnv *jni_env) {
+  if (!FLAGS_cprof_status_file.empty()) {
+    status_file_.reset(new StatusFile(FLAGS_cprof_status_file));
+    SetStatus("initializing");
+  }
+  JNIEnvWrapper jni(jni_env);
+  jclass cls = jni.FindClass("java/lang/Thread");
+  jmethodID constructor =
+      cls == nullptr
+          ? nullptr
+          : jni.GetMethodID(cls, "<init>", "(Ljava/lang/String;)V");
+  jobject local_thread =
+      constructor == nullptr
+          ? nullptr
+          : jni.NewObject(cls, constructor,
+                           jni.NewStringUTF(kWorkerThreadName));
+  jobject thread =
+      local_thread == nullptr ? nullptr : jni_env->NewGlobalRef(local_thread);
   if (thread == nullptr) {
     LOG(ERROR) << "Failed to construct cloud profiler worker thread";
+    SetStatus("error");
     return;
   }
 
-  std::string java_version = JavaVersion(jni);
+  std::string java_version = JavaVersion(&jni);
   LOG(INFO) << "Java version: " << java_version;
-  std::vector<google::devtools::cloudprofiler::v2::ProfileType> types = {
-      api::CPU, api::WALL};
+  if (FLAGS_cprof_autodetect_version &&
+      DefaultCloudEnv()->ServiceVersion().empty()) {
+    std::string service_version = ManifestVersion(&jni);
+    if (!service_version.empty()) {
+      LOG(INFO) << "Using service version '" << service_version
+                << "' from the main jar manifest";
+      DefaultCloudEnv()->SetDefaultServiceVersion(service_version);
+    }
+  }
+  std::vector<google::devtools::cloudprofiler::v2::ProfileType> types;
+  if (google::javaprofiler::Asgct::GetAsgct() != nullptr) {
+    types.push_back(api::CPU);
+    types.push_back(api::WALL);
+  }
   if (google::javaprofiler::HeapMonitor::Enabled()) {
     LOG(INFO) << "Heap allocation sampling supported for this JDK";
     types.push_back(api::HEAP);
   }
+  if (types.empty()) {
+    LOG(ERROR) << "No profile type is supported for this JVM, the profiling "
+               << "is disabled";
+    SetStatus("error");
+    return;
+  }
+
+  if (FLAGS_cprof_infer_service && DefaultCloudEnv()->Service().empty()) {
+    std::string service = InferredService(&jni);
+    if (!service.empty()) {
+      LOG(INFO) << "Using service name '" << service << "' inferred from the "
+                << "main class, set '-cprof_service' to override it";
+      DefaultCloudEnv()->SetDefaultService(service);
+    } else {
+      LOG(WARNING) << "Failed to infer the service name from the main class";
+    }
+  }
+
+  if (FLAGS_cprof_profile_filename.empty() &&
+      FLAGS_cprof_replay_fixture.empty() &&
+      DefaultCloudEnv()->Service().empty()) {
+    // Whether the agent options are missing or empty, the profiles cannot be
+    // created without a service name.
+    LOG(ERROR) << "A service name is required: set it with the "
+               << "'-cprof_service' agent option, e.g. "
+               << "-agentpath:.../profiler_java_agent.so=-cprof_service=myapp, "
+               << "or the GAE_SERVICE or K_SERVICE environment variable. "
+               << "Profiling is disabled";
+    SetStatus("error");
+    return;
+  }
 
   // Initialize the throttler here rather in the constructor, since the
   // constructor is invoked too early, before the heap profiler is initialized.
-  throttler_ = FLAGS_cprof_profile_filename.empty()
-                   ? std::unique_ptr<Throttler>(
-                         new APIThrottler(types, "java", java_version))
-                   : std::unique_ptr<Throttler>(
-                         new TimedThrottler(FLAGS_cprof_profile_filename));
+  if (!FLAGS_cprof_replay_fixture.empty()) {
+    throttler_.reset(new ReplayThrottler(FLAGS_cprof_replay_fixture,
+                                         FLAGS_cprof_profile_filename));
+  } else if (FLAGS_cprof_profile_filename.empty()) {
+    throttler_.reset(new APIThrottler(types, "java", java_version));
+  } else {
+    throttler_.reset(new TimedThrottler(FLAGS_cprof_profile_filename));
+  }
+
+  if (FLAGS_cprof_metrics_port > 0) {
+    metrics_server_.reset(new MetricsServer(AgentStats::Get()));
+    if (!metrics_server_->Start(FLAGS_cprof_metrics_bind_address,
+                                FLAGS_cprof_metrics_port)) {
+      metrics_server_.reset();
+    }
+  }
+
+  if (FLAGS_cprof_heartbeat_sec > 0) {
+    heartbeat_.reset(new Heartbeat(AgentStats::Get(), DefaultClock()));
+    heartbeat_->Start(FLAGS_cprof_heartbeat_sec);
+  }
 
   // Pass 'this' as the arg to access members from the worker thread.
   jvmtiError err = jvmti_->RunAgentThread(thread, ProfileThread, this,
                                           JVMTI_THREAD_MIN_PRIORITY);
   if (err) {
     LOG(ERROR) << "Failed to start cloud profiler worker thread";
+    SetStatus("error");
     return;
   }
+  // Stop() relies on the worker thread holding mutex_, which a VMDeath
+  // right after the start of a short-lived JVM could otherwise precede.
+  std::unique_lock<std::mutex> running_lock(running_mutex_);
+  running_cv_.wait(running_lock, [this] { return running_; });
 
   enabled_ = FLAGS_cprof_enabled;
 }
 
+void Worker::SetStatus(const char *state) {
+  if (status_file_) {
+    status_file_->SetState(state);
+  }
+}
+
 void Worker::Stop() {
   stopping_.store(true, std::memory_order_release);
+  if (!throttler_) {
+    // The worker was not started.
+    return;
+  }
+  if (FLAGS_cprof_flush_on_exit) {
+    // Setting stopping_ ended the collection. Let the worker thread upload
+    // the final window before closing the throttler, which would cancel the
+    // upload: the worker thread holds the mutex until it is done.
+    std::lock_guard<std::mutex> lock(mutex_);
+    throttler_->Close();
+    if (metrics_server_) {
+      metrics_server_->Stop();
+    }
+    if (heartbeat_) {
+      heartbeat_->Stop();
+    }
+    return;
+  }
   // Close the throttler which will initiate cancellation of WaitNext / Upload.
   throttler_->Close();
   // Wait till the worker thread is done.
   std::lock_guard<std::mutex> lock(mutex_);
+  if (metrics_server_) {
+    metrics_server_->Stop();
+  }
+  if (heartbeat_) {
+    heartbeat_->Stop();
+  }
 }
 
 namespace {
 
-std::string Collect(Profiler *p, JNIEnv *env,
-                    google::javaprofiler::NativeProcessInfo *native_info) {
-  const char *profile_type = p->ProfileType();
+// Encodes the profile collected by the profiler.
+EncodedProfile Encode(Profiler *p, JNIEnv *env,
+                      google::javaprofiler::NativeProcessInfo *native_info) {
+  EncodedProfile profile;
+  profile.profile_type = p->ProfileType();
+  native_info->Refresh();
+  profile.bytes = p->SerializeProfile(env, *native_info);
+  profile.sample_count = p->SampleCount();
+  profile.stack_count = p->StackCount();
+  profile.duration_nanos = p->DurationNanos();
+  return profile;
+}
+
+// Collects and encodes a profile. The bytes are empty on failure.
+EncodedProfile Collect(Profiler *p, JNIEnv *env,
+                       google::javaprofiler::NativeProcessInfo *native_info) {
   if (!p->Collect()) {
-    LOG(ERROR) << "Failure: Could not collect " << profile_type << " profile";
-    return "";
+    LOG(ERROR) << "Failure: Could not collect " << p->ProfileType()
+               << " profile";
+    EncodedProfile profile;
+    profile.profile_type = p->ProfileType();
+    return profile;
   }
-  native_info->Refresh();
-  return p->SerializeProfile(env, *native_info);
+  return Encode(p, env, native_info);
+}
+
+// Bounds of the -cprof_*_duration_sec overrides, the backend expects the
+// profile of a creation to be uploaded within a couple of minutes.
+const int kMinProfileDurationSec = 1;
+const int kMaxProfileDurationSec = 60;
+
+// Bound of -cprof_align_windows_sec. The wait for the boundary, shorter than
+// the period, and the collection, of at most kMaxProfileDurationSec, must
+// together fit in the couple of minutes the backend allows.
+const int kMaxAlignWindowsSec = 60;
+
+// Returns the -cprof_align_windows_sec period, bounded, zero when not set.
+int64_t AlignPeriodNanos() {
+  return std::min(FLAGS_cprof_align_windows_sec, kMaxAlignWindowsSec) *
+         kNanosPerSecond;
+}
+
+// Returns the duration to collect the profile of the given type for: the
+// -cprof_*_duration_sec flag of the type when set, bounded, otherwise the
+// duration asked by the throttler.
+int64_t ProfileDurationNanos(const std::string &profile_type,
+                             int64_t requested_ns) {
+  int duration_sec = 0;
+  if (profile_type == kTypeCPU) {
+    duration_sec = FLAGS_cprof_cpu_duration_sec;
+  } else if (profile_type == kTypeWall) {
+    duration_sec = FLAGS_cprof_wall_duration_sec;
+  } else if (profile_type == kTypeHeap) {
+    duration_sec = FLAGS_cprof_heap_duration_sec;
+  }
+  if (duration_sec <= 0) {
+    return requested_ns;
+  }
+  duration_sec = std::max(kMinProfileDurationSec,
+                          std::min(duration_sec, kMaxProfileDurationSec));
+  return duration_sec * kNanosPerSecond;
+}
+
+// Returns true, logging it, once the -cprof_max_profile_cycles cap is reached.
+bool CycleCapReached(int cycles) {
+  if (FLAGS_cprof_max_profile_cycles <= 0 ||
+      cycles < FLAGS_cprof_max_profile_cycles) {
+    return false;
+  }
+  LOG(INFO) << "Reached the maximum of " << FLAGS_cprof_max_profile_cycles
+            << " profile cycles, stopping profiling";
+  return true;
 }
 
 class JNILocalFrame {
  public:
   explicit JNILocalFrame(JNIEnv *jni_env) : jni_env_(jni_env) {
-    // Put 100, it doesn't really matter: new spec implementations don't care.
-    jni_env_->PushLocalFrame(100);
+    // The symbolization deletes most of its local references as it goes, the
+    // capacity bounds the ones held at once. -Xcheck:jni warns beyond it.
+    pushed_ = jni_env_->PushLocalFrame(FLAGS_cprof_local_ref_capacity) == 0;
+    if (!pushed_) {
+      // Fails with a pending OutOfMemoryError.
+      jni_env_->ExceptionClear();
+      LOG(WARNING) << "Failed to reserve " << FLAGS_cprof_local_ref_capacity
+                   << " JNI local references, see -cprof_local_ref_capacity";
+    }
   }
 
-  ~JNILocalFrame() { jni_env_->PopLocalFrame(nullptr); }
+  ~JNILocalFrame() {
+    if (pushed_) {
+      jni_env_->PopLocalFrame(nullptr);
+    }
+  }
 
   // Not copyable or movable.
   JNILocalFrame(const JNILocalFrame &) = delete;
@@ -138,6 +558,7 @@ class JNILocalFrame {
 
  private:
   JNIEnv *jni_env_;
+  bool pushed_;
 };
 
 }  // namespace
@@ -149,10 +570,77 @@ void Worker::DisableProfiling() { enabled_ = false; }
 void Worker::ProfileThread(jvmtiEnv *jvmti_env, JNIEnv *jni_env, void *arg) {
   Worker *w = static_cast<Worker *>(arg);
   std::lock_guard<std::mutex> lock(w->mutex_);
+  {
+    std::lock_guard<std::mutex> running_lock(w->running_mutex_);
+    w->running_ = true;
+  }
+  w->running_cv_.notify_all();
+  pthread_setname_np(pthread_self(), kWorkerThreadName);
+  w->SetStatus("profiling");
 
   google::javaprofiler::NativeProcessInfo n("/proc/self/maps");
 
-  while (w->throttler_->WaitNext()) {
+  if (FLAGS_cprof_dump_config) {
+    // Dumped from the worker thread, the metadata server queries would delay
+    // the JVM startup otherwise.
+    std::string json = ConfigJson(EffectiveConfig(DefaultCloudEnv()));
+    fprintf(stderr, "Cloud Profiler agent effective configuration: %s",
+            json.c_str());
+    LOG(INFO) << "Effective configuration: " << json;
+  }
+
+  if (FLAGS_cprof_flush_on_exit) {
+    if (google::javaprofiler::Asgct::GetAsgct() == nullptr) {
+      LOG(WARNING) << "AsyncGetCallTrace is not available, nothing to flush "
+                   << "on exit";
+      return;
+    }
+    CPUProfiler p(w->jvmti_, w->threads_, 0,
+                  FLAGS_cprof_cpu_sampling_period_msec * kNanosPerMilli);
+    if (!p.CollectUntil(w->stopping_)) {
+      LOG(ERROR) << "Failure: Could not collect " << p.ProfileType()
+                 << " profile";
+      return;
+    }
+    EncodedProfile profile = Encode(&p, jni_env, &n);
+    AgentStats *stats = AgentStats::Get();
+    stats->RecordCollection(!profile.bytes.empty());
+    int64_t profile_bytes = profile.bytes.size();
+    bool uploaded = w->throttler_->UploadOffline(std::move(profile));
+    stats->RecordUpload(uploaded, profile_bytes);
+    if (!uploaded) {
+      LOG(ERROR) << "Error on the profile upload at exit";
+    } else if (w->status_file_) {
+      w->status_file_->RecordUpload();
+    }
+    w->SetStatus("stopped");
+    return;
+  }
+
+  AgentStats *stats = AgentStats::Get();
+  int64_t now_ns = TimeSpecToNanos(DefaultClock()->Now());
+
+  // Let the JVM warm up before the first profile is requested. Sleep in short
+  // steps to not hold up the shutdown.
+  if (FLAGS_cprof_warmup_delay_sec > 0) {
+    stats->SetPhase("warmup",
+                    now_ns + FLAGS_cprof_warmup_delay_sec * kNanosPerSecond);
+  }
+  for (int i = 0; i < FLAGS_cprof_warmup_delay_sec && !w->stopping_; i++) {
+    DefaultClock()->SleepFor(NanosToTimeSpec(kNanosPerSecond));
+  }
+
+  if (FLAGS_cprof_align_windows_sec > kMaxAlignWindowsSec) {
+    LOG(WARNING) << "-cprof_align_windows_sec=" << FLAGS_cprof_align_windows_sec
+                 << " would delay the uploads past the backend deadline, "
+                 << "aligning on " << kMaxAlignWindowsSec << "s instead";
+  }
+
+  bool first_profile = true;
+  bool stopped_on_failure = false;
+  int cycles = 0;
+  stats->SetPhase("waiting for the next profile");
+  while (!CycleCapReached(cycles) && w->throttler_->WaitNext()) {
     if (w->stopping_) {
       // The worker is exiting.
       break;
@@ -161,6 +649,29 @@ void Worker::ProfileThread(jvmtiEnv *jvmti_env, JNIEnv *jni_env, void *arg) {
       // Skip the collection and upload steps when profiling is disabled.
       continue;
     }
+    std::string pt = w->throttler_->ProfileType();
+    int64_t duration_ns =
+        ProfileDurationNanos(pt, w->throttler_->DurationNanos());
+    if (FLAGS_cprof_align_windows_sec > 0) {
+      // The wait is taken on the monotonic clock, so that a wall clock step
+      // during the wait, e.g. an NTP correction of the skew, neither shortens
+      // nor prolongs it beyond the period.
+      int64_t wait_ns =
+          NanosToAlignedBoundary(WallClockNanos(), AlignPeriodNanos());
+      stats->SetPhase("aligning", TimeSpecToNanos(DefaultClock()->Now()) +
+                                      wait_ns + duration_ns);
+      // Sleep in short steps to not hold up the shutdown.
+      const int64_t kStepNanos = kNanosPerSecond;
+      for (; wait_ns > 0 && !w->stopping_; wait_ns -= kStepNanos) {
+        DefaultClock()->SleepFor(
+            NanosToTimeSpec(std::min(wait_ns, kStepNanos)));
+      }
+      if (w->stopping_) {
+        break;
+      }
+    }
+    stats->SetPhase("collecting",
+                    TimeSpecToNanos(DefaultClock()->Now()) + duration_ns);
 
     // There are a number of JVMTI functions the agent uses that return
     // local references. Normally, local references are freed when a JNI
@@ -174,16 +685,21 @@ void Worker::ProfileThread(jvmtiEnv *jvmti_env, JNIEnv *jni_env, void *arg) {
     // so that, if ever JNI handle leaks do happen again, this will release the
     // handles automatically.
     JNILocalFrame local_frame(jni_env);
-    std::string profile;
-    std::string pt = w->throttler_->ProfileType();
+    EncodedProfile profile;
+    if ((pt == kTypeCPU || pt == kTypeWall) &&
+        google::javaprofiler::Asgct::GetAsgct() == nullptr) {
+      LOG(WARNING) << "Asked for a " << pt << " profile but "
+                   << "AsyncGetCallTrace is not available";
+      continue;
+    }
     if (pt == kTypeCPU) {
-      CPUProfiler p(w->jvmti_, w->threads_, w->throttler_->DurationNanos(),
+      CPUProfiler p(w->jvmti_, w->threads_, duration_ns,
                     FLAGS_cprof_cpu_sampling_period_msec * kNanosPerMilli);
       profile = Collect(&p, jni_env, &n);
     } else if (pt == kTypeWall) {
       // Note that the requested sampling period for the wall profiling may be
       // increased if the number of live threads is too large.
-      WallProfiler p(w->jvmti_, w->threads_, w->throttler_->DurationNanos(),
+      WallProfiler p(w->jvmti_, jni_env, w->threads_, duration_ns,
                      FLAGS_cprof_wall_sampling_period_msec * kNanosPerMilli);
       profile = Collect(&p, jni_env, &n);
     } else if (pt == kTypeHeap) {
@@ -191,6 +707,18 @@ void Worker::ProfileThread(jvmtiEnv *jvmti_env, JNIEnv *jni_env, void *arg) {
         LOG(WARNING) << "Asked for a heap sampler but it is disabled";
         continue;
       }
+      if (FLAGS_cprof_heap_duration_sec > 0) {
+        // Sleep in short steps to not hold up the shutdown.
+        const int64_t kStepNanos = kNanosPerSecond;
+        for (int64_t wait_ns = duration_ns; wait_ns > 0 && !w->stopping_;
+             wait_ns -= kStepNanos) {
+          DefaultClock()->SleepFor(
+              NanosToTimeSpec(std::min(wait_ns, kStepNanos)));
+        }
+        if (w->stopping_) {
+          break;
+        }
+      }
 
       // Note: we do not force GC here, instead we rely on what was seen as
       // still live at the last GC; this means that technically:
@@ -198,22 +726,81 @@ void Worker::ProfileThread(jvmtiEnv *jvmti_env, JNIEnv *jni_env, void *arg) {
       //   - Some other objects might be sampled but not show up yet.
       // On the flip side, this allows the profile collection to not provoke a
       // GC.
-      perftools::profiles::Builder::Marshal(
-          *google::javaprofiler::HeapMonitor::GetHeapProfiles(
-              jni_env, false /* force_gc */),
-          &profile);
+      std::unique_ptr<perftools::profiles::Profile> heap_profile =
+          google::javaprofiler::HeapMonitor::GetHeapProfiles(
+              jni_env, false /* force_gc */);
+      // The heap profile is a snapshot, taken now, of the duration requested.
+      heap_profile->set_time_nanos(WallClockNanos());
+      heap_profile->set_duration_nanos(duration_ns);
+      profile.profile_type = pt;
+      profile.sample_count = heap_profile->sample_size();
+      profile.stack_count = heap_profile->sample_size();
+      profile.duration_nanos = duration_ns;
+      if (ValidateProfile(*heap_profile)) {
+        perftools::profiles::Builder::Marshal(*heap_profile, &profile.bytes);
+      }
     } else {
       LOG(ERROR) << "Unknown profile type '" << pt << "', skipping the upload";
       continue;
     }
-    if (profile.empty()) {
+    cycles++;
+    stats->SetPhase("waiting for the next profile");
+    stats->RecordCollection(!profile.bytes.empty());
+    stats->RecordSamples(profile.sample_count);
+    if (profile.bytes.empty()) {
       LOG(ERROR) << "No profile bytes collected, skipping the upload";
       continue;
     }
-    if (!w->throttler_->Upload(profile)) {
+    if (profile.sample_count == 0 && !FLAGS_cprof_upload_empty_profiles) {
+      LOG(INFO) << "No samples collected, skipping the upload";
+      continue;
+    }
+    if (first_profile) {
+      first_profile = false;
+      if (FLAGS_cprof_skip_first_profile) {
+        LOG(INFO) << "Discarding the first collected profile";
+        continue;
+      }
+    }
+    int64_t sample_count = profile.sample_count;
+    int64_t stack_count = profile.stack_count;
+    int64_t profile_bytes = profile.bytes.size();
+    // The upload is synchronous: the next profile is only requested, and so
+    // collected, once it returns. At most one upload is thus ever in flight
+    // and a slow upload delays the next collection window rather than
+    // overlapping it.
+    stats->SetPhase("uploading");
+    bool uploaded = w->throttler_->Upload(std::move(profile));
+    stats->SetPhase("waiting for the next profile");
+    stats->RecordUpload(uploaded, profile_bytes);
+    if (!uploaded) {
       LOG(ERROR) << "Error on profile upload, discarding the profile";
+      if (FLAGS_cprof_stop_on_permanent_upload_failure &&
+          w->throttler_->PermanentUploadFailure()) {
+        stopped_on_failure = true;
+        break;
+      }
+    } else {
+      if (w->status_file_) {
+        w->status_file_->RecordUpload();
+      }
+      LOG(INFO) << "Uploaded '" << pt << "' profile: " << sample_count
+                << " samples, " << stack_count << " unique stacks, "
+                << profile_bytes << " bytes, collected over "
+                << duration_ns / kNanosPerMilli << "ms";
     }
   }
+  if (stopped_on_failure) {
+    LOG(ERROR) << "STOPPING PROFILING: the profile upload failed with an "
+               << "error that will not go away on retry, fix the error "
+               << "above and restart the application to resume profiling, "
+               << "or set -cprof_stop_on_permanent_upload_failure=false to "
+               << "keep collecting profiles";
+  }
+  // Nothing will be collected anymore, so release the heap samples too.
+  google::javaprofiler::HeapMonitor::Disable();
+  w->SetStatus(stopped_on_failure ? "error" : "stopped");
+  stats->SetPhase("stopped");
   LOG(INFO) << "Exiting the profiling loop";
 }
 
@@ -18,21 +18,50 @@
 #define CLOUD_PROFILER_AGENT_JAVA_WORKER_H_
 
 #include <atomic>
+#include <condition_variable>  // NOLINT
 #include <mutex>  // NOLINT
+#include <string>
 
 #include "src/globals.h"
+#include "src/heartbeat.h"
+#include "src/metrics_server.h"
+#include "src/status_file.h"
 #include "src/threads.h"
 #include "src/throttler.h"
 
 namespace cloud {
 namespace profiler {
 
+// Returns a service name derived from the first token of the given
+// sun.java.command, the main class or the main jar, e.g. "myapp" for
+// "com.example.MyApp" or "/srv/my-app.jar". Returns an empty string if no
+// valid name results.
+std::string ServiceFromCommand(const std::string &command);
+
+// Returns the time to wait from now_ns, in nanoseconds of the wall clock, for
+// the next boundary of the period, in [0, period). Just past a boundary is
+// aligned already. Public for testing.
+int64_t NanosToAlignedBoundary(int64_t now_ns, int64_t period_ns);
+
 class Worker {
  public:
   Worker(jvmtiEnv *jvmti, ThreadTable *threads)
-      : jvmti_(jvmti), threads_(threads), stopping_() {}
+      : jvmti_(jvmti), threads_(threads), running_(false), stopping_() {}
 
+  // Starts the worker, returning once the worker thread runs, so that Stop()
+  // called right after waits for it.
   void Start(JNIEnv *jni);
+  // Stops the worker, returning once the worker thread is done. With
+  // -cprof_flush_on_exit, the shutdown runs in this order, so that the final
+  // window is uploaded before anything it depends on goes away:
+  //   1. the sampling stops, and the final window is swapped out of the
+  //      profiler and serialized, on the worker thread;
+  //   2. the worker thread uploads it, bounded by the upload deadline;
+  //   3. the throttler is closed, once the worker thread is done;
+  //   4. the metrics server stops.
+  // Otherwise the throttler is closed first, cancelling the pending profile
+  // creation or upload, since a partial window is not worth delaying the
+  // shutdown for.
   void Stop();
 
   static void EnableProfiling();
@@ -40,11 +69,20 @@ class Worker {
 
  private:
   static void ProfileThread(jvmtiEnv *jvmti_env, JNIEnv *jni_env, void *arg);
+  // Sets the state reported in the -cprof_status_file, if any.
+  void SetStatus(const char *state);
 
   jvmtiEnv *jvmti_;
   ThreadTable *threads_;
   std::unique_ptr<Throttler> throttler_;
+  std::unique_ptr<MetricsServer> metrics_server_;
+  std::unique_ptr<Heartbeat> heartbeat_;
+  std::unique_ptr<StatusFile> status_file_;
   std::mutex mutex_;  // Held by the worker thread while it's running.
+  // Set once the worker thread holds mutex_, guarded by running_mutex_.
+  std::mutex running_mutex_;
+  std::condition_variable running_cv_;
+  bool running_;
   std::atomic<bool> stopping_;
   static std::atomic<bool> enabled_;
   DISALLOW_COPY_AND_ASSIGN(Worker);
@@ -0,0 +1,88 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "src/worker.h"
+
+#include <string>
+
+#include "gtest/gtest.h"
+#include "src/clock.h"
+
+namespace cloud {
+namespace profiler {
+namespace {
+
+TEST(ServiceFromCommandTest, UsesTheMainClassName) {
+  EXPECT_EQ("myapp", ServiceFromCommand("com.example.MyApp"));
+  EXPECT_EQ("myapp", ServiceFromCommand("com.example.MyApp --port 8080"));
+  EXPECT_EQ("main", ServiceFromCommand("Main"));
+}
+
+TEST(ServiceFromCommandTest, UsesTheMainJarName) {
+  EXPECT_EQ("my-app", ServiceFromCommand("/srv/my-app.jar"));
+  EXPECT_EQ("my-app", ServiceFromCommand("/srv/My-App.jar -v"));
+  EXPECT_EQ("server", ServiceFromCommand("server.jar"));
+}
+
+TEST(ServiceFromCommandTest, ReplacesTheInvalidCharacters) {
+  EXPECT_EQ("my-app", ServiceFromCommand("com.example.My$App"));
+  EXPECT_EQ("my-app-1.2", ServiceFromCommand("lib/my+app-1.2.jar"));
+}
+
+TEST(ServiceFromCommandTest, ReturnsEmptyWithoutValidName) {
+  EXPECT_EQ("", ServiceFromCommand(""));
+  // A service name starts with a letter.
+  EXPECT_EQ("", ServiceFromCommand("/srv/1app.jar"));
+  EXPECT_EQ("", ServiceFromCommand("com.example.$Proxy"));
+}
+
+TEST(NanosToAlignedBoundaryTest, WaitsForTheNextBoundary) {
+  const int64_t kPeriod = 60 * kNanosPerSecond;
+  EXPECT_EQ(30 * kNanosPerSecond,
+            NanosToAlignedBoundary(1230 * kNanosPerSecond, kPeriod));
+  EXPECT_EQ(kNanosPerMilli,
+            NanosToAlignedBoundary(1260 * kNanosPerSecond - kNanosPerMilli,
+                                   kPeriod));
+}
+
+TEST(NanosToAlignedBoundaryTest, DoesNotWaitJustPastTheBoundary) {
+  const int64_t kPeriod = 60 * kNanosPerSecond;
+  EXPECT_EQ(0, NanosToAlignedBoundary(1200 * kNanosPerSecond, kPeriod));
+  EXPECT_EQ(0, NanosToAlignedBoundary(1200 * kNanosPerSecond +
+                                          100 * kNanosPerMilli,
+                                      kPeriod));
+  // Past the tolerance, the next boundary is waited for.
+  EXPECT_EQ(kPeriod - 101 * kNanosPerMilli,
+            NanosToAlignedBoundary(1200 * kNanosPerSecond +
+                                       101 * kNanosPerMilli,
+                                   kPeriod));
+}
+
+TEST(NanosToAlignedBoundaryTest, StaysWithinThePeriod) {
+  const int64_t kPeriod = 7 * kNanosPerSecond;
+  for (int64_t now = 0; now < 3 * kPeriod; now += 333 * kNanosPerMilli) {
+    int64_t wait = NanosToAlignedBoundary(now, kPeriod);
+    EXPECT_GE(wait, 0);
+    EXPECT_LT(wait, kPeriod);
+    if (wait > 0) {
+      EXPECT_EQ(0, (now + wait) % kPeriod);
+    }
+  }
+}
+
+}  // namespace
+}  // namespace profiler
+}  // namespace cloud
@@ -21,6 +21,7 @@ namespace javaprofiler {
 
 __thread JNIEnv *Accessors::env_;
 __thread int64 Accessors::attr_;
+__thread int64 Accessors::thread_id_;
 __thread Tags *Accessors::tags_;
 
 void Accessors::InitTags() {
@@ -32,6 +32,11 @@ class Accessors {
   static void SetAttribute(int64 value) { attr_ = value; }
   static int64 GetAttribute() { return attr_; }
 
+  // Stable identifier of the current thread, unlike its OS thread ID which can
+  // be reused once the thread exits. Zero when not set.
+  static void SetThreadId(int64 value) { thread_id_ = value; }
+  static int64 GetThreadId() { return thread_id_; }
+
   // Allocates the current thread's tags storage which can be later retrieved by
   // GetTags(). If the tags storage is already allocated, asserts an error.
   static void InitTags();
@@ -95,10 +100,13 @@ class Accessors {
 #if defined(JAVAPROFILER_GLOBAL_DYNAMIC_TLS) || defined(ALPINE)
   static __thread JNIEnv *env_ __attribute__((tls_model("global-dynamic")));
   static __thread int64 attr_ __attribute__((tls_model("global-dynamic")));
+  static __thread int64 thread_id_
+      __attribute__((tls_model("global-dynamic")));
   static __thread Tags *tags_ __attribute__((tls_model("global-dynamic")));
 #else
   static __thread JNIEnv *env_ __attribute__((tls_model("initial-exec")));
   static __thread int64 attr_ __attribute__((tls_model("initial-exec")));
+  static __thread int64 thread_id_ __attribute__((tls_model("initial-exec")));
   static __thread Tags *tags_ __attribute__((tls_model("initial-exec")));
 #endif
 };
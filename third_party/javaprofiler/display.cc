@@ -24,6 +24,8 @@ namespace javaprofiler {
 
 namespace {
 
+bool resolve_line_numbers = true;
+
 // This method changes the standard class signature "Lfoo/bar;" format
 // to a more readable "foo.bar" format.
 bool CleanJavaSignature(char *signature_ptr) {
@@ -92,6 +94,8 @@ void GetClassAndFileName(jvmtiEnv *jvmti, jmethodID method_id,
                          jclass declaring_class, std::string *file_name,
                          std::string *class_name) {
   JvmtiScopedPtr<char> source_name_ptr(jvmti);
+  // JVMTI_ERROR_ABSENT_INFORMATION is returned for the classes compiled
+  // without the source file attribute.
   if (JVMTI_ERROR_NONE !=
       jvmti->GetSourceFileName(declaring_class, source_name_ptr.GetRef())) {
     *file_name = kFileUnknown;
@@ -144,6 +148,8 @@ void FillMethodSignatureAndLine(jvmtiEnv *jvmti, const JVMPI_CallFrame &frame,
 
 }  // end namespace
 
+void SetResolveLineNumbers(bool resolve) { resolve_line_numbers = resolve; }
+
 jint GetLineNumber(jvmtiEnv *jvmti, jmethodID method, jlocation location) {
   jint entry_count;
   JvmtiScopedPtr<jvmtiLineNumberEntry> table_ptr_ctr(jvmti);
@@ -153,20 +159,27 @@ jint GetLineNumber(jvmtiEnv *jvmti, jmethodID method, jlocation location) {
   if (location < 0) {
     return -1;
   }
+  if (!resolve_line_numbers) {
+    return 0;
+  }
 
   int jvmti_error =
       jvmti->GetLineNumberTable(method, &entry_count, table_ptr_ctr.GetRef());
 
-  if (JVMTI_ERROR_NONE != jvmti_error || entry_count <= 0) {
-    if (JVMTI_ERROR_ABSENT_INFORMATION == jvmti_error) {
-      static bool no_debug_info = false;
-      if (!no_debug_info) {
-        LOG(INFO)
-            << "No line number information was found in your bytecode."
-               " Some monitoring interfaces may report -1 ""for line numbers.";
-        no_debug_info = true;
-      }
+  if (JVMTI_ERROR_ABSENT_INFORMATION == jvmti_error ||
+      (JVMTI_ERROR_NONE == jvmti_error && entry_count <= 0)) {
+    // The class was compiled without debug information. This is not an
+    // error, the line is reported as unknown.
+    static bool no_debug_info = false;
+    if (!no_debug_info) {
+      LOG(INFO)
+          << "No line number information was found in your bytecode."
+             " Some monitoring interfaces may report 0 for line numbers.";
+      no_debug_info = true;
     }
+    return 0;
+  }
+  if (JVMTI_ERROR_NONE != jvmti_error) {
     return -1;
   }
 
@@ -194,6 +207,39 @@ jint GetLineNumber(jvmtiEnv *jvmti, jmethodID method, jlocation location) {
   return -1;
 }
 
+jint GetMethodStartLine(jvmtiEnv *jvmti, jmethodID method) {
+  if (!resolve_line_numbers) {
+    return 0;
+  }
+  jint entry_count;
+  JvmtiScopedPtr<jvmtiLineNumberEntry> table_ptr_ctr(jvmti);
+  if (JVMTI_ERROR_NONE !=
+          jvmti->GetLineNumberTable(method, &entry_count,
+                                    table_ptr_ctr.GetRef()) ||
+      entry_count <= 0) {
+    return 0;
+  }
+
+  // The entries are ordered by location, not by line: the first entry is not
+  // the lowest line when e.g. a loop condition is compiled after its body.
+  jvmtiLineNumberEntry *table_ptr = table_ptr_ctr.Get();
+  jint start_line = table_ptr[0].line_number;
+  for (int l = 1; l < entry_count; l++) {
+    if (table_ptr[l].line_number < start_line) {
+      start_line = table_ptr[l].line_number;
+    }
+  }
+  return start_line;
+}
+
+jint GetClassStatus(jvmtiEnv *jvmti, jclass klass) {
+  jint status = 0;
+  if (jvmti->GetClassStatus(klass, &status) != JVMTI_ERROR_NONE) {
+    return 0;
+  }
+  return status;
+}
+
 bool GetStackFrameElements(JNIEnv *jni, jvmtiEnv *jvmti,
                            const JVMPI_CallFrame &frame, std::string *file_name,
                            std::string *class_name, std::string *method_name,
@@ -205,8 +251,21 @@ bool GetStackFrameElements(JNIEnv *jni, jvmtiEnv *jvmti,
   }
 
   jclass declaring_class = nullptr;
-  if (JVMTI_ERROR_NONE !=
-      jvmti->GetMethodDeclaringClass(frame.method_id, &declaring_class)) {
+  jvmtiError error =
+      jvmti->GetMethodDeclaringClass(frame.method_id, &declaring_class);
+  if (error == JVMTI_ERROR_INVALID_METHODID ||
+      error == JVMTI_ERROR_WRONG_PHASE) {
+    // The method ID is not usable, e.g. the declaring class was unloaded or
+    // the VM is shutting down; the other lookups would fail the same way.
+    // Methods of classes shared through CDS archives resolve like any other.
+    FillFieldsWithUnknown(file_name, class_name, method_name, signature,
+                          line_number);
+    if (error == JVMTI_ERROR_INVALID_METHODID) {
+      *method_name = kMethodIDUnknown;
+    }
+    return true;
+  }
+  if (error != JVMTI_ERROR_NONE) {
     *file_name = kFileUnknown;
     *class_name = kClassUnknown;
     FillMethodSignatureAndLine(jvmti, frame, method_name, signature,
@@ -215,6 +274,15 @@ bool GetStackFrameElements(JNIEnv *jni, jvmtiEnv *jvmti,
   }
 
   ScopedLocalRef<jclass> declaring_class_managed(jni, declaring_class);
+  if ((GetClassStatus(jvmti, declaring_class) &
+       JVMTI_CLASS_STATUS_PREPARED) == 0) {
+    // The lookups may fail on a class that is not prepared yet, e.g. sampled
+    // while its static initializer runs early in the class loading. Nothing
+    // is cached for it, the next profile symbolizes it once prepared.
+    FillFieldsWithUnknown(file_name, class_name, method_name, signature,
+                          line_number);
+    return true;
+  }
   return GetStackFrameElements(jvmti, frame, declaring_class, file_name,
                                class_name, method_name, signature, line_number);
 }
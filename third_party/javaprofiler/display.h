@@ -28,11 +28,25 @@
 namespace google {
 namespace javaprofiler {
 
+// Sets whether the line numbers are looked up in the line number tables of the
+// methods. When not, GetLineNumber and GetMethodStartLine return 0 for the
+// Java methods, saving the JVMTI calls. Not thread-safe, meant to be called
+// once at startup.
+void SetResolveLineNumbers(bool resolve);
+
 // Walks the line number table and return the associated Java line number from a
 // given method and location.
 // Returns -1 on error or for native methods.
 jint GetLineNumber(jvmtiEnv *jvmti, jmethodID method, jlocation location);
 
+// Returns the first line of a method, the lowest line of its line number
+// table. Returns 0 when unknown, e.g. for native methods or the classes
+// compiled without debug information.
+jint GetMethodStartLine(jvmtiEnv *jvmti, jmethodID method);
+
+// Returns the JVMTI_CLASS_STATUS_* bits of the class, or 0 on error.
+jint GetClassStatus(jvmtiEnv *jvmti, jclass klass);
+
 // Fill the file_name, class_name, method_name, and line_number parameters using
 // the information provided by the frame and using the JVMTI environment.
 // When unknown, it fills the parameters with: UnknownFile, UnknownClass,
@@ -15,10 +15,21 @@
  */
 
 #include "third_party/javaprofiler/heap_sampler.h"
+
+#include <pthread.h>
+
+#include <algorithm>
+#include <cmath>
+
 #include "third_party/javaprofiler/profile_proto_builder.h"
+#include "third_party/javaprofiler/sample_budget.h"
 
 namespace {
 
+// Name of the GC waiting thread, both in Java and for the OS. The OS thread
+// names are limited to 15 characters.
+const char kGCWaitingThreadName[] = "cprof-heap-gc";
+
 std::unique_ptr<std::vector<google::javaprofiler::JVMPI_CallFrame>>
 TransformFrames(jvmtiFrameInfo *stack_frames, int count) {
   auto frames =
@@ -36,12 +47,21 @@ TransformFrames(jvmtiFrameInfo *stack_frames, int count) {
   return frames;
 }
 
+// Returns the probability that an object of the given size is sampled with
+// the sampling interval, zero sampling every object.
+double SampledProbability(jlong size, int sampling_interval) {
+  if (sampling_interval <= 0) {
+    return 1;
+  }
+  return 1 - std::exp(-static_cast<double>(size) / sampling_interval);
+}
+
 extern "C" JNIEXPORT void SampledObjectAlloc(jvmtiEnv *jvmti_env,
                                              JNIEnv *jni_env, jthread thread,
                                              jobject object,
                                              jclass object_klass, jlong size) {
-  google::javaprofiler::HeapMonitor::AddSample(jni_env, thread, object,
-                                               object_klass, size);
+  google::javaprofiler::HeapMonitor::OnSampledObjectAlloc(
+      jni_env, thread, object, object_klass, size);
 }
 
 extern "C" JNIEXPORT void GarbageCollectionFinish(jvmtiEnv *jvmti_env) {
@@ -52,16 +72,47 @@ extern "C" JNIEXPORT void GarbageCollectionFinish(jvmtiEnv *jvmti_env) {
 
 namespace google {
 namespace javaprofiler {
+const int HeapMonitor::kMinRecommendedSamplingInterval;
 std::atomic<jvmtiEnv *> HeapMonitor::jvmti_;
 std::atomic<int> HeapMonitor::sampling_interval_;
+jvmtiEventCallbacks *HeapMonitor::callbacks_;
+std::atomic<int64> HeapMonitor::dropped_null_objects_;
+std::atomic<int64> HeapMonitor::dropped_zero_size_;
+
+void HeapMonitor::OnSampledObjectAlloc(JNIEnv *jni_env, jthread thread,
+                                       jobject object, jclass object_klass,
+                                       jlong size) {
+  // Neither should happen, but they have on buggy JVMs: a null object would
+  // make a sample that is never live, a zero size one that weighs nothing.
+  if (object == nullptr) {
+    dropped_null_objects_++;
+    return;
+  }
+  if (size <= 0) {
+    dropped_zero_size_++;
+    return;
+  }
+  if (!SampleBudget::TryAcquire()) {
+    return;
+  }
+  AddSample(jni_env, thread, object, object_klass, size);
+}
 
 HeapEventStorage::HeapEventStorage(jvmtiEnv *jvmti, ProfileFrameCache *cache,
                                    int max_garbage_size)
-    : max_garbage_size_(max_garbage_size),
+    : pending_objects_(nullptr),
+      max_garbage_size_(max_garbage_size),
       cur_garbage_pos_(0),
       jvmti_(jvmti), cache_(cache) {
 }
 
+HeapEventStorage::~HeapEventStorage() {
+  // The pending objects are only referenced by the lock-free list, move them
+  // to newly_allocated_objects_ for them to be freed with the others.
+  std::lock_guard<std::mutex> lock(storage_lock_);
+  TakePendingObjects();
+}
+
 void HeapEventStorage::Add(JNIEnv *jni, jthread thread, jobject object,
                            jclass klass, jlong size) {
   const int kMaxFrames = 128;
@@ -71,21 +122,50 @@ void HeapEventStorage::Add(JNIEnv *jni, jthread thread, jobject object,
       jvmti_->GetStackTrace(thread, 0, kMaxFrames, stack_frames, &count);
 
   if (err == JVMTI_ERROR_NONE && count > 0) {
+    int64 charged_bytes =
+        sizeof(HeapObjectTrace) + sizeof(std::vector<JVMPI_CallFrame>) +
+        count * sizeof(JVMPI_CallFrame);
+    if (!MemoryBudget::TryCharge(charged_bytes)) {
+      // Over the agent memory budget, drop the sample.
+      return;
+    }
     auto frames = TransformFrames(stack_frames, count);
 
     jweak weak_ref = jni->NewWeakGlobalRef(object);
     if (jni->ExceptionCheck()) {
+      // Cleared, the allocating thread goes on running Java code.
+      jni->ExceptionClear();
       LOG(WARNING) << "Failed to create NewWeakGlobalRef, skipping heap sample";
+      MemoryBudget::Release(charged_bytes);
       return;
     }
 
-    auto live_object = std::unique_ptr<HeapObjectTrace>(
-        new HeapObjectTrace(weak_ref, size, std::move(frames)));
+    auto *live_object = new HeapObjectTrace(weak_ref, size, std::move(frames),
+                                            charged_bytes);
+
+    // Push without locking, the storage lock can be held for long by the
+    // profile collection.
+    HeapObjectTrace *head = pending_objects_.load(std::memory_order_relaxed);
+    do {
+      live_object->SetNextPending(head);
+    } while (!pending_objects_.compare_exchange_weak(
+        head, live_object, std::memory_order_release,
+        std::memory_order_relaxed));
+  }
+}
 
-    // Only now lock and get things done quickly.
-    std::lock_guard<std::mutex> lock(storage_lock_);
-    newly_allocated_objects_.push_back(std::move(live_object));
+void HeapEventStorage::TakePendingObjects() {
+  HeapObjectTrace *head =
+      pending_objects_.exchange(nullptr, std::memory_order_acquire);
+  size_t first = newly_allocated_objects_.size();
+  while (head != nullptr) {
+    HeapObjectTrace *next = head->NextPending();
+    head->SetNextPending(nullptr);
+    newly_allocated_objects_.push_back(std::unique_ptr<HeapObjectTrace>(head));
+    head = next;
   }
+  std::reverse(newly_allocated_objects_.begin() + first,
+               newly_allocated_objects_.end());
 }
 
 void HeapEventStorage::AddToGarbage(std::unique_ptr<HeapObjectTrace> obj) {
@@ -112,6 +192,7 @@ void HeapEventStorage::MoveLiveObjects(
 
 void HeapEventStorage::CompactSamples(JNIEnv *env) {
   std::lock_guard<std::mutex> lock(storage_lock_);
+  TakePendingObjects();
 
   std::vector<std::unique_ptr<HeapObjectTrace>> still_live;
 
@@ -125,6 +206,38 @@ void HeapEventStorage::CompactSamples(JNIEnv *env) {
   newly_allocated_objects_.clear();
 }
 
+void HeapEventStorage::ResampleObjects(
+    JNIEnv *env, std::vector<std::unique_ptr<HeapObjectTrace>> *objects,
+    int from_interval, int to_interval) {
+  std::uniform_real_distribution<double> dist(0, 1);
+  std::vector<std::unique_ptr<HeapObjectTrace>> kept;
+  for (auto &elem : *objects) {
+    double keep = SampledProbability(elem->Size(), to_interval) /
+                  SampledProbability(elem->Size(), from_interval);
+    if (dist(resample_gen_) < keep) {
+      kept.push_back(std::move(elem));
+    } else {
+      elem->DeleteWeakReference(env);
+    }
+  }
+  *objects = std::move(kept);
+}
+
+void HeapEventStorage::Resample(JNIEnv *env, int from_interval,
+                                int to_interval) {
+  std::lock_guard<std::mutex> lock(storage_lock_);
+  TakePendingObjects();
+
+  ResampleObjects(env, &newly_allocated_objects_, from_interval, to_interval);
+  ResampleObjects(env, &live_objects_, from_interval, to_interval);
+  // Oldest first, for AddToGarbage to keep replacing the oldest object.
+  std::rotate(garbage_objects_.begin(),
+              garbage_objects_.begin() + cur_garbage_pos_,
+              garbage_objects_.end());
+  cur_garbage_pos_ = 0;
+  ResampleObjects(env, &garbage_objects_, from_interval, to_interval);
+}
+
 std::unique_ptr<perftools::profiles::Profile> HeapEventStorage::ConvertToProto(
     ProfileProtoBuilder *builder,
     const std::vector<std::unique_ptr<HeapObjectTrace>> &objects) {
@@ -182,8 +295,20 @@ std::unique_ptr<perftools::profiles::Profile> HeapEventStorage::GetProfiles(
 
 bool HeapMonitor::CreateGCWaitingThread(jvmtiEnv* jvmti, JNIEnv* jni) {
   jclass cls = jni->FindClass("java/lang/Thread");
-  jmethodID constructor = jni->GetMethodID(cls, "<init>", "()V");
-  jobject thread = jni->NewGlobalRef(jni->NewObject(cls, constructor));
+  jmethodID constructor =
+      cls == nullptr
+          ? nullptr
+          : jni->GetMethodID(cls, "<init>", "(Ljava/lang/String;)V");
+  jstring name = jni->NewStringUTF(kGCWaitingThreadName);
+  jobject local_thread = constructor == nullptr || name == nullptr
+                             ? nullptr
+                             : jni->NewObject(cls, constructor, name);
+  jobject thread =
+      local_thread == nullptr ? nullptr : jni->NewGlobalRef(local_thread);
+  if (jni->ExceptionCheck()) {
+    jni->ExceptionClear();
+    thread = nullptr;
+  }
   if (thread == nullptr) {
     LOG(WARNING) << "Failed to construct the GC waiting thread";
     return false;
@@ -234,6 +359,7 @@ void HeapMonitor::AddCallback(jvmtiEventCallbacks *callbacks) {
 #ifdef ENABLE_HEAP_SAMPLING
   callbacks->SampledObjectAlloc = &SampledObjectAlloc;
   callbacks->GarbageCollectionFinish = &GarbageCollectionFinish;
+  callbacks_ = callbacks;
 #endif
 }
 
@@ -305,13 +431,35 @@ void HeapMonitor::Disable() {
     return;
   }
 
+  // The garbage collection finish callback may have been replaced by one
+  // chaining to the heap sampler, whose event has to stay enabled.
+  bool own_gc_finish =
+      callbacks_ == nullptr ||
+      callbacks_->GarbageCollectionFinish == &GarbageCollectionFinish;
   jvmti->SetEventNotificationMode(JVMTI_DISABLE,
                                   JVMTI_EVENT_SAMPLED_OBJECT_ALLOC, nullptr);
-  jvmti->SetEventNotificationMode(JVMTI_DISABLE,
-                                  JVMTI_EVENT_GARBAGE_COLLECTION_FINISH,
-                                  nullptr);
+  if (own_gc_finish) {
+    jvmti->SetEventNotificationMode(JVMTI_DISABLE,
+                                    JVMTI_EVENT_GARBAGE_COLLECTION_FINISH,
+                                    nullptr);
+  }
   jvmti_.store(nullptr);
 
+  // Remove the heap sampling callbacks, rather than leave them in place for
+  // a later enabling of the events, e.g. by another feature, to call into.
+  if (callbacks_ != nullptr) {
+    if (callbacks_->SampledObjectAlloc == &SampledObjectAlloc) {
+      callbacks_->SampledObjectAlloc = nullptr;
+    }
+    if (own_gc_finish) {
+      callbacks_->GarbageCollectionFinish = nullptr;
+    }
+    if (jvmti->SetEventCallbacks(callbacks_, sizeof(jvmtiEventCallbacks)) !=
+        JVMTI_ERROR_NONE) {
+      LOG(WARNING) << "Failed to remove the heap sampling callbacks";
+    }
+  }
+
   // Notify the agent thread that we are done.
   google::javaprofiler::HeapMonitor::GetInstance()->ShutdownGCWaitingThread();
 
@@ -371,6 +519,7 @@ HeapMonitor::GcEvent HeapMonitor::WaitForGC() {
 
 void HeapMonitor::GCWaitingThread(jvmtiEnv* jvmti_env, JNIEnv* jni_env,
                                   void* arg) {
+  pthread_setname_np(pthread_self(), kGCWaitingThreadName);
   GetInstance()->GCWaitingThreadRun(jni_env);
 }
 
@@ -391,6 +540,33 @@ void HeapMonitor::GCWaitingThreadRun(JNIEnv* jni_env) {
 
 void HeapMonitor::CompactData(JNIEnv* jni_env) {
   storage_.CompactSamples(jni_env);
+  // The compaction released the collected objects, what is left is live.
+  if (MemoryBudget::NearLimit()) {
+    ShedLoad(jni_env);
+  }
+}
+
+void HeapMonitor::ShedLoad(JNIEnv* jni_env) {
+#ifdef ENABLE_HEAP_SAMPLING
+  const int kMaxSamplingInterval = 1 << 30;
+  jvmtiEnv *jvmti = jvmti_.load();
+  int interval = sampling_interval_.load();
+  if (jvmti == nullptr || interval >= kMaxSamplingInterval) {
+    return;
+  }
+  // Doubling a zero interval, sampling every allocation, would shed nothing.
+  int coarser = std::max(interval, kMinRecommendedSamplingInterval) * 2;
+  if (jvmti->SetHeapSamplingInterval(coarser) != JVMTI_ERROR_NONE) {
+    return;
+  }
+  int64 used = MemoryBudget::Used();
+  storage_.Resample(jni_env, interval, coarser);
+  sampling_interval_.store(coarser);
+  LOG(WARNING) << "The heap samples hold " << used << " bytes, near the "
+               << "agent memory budget, raising the heap sampling interval to "
+               << coarser << " bytes and resampling them down to "
+               << MemoryBudget::Used() << " bytes";
+#endif
 }
 
 }  // namespace javaprofiler
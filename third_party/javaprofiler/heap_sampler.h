@@ -20,13 +20,16 @@
 #include <jni.h>
 #include <jvmti.h>
 
+#include <atomic>
 #include <condition_variable>  // NOLINT
 #include <list>
 #include <memory>
 #include <mutex>  // NOLINT
+#include <random>
 #include <vector>
 
 #include "third_party/javaprofiler/globals.h"
+#include "third_party/javaprofiler/memory_budget.h"
 #include "third_party/javaprofiler/profile_proto_builder.h"
 
 namespace google {
@@ -39,11 +42,19 @@ class HeapEventStorage {
   HeapEventStorage(jvmtiEnv *jvmti, ProfileFrameCache *cache = nullptr,
                    int max_garbage_size = 200);
 
+  // Frees the stored objects, including the ones still pending. Their weak
+  // references are left to the JVM, deleting them needs a JNIEnv.
+  ~HeapEventStorage();
+
   // TODO: establish correct shutdown sequence: how do we ensure that
   // things are not going to go awfully wrong at shutdown, is it this class' job
   // or should it be the owner of this class' instance's job?
 
-  // Adds an object to the storage system.
+  // Adds an object to the storage system. Runs on the allocating thread, from
+  // the SampledObjectAlloc callback: its cost is the stack walk, proportional
+  // to the stack depth, and a few allocations. It never waits for the storage
+  // lock, which the profile collection holds while symbolizing the stacks, but
+  // pushes the object to a lock-free pending list instead.
   void Add(JNIEnv *jni, jthread thread, jobject object, jclass klass,
            jlong size);
 
@@ -69,6 +80,12 @@ class HeapEventStorage {
 
   void CompactSamples(JNIEnv *env);
 
+  // Drops each stored object with the probability that it would not have been
+  // sampled with the coarser to_interval, given it was with from_interval.
+  // The objects left are then as if sampled with to_interval, and the heap
+  // profiles built with it stay accurate.
+  void Resample(JNIEnv *env, int from_interval, int to_interval);
+
   // Not copyable or movable.
   HeapEventStorage(const HeapEventStorage&) = delete;
   HeapEventStorage& operator=(const HeapEventStorage&) = delete;
@@ -79,10 +96,19 @@ class HeapEventStorage {
   class HeapObjectTrace {
    public:
     // This object owns the jweak object parameter. It is freed when the object
-    // is sent to the garbage list, and the object is set to nullptr.
+    // is sent to the garbage list, and the object is set to nullptr. The
+    // charged bytes were charged to the MemoryBudget for this object, and are
+    // released with it.
     HeapObjectTrace(jweak object, jlong size,
-                    std::unique_ptr<std::vector<JVMPI_CallFrame>> frames)
-        : object_(object), size_(size), frames_(std::move(frames)) {}
+                    std::unique_ptr<std::vector<JVMPI_CallFrame>> frames,
+                    int64 charged_bytes)
+        : object_(object),
+          size_(size),
+          frames_(std::move(frames)),
+          next_pending_(nullptr),
+          charged_bytes_(charged_bytes) {}
+
+    ~HeapObjectTrace() { MemoryBudget::Release(charged_bytes_); }
 
     std::vector<JVMPI_CallFrame> *Frames() const {
       return frames_.get();
@@ -93,8 +119,10 @@ class HeapEventStorage {
     }
 
     void DeleteWeakReference(JNIEnv* env) {
-      env->DeleteWeakGlobalRef(object_);
-      object_ = nullptr;
+      if (object_ != nullptr) {
+        env->DeleteWeakGlobalRef(object_);
+        object_ = nullptr;
+      }
     }
 
     bool IsLive(JNIEnv *env) {
@@ -103,6 +131,10 @@ class HeapEventStorage {
       return !env->IsSameObject(object_, NULL);
     }
 
+    // The next object in the pending list, see pending_objects_.
+    HeapObjectTrace *NextPending() const { return next_pending_; }
+    void SetNextPending(HeapObjectTrace *next) { next_pending_ = next; }
+
     // Not copyable or movable.
     HeapObjectTrace(const HeapObjectTrace&) = delete;
     HeapObjectTrace& operator=(const HeapObjectTrace&) = delete;
@@ -111,6 +143,8 @@ class HeapEventStorage {
     jweak object_;
     int size_;
     std::unique_ptr<std::vector<JVMPI_CallFrame>> frames_;
+    HeapObjectTrace *next_pending_;
+    int64 charged_bytes_;
   };
 
   static std::unique_ptr<perftools::profiles::Profile> ConvertToProto(
@@ -130,6 +164,20 @@ class HeapEventStorage {
       JNIEnv *env, std::vector<std::unique_ptr<HeapObjectTrace>> *objects,
       std::vector<std::unique_ptr<HeapObjectTrace>> *still_live_objects);
 
+  // Keeps each of the objects with the given probability, for their size,
+  // deleting the weak references of the others.
+  void ResampleObjects(
+      JNIEnv *env, std::vector<std::unique_ptr<HeapObjectTrace>> *objects,
+      int from_interval, int to_interval);
+
+  // Moves the pending objects to newly_allocated_objects_, in allocation
+  // order. Must be called with storage_lock_ held.
+  void TakePendingObjects();
+
+  // Objects added since the last compaction, as a lock-free stack linked
+  // through NextPending(), most recent first. Owns the objects.
+  std::atomic<HeapObjectTrace *> pending_objects_;
+
   std::vector<std::unique_ptr<HeapObjectTrace>> newly_allocated_objects_;
   std::vector<std::unique_ptr<HeapObjectTrace>> live_objects_;
 
@@ -140,6 +188,8 @@ class HeapEventStorage {
   std::vector<std::unique_ptr<HeapObjectTrace>> garbage_objects_;
 
   std::mutex storage_lock_;
+  // Decides which objects Resample keeps, guarded by storage_lock_.
+  std::default_random_engine resample_gen_;
   jvmtiEnv *jvmti_;
   ProfileFrameCache *cache_;
 };
@@ -147,6 +197,9 @@ class HeapEventStorage {
 // Due to the JVMTI callback, everything here is static.
 class HeapMonitor {
  public:
+  // Sampling intervals below this are likely to incur a high overhead.
+  static const int kMinRecommendedSamplingInterval = 16 * 1024;
+
   static bool Enable(jvmtiEnv *jvmti, JNIEnv* jni, int sampling_interval);
   static void Disable();
 
@@ -167,6 +220,21 @@ class HeapMonitor {
     GetInstance()->storage_.Add(jni_env, thread, object, object_klass, size);
   }
 
+  // Returns the number of allocation samples dropped since the start because
+  // the JVM reported a null object, or a zero size, which buggy JVMs do.
+  static int64 DroppedNullObjectCount() { return dropped_null_objects_; }
+  static int64 DroppedZeroSizeCount() { return dropped_zero_size_; }
+
+  // Records an allocation sample reported by the JVM, unless it is invalid.
+  // Public for testing.
+  static void OnSampledObjectAlloc(JNIEnv *jni_env, jthread thread,
+                                   jobject object, jclass object_klass,
+                                   jlong size);
+
+  // Adds the heap sampling callbacks to the given callbacks, leaving the
+  // others untouched. The callbacks are not set with the JVMTI environment.
+  // They must outlive the monitor: Disable removes the heap sampling callbacks
+  // from them, and sets them again with the JVMTI environment.
   static void AddCallback(jvmtiEventCallbacks *callbacks);
 
   static void NotifyGCWaitingThread() {
@@ -207,11 +275,19 @@ class HeapMonitor {
 
   void CompactData(JNIEnv* jni_env);
 
+  // Doubles the sampling interval, as the storage nears the MemoryBudget
+  // limit, and resamples the objects already stored to the new interval.
+  void ShedLoad(JNIEnv* jni_env);
+
   static std::unique_ptr<perftools::profiles::Profile> EmptyHeapProfile(
       JNIEnv *jni_env);
 
   static std::atomic<jvmtiEnv *> jvmti_;
   static std::atomic<int> sampling_interval_;
+  // The consolidated callbacks given to AddCallback, if any.
+  static jvmtiEventCallbacks *callbacks_;
+  static std::atomic<int64> dropped_null_objects_;
+  static std::atomic<int64> dropped_zero_size_;
 
   std::list<GcEvent> gc_notify_events_;
   std::condition_variable gc_waiting_cv_;
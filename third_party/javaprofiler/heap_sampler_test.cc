@@ -0,0 +1,216 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "third_party/javaprofiler/heap_sampler.h"
+
+#include <string.h>
+
+#include <algorithm>
+#include <chrono>  // NOLINT
+#include <condition_variable>  // NOLINT
+#include <mutex>  // NOLINT
+#include <thread>  // NOLINT
+
+#include "gtest/gtest.h"
+
+namespace google {
+namespace javaprofiler {
+namespace {
+
+// The invalid samples are dropped before reaching the storage, so no JVM is
+// needed to report them.
+TEST(HeapMonitorTest, DropsNullObjectSamples) {
+  int64 null_objects = HeapMonitor::DroppedNullObjectCount();
+  int64 zero_size = HeapMonitor::DroppedZeroSizeCount();
+  HeapMonitor::OnSampledObjectAlloc(nullptr, nullptr, nullptr, nullptr, 16);
+  EXPECT_EQ(null_objects + 1, HeapMonitor::DroppedNullObjectCount());
+  EXPECT_EQ(zero_size, HeapMonitor::DroppedZeroSizeCount());
+}
+
+TEST(HeapMonitorTest, DropsZeroSizeSamples) {
+  // Never dereferenced, the sample is dropped on its size.
+  jobject object = reinterpret_cast<jobject>(0x1000);
+  int64 null_objects = HeapMonitor::DroppedNullObjectCount();
+  int64 zero_size = HeapMonitor::DroppedZeroSizeCount();
+  HeapMonitor::OnSampledObjectAlloc(nullptr, nullptr, object, nullptr, 0);
+  HeapMonitor::OnSampledObjectAlloc(nullptr, nullptr, object, nullptr, -8);
+  EXPECT_EQ(null_objects, HeapMonitor::DroppedNullObjectCount());
+  EXPECT_EQ(zero_size + 2, HeapMonitor::DroppedZeroSizeCount());
+}
+
+// A fake JVM for the storage: every thread has the same two frames and the
+// weak references are the objects themselves.
+jvmtiError JNICALL FakeGetStackTrace(jvmtiEnv *env, jthread thread,
+                                     jint start_depth, jint max_frame_count,
+                                     jvmtiFrameInfo *frame_buffer,
+                                     jint *count_ptr) {
+  *count_ptr = std::min<jint>(2, max_frame_count);
+  for (jint i = 0; i < *count_ptr; i++) {
+    frame_buffer[i].method = reinterpret_cast<jmethodID>(i + 1);
+    frame_buffer[i].location = i;
+  }
+  return JVMTI_ERROR_NONE;
+}
+
+jweak JNICALL FakeNewWeakGlobalRef(JNIEnv *env, jobject obj) { return obj; }
+
+int deleted_weak_refs;
+
+void JNICALL FakeDeleteWeakGlobalRef(JNIEnv *env, jweak ref) {
+  deleted_weak_refs++;
+}
+
+jboolean JNICALL FakeExceptionCheck(JNIEnv *env) { return JNI_FALSE; }
+
+// Lets the test add an object while the compaction holds the storage lock.
+std::mutex sync_mutex;
+std::condition_variable sync_cv;
+bool compacting;
+bool added;
+bool compaction_timed_out;
+
+// Called by the compaction, with the storage lock held, to check whether an
+// object is live. Waits for the test to add an object, bounded so that the
+// test fails rather than hangs when the add waits for the storage lock.
+jboolean JNICALL WaitingIsSameObject(JNIEnv *env, jobject obj1, jobject obj2) {
+  std::unique_lock<std::mutex> lock(sync_mutex);
+  compacting = true;
+  sync_cv.notify_all();
+  if (!sync_cv.wait_for(lock, std::chrono::seconds(10),
+                        [] { return added; })) {
+    compaction_timed_out = true;
+  }
+  return JNI_FALSE;  // Live.
+}
+
+class HeapEventStorageTest : public ::testing::Test {
+ protected:
+  void SetUp() override {
+    memset(&jvmti_functions_, 0, sizeof(jvmti_functions_));
+    jvmti_functions_.GetStackTrace = &FakeGetStackTrace;
+    jvmti_.functions = &jvmti_functions_;
+    memset(&jni_functions_, 0, sizeof(jni_functions_));
+    jni_functions_.NewWeakGlobalRef = &FakeNewWeakGlobalRef;
+    jni_functions_.DeleteWeakGlobalRef = &FakeDeleteWeakGlobalRef;
+    jni_functions_.ExceptionCheck = &FakeExceptionCheck;
+    jni_functions_.IsSameObject = &WaitingIsSameObject;
+    jni_.functions = &jni_functions_;
+    compacting = false;
+    added = false;
+    compaction_timed_out = false;
+    deleted_weak_refs = 0;
+  }
+
+  static jobject Object(intptr_t id) { return reinterpret_cast<jobject>(id); }
+
+  jvmtiInterface_1 jvmti_functions_;
+  jvmtiEnv jvmti_;
+  JNINativeInterface_ jni_functions_;
+  JNIEnv jni_;
+};
+
+TEST_F(HeapEventStorageTest, AddDoesNotTakeTheStorageLock) {
+  HeapEventStorage storage(&jvmti_);
+  storage.Add(&jni_, nullptr, Object(1), nullptr, 16);
+
+  std::thread compaction([this, &storage] { storage.CompactSamples(&jni_); });
+  {
+    std::unique_lock<std::mutex> lock(sync_mutex);
+    sync_cv.wait(lock, [] { return compacting; });
+  }
+  // The compaction holds the storage lock until the object is added.
+  storage.Add(&jni_, nullptr, Object(2), nullptr, 32);
+  {
+    std::lock_guard<std::mutex> lock(sync_mutex);
+    added = true;
+  }
+  sync_cv.notify_all();
+  compaction.join();
+  EXPECT_FALSE(compaction_timed_out);
+}
+
+TEST_F(HeapEventStorageTest, DestructorFreesThePendingObjects) {
+  added = true;  // Do not wait in the compaction.
+  int64 used = MemoryBudget::Used();
+  {
+    HeapEventStorage storage(&jvmti_);
+    storage.Add(&jni_, nullptr, Object(1), nullptr, 16);
+    storage.CompactSamples(&jni_);
+    int64 used_by_live = MemoryBudget::Used();
+    EXPECT_GT(used_by_live, used);
+
+    // Pending until the next compaction.
+    storage.Add(&jni_, nullptr, Object(2), nullptr, 32);
+    storage.Add(&jni_, nullptr, Object(3), nullptr, 64);
+    EXPECT_GT(MemoryBudget::Used(), used_by_live);
+  }
+  // Each object releases its charge when freed.
+  EXPECT_EQ(used, MemoryBudget::Used());
+}
+
+TEST_F(HeapEventStorageTest, ShedsLoadWithinTheMemoryBudget) {
+  added = true;  // Do not wait in the compaction.
+  int64 used = MemoryBudget::Used();
+  HeapEventStorage storage(&jvmti_);
+  storage.Add(&jni_, nullptr, Object(1), nullptr, 16);
+  int64 object_bytes = MemoryBudget::Used() - used;
+  int64 limit = used + 1000 * object_bytes;
+  MemoryBudget::SetLimit(limit);
+
+  int64 rejected = MemoryBudget::RejectedCount();
+  for (int i = 2; i <= 2000; i++) {
+    storage.Add(&jni_, nullptr, Object(i), nullptr, 16);
+  }
+  // The objects beyond the budget are dropped.
+  EXPECT_EQ(limit, MemoryBudget::Used());
+  EXPECT_EQ(rejected + 1000, MemoryBudget::RejectedCount());
+  EXPECT_TRUE(MemoryBudget::NearLimit());
+
+  // The small objects are about half as likely to be sampled with twice the
+  // interval.
+  storage.CompactSamples(&jni_);
+  storage.Resample(&jni_, 16 * 1024, 32 * 1024);
+  int64 kept = (MemoryBudget::Used() - used) / object_bytes;
+  EXPECT_GT(kept, 400);
+  EXPECT_LT(kept, 600);
+  EXPECT_EQ(1000 - kept, deleted_weak_refs);
+  EXPECT_FALSE(MemoryBudget::NearLimit());
+
+  // The budget freed by the resampling is available again.
+  storage.Add(&jni_, nullptr, Object(2001), nullptr, 16);
+  EXPECT_EQ(used + (kept + 1) * object_bytes, MemoryBudget::Used());
+  MemoryBudget::SetLimit(0);
+}
+
+TEST_F(HeapEventStorageTest, ResampleKeepsTheLargeObjects) {
+  added = true;  // Do not wait in the compaction.
+  int64 used = MemoryBudget::Used();
+  HeapEventStorage storage(&jvmti_);
+  for (int i = 1; i <= 100; i++) {
+    storage.Add(&jni_, nullptr, Object(i), nullptr, 64 * 1024 * 1024);
+  }
+  int64 used_by_objects = MemoryBudget::Used() - used;
+
+  // The pending objects are resampled too. The objects many times the
+  // interval are sampled with either interval.
+  storage.Resample(&jni_, 16 * 1024, 32 * 1024);
+  EXPECT_EQ(used_by_objects, MemoryBudget::Used() - used);
+  EXPECT_EQ(0, deleted_weak_refs);
+}
+
+}  // namespace
+}  // namespace javaprofiler
+}  // namespace google
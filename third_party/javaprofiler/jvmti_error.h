@@ -17,14 +17,25 @@
 #ifndef THIRD_PARTY_JAVAPROFILER_JVMTI_ERROR_H_
 #define THIRD_PARTY_JAVAPROFILER_JVMTI_ERROR_H_
 
+// Debug builds with CPROF_TRACE_JVMTI defined (make TRACE_JVMTI=1) log every
+// JVMTI call wrapped in the macros below along with its error code. This is
+// verbose and slow, never define it in release builds.
+#ifdef CPROF_TRACE_JVMTI
+#define JVMTI_TRACE(call, err) \
+  LOG(INFO) << "JVMTI call " << #call << " returned " << (err)
+#else
+#define JVMTI_TRACE(call, err)
+#endif
+
 // Wrap JVMTI functions in this in void functions.
 #define JVMTI_ERROR(error) JVMTI_ERROR_CLEANUP(error, /* nothing */)
 
 // Wrap JVMTI functions in this in void functions that require cleanup.
 #define JVMTI_ERROR_CLEANUP(error, cleanup)      \
   {                                              \
-    int ernum;                                   \
-    if ((ernum = (error)) != JVMTI_ERROR_NONE) { \
+    int ernum = (error);                         \
+    JVMTI_TRACE(error, ernum);                   \
+    if (ernum != JVMTI_ERROR_NONE) {             \
       LOG(INFO) << "JVMTI error " << ernum;      \
       cleanup;                                   \
       return;                                    \
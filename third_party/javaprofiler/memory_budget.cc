@@ -0,0 +1,51 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "third_party/javaprofiler/memory_budget.h"
+
+namespace google {
+namespace javaprofiler {
+
+std::atomic<int64> MemoryBudget::limit_;
+std::atomic<int64> MemoryBudget::used_;
+std::atomic<int64> MemoryBudget::rejected_;
+
+void MemoryBudget::SetLimit(int64 bytes) { limit_ = bytes; }
+
+bool MemoryBudget::TryCharge(int64 bytes) {
+  int64 limit = limit_.load(std::memory_order_relaxed);
+  if (limit <= 0) {
+    used_ += bytes;
+    return true;
+  }
+  int64 used = used_.load(std::memory_order_relaxed);
+  do {
+    if (used + bytes > limit) {
+      rejected_++;
+      return false;
+    }
+  } while (!used_.compare_exchange_weak(used, used + bytes,
+                                        std::memory_order_relaxed));
+  return true;
+}
+
+void MemoryBudget::Release(int64 bytes) { used_ -= bytes; }
+
+bool MemoryBudget::NearLimit() {
+  int64 limit = limit_.load(std::memory_order_relaxed);
+  return limit > 0 && used_.load(std::memory_order_relaxed) > limit / 4 * 3;
+}
+
+}  // namespace javaprofiler
+}  // namespace google
@@ -0,0 +1,64 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef GOOGLE_JAVAPROFILER_MEMORY_BUDGET_H_
+#define GOOGLE_JAVAPROFILER_MEMORY_BUDGET_H_
+
+#include <atomic>
+
+#include "third_party/javaprofiler/globals.h"
+
+namespace google {
+namespace javaprofiler {
+
+// Accounting of the memory held by the agent's long-lived sample storage
+// against a configured limit. The storage charges its allocations before
+// making them and drops what does not fit, and sheds load once the use nears
+// the limit, so that the agent footprint stays bounded.
+class MemoryBudget {
+ public:
+  // Sets the limit in bytes. Zero, the default, means no limit.
+  static void SetLimit(int64 bytes);
+
+  // Charges the bytes to the budget. Returns false, charging nothing, if
+  // they would exceed the limit.
+  static bool TryCharge(int64 bytes);
+
+  // Releases bytes previously charged.
+  static void Release(int64 bytes);
+
+  // Returns true once the use is above three quarters of the limit, when the
+  // storage should shed load.
+  static bool NearLimit();
+
+  // Returns the number of bytes currently charged.
+  static int64 Used() { return used_; }
+
+  // Returns the number of charges rejected since the start.
+  static int64 RejectedCount() { return rejected_; }
+
+ private:
+  static std::atomic<int64> limit_;
+  static std::atomic<int64> used_;
+  static std::atomic<int64> rejected_;
+
+  DISALLOW_IMPLICIT_CONSTRUCTORS(MemoryBudget);
+};
+
+}  // namespace javaprofiler
+}  // namespace google
+
+#endif  // GOOGLE_JAVAPROFILER_MEMORY_BUDGET_H_
@@ -20,30 +20,111 @@
 
 #include "third_party/javaprofiler/native.h"
 
+#include <elf.h>
 #include <libgen.h>
 
 #include <cinttypes>
 #include <cstdio>
 #include <cstring>
 #include <memory>
+#include <unordered_map>
 
 namespace google {
 namespace javaprofiler {
 
+namespace {
+
+// Reads size bytes at the given offset of the file into buf.
+bool ReadAt(FILE *f, uint64_t offset, void *buf, size_t size) {
+  return fseek(f, offset, SEEK_SET) == 0 && fread(buf, 1, size, f) == size;
+}
+
+}  // namespace
+
+std::string BuildIdFromNotes(const char *notes, size_t size) {
+  size_t pos = 0;
+  while (pos + sizeof(Elf64_Nhdr) <= size) {
+    Elf64_Nhdr nhdr;
+    memcpy(&nhdr, notes + pos, sizeof(nhdr));
+    pos += sizeof(nhdr);
+    size_t name_size = (nhdr.n_namesz + 3) & ~3;
+    size_t desc_size = (nhdr.n_descsz + 3) & ~3;
+    if (pos + name_size + nhdr.n_descsz > size) {
+      break;
+    }
+    if (nhdr.n_type == NT_GNU_BUILD_ID && nhdr.n_namesz == 4 &&
+        memcmp(notes + pos, "GNU", 4) == 0) {
+      const unsigned char *desc =
+          reinterpret_cast<const unsigned char *>(notes + pos + name_size);
+      static const char kHexDigits[] = "0123456789abcdef";
+      std::string build_id;
+      for (size_t i = 0; i < nhdr.n_descsz; i++) {
+        build_id.push_back(kHexDigits[desc[i] >> 4]);
+        build_id.push_back(kHexDigits[desc[i] & 0xf]);
+      }
+      return build_id;
+    }
+    pos += name_size + desc_size;
+  }
+  return "";
+}
+
+std::string ReadElfBuildId(const std::string &filename) {
+  FILE *f = fopen(filename.c_str(), "rb");
+  if (f == nullptr) {
+    return "";
+  }
+
+  std::string build_id;
+  Elf64_Ehdr ehdr;
+  if (ReadAt(f, 0, &ehdr, sizeof(ehdr)) &&
+      memcmp(ehdr.e_ident, ELFMAG, SELFMAG) == 0 &&
+      ehdr.e_ident[EI_CLASS] == ELFCLASS64 &&
+      ehdr.e_shentsize == sizeof(Elf64_Shdr)) {
+    for (int i = 0; i < ehdr.e_shnum && build_id.empty(); i++) {
+      Elf64_Shdr shdr;
+      if (!ReadAt(f, ehdr.e_shoff + i * sizeof(shdr), &shdr, sizeof(shdr))) {
+        break;
+      }
+      // The build ID note is small, skip unreasonably large note sections.
+      if (shdr.sh_type != SHT_NOTE || shdr.sh_size > 4096) {
+        continue;
+      }
+      std::unique_ptr<char[]> notes(new char[shdr.sh_size]);
+      if (ReadAt(f, shdr.sh_offset, &notes[0], shdr.sh_size)) {
+        build_id = BuildIdFromNotes(&notes[0], shdr.sh_size);
+      }
+    }
+  }
+  fclose(f);
+  return build_id;
+}
+
 NativeProcessInfo::NativeProcessInfo(const std::string &procmaps_filename)
-    : procmaps_filename_(procmaps_filename) {
+    : procmaps_filename_(procmaps_filename),
+      available_(false),
+      unavailable_logged_(false) {
   Refresh();
 }
 
 void NativeProcessInfo::Refresh() {
   FILE *f = fopen(procmaps_filename_.c_str(), "r");
 
+  mappings_.clear();
+  available_ = f != nullptr;
   if (f == nullptr) {
-    LOG(ERROR) << "Could not open maps file: " << procmaps_filename_;
+    if (!unavailable_logged_) {
+      unavailable_logged_ = true;
+      LOG(WARNING) << "Could not open maps file: " << procmaps_filename_
+                   << ", the profiles will have no native mappings and the "
+                   << "native frames will show as [Native code]";
+    }
     return;
   }
 
-  mappings_.clear();
+  // A file usually has several executable mappings, look up its build ID
+  // once. Only the files still mapped are kept for the next refresh.
+  std::unordered_map<std::string, CachedBuildId> build_ids;
 
   const size_t kBufferLineSize = 2048;
   std::unique_ptr<char[]> line(new char[kBufferLineSize]);
@@ -76,10 +157,39 @@ void NativeProcessInfo::Refresh() {
 
     const char *filename = &line[filename_index];
     size_t filename_len = strcspn(filename, " \t\n");
+    std::string name(filename, filename_len);
+    auto build_id = build_ids.find(name);
+    if (build_id == build_ids.end()) {
+      build_id = build_ids.emplace(name, LookupBuildId(name)).first;
+    }
     mappings_.emplace_back(
-        Mapping{start, limit, std::string(filename, filename_len)});
+        Mapping{start, limit, name, build_id->second.build_id});
   }
   fclose(f);
+  build_ids_.swap(build_ids);
+}
+
+NativeProcessInfo::CachedBuildId NativeProcessInfo::LookupBuildId(
+    const std::string &name) const {
+  CachedBuildId entry = {};
+  struct stat st;
+  if (stat(name.c_str(), &st) != 0) {
+    // E.g. [vdso] or a deleted file, there is nothing to read.
+    return entry;
+  }
+  entry.device = st.st_dev;
+  entry.inode = st.st_ino;
+  entry.mtime = st.st_mtim;
+  auto cached = build_ids_.find(name);
+  if (cached != build_ids_.end() && cached->second.device == entry.device &&
+      cached->second.inode == entry.inode &&
+      cached->second.mtime.tv_sec == entry.mtime.tv_sec &&
+      cached->second.mtime.tv_nsec == entry.mtime.tv_nsec) {
+    entry.build_id = cached->second.build_id;
+  } else {
+    entry.build_id = ReadElfBuildId(name);
+  }
+  return entry;
 }
 
 }  // namespace javaprofiler
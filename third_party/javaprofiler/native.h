@@ -18,7 +18,11 @@
 #define CLOUD_PROFILER_AGENT_JAVA_NATIVE_H_
 
 #include <stdint.h>
+#include <sys/stat.h>
+#include <time.h>
 
+#include <string>
+#include <unordered_map>
 #include <vector>
 
 #include "third_party/javaprofiler/globals.h"
@@ -36,17 +40,52 @@ class NativeProcessInfo {
   struct Mapping {
     uint64 start, limit;
     std::string name;
+    // Hex-encoded GNU build ID of the mapped file, empty if unavailable.
+    std::string build_id;
   };
 
+  // Re-reads the mappings. The build IDs are only read from the files which
+  // changed since the previous refresh.
   void Refresh();
   const std::vector<Mapping> &Mappings() const { return mappings_; }
 
+  // Returns false if the maps file could not be read at the last refresh,
+  // e.g. on non-Linux systems or in restricted containers. The mappings are
+  // then empty.
+  bool Available() const { return available_; }
+
  private:
+  // Build ID of a mapped file, with the identity of the file it was read from.
+  struct CachedBuildId {
+    dev_t device;
+    ino_t inode;
+    struct timespec mtime;
+    std::string build_id;
+  };
+
+  // Returns the build ID of the given file, reusing the one of the previous
+  // refresh if the file has the same device, inode and modification time.
+  CachedBuildId LookupBuildId(const std::string &name) const;
+
   const std::string procmaps_filename_;
   std::vector<Mapping> mappings_;
+  // Build IDs of the files mapped at the last refresh, by path.
+  std::unordered_map<std::string, CachedBuildId> build_ids_;
+  bool available_;
+  // The unavailable maps file is only reported once.
+  bool unavailable_logged_;
   DISALLOW_COPY_AND_ASSIGN(NativeProcessInfo);
 };
 
+// Returns the hex-encoded build ID stored in the .note.gnu.build-id section
+// of the given ELF file, or an empty string if the file can't be read, is not
+// a 64-bit ELF file or has no build ID.
+std::string ReadElfBuildId(const std::string &filename);
+
+// Returns the hex-encoded descriptor of the NT_GNU_BUILD_ID note in the given
+// note section contents, or an empty string if there is none.
+std::string BuildIdFromNotes(const char *notes, size_t size);
+
 }  // namespace javaprofiler
 }  // namespace google
 
@@ -0,0 +1,194 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "third_party/javaprofiler/native.h"
+
+#include <elf.h>
+#include <fcntl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <unistd.h>
+
+#include <string>
+
+#include "gtest/gtest.h"
+
+namespace google {
+namespace javaprofiler {
+namespace {
+
+// Returns a note with the given type, name and descriptor, padded as in the
+// ELF note sections.
+std::string Note(uint32_t type, const std::string &name,
+                 const std::string &desc) {
+  Elf64_Nhdr nhdr;
+  nhdr.n_namesz = name.size() + 1;
+  nhdr.n_descsz = desc.size();
+  nhdr.n_type = type;
+  std::string note(reinterpret_cast<const char *>(&nhdr), sizeof(nhdr));
+  note += name;
+  note.resize((note.size() + 1 + 3) & ~3, '\0');
+  note += desc;
+  note.resize((note.size() + 3) & ~3, '\0');
+  return note;
+}
+
+std::string GnuBuildIdNote(const std::string &desc) {
+  return Note(NT_GNU_BUILD_ID, "GNU", desc);
+}
+
+// Returns a 64-bit ELF file with a single note section.
+std::string ElfWithNotes(const std::string &notes) {
+  Elf64_Ehdr ehdr;
+  memset(&ehdr, 0, sizeof(ehdr));
+  memcpy(ehdr.e_ident, ELFMAG, SELFMAG);
+  ehdr.e_ident[EI_CLASS] = ELFCLASS64;
+  ehdr.e_shentsize = sizeof(Elf64_Shdr);
+  ehdr.e_shnum = 2;
+  ehdr.e_shoff = (sizeof(ehdr) + notes.size() + 7) & ~7;
+
+  Elf64_Shdr shdrs[2];
+  memset(shdrs, 0, sizeof(shdrs));
+  shdrs[1].sh_type = SHT_NOTE;
+  shdrs[1].sh_offset = sizeof(ehdr);
+  shdrs[1].sh_size = notes.size();
+
+  std::string elf(reinterpret_cast<const char *>(&ehdr), sizeof(ehdr));
+  elf += notes;
+  elf.resize(ehdr.e_shoff, '\0');
+  elf.append(reinterpret_cast<const char *>(shdrs), sizeof(shdrs));
+  return elf;
+}
+
+TEST(BuildIdFromNotesTest, ReturnsTheHexEncodedBuildId) {
+  std::string notes = GnuBuildIdNote("\x01\x23\xab\xff");
+  EXPECT_EQ("0123abff", BuildIdFromNotes(notes.data(), notes.size()));
+}
+
+TEST(BuildIdFromNotesTest, SkipsTheOtherNotes) {
+  std::string notes = Note(NT_GNU_ABI_TAG, "GNU", std::string(16, '\0')) +
+                      Note(NT_GNU_BUILD_ID, "Go", "\x11\x22") +
+                      GnuBuildIdNote("\xde\xad\xbe\xef\x42");
+  EXPECT_EQ("deadbeef42", BuildIdFromNotes(notes.data(), notes.size()));
+}
+
+TEST(BuildIdFromNotesTest, ReturnsEmptyWithoutBuildId) {
+  EXPECT_EQ("", BuildIdFromNotes("", 0));
+  std::string notes = Note(NT_GNU_ABI_TAG, "GNU", std::string(16, '\0'));
+  EXPECT_EQ("", BuildIdFromNotes(notes.data(), notes.size()));
+}
+
+TEST(BuildIdFromNotesTest, ReturnsEmptyOnTruncatedNotes) {
+  std::string notes = GnuBuildIdNote("\x01\x23\x45\x67\x89\xab\xcd\xef");
+  EXPECT_EQ("", BuildIdFromNotes(notes.data(), notes.size() - 4));
+  EXPECT_EQ("", BuildIdFromNotes(notes.data(), sizeof(Elf64_Nhdr) - 1));
+}
+
+class NativeProcessInfoTest : public ::testing::Test {
+ protected:
+  void SetUp() override {
+    char dir[] = "/tmp/native_testXXXXXX";
+    ASSERT_NE(nullptr, mkdtemp(dir));
+    dir_ = dir;
+    lib_path_ = dir_ + "/libfoo.so";
+    maps_path_ = dir_ + "/maps";
+    WriteFile(maps_path_,
+              "7f0000000000-7f0000001000 r-xp 00000000 08:01 42 " + lib_path_ +
+                  "\n"
+                  "7f0000002000-7f0000003000 r--p 00000000 08:01 42 " +
+                  lib_path_ + "\n");
+  }
+
+  void TearDown() override {
+    unlink(lib_path_.c_str());
+    unlink(maps_path_.c_str());
+    rmdir(dir_.c_str());
+  }
+
+  void WriteFile(const std::string &path, const std::string &content) {
+    FILE *f = fopen(path.c_str(), "w");
+    ASSERT_NE(nullptr, f);
+    fwrite(content.data(), 1, content.size(), f);
+    fclose(f);
+  }
+
+  void WriteLib(const std::string &build_id) {
+    WriteFile(lib_path_, ElfWithNotes(GnuBuildIdNote(build_id)));
+  }
+
+  // Sets the modification time of the library, in seconds since the epoch.
+  void SetLibMtime(time_t sec) {
+    struct timespec times[2] = {{sec, 0}, {sec, 0}};
+    ASSERT_EQ(0, utimensat(AT_FDCWD, lib_path_.c_str(), times, 0));
+  }
+
+  std::string dir_;
+  std::string lib_path_;
+  std::string maps_path_;
+};
+
+TEST_F(NativeProcessInfoTest, ReadsTheBuildIdsOfTheExecutableMappings) {
+  WriteLib("\xca\xfe");
+  NativeProcessInfo info(maps_path_);
+  ASSERT_TRUE(info.Available());
+  ASSERT_EQ(1u, info.Mappings().size());
+  EXPECT_EQ(lib_path_, info.Mappings()[0].name);
+  EXPECT_EQ("cafe", info.Mappings()[0].build_id);
+}
+
+TEST_F(NativeProcessInfoTest, CachesTheBuildIdsOfTheUnchangedFiles) {
+  WriteLib("\xca\xfe");
+  SetLibMtime(1000);
+  NativeProcessInfo info(maps_path_);
+  ASSERT_EQ(1u, info.Mappings().size());
+  EXPECT_EQ("cafe", info.Mappings()[0].build_id);
+
+  // Same file and modification time, the build ID is not read again.
+  WriteLib("\xbe\xef");
+  SetLibMtime(1000);
+  info.Refresh();
+  ASSERT_EQ(1u, info.Mappings().size());
+  EXPECT_EQ("cafe", info.Mappings()[0].build_id);
+
+  // The file changed.
+  SetLibMtime(2000);
+  info.Refresh();
+  ASSERT_EQ(1u, info.Mappings().size());
+  EXPECT_EQ("beef", info.Mappings()[0].build_id);
+}
+
+TEST_F(NativeProcessInfoTest, RereadsTheReplacedFiles) {
+  WriteLib("\xca\xfe");
+  SetLibMtime(1000);
+  NativeProcessInfo info(maps_path_);
+  ASSERT_EQ(1u, info.Mappings().size());
+  EXPECT_EQ("cafe", info.Mappings()[0].build_id);
+
+  // A new file with the same modification time has another inode.
+  std::string tmp_path = dir_ + "/libfoo.so.new";
+  WriteFile(tmp_path, ElfWithNotes(GnuBuildIdNote("\xbe\xef")));
+  ASSERT_EQ(0, rename(tmp_path.c_str(), lib_path_.c_str()));
+  SetLibMtime(1000);
+  info.Refresh();
+  ASSERT_EQ(1u, info.Mappings().size());
+  EXPECT_EQ("beef", info.Mappings()[0].build_id);
+}
+
+}  // namespace
+}  // namespace javaprofiler
+}  // namespace google
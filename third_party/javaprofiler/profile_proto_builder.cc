@@ -44,6 +44,9 @@ ProfileProtoBuilder::ProfileProtoBuilder(JNIEnv *jni_env, jvmtiEnv *jvmti_env,
   AddSampleType(count_type);
   AddSampleType(metric_type);
   SetPeriodType(metric_type);
+  // Have the viewers open the profile on the metric rather than the count.
+  builder_.mutable_profile()->set_default_sample_type(
+      builder_.StringId(metric_type.type.c_str()));
 }
 
 void ProfileProtoBuilder::AddTraces(const ProfileStackTrace *traces,
@@ -221,13 +224,17 @@ int64 ProfileProtoBuilder::Location(MethodInfo *method,
     return location_id;
   }
 
-  int line_number = GetLineNumber(jvmti_env_, frame.method_id, bci);
+  // The line of a redacted method would tell about it.
+  int line_number = method->MethodName() == kRedactedFrameName
+                        ? 0
+                        : GetLineNumber(jvmti_env_, frame.method_id, bci);
 
   perftools::profiles::Location *location =
       location_builder_.LocationFor(method->ClassName(),
                                     method->MethodName(),
                                     method->FileName(),
-                                    line_number);
+                                    line_number,
+                                    method->StartLine());
 
   method->AddLocation(bci, location->id());
   return location->id();
@@ -249,11 +256,19 @@ MethodInfo *ProfileProtoBuilder::Method(jmethodID method_id) {
   GetStackFrameElements(jni_env_, jvmti_env_, jvm_frame, &file_name,
                         &class_name, &method_name, &signature, nullptr);
 
-  FixMethodParameters(&signature);
-  std::string full_method_name = class_name + "." + method_name + signature;
-
-  std::unique_ptr<MethodInfo> unique_method(
-      new MethodInfo(full_method_name, class_name, file_name));
+  std::unique_ptr<MethodInfo> unique_method;
+  if (IsRedactedClass(class_name)) {
+    unique_method.reset(new MethodInfo(kRedactedFrameName, "", ""));
+  } else {
+    FixMethodParameters(&signature);
+    if (AnonymizedSymbols()) {
+      AnonymizeFrame(&class_name, &method_name, &signature, &file_name);
+    }
+    std::string full_method_name = class_name + "." + method_name + signature;
+    unique_method.reset(
+        new MethodInfo(full_method_name, class_name, file_name,
+                       GetMethodStartLine(jvmti_env_, method_id)));
+  }
 
   auto method_ptr = unique_method.get();
   methods_[method_id] = std::move(unique_method);
@@ -331,7 +346,7 @@ bool LocationBuilder::LocationInfoEquals::operator()(
 
 perftools::profiles::Location *LocationBuilder::LocationFor(
     const std::string &class_name, const std::string &function_name,
-    const std::string &file_name, int line_number) {
+    const std::string &file_name, int line_number, int start_line) {
   auto profile = builder_->mutable_profile();
 
   LocationInfo info{ class_name, function_name, file_name, line_number };
@@ -351,7 +366,8 @@ perftools::profiles::Location *LocationBuilder::LocationFor(
   std::string simplified_name = function_name;
   SimplifyFunctionName(&simplified_name);
   auto function_id = builder_->FunctionId(
-      simplified_name.c_str(), function_name.c_str(), file_name.c_str(), 0);
+      simplified_name.c_str(), function_name.c_str(), file_name.c_str(),
+      start_line);
 
   line->set_function_id(function_id);
   line->set_line(line_number);
@@ -20,6 +20,7 @@
 #include <jvmti.h>
 #include <link.h>
 
+#include <algorithm>
 #include <cmath>
 #include <memory>
 #include <string>
@@ -164,11 +165,13 @@ class LocationBuilder {
 
   // Return an existing or new location matching the given parameters,
   // modifying the profile as needed to add new function and location
-  // information.
+  // information. The start line is the first line of the function, 0 when
+  // unknown.
   perftools::profiles::Location *LocationFor(const std::string &class_name,
                                              const std::string &function_name,
                                              const std::string &file_name,
-                                             int line_number);
+                                             int line_number,
+                                             int start_line = 0);
 
  private:
   struct LocationInfo {
@@ -273,6 +276,9 @@ class ProfileProtoBuilder {
     return false;
   }
 
+  // Sets the profile period type, the metric type unless overridden.
+  void SetPeriodType(const SampleType &metric_type);
+
   // An implementation must decide how many frames to skip in a trace.
   virtual int SkipTopNativeFrames(const JVMPI_CallTrace &trace) = 0;
 
@@ -326,7 +332,6 @@ class ProfileProtoBuilder {
   };
 
   void AddSampleType(const SampleType &sample_type);
-  void SetPeriodType(const SampleType &metric_type);
   void InitSampleValues(perftools::profiles::Sample *sample, int64 count,
                         int64 metric);
   void UpdateSampleValues(perftools::profiles::Sample *sample, int64 count,
@@ -405,7 +410,12 @@ class HeapProfileProtoBuilder : public ProfileProtoBuilder {
       : ProfileProtoBuilder(
             jni_env, jvmti_env, cache, sampling_rate,
             ProfileProtoBuilder::SampleType("inuse_objects", "count"),
-            ProfileProtoBuilder::SampleType("inuse_space", "bytes")) {}
+            ProfileProtoBuilder::SampleType("inuse_space", "bytes")) {
+    // An object is sampled every sampling_rate allocated bytes on average.
+    // Sampling every allocation has a zero rate, the period must be positive.
+    SetPeriodType(ProfileProtoBuilder::SampleType("space", "bytes"));
+    builder_.mutable_profile()->set_period(std::max<int64>(sampling_rate, 1));
+  }
 
   std::unique_ptr<perftools::profiles::Profile> CreateProto() override {
     return CreateUnsampledProto();
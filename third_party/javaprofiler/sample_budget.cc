@@ -0,0 +1,94 @@
+// Copyright 2018 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#include "third_party/javaprofiler/sample_budget.h"
+
+#include <time.h>
+
+#include "third_party/javaprofiler/clock.h"
+
+namespace google {
+namespace javaprofiler {
+
+std::atomic<int64> SampleBudget::rate_;
+std::atomic<int64> SampleBudget::tokens_;
+std::atomic<int64> SampleBudget::last_refill_ns_;
+std::atomic<int64> SampleBudget::dropped_;
+
+namespace {
+
+int64 NowNanos() {
+  // clock_gettime is async-signal-safe.
+  struct timespec now;
+  clock_gettime(CLOCK_MONOTONIC, &now);
+  return TimeSpecToNanos(now);
+}
+
+}  // namespace
+
+void SampleBudget::SetRate(int64 samples_per_sec) {
+  tokens_ = samples_per_sec;
+  last_refill_ns_ = NowNanos();
+  rate_ = samples_per_sec;
+}
+
+void SampleBudget::Refill(int64 rate, int64 now_ns) {
+  int64 last_ns = last_refill_ns_.load(std::memory_order_relaxed);
+  int64 elapsed_ns = now_ns - last_ns;
+  int64 accrued, next_ns;
+  if (elapsed_ns >= kNanosPerSecond) {
+    // The bucket is full again.
+    accrued = rate;
+    next_ns = now_ns;
+  } else {
+    accrued = elapsed_ns * rate / kNanosPerSecond;
+    if (accrued <= 0) {
+      return;
+    }
+    // Advance by the time worth of the accrued tokens to not lose the
+    // fractional part.
+    next_ns = last_ns + accrued * kNanosPerSecond / rate;
+  }
+  // Only the caller winning the race adds the tokens.
+  if (!last_refill_ns_.compare_exchange_strong(last_ns, next_ns,
+                                               std::memory_order_relaxed)) {
+    return;
+  }
+  int64 tokens = tokens_.load(std::memory_order_relaxed);
+  int64 refilled;
+  do {
+    refilled = tokens + accrued < rate ? tokens + accrued : rate;
+  } while (!tokens_.compare_exchange_weak(tokens, refilled,
+                                          std::memory_order_relaxed));
+}
+
+bool SampleBudget::TryAcquire() {
+  int64 rate = rate_.load(std::memory_order_relaxed);
+  if (rate <= 0) {
+    return true;
+  }
+  Refill(rate, NowNanos());
+  int64 tokens = tokens_.load(std::memory_order_relaxed);
+  do {
+    if (tokens <= 0) {
+      dropped_++;
+      return false;
+    }
+  } while (!tokens_.compare_exchange_weak(tokens, tokens - 1,
+                                          std::memory_order_relaxed));
+  return true;
+}
+
+}  // namespace javaprofiler
+}  // namespace google
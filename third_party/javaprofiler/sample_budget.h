@@ -0,0 +1,58 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef GOOGLE_JAVAPROFILER_SAMPLE_BUDGET_H_
+#define GOOGLE_JAVAPROFILER_SAMPLE_BUDGET_H_
+
+#include <atomic>
+
+#include "third_party/javaprofiler/globals.h"
+
+namespace google {
+namespace javaprofiler {
+
+// Token bucket shared by all the samplers, bounding the combined number of
+// samples recorded per second across the profile types. It holds at most one
+// second worth of tokens, which is the largest allowed burst.
+class SampleBudget {
+ public:
+  // Sets the number of samples allowed per second. Zero, the default, means
+  // no limit.
+  static void SetRate(int64 samples_per_sec);
+
+  // Takes a token for a sample. Returns false if the budget is exhausted, in
+  // which case the sample should be dropped. Async-signal-safe.
+  static bool TryAcquire();
+
+  // Returns the number of samples dropped since the start.
+  static int64 DroppedCount() { return dropped_; }
+
+ private:
+  // Adds the tokens accrued since the last refill.
+  static void Refill(int64 rate, int64 now_ns);
+
+  static std::atomic<int64> rate_;
+  static std::atomic<int64> tokens_;
+  static std::atomic<int64> last_refill_ns_;
+  static std::atomic<int64> dropped_;
+
+  DISALLOW_IMPLICIT_CONSTRUCTORS(SampleBudget);
+};
+
+}  // namespace javaprofiler
+}  // namespace google
+
+#endif  // GOOGLE_JAVAPROFILER_SAMPLE_BUDGET_H_
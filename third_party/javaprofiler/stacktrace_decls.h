@@ -118,6 +118,9 @@ enum CallTraceErrors {
   kNoAgentTracingFunction = -25,
   // The client passed in a nullptr trace or ucontext argument.
   kNullArgument = -26,
+  // Not an error: the frame between the stack of an async task and the stack
+  // of the thread that submitted it.
+  kAsyncBoundary = -27,
 };
 
 // Maximum absolute value of the error code we expect
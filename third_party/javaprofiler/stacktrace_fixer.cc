@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#include <stdint.h>
+#include <stdio.h>
 #include <string.h>
 
 #include <algorithm>
@@ -51,37 +53,46 @@ void SimplifyDynamicClassName(std::string *name) {
   SimplifySuffixedName(name, "$$", "0123456789abcdef");
 }
 
-// Simplifies the name of a lambda method to replace $$Lambda$[0-9]+\.[0-9]+ by
-// $$Lambda$ to remove unique values, for example in
-// com.google.something.Something$$Lambda$197.1849072452.run.
+// Simplifies the name of a lambda method to replace the unique values
+// following $$Lambda by a single $. The format of the generated class name
+// differs across JVMs, for example:
+//   com.google.something.Something$$Lambda$197.1849072452.run (JDK 8)
+//   com.google.something.Something$$Lambda$14.0x0000000800c02a00.run (JDK 15)
+//   com.google.something.Something$$Lambda.0x0000000800c02a00.run (JDK 21)
+// all become com.google.something.Something$$Lambda$.run.
 void SimplifyLambdaName(std::string *name) {
-  constexpr char trigger[] = "$$Lambda$";
+  constexpr char trigger[] = "$$Lambda";
   constexpr char digits[] = "0123456789";
+  constexpr char hex_digits[] = "0123456789abcdef";
   const size_t trigger_length = strlen(trigger);
 
-  // Assume and handle just one instance of a $$Lambda$ pattern.
+  // Assume and handle just one instance of a $$Lambda pattern.
   size_t first = name->find(trigger);
   if (first == std::string::npos) {
     return;
   }
   first += trigger_length;
-  if (first >= name->size() || !isdigit((*name)[first])) {
-    return;
+  size_t last = first;
+  // The $<counter> part is not present in newer JVMs.
+  if (last < name->size() && (*name)[last] == '$') {
+    last = name->find_first_not_of(digits, last + 1);
   }
-  size_t last = name->find_first_not_of(digits, first);
   if (last == std::string::npos || (*name)[last] != '.') {
     return;
   }
   last++;  // skip the dot
-  if (last >= name->size() || !isdigit((*name)[last])) {
+  if (name->compare(last, 2, "0x") == 0) {
+    last = name->find_first_not_of(hex_digits, last + 2);
+  } else if (last < name->size() && isdigit((*name)[last])) {
+    last = name->find_first_not_of(digits, last);
+  } else {
     return;
   }
-  last = name->find_first_not_of(digits, last);
   if (last == std::string::npos) {
-    name->erase(first);
+    name->replace(first, std::string::npos, "$");
     return;
   }
-  name->erase(first, last - first);
+  name->replace(first, last - first, "$");
 }
 
 constexpr char digits[] = "0123456789";
@@ -262,6 +273,29 @@ void SimplifyFunctionName(std::string *name) {
   SimplifyInternalReflectionMethodName(name);
 }
 
+void CollapseLambdaMethodName(std::string *name) {
+  constexpr char trigger[] = "lambda$";
+  constexpr char digits[] = "0123456789";
+
+  size_t first = name->find(trigger);
+  if (first == std::string::npos) {
+    return;
+  }
+  // Look for the $<index> suffix javac appends to the enclosing method name,
+  // as in lambda$main$0.
+  first = name->find('$', first + strlen(trigger));
+  if (first == std::string::npos || first + 1 >= name->size() ||
+      !isdigit((*name)[first + 1])) {
+    return;
+  }
+  size_t last = name->find_first_not_of(digits, first + 1);
+  if (last == std::string::npos) {
+    name->erase(first);
+    return;
+  }
+  name->erase(first, last - first);
+}
+
 void FixPath(std::string *s) { std::replace(s->begin(), s->end(), '/', '.'); }
 
 void PrettyPrintSignature(std::string *s) {
@@ -271,6 +305,74 @@ void PrettyPrintSignature(std::string *s) {
   *s = result;
 }
 
+const char kRedactedFrameName[] = "<redacted>";
+
+namespace {
+
+// Package name prefixes, including the trailing '.'.
+std::vector<std::string> *RedactedPackagePrefixes() {
+  static std::vector<std::string> *prefixes = new std::vector<std::string>();
+  return prefixes;
+}
+
+// Salt of the anonymized names, null when the symbols are not anonymized.
+std::string *anonymize_salt = nullptr;
+
+}  // namespace
+
+void SetRedactedPackages(const std::vector<std::string> &packages) {
+  std::vector<std::string> *prefixes = RedactedPackagePrefixes();
+  prefixes->clear();
+  for (const std::string &package : packages) {
+    if (!package.empty()) {
+      prefixes->push_back(package + ".");
+    }
+  }
+}
+
+bool IsRedactedClass(const std::string &class_name) {
+  for (const std::string &prefix : *RedactedPackagePrefixes()) {
+    if (class_name.compare(0, prefix.length(), prefix) == 0) {
+      return true;
+    }
+  }
+  return false;
+}
+
+void SetAnonymizedSymbols(const std::string &salt) {
+  delete anonymize_salt;
+  anonymize_salt = new std::string(salt);
+}
+
+bool AnonymizedSymbols() { return anonymize_salt != nullptr; }
+
+std::string AnonymizedName(const char *prefix, const std::string &name) {
+  // FNV-1a, stable across runs and platforms.
+  uint64_t hash = 14695981039346656037ULL;
+  std::string salt = anonymize_salt != nullptr ? *anonymize_salt : "";
+  for (const std::string &s : {salt, name}) {
+    for (unsigned char c : s) {
+      hash = (hash ^ c) * 1099511628211ULL;
+    }
+  }
+  char hex[17];
+  snprintf(hex, sizeof(hex), "%016llx",
+           static_cast<unsigned long long>(hash));  // NOLINT(runtime/int)
+  return prefix + std::string(hex);
+}
+
+void AnonymizeFrame(std::string *class_name, std::string *method_name,
+                    std::string *signature, std::string *file_name) {
+  *class_name = AnonymizedName("C", *class_name);
+  *method_name = AnonymizedName("m", *method_name);
+  if (!signature->empty()) {
+    *signature = "(" + AnonymizedName("s", *signature) + ")";
+  }
+  if (!file_name->empty()) {
+    *file_name = AnonymizedName("F", *file_name);
+  }
+}
+
 void FixMethodParameters(std::string *signature) {
   if (signature == nullptr || signature->empty() ||
       signature->at(0) != '(') {
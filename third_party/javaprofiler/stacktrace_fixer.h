@@ -20,6 +20,9 @@
 #ifndef THIRD_PARTY_JAVAPROFILER_STACKTRACE_FIXER_H_
 #define THIRD_PARTY_JAVAPROFILER_STACKTRACE_FIXER_H_
 
+#include <string>
+#include <vector>
+
 #include "third_party/javaprofiler/globals.h"
 
 namespace google {
@@ -28,6 +31,11 @@ namespace javaprofiler {
   // related functions under a single name.
 void SimplifyFunctionName(std::string *name);
 
+// Collapses the synthetic methods javac generates for the lambdas of a single
+// enclosing method into one name by dropping their index, e.g.
+// "Foo.lambda$main$0" and "Foo.lambda$main$1" both become "Foo.lambda$main".
+void CollapseLambdaMethodName(std::string *name);
+
 // Fix the parameter signature from a JVM type signature to a pretty-print
 // one.
 void FixMethodParameters(std::string *signature);
@@ -37,6 +45,35 @@ void FixPath(std::string *s);
 
 // Pretty-prints a JVM type signature.
 void PrettyPrintSignature(std::string *s);
+
+// Name replacing the frames of the redacted classes in the profiles.
+extern const char kRedactedFrameName[];
+
+// Sets the Java packages, e.g. "com.example.secrets", whose frames are
+// redacted from the profiles. Not thread-safe, meant to be called once at
+// startup.
+void SetRedactedPackages(const std::vector<std::string> &packages);
+
+// Returns whether the class, given as "pkg.name.class", belongs to one of the
+// redacted packages or their subpackages.
+bool IsRedactedClass(const std::string &class_name);
+
+// Enables the anonymization of the symbols in all profiles, hashed with the
+// salt. Not thread-safe, meant to be called once at startup.
+void SetAnonymizedSymbols(const std::string &salt);
+
+// Returns whether the symbols are anonymized.
+bool AnonymizedSymbols();
+
+// Returns the prefix followed by the hex 64-bit FNV-1a hash of the salted
+// name. The hash is stable across runs and agent builds, so that whoever
+// knows the salt can map the known names to their hashes.
+std::string AnonymizedName(const char *prefix, const std::string &name);
+
+// Replaces the names of a Java frame with their salted hashes. The signature,
+// pretty-printed by FixMethodParameters, keeps its parentheses.
+void AnonymizeFrame(std::string *class_name, std::string *method_name,
+                    std::string *signature, std::string *file_name);
 }  // namespace javaprofiler
 }  // namespace google
 
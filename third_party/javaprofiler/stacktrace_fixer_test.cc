@@ -0,0 +1,79 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "third_party/javaprofiler/stacktrace_fixer.h"
+
+#include <string>
+
+#include "gtest/gtest.h"
+
+namespace google {
+namespace javaprofiler {
+namespace {
+
+std::string Simplified(std::string name) {
+  SimplifyFunctionName(&name);
+  return name;
+}
+
+std::string Collapsed(std::string name) {
+  CollapseLambdaMethodName(&name);
+  return name;
+}
+
+TEST(StacktraceFixerTest, SimplifiesTheLambdaNamesOfAllJvms) {
+  const std::string expected = "com.google.something.Something$$Lambda$.run";
+  // JDK 8.
+  EXPECT_EQ(expected, Simplified("com.google.something.Something$$Lambda$197"
+                                 ".1849072452.run"));
+  // JDK 15.
+  EXPECT_EQ(expected, Simplified("com.google.something.Something$$Lambda$14"
+                                 ".0x0000000800c02a00.run"));
+  // JDK 21.
+  EXPECT_EQ(expected, Simplified("com.google.something.Something$$Lambda"
+                                 ".0x0000000800c02a00.run"));
+}
+
+TEST(StacktraceFixerTest, SimplifiesTheLambdaNamesWithoutMethod) {
+  EXPECT_EQ("Foo$$Lambda$", Simplified("Foo$$Lambda$197.1849072452"));
+  EXPECT_EQ("Foo$$Lambda$", Simplified("Foo$$Lambda.0x0000000800c02a00"));
+}
+
+TEST(StacktraceFixerTest, KeepsTheNamesWhichAreNotLambdas) {
+  EXPECT_EQ("Foo.run", Simplified("Foo.run"));
+  EXPECT_EQ("Foo$$LambdaFactory.run", Simplified("Foo$$LambdaFactory.run"));
+  // No unique value follows the counter.
+  EXPECT_EQ("Foo$$Lambda$197.run", Simplified("Foo$$Lambda$197.run"));
+}
+
+TEST(StacktraceFixerTest, CollapsesTheLambdaMethodIndex) {
+  EXPECT_EQ("Foo.lambda$main", Collapsed("Foo.lambda$main$0"));
+  EXPECT_EQ("Foo.lambda$main", Collapsed("Foo.lambda$main$12"));
+  EXPECT_EQ("Foo.lambda$new", Collapsed("Foo.lambda$new$3"));
+  EXPECT_EQ("Foo.lambda$static", Collapsed("Foo.lambda$static$1"));
+}
+
+TEST(StacktraceFixerTest, KeepsTheMethodsWithoutLambdaIndex) {
+  EXPECT_EQ("Foo.main", Collapsed("Foo.main"));
+  EXPECT_EQ("Foo.main$0", Collapsed("Foo.main$0"));
+  EXPECT_EQ("Foo.lambda$main", Collapsed("Foo.lambda$main"));
+  EXPECT_EQ("Foo.lambda$main$", Collapsed("Foo.lambda$main$"));
+  EXPECT_EQ("Foo.lambda$main$bar", Collapsed("Foo.lambda$main$bar"));
+}
+
+}  // namespace
+}  // namespace javaprofiler
+}  // namespace google
@@ -14,6 +14,8 @@
 
 #include "third_party/javaprofiler/stacktraces.h"
 
+#include <algorithm>
+
 namespace google {
 namespace javaprofiler {
 
@@ -23,8 +25,10 @@ std::mutex *AttributeTable::mutex_;
 std::unordered_map<std::string, int> *AttributeTable::string_map_;
 std::vector<std::string> *AttributeTable::strings_;
 
-bool AsyncSafeTraceMultiset::Add(int attr, JVMPI_CallTrace *trace) {
-  uint64 hash_val = CalculateHash(attr, trace->num_frames, &trace->frames[0]);
+bool AsyncSafeTraceMultiset::Add(int attr, const SampleContext &context,
+                                 JVMPI_CallTrace *trace) {
+  uint64 hash_val =
+      CalculateHash(attr, context, trace->num_frames, &trace->frames[0]);
 
   for (int64 i = 0; i < MaxEntries(); i++) {
     int64 idx = (i + hash_val) % MaxEntries();
@@ -49,6 +53,7 @@ bool AsyncSafeTraceMultiset::Add(int attr, JVMPI_CallTrace *trace) {
           entry.trace.frames = fb;
           entry.trace.num_frames = num_frames;
           entry.attr = attr;
+          entry.context = context;
           entry.count.store(static_cast<int64>(1), std::memory_order_release);
           return true;
         }
@@ -58,7 +63,8 @@ bool AsyncSafeTraceMultiset::Add(int attr, JVMPI_CallTrace *trace) {
         // Worst case we may end with multiple entries with the same trace.
         break;
       default:
-        if (attr == entry.attr && trace->num_frames == entry.trace.num_frames &&
+        if (attr == entry.attr && context == entry.context &&
+            trace->num_frames == entry.trace.num_frames &&
             Equal(trace->num_frames, entry.trace.frames, trace->frames)) {
           // Bump using a compare-swap instead of fetch_add to ensure
           // it hasn't been locked by a thread doing Extract().
@@ -80,7 +86,8 @@ bool AsyncSafeTraceMultiset::Add(int attr, JVMPI_CallTrace *trace) {
   return false;
 }
 
-int AsyncSafeTraceMultiset::Extract(int location, int64 *attr, int max_frames,
+int AsyncSafeTraceMultiset::Extract(int location, int64 *attr,
+                                    SampleContext *context, int max_frames,
                                     JVMPI_CallFrame *frames, int64 *count) {
   if (location < 0 || location >= MaxEntries()) {
     return 0;
@@ -99,6 +106,7 @@ int AsyncSafeTraceMultiset::Extract(int location, int64 *attr, int max_frames,
   c = entry.count.exchange(kTraceCountLocked, std::memory_order_acquire);
 
   *attr = entry.attr;
+  *context = entry.context;
   for (int i = 0; i < num_frames; ++i) {
     frames[i].lineno = entry.trace.frames[i].lineno;
     frames[i].method_id = entry.trace.frames[i].method_id;
@@ -115,10 +123,11 @@ int AsyncSafeTraceMultiset::Extract(int location, int64 *attr, int max_frames,
   return num_frames;
 }
 
-void TraceMultiset::Add(int64 attr, int num_frames, JVMPI_CallFrame *frames,
-                        int64 count) {
+void TraceMultiset::Add(int64 attr, const SampleContext &context,
+                        int num_frames, JVMPI_CallFrame *frames, int64 count) {
   CallTrace t;
   t.attr = attr;
+  t.context = context;
   t.frames = std::vector<JVMPI_CallFrame>(frames, frames + num_frames);
 
   auto entry = traces_.find(t);
@@ -135,23 +144,80 @@ int HarvestSamples(AsyncSafeTraceMultiset *from, TraceMultiset *to) {
   for (int64 i = 0; i < num_traces; i++) {
     JVMPI_CallFrame frame[kMaxFramesToCapture];
     int64 attr, count;
+    SampleContext context;
 
-    int num_frames =
-        from->Extract(i, &attr, kMaxFramesToCapture, &frame[0], &count);
+    int num_frames = from->Extract(i, &attr, &context, kMaxFramesToCapture,
+                                   &frame[0], &count);
     if (num_frames > 0 && count > 0) {
       ++trace_count;
-      to->Add(attr, num_frames, &frame[0], count);
+      to->Add(attr, context, num_frames, &frame[0], count);
     }
   }
   return trace_count;
 }
 
-uint64 CalculateHash(int64 attr, int num_frames,
-                       const JVMPI_CallFrame *frame) {
+bool GetThreadListStackTraces(
+    jvmtiEnv *jvmti, const std::vector<jthread> &threads, int max_depth,
+    std::vector<std::vector<jvmtiFrameInfo>> *traces) {
+  traces->clear();
+  if (threads.empty()) {
+    return true;
+  }
+
+  // The stack info array and the frame buffers it points to are allocated
+  // as a single block by the JVM, so a single Deallocate releases them all.
+  JvmtiScopedPtr<jvmtiStackInfo> stack_info(jvmti);
+  jvmtiError err = jvmti->GetThreadListStackTraces(
+      threads.size(), threads.data(), max_depth, stack_info.GetRef());
+  if (err != JVMTI_ERROR_NONE) {
+    LOG(ERROR) << "GetThreadListStackTraces failed with error " << err;
+    return false;
+  }
+
+  traces->resize(threads.size());
+  const jvmtiStackInfo *info = stack_info.Get();
+  for (size_t i = 0; i < threads.size(); ++i) {
+    if (info[i].state & JVMTI_THREAD_STATE_TERMINATED) {
+      continue;
+    }
+    (*traces)[i].assign(info[i].frame_buffer,
+                        info[i].frame_buffer + info[i].frame_count);
+  }
+  return true;
+}
+
+int ToCallFrames(const std::vector<jvmtiFrameInfo> &trace, int max_frames,
+                 JVMPI_CallFrame *frames) {
+  // The JNI methods have a -3 line number in the AsyncGetCallTrace traces.
+  const jint kJniMethodLineNum = -3;
+  int num_frames = std::min<int>(trace.size(), max_frames);
+  for (int i = 0; i < num_frames; ++i) {
+    jlocation location = trace[i].location;
+    frames[i].lineno =
+        location >= 0 ? static_cast<jint>(location) : kJniMethodLineNum;
+    frames[i].method_id = trace[i].method;
+  }
+  return num_frames;
+}
+
+uint64 CalculateHash(int64 attr, const SampleContext &context, int num_frames,
+                     const JVMPI_CallFrame *frame) {
   // Make hash-value
   uint64 h = attr;
   h += h << 10;
   h ^= h >> 6;
+  h += context.thread_id;
+  h += h << 10;
+  h ^= h >> 6;
+  h += context.time_bucket;
+  h += h << 10;
+  h ^= h >> 6;
+  h += context.in_gc;
+  h += h << 10;
+  h ^= h >> 6;
+  h += context.truncated;
+  h += h << 10;
+  h ^= h >> 6;
   for (int i = 0; i < num_frames; i++) {
     h += reinterpret_cast<uintptr_t>(frame[i].method_id);
     h += h << 10;
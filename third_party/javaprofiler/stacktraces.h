@@ -33,8 +33,29 @@ namespace javaprofiler {
 // Maximum number of frames to store from the stack traces sampled.
 const int kMaxFramesToCapture = 128;
 
-uint64 CalculateHash(int64 attr, int num_frames,
-                       const JVMPI_CallFrame *frame);
+// Context of a sample, recorded along with its stack trace. Samples with the
+// same stack trace are only aggregated when their contexts are equal. Zero
+// fields mean the information was not recorded.
+struct SampleContext {
+  // Synthetic ID of the sampled thread, see Accessors::GetThreadId().
+  int64 thread_id;
+  // One-based index of the time bucket of the collection window the sample
+  // was taken in.
+  int64 time_bucket;
+  // Whether the sample was taken while a garbage collection was in progress.
+  bool in_gc;
+  // Whether AsyncGetCallTrace filled all the kMaxFramesToCapture frames, the
+  // actual Java stack then most likely being deeper.
+  bool truncated;
+};
+
+inline bool operator==(const SampleContext &c1, const SampleContext &c2) {
+  return c1.thread_id == c2.thread_id && c1.time_bucket == c2.time_bucket &&
+         c1.in_gc == c2.in_gc && c1.truncated == c2.truncated;
+}
+
+uint64 CalculateHash(int64 attr, const SampleContext &context, int num_frames,
+                     const JVMPI_CallFrame *frame);
 bool Equal(int num_frames, const JVMPI_CallFrame *f1,
            const JVMPI_CallFrame *f2);
 
@@ -125,7 +146,7 @@ class AsyncSafeTraceMultiset {
 
   // Add a trace to the set. If it is already present, increment its
   // count. This operation is thread safe and async safe.
-  bool Add(int attr, JVMPI_CallTrace *trace);
+  bool Add(int attr, const SampleContext &context, JVMPI_CallTrace *trace);
 
   // Extract a trace from the array. frames must point to at least
   // max_frames contiguous frames. It will return the number of frames
@@ -133,8 +154,8 @@ class AsyncSafeTraceMultiset {
   // there is no valid trace at this location.  This operation is
   // thread safe with respect to Add() but only a single call to
   // Extract can be done at a time.
-  int Extract(int location, int64 *attr, int max_frames,
-              JVMPI_CallFrame *frames, int64 *count);
+  int Extract(int location, int64 *attr, SampleContext *context,
+              int max_frames, JVMPI_CallFrame *frames, int64 *count);
 
   int64 MaxEntries() const { return kMaxStackTraces; }
 
@@ -143,6 +164,8 @@ class AsyncSafeTraceMultiset {
     // attr is an integer attribute for the stack trace. On encode
     // this will represent a sample label.
     int attr;
+    // context of the samples of the trace.
+    SampleContext context;
     // trace is a triple containing the JNIEnv and the individual call frames.
     // The frames are stored in frame_buffer.
     JVMPI_CallTrace trace;
@@ -178,18 +201,19 @@ class TraceMultiset {
   typedef struct {
     std::vector<JVMPI_CallFrame> frames;
     int64 attr;
+    SampleContext context;
   } CallTrace;
 
   struct CallTraceHash {
     std::size_t operator()(const CallTrace &trace) const {
-      return CalculateHash(trace.attr, trace.frames.size(),
+      return CalculateHash(trace.attr, trace.context, trace.frames.size(),
                            trace.frames.data());
     }
   };
 
   struct CallTraceEqual {
     bool operator()(const CallTrace &t1, const CallTrace &t2) const {
-      if (t1.attr != t2.attr) {
+      if (t1.attr != t2.attr || !(t1.context == t2.context)) {
         return false;
       }
       if (t1.frames.size() != t2.frames.size()) {
@@ -207,8 +231,8 @@ class TraceMultiset {
 
   // Add a trace to the array. If it is already in the array,
   // increment its count.
-  void Add(int64 attr, int num_frames, JVMPI_CallFrame *frames,
-           int64 count);
+  void Add(int64 attr, const SampleContext &context, int num_frames,
+           JVMPI_CallFrame *frames, int64 count);
 
   typedef CountMap::iterator iterator;
   typedef CountMap::const_iterator const_iterator;
@@ -223,6 +247,9 @@ class TraceMultiset {
 
   void Clear() { traces_.clear(); }
 
+  // Exchanges the traces with the other multiset.
+  void Swap(TraceMultiset *other) { traces_.swap(other->traces_); }
+
  private:
   CountMap traces_;
   DISALLOW_COPY_AND_ASSIGN(TraceMultiset);
@@ -234,6 +261,23 @@ class TraceMultiset {
 // samples into the asyncsafe set.
 int HarvestSamples(AsyncSafeTraceMultiset *from, TraceMultiset *to);
 
+// Collects the stack traces of a subset of threads with a single call to
+// GetThreadListStackTraces, which is cheaper than per-thread GetStackTrace
+// calls. Up to max_depth frames are returned for each thread, in the order
+// the threads were given. The trace of a thread that could not be walked
+// (e.g. it has terminated) is left empty. Returns false on a JVMTI error.
+bool GetThreadListStackTraces(jvmtiEnv *jvmti,
+                              const std::vector<jthread> &threads,
+                              int max_depth,
+                              std::vector<std::vector<jvmtiFrameInfo>> *traces);
+
+// Converts a trace returned by GetThreadListStackTraces to the form of the
+// AsyncGetCallTrace traces, keeping up to max_frames frames from the leaf.
+// The native methods, which have no bytecode location, get the -3 line
+// number AsyncGetCallTrace gives them. Returns the number of frames set.
+int ToCallFrames(const std::vector<jvmtiFrameInfo> &trace, int max_frames,
+                 JVMPI_CallFrame *frames);
+
 }  // namespace javaprofiler
 }  // namespace google
 
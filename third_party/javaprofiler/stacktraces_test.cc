@@ -0,0 +1,174 @@
+/*
+ * Copyright 2018 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "third_party/javaprofiler/stacktraces.h"
+
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include <algorithm>
+#include <vector>
+
+#include "gtest/gtest.h"
+
+namespace google {
+namespace javaprofiler {
+namespace {
+
+// The fake thread references are the thread IDs. The thread of ID n has n
+// frames, except the one of ID kTerminatedThread which has terminated.
+const intptr_t kTerminatedThread = 3;
+
+jthread FakeThread(intptr_t id) { return reinterpret_cast<jthread>(id); }
+
+jmethodID FakeMethod(intptr_t thread_id, int depth) {
+  return reinterpret_cast<jmethodID>(thread_id * 1000 + depth);
+}
+
+int deallocations;
+
+jvmtiError JNICALL FakeDeallocate(jvmtiEnv *env, unsigned char *mem) {
+  free(mem);
+  deallocations++;
+  return JVMTI_ERROR_NONE;
+}
+
+jvmtiError JNICALL FakeGetThreadListStackTraces(
+    jvmtiEnv *env, jint thread_count, const jthread *thread_list,
+    jint max_frame_count, jvmtiStackInfo **stack_info_ptr) {
+  // As from the JVM, the stack infos and their frames are a single block.
+  size_t infos_size = thread_count * sizeof(jvmtiStackInfo);
+  char *block = static_cast<char *>(malloc(
+      infos_size + thread_count * max_frame_count * sizeof(jvmtiFrameInfo)));
+  jvmtiStackInfo *infos = reinterpret_cast<jvmtiStackInfo *>(block);
+  jvmtiFrameInfo *frames =
+      reinterpret_cast<jvmtiFrameInfo *>(block + infos_size);
+  for (jint i = 0; i < thread_count; i++) {
+    intptr_t id = reinterpret_cast<intptr_t>(thread_list[i]);
+    jvmtiStackInfo *info = &infos[i];
+    info->thread = thread_list[i];
+    info->frame_buffer = frames + i * max_frame_count;
+    if (id == kTerminatedThread) {
+      info->state = JVMTI_THREAD_STATE_TERMINATED;
+      info->frame_count = 0;
+      continue;
+    }
+    info->state = JVMTI_THREAD_STATE_ALIVE | JVMTI_THREAD_STATE_RUNNABLE;
+    info->frame_count = std::min<jint>(id, max_frame_count);
+    for (jint j = 0; j < info->frame_count; j++) {
+      info->frame_buffer[j].method = FakeMethod(id, j);
+      info->frame_buffer[j].location = j;
+    }
+  }
+  *stack_info_ptr = infos;
+  return JVMTI_ERROR_NONE;
+}
+
+class GetThreadListStackTracesTest : public ::testing::Test {
+ protected:
+  void SetUp() override {
+    memset(&functions_, 0, sizeof(functions_));
+    functions_.GetThreadListStackTraces = &FakeGetThreadListStackTraces;
+    functions_.Deallocate = &FakeDeallocate;
+    jvmti_.functions = &functions_;
+    deallocations = 0;
+  }
+
+  jvmtiInterface_1 functions_;
+  jvmtiEnv jvmti_;
+};
+
+TEST_F(GetThreadListStackTracesTest, ReturnsTheTracesOfTheSubset) {
+  std::vector<jthread> threads = {FakeThread(2), FakeThread(5)};
+  std::vector<std::vector<jvmtiFrameInfo>> traces;
+  ASSERT_TRUE(GetThreadListStackTraces(&jvmti_, threads, 128, &traces));
+
+  ASSERT_EQ(2u, traces.size());
+  ASSERT_EQ(2u, traces[0].size());
+  ASSERT_EQ(5u, traces[1].size());
+  for (int j = 0; j < 5; j++) {
+    EXPECT_EQ(FakeMethod(5, j), traces[1][j].method);
+    EXPECT_EQ(j, traces[1][j].location);
+  }
+  EXPECT_EQ(FakeMethod(2, 1), traces[0][1].method);
+  // The stack infos and the frames are released at once.
+  EXPECT_EQ(1, deallocations);
+}
+
+TEST_F(GetThreadListStackTracesTest, LeavesTheTerminatedThreadsEmpty) {
+  std::vector<jthread> threads = {FakeThread(1), FakeThread(kTerminatedThread),
+                                  FakeThread(4)};
+  std::vector<std::vector<jvmtiFrameInfo>> traces;
+  ASSERT_TRUE(GetThreadListStackTraces(&jvmti_, threads, 128, &traces));
+
+  ASSERT_EQ(3u, traces.size());
+  EXPECT_EQ(1u, traces[0].size());
+  EXPECT_TRUE(traces[1].empty());
+  EXPECT_EQ(4u, traces[2].size());
+}
+
+TEST_F(GetThreadListStackTracesTest, BoundsTheDepth) {
+  std::vector<jthread> threads = {FakeThread(6)};
+  std::vector<std::vector<jvmtiFrameInfo>> traces;
+  ASSERT_TRUE(GetThreadListStackTraces(&jvmti_, threads, 4, &traces));
+
+  ASSERT_EQ(1u, traces.size());
+  EXPECT_EQ(4u, traces[0].size());
+}
+
+TEST_F(GetThreadListStackTracesTest, SkipsTheCallWithoutThreads) {
+  functions_.GetThreadListStackTraces = nullptr;
+  std::vector<std::vector<jvmtiFrameInfo>> traces(1);
+  ASSERT_TRUE(GetThreadListStackTraces(&jvmti_, {}, 128, &traces));
+  EXPECT_TRUE(traces.empty());
+  EXPECT_EQ(0, deallocations);
+}
+
+TEST(ToCallFramesTest, ConvertsTheLocations) {
+  std::vector<jvmtiFrameInfo> trace(3);
+  trace[0].method = FakeMethod(1, 0);
+  trace[0].location = -1;  // A native method.
+  trace[1].method = FakeMethod(1, 1);
+  trace[1].location = 0;
+  trace[2].method = FakeMethod(1, 2);
+  trace[2].location = 42;
+
+  JVMPI_CallFrame frames[3];
+  ASSERT_EQ(3, ToCallFrames(trace, 3, frames));
+  EXPECT_EQ(-3, frames[0].lineno);
+  EXPECT_EQ(FakeMethod(1, 0), frames[0].method_id);
+  EXPECT_EQ(0, frames[1].lineno);
+  EXPECT_EQ(42, frames[2].lineno);
+  EXPECT_EQ(FakeMethod(1, 2), frames[2].method_id);
+}
+
+TEST(ToCallFramesTest, KeepsTheLeafFrames) {
+  std::vector<jvmtiFrameInfo> trace(5);
+  for (int j = 0; j < 5; j++) {
+    trace[j].method = FakeMethod(1, j);
+    trace[j].location = j;
+  }
+
+  JVMPI_CallFrame frames[2];
+  ASSERT_EQ(2, ToCallFrames(trace, 2, frames));
+  EXPECT_EQ(FakeMethod(1, 0), frames[0].method_id);
+  EXPECT_EQ(FakeMethod(1, 1), frames[1].method_id);
+}
+
+}  // namespace
+}  // namespace javaprofiler
+}  // namespace google